@@ -0,0 +1,37 @@
+#![feature(test)]
+
+extern crate oxipng;
+extern crate test;
+
+use oxipng::*;
+use std::fs;
+use std::sync::Arc;
+use test::Bencher;
+
+#[bench]
+fn buffer_pool_fresh(b: &mut Bencher) {
+    let input = test::black_box(fs::read("tests/files/rgb_16_should_be_rgb_8.png").unwrap());
+    let opts = Options::default();
+
+    b.iter(|| optimize_from_memory(&input, &opts).ok());
+}
+
+#[bench]
+fn buffer_pool_shared(b: &mut Bencher) {
+    let input = test::black_box(fs::read("tests/files/rgb_16_should_be_rgb_8.png").unwrap());
+    let pool = Arc::new(BufferPool::new());
+    let opts = Options {
+        buffer_pool: Some(pool.clone()),
+        ..Options::default()
+    };
+
+    // The first run fills the pool, which later runs should mostly take their buffers from
+    optimize_from_memory(&input, &opts).ok();
+    let first_allocations = pool.allocations();
+    let mut runs = 0;
+    b.iter(|| {
+        runs += 1;
+        optimize_from_memory(&input, &opts).ok()
+    });
+    assert!((pool.allocations() - first_allocations) / runs < first_allocations);
+}
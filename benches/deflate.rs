@@ -15,7 +15,7 @@ fn deflate_16_bits(b: &mut Bencher) {
 
     b.iter(|| {
         let min = AtomicMin::new(None);
-        deflate(png.raw.data.as_ref(), 12, &min)
+        deflate(png.raw().data().as_ref(), 12, &min)
     });
 }
 
@@ -26,7 +26,7 @@ fn deflate_8_bits(b: &mut Bencher) {
 
     b.iter(|| {
         let min = AtomicMin::new(None);
-        deflate(png.raw.data.as_ref(), 12, &min)
+        deflate(png.raw().data().as_ref(), 12, &min)
     });
 }
 
@@ -39,7 +39,7 @@ fn deflate_4_bits(b: &mut Bencher) {
 
     b.iter(|| {
         let min = AtomicMin::new(None);
-        deflate(png.raw.data.as_ref(), 12, &min)
+        deflate(png.raw().data().as_ref(), 12, &min)
     });
 }
 
@@ -52,7 +52,7 @@ fn deflate_2_bits(b: &mut Bencher) {
 
     b.iter(|| {
         let min = AtomicMin::new(None);
-        deflate(png.raw.data.as_ref(), 12, &min)
+        deflate(png.raw().data().as_ref(), 12, &min)
     });
 }
 
@@ -65,7 +65,7 @@ fn deflate_1_bits(b: &mut Bencher) {
 
     b.iter(|| {
         let min = AtomicMin::new(None);
-        deflate(png.raw.data.as_ref(), 12, &min)
+        deflate(png.raw().data().as_ref(), 12, &min)
     });
 }
 
@@ -74,5 +74,14 @@ fn inflate_generic(b: &mut Bencher) {
     let input = test::black_box(PathBuf::from("tests/files/rgb_16_should_be_rgb_16.png"));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| inflate(png.idat_data.as_ref(), png.raw.ihdr.raw_data_size()));
+    b.iter(|| inflate(png.idat_data().as_ref(), png.raw().ihdr().raw_data_size()));
+}
+
+#[bench]
+fn crc32_generic(b: &mut Bencher) {
+    let input = test::black_box(PathBuf::from("tests/files/rgb_16_should_be_rgb_16.png"));
+    let png = PngData::new(&input, &Options::default()).unwrap();
+
+    b.bytes = png.raw().data().len() as u64;
+    b.iter(|| crc32(png.raw().data().as_ref()));
 }
@@ -14,7 +14,7 @@ fn filters_16_bits_filter_0(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::None, false);
+        png.raw().filter_image(RowFilter::None, false);
     });
 }
 
@@ -24,7 +24,7 @@ fn filters_8_bits_filter_0(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::None, false);
+        png.raw().filter_image(RowFilter::None, false);
     });
 }
 
@@ -36,7 +36,7 @@ fn filters_4_bits_filter_0(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::None, false);
+        png.raw().filter_image(RowFilter::None, false);
     });
 }
 
@@ -48,7 +48,7 @@ fn filters_2_bits_filter_0(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::None, false);
+        png.raw().filter_image(RowFilter::None, false);
     });
 }
 
@@ -60,7 +60,7 @@ fn filters_1_bits_filter_0(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::None, false);
+        png.raw().filter_image(RowFilter::None, false);
     });
 }
 
@@ -70,7 +70,7 @@ fn filters_16_bits_filter_1(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Sub, false);
+        png.raw().filter_image(RowFilter::Sub, false);
     });
 }
 
@@ -80,7 +80,7 @@ fn filters_8_bits_filter_1(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Sub, false);
+        png.raw().filter_image(RowFilter::Sub, false);
     });
 }
 
@@ -92,7 +92,7 @@ fn filters_4_bits_filter_1(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Sub, false);
+        png.raw().filter_image(RowFilter::Sub, false);
     });
 }
 
@@ -104,7 +104,7 @@ fn filters_2_bits_filter_1(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Sub, false);
+        png.raw().filter_image(RowFilter::Sub, false);
     });
 }
 
@@ -116,7 +116,7 @@ fn filters_1_bits_filter_1(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Sub, false);
+        png.raw().filter_image(RowFilter::Sub, false);
     });
 }
 
@@ -126,7 +126,7 @@ fn filters_16_bits_filter_2(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Up, false);
+        png.raw().filter_image(RowFilter::Up, false);
     });
 }
 
@@ -136,7 +136,7 @@ fn filters_8_bits_filter_2(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Up, false);
+        png.raw().filter_image(RowFilter::Up, false);
     });
 }
 
@@ -148,7 +148,7 @@ fn filters_4_bits_filter_2(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Up, false);
+        png.raw().filter_image(RowFilter::Up, false);
     });
 }
 
@@ -160,7 +160,7 @@ fn filters_2_bits_filter_2(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Up, false);
+        png.raw().filter_image(RowFilter::Up, false);
     });
 }
 
@@ -172,7 +172,7 @@ fn filters_1_bits_filter_2(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Up, false);
+        png.raw().filter_image(RowFilter::Up, false);
     });
 }
 
@@ -182,7 +182,7 @@ fn filters_16_bits_filter_3(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Average, false);
+        png.raw().filter_image(RowFilter::Average, false);
     });
 }
 
@@ -192,7 +192,7 @@ fn filters_8_bits_filter_3(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Average, false);
+        png.raw().filter_image(RowFilter::Average, false);
     });
 }
 
@@ -204,7 +204,7 @@ fn filters_4_bits_filter_3(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Average, false);
+        png.raw().filter_image(RowFilter::Average, false);
     });
 }
 
@@ -216,7 +216,7 @@ fn filters_2_bits_filter_3(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Average, false);
+        png.raw().filter_image(RowFilter::Average, false);
     });
 }
 
@@ -228,7 +228,7 @@ fn filters_1_bits_filter_3(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Average, false);
+        png.raw().filter_image(RowFilter::Average, false);
     });
 }
 
@@ -238,7 +238,7 @@ fn filters_16_bits_filter_4(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Paeth, false);
+        png.raw().filter_image(RowFilter::Paeth, false);
     });
 }
 
@@ -248,7 +248,7 @@ fn filters_8_bits_filter_4(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Paeth, false);
+        png.raw().filter_image(RowFilter::Paeth, false);
     });
 }
 
@@ -260,7 +260,7 @@ fn filters_4_bits_filter_4(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Paeth, false);
+        png.raw().filter_image(RowFilter::Paeth, false);
     });
 }
 
@@ -272,7 +272,7 @@ fn filters_2_bits_filter_4(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Paeth, false);
+        png.raw().filter_image(RowFilter::Paeth, false);
     });
 }
 
@@ -284,7 +284,7 @@ fn filters_1_bits_filter_4(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Paeth, false);
+        png.raw().filter_image(RowFilter::Paeth, false);
     });
 }
 
@@ -294,7 +294,7 @@ fn filters_16_bits_filter_5(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::MinSum, false);
+        png.raw().filter_image(RowFilter::MinSum, false);
     });
 }
 
@@ -304,7 +304,7 @@ fn filters_8_bits_filter_5(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::MinSum, false);
+        png.raw().filter_image(RowFilter::MinSum, false);
     });
 }
 
@@ -316,7 +316,7 @@ fn filters_4_bits_filter_5(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::MinSum, false);
+        png.raw().filter_image(RowFilter::MinSum, false);
     });
 }
 
@@ -328,7 +328,7 @@ fn filters_2_bits_filter_5(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::MinSum, false);
+        png.raw().filter_image(RowFilter::MinSum, false);
     });
 }
 
@@ -340,6 +340,56 @@ fn filters_1_bits_filter_5(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::MinSum, false);
+        png.raw().filter_image(RowFilter::MinSum, false);
+    });
+}
+
+fn large_rgba_image() -> PngImage {
+    let ihdr = IhdrData {
+        width: 4096,
+        height: 4096,
+        color_type: ColorType::RGBA,
+        bit_depth: BitDepth::Eight,
+        interlaced: Interlacing::None,
+    };
+    let data = (0..4096 * 4096 * 4)
+        .map(|i: u32| (i ^ (i >> 13)) as u8)
+        .collect();
+    PngImage::new(ihdr, data).unwrap()
+}
+
+#[bench]
+fn filters_4096_rgba_filter_3(b: &mut Bencher) {
+    let png = test::black_box(large_rgba_image());
+
+    b.iter(|| {
+        png.filter_image(RowFilter::Average, false);
+    });
+}
+
+#[bench]
+fn filters_4096_rgba_filter_4(b: &mut Bencher) {
+    let png = test::black_box(large_rgba_image());
+
+    b.iter(|| {
+        png.filter_image(RowFilter::Paeth, false);
+    });
+}
+
+#[bench]
+fn filters_4096_rgba_filter_5(b: &mut Bencher) {
+    let png = test::black_box(large_rgba_image());
+
+    b.iter(|| {
+        png.filter_image(RowFilter::MinSum, false);
+    });
+}
+
+#[bench]
+fn filters_4096_rgba_filter_7(b: &mut Bencher) {
+    let png = test::black_box(large_rgba_image());
+
+    b.iter(|| {
+        png.filter_image(RowFilter::Bigrams, false);
     });
 }
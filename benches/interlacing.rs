@@ -13,7 +13,7 @@ fn interlacing_16_bits(b: &mut Bencher) {
     let input = test::black_box(PathBuf::from("tests/files/rgb_16_should_be_rgb_16.png"));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| png.raw.change_interlacing(Interlacing::Adam7));
+    b.iter(|| png.raw().change_interlacing(Interlacing::Adam7));
 }
 
 #[bench]
@@ -21,7 +21,7 @@ fn interlacing_8_bits(b: &mut Bencher) {
     let input = test::black_box(PathBuf::from("tests/files/rgb_8_should_be_rgb_8.png"));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| png.raw.change_interlacing(Interlacing::Adam7));
+    b.iter(|| png.raw().change_interlacing(Interlacing::Adam7));
 }
 
 #[bench]
@@ -31,7 +31,7 @@ fn interlacing_4_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| png.raw.change_interlacing(Interlacing::Adam7));
+    b.iter(|| png.raw().change_interlacing(Interlacing::Adam7));
 }
 
 #[bench]
@@ -41,7 +41,7 @@ fn interlacing_2_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| png.raw.change_interlacing(Interlacing::Adam7));
+    b.iter(|| png.raw().change_interlacing(Interlacing::Adam7));
 }
 
 #[bench]
@@ -51,7 +51,7 @@ fn interlacing_1_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| png.raw.change_interlacing(Interlacing::Adam7));
+    b.iter(|| png.raw().change_interlacing(Interlacing::Adam7));
 }
 
 #[bench]
@@ -61,7 +61,7 @@ fn deinterlacing_16_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| png.raw.change_interlacing(Interlacing::None));
+    b.iter(|| png.raw().change_interlacing(Interlacing::None));
 }
 
 #[bench]
@@ -71,7 +71,7 @@ fn deinterlacing_8_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| png.raw.change_interlacing(Interlacing::None));
+    b.iter(|| png.raw().change_interlacing(Interlacing::None));
 }
 
 #[bench]
@@ -81,7 +81,7 @@ fn deinterlacing_4_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| png.raw.change_interlacing(Interlacing::None));
+    b.iter(|| png.raw().change_interlacing(Interlacing::None));
 }
 
 #[bench]
@@ -91,7 +91,7 @@ fn deinterlacing_2_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| png.raw.change_interlacing(Interlacing::None));
+    b.iter(|| png.raw().change_interlacing(Interlacing::None));
 }
 
 #[bench]
@@ -101,5 +101,22 @@ fn deinterlacing_1_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| png.raw.change_interlacing(Interlacing::None));
+    b.iter(|| png.raw().change_interlacing(Interlacing::None));
+}
+
+#[bench]
+fn interlacing_fixed_optimize(b: &mut Bencher) {
+    let input = std::fs::read("tests/files/interlaced_rgb_8_should_be_rgb_8.png").unwrap();
+    let opts = Options::from_preset(1);
+
+    b.iter(|| optimize_from_memory(&input, &opts));
+}
+
+#[bench]
+fn interlacing_auto_optimize(b: &mut Bencher) {
+    let input = std::fs::read("tests/files/interlaced_rgb_8_should_be_rgb_8.png").unwrap();
+    let mut opts = Options::from_preset(1);
+    opts.interlace_auto = true;
+
+    b.iter(|| optimize_from_memory(&input, &opts));
 }
@@ -13,7 +13,7 @@ fn reductions_16_to_8_bits(b: &mut Bencher) {
     let input = test::black_box(PathBuf::from("tests/files/rgb_16_should_be_rgb_8.png"));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| bit_depth::reduced_bit_depth_16_to_8(&png.raw, false));
+    b.iter(|| bit_depth::reduced_bit_depth_16_to_8(&png.raw(), false));
 }
 
 #[bench]
@@ -21,7 +21,7 @@ fn reductions_16_to_8_bits_scaled(b: &mut Bencher) {
     let input = test::black_box(PathBuf::from("tests/files/rgb_16_should_be_rgb_16.png"));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| bit_depth::reduced_bit_depth_16_to_8(&png.raw, true));
+    b.iter(|| bit_depth::reduced_bit_depth_16_to_8(&png.raw(), true));
 }
 
 #[bench]
@@ -31,7 +31,7 @@ fn reductions_8_to_4_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw));
+    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw()));
 }
 
 #[bench]
@@ -41,7 +41,7 @@ fn reductions_8_to_2_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw));
+    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw()));
 }
 
 #[bench]
@@ -51,7 +51,7 @@ fn reductions_8_to_1_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw));
+    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw()));
 }
 
 #[bench]
@@ -61,7 +61,7 @@ fn reductions_4_to_2_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw));
+    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw()));
 }
 
 #[bench]
@@ -71,7 +71,7 @@ fn reductions_4_to_1_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw));
+    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw()));
 }
 
 #[bench]
@@ -81,7 +81,7 @@ fn reductions_2_to_1_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw));
+    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw()));
 }
 
 #[bench]
@@ -91,7 +91,7 @@ fn reductions_grayscale_8_to_4_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw));
+    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw()));
 }
 
 #[bench]
@@ -101,7 +101,7 @@ fn reductions_grayscale_8_to_2_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw));
+    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw()));
 }
 
 #[bench]
@@ -111,7 +111,7 @@ fn reductions_grayscale_8_to_1_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw));
+    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw()));
 }
 
 #[bench]
@@ -121,7 +121,7 @@ fn reductions_grayscale_4_to_2_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw));
+    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw()));
 }
 
 #[bench]
@@ -131,7 +131,7 @@ fn reductions_grayscale_4_to_1_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw));
+    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw()));
 }
 
 #[bench]
@@ -141,7 +141,7 @@ fn reductions_grayscale_2_to_1_bits(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw));
+    b.iter(|| bit_depth::reduced_bit_depth_8_or_less(&png.raw()));
 }
 
 #[bench]
@@ -149,7 +149,7 @@ fn reductions_rgba_to_rgb_16(b: &mut Bencher) {
     let input = test::black_box(PathBuf::from("tests/files/rgba_16_should_be_rgb_16.png"));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| alpha::reduced_alpha_channel(&png.raw, false));
+    b.iter(|| alpha::reduced_alpha_channel(&png.raw(), false));
 }
 
 #[bench]
@@ -157,7 +157,7 @@ fn reductions_rgba_to_rgb_8(b: &mut Bencher) {
     let input = test::black_box(PathBuf::from("tests/files/rgba_8_should_be_rgb_8.png"));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| alpha::reduced_alpha_channel(&png.raw, false));
+    b.iter(|| alpha::reduced_alpha_channel(&png.raw(), false));
 }
 
 #[bench]
@@ -167,7 +167,7 @@ fn reductions_rgba_to_grayscale_alpha_16(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| color::reduced_rgb_to_grayscale(&png.raw));
+    b.iter(|| color::reduced_rgb_to_grayscale(&png.raw()));
 }
 
 #[bench]
@@ -177,7 +177,7 @@ fn reductions_rgba_to_grayscale_alpha_8(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| color::reduced_rgb_to_grayscale(&png.raw));
+    b.iter(|| color::reduced_rgb_to_grayscale(&png.raw()));
 }
 
 #[bench]
@@ -188,7 +188,7 @@ fn reductions_rgba_to_grayscale_16(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        color::reduced_rgb_to_grayscale(&png.raw)
+        color::reduced_rgb_to_grayscale(&png.raw())
             .and_then(|r| alpha::reduced_alpha_channel(&r, false))
     });
 }
@@ -201,7 +201,7 @@ fn reductions_rgba_to_grayscale_8(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        color::reduced_rgb_to_grayscale(&png.raw)
+        color::reduced_rgb_to_grayscale(&png.raw())
             .and_then(|r| alpha::reduced_alpha_channel(&r, false))
     });
 }
@@ -213,7 +213,7 @@ fn reductions_rgb_to_grayscale_16(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| color::reduced_rgb_to_grayscale(&png.raw));
+    b.iter(|| color::reduced_rgb_to_grayscale(&png.raw()));
 }
 
 #[bench]
@@ -221,7 +221,7 @@ fn reductions_rgb_to_grayscale_8(b: &mut Bencher) {
     let input = test::black_box(PathBuf::from("tests/files/rgb_8_should_be_grayscale_8.png"));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| color::reduced_rgb_to_grayscale(&png.raw));
+    b.iter(|| color::reduced_rgb_to_grayscale(&png.raw()));
 }
 
 #[bench]
@@ -229,7 +229,7 @@ fn reductions_rgba_to_palette_8(b: &mut Bencher) {
     let input = test::black_box(PathBuf::from("tests/files/rgba_8_should_be_palette_8.png"));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| color::reduced_to_indexed(&png.raw, true));
+    b.iter(|| color::reduced_to_indexed(&png.raw(), true));
 }
 
 #[bench]
@@ -237,7 +237,7 @@ fn reductions_rgb_to_palette_8(b: &mut Bencher) {
     let input = test::black_box(PathBuf::from("tests/files/rgb_8_should_be_palette_8.png"));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| color::reduced_to_indexed(&png.raw, true));
+    b.iter(|| color::reduced_to_indexed(&png.raw(), true));
 }
 
 #[bench]
@@ -247,7 +247,7 @@ fn reductions_grayscale_8_to_palette_8(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| color::reduced_to_indexed(&png.raw, true));
+    b.iter(|| color::reduced_to_indexed(&png.raw(), true));
 }
 
 #[bench]
@@ -257,7 +257,7 @@ fn reductions_palette_8_to_grayscale_8(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| color::indexed_to_channels(&png.raw, true));
+    b.iter(|| color::indexed_to_channels(&png.raw(), true));
 }
 
 #[bench]
@@ -267,7 +267,7 @@ fn reductions_palette_duplicate_reduction(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| palette::reduced_palette(&png.raw, false));
+    b.iter(|| palette::reduced_palette(&png.raw(), false));
 }
 
 #[bench]
@@ -277,7 +277,7 @@ fn reductions_palette_unused_reduction(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| palette::reduced_palette(&png.raw, false));
+    b.iter(|| palette::reduced_palette(&png.raw(), false));
 }
 
 #[bench]
@@ -287,7 +287,7 @@ fn reductions_palette_full_reduction(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| palette::reduced_palette(&png.raw, false));
+    b.iter(|| palette::reduced_palette(&png.raw(), false));
 }
 
 #[bench]
@@ -297,7 +297,7 @@ fn reductions_palette_sort(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| palette::sorted_palette(&png.raw));
+    b.iter(|| palette::sorted_palette(&png.raw()));
 }
 
 #[bench]
@@ -305,5 +305,13 @@ fn reductions_alpha(b: &mut Bencher) {
     let input = test::black_box(PathBuf::from("tests/files/rgba_8_reduce_alpha.png"));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| alpha::cleaned_alpha_channel(&png.raw));
+    b.iter(|| alpha::cleaned_alpha_channel(&png.raw()));
+}
+
+#[bench]
+fn reductions_alpha_aggressive(b: &mut Bencher) {
+    let input = test::black_box(PathBuf::from("tests/files/rgba_8_reduce_alpha.png"));
+    let png = PngData::new(&input, &Options::default()).unwrap();
+
+    b.iter(|| alpha::aggressively_cleaned_alpha_channel(&png.raw()));
 }
@@ -14,7 +14,7 @@ fn filters_minsum(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::MinSum, false);
+        png.raw().filter_image(RowFilter::MinSum, false);
     });
 }
 
@@ -24,7 +24,7 @@ fn filters_entropy(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Entropy, false);
+        png.raw().filter_image(RowFilter::Entropy, false);
     });
 }
 
@@ -34,7 +34,7 @@ fn filters_bigrams(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Bigrams, false);
+        png.raw().filter_image(RowFilter::Bigrams, false);
     });
 }
 
@@ -44,7 +44,7 @@ fn filters_bigent(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::BigEnt, false);
+        png.raw().filter_image(RowFilter::BigEnt, false);
     });
 }
 
@@ -54,6 +54,82 @@ fn filters_brute(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        png.raw.filter_image(RowFilter::Brute, false);
+        png.raw().filter_image(RowFilter::Brute, false);
     });
 }
+
+#[bench]
+fn filters_weighted_msad(b: &mut Bencher) {
+    let input = test::black_box(PathBuf::from("tests/files/rgb_8_should_be_rgb_8.png"));
+    let png = PngData::new(&input, &Options::default()).unwrap();
+
+    b.iter(|| {
+        png.raw().filter_image(RowFilter::WeightedMsad, false);
+    });
+}
+
+#[bench]
+fn filters_lookback(b: &mut Bencher) {
+    let input = test::black_box(PathBuf::from("tests/files/rgb_8_should_be_rgb_8.png"));
+    let png = PngData::new(&input, &Options::default()).unwrap();
+
+    b.iter(|| {
+        png.raw().filter_image(RowFilter::LookBack, false);
+    });
+}
+
+#[bench]
+fn strategies_full_trials(b: &mut Bencher) {
+    let input = std::fs::read("tests/files/issue-195.png").unwrap();
+    let opts = Options::from_preset(4);
+
+    b.iter(|| optimize_from_memory(&input, &opts));
+}
+
+#[bench]
+fn strategies_trial_compression(b: &mut Bencher) {
+    let input = std::fs::read("tests/files/issue-195.png").unwrap();
+    let opts = Options {
+        trial_compression: Some(7),
+        ..Options::from_preset(4)
+    };
+
+    b.iter(|| optimize_from_memory(&input, &opts));
+}
+
+#[bench]
+fn strategies_converging(b: &mut Bencher) {
+    // Small noise which brightens every few rows, where the Entropy, Bigrams and BigEnt
+    // strategies all produce the same filtered data
+    let mut state = 1u32;
+    let row: Vec<u8> = (0..512)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state % 8) as u8 + 100
+        })
+        .collect();
+    let data: Vec<u8> = (0..256u32)
+        .flat_map(|y| row.iter().map(move |v| v + (y / 4) as u8))
+        .collect();
+    let image = RawImage::new(
+        512,
+        256,
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Eight,
+        data,
+    )
+    .unwrap();
+    let opts = Options {
+        filter: [RowFilter::Entropy, RowFilter::Bigrams, RowFilter::BigEnt]
+            .into_iter()
+            .collect(),
+        color_type_reduction: false,
+        ..Options::from_preset(4)
+    };
+
+    b.iter(|| image.create_optimized_png(&opts));
+}
@@ -18,7 +18,7 @@ fn zopfli_16_bits_strategy_0(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        zopfli_deflate(png.raw.data.as_ref(), DEFAULT_ZOPFLI_ITERATIONS).ok();
+        zopfli_deflate(png.raw().data().as_ref(), DEFAULT_ZOPFLI_ITERATIONS).ok();
     });
 }
 
@@ -28,7 +28,7 @@ fn zopfli_8_bits_strategy_0(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        zopfli_deflate(png.raw.data.as_ref(), DEFAULT_ZOPFLI_ITERATIONS).ok();
+        zopfli_deflate(png.raw().data().as_ref(), DEFAULT_ZOPFLI_ITERATIONS).ok();
     });
 }
 
@@ -40,7 +40,7 @@ fn zopfli_4_bits_strategy_0(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        zopfli_deflate(png.raw.data.as_ref(), DEFAULT_ZOPFLI_ITERATIONS).ok();
+        zopfli_deflate(png.raw().data().as_ref(), DEFAULT_ZOPFLI_ITERATIONS).ok();
     });
 }
 
@@ -52,7 +52,7 @@ fn zopfli_2_bits_strategy_0(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        zopfli_deflate(png.raw.data.as_ref(), DEFAULT_ZOPFLI_ITERATIONS).ok();
+        zopfli_deflate(png.raw().data().as_ref(), DEFAULT_ZOPFLI_ITERATIONS).ok();
     });
 }
 
@@ -64,6 +64,33 @@ fn zopfli_1_bits_strategy_0(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        zopfli_deflate(png.raw.data.as_ref(), DEFAULT_ZOPFLI_ITERATIONS).ok();
+        zopfli_deflate(png.raw().data().as_ref(), DEFAULT_ZOPFLI_ITERATIONS).ok();
+    });
+}
+
+/// Enough image data to be compressed in several sections in parallel
+fn large_filtered_data() -> Vec<u8> {
+    let input = PathBuf::from("tests/files/issue-141.png");
+    let png = PngData::new(&input, &Options::default()).unwrap();
+    let mut data = png.raw().filter_image(RowFilter::None, false);
+    data.truncate(3_000_000);
+    data
+}
+
+#[bench]
+fn zopfli_large_sequential(b: &mut Bencher) {
+    let data = large_filtered_data();
+
+    b.iter(|| {
+        zopfli_deflate_raw_sequential(&data, NonZeroU8::new(1).unwrap()).ok();
+    });
+}
+
+#[bench]
+fn zopfli_large_parallel(b: &mut Bencher) {
+    let data = large_filtered_data();
+
+    b.iter(|| {
+        zopfli_deflate_raw(&data, NonZeroU8::new(1).unwrap()).ok();
     });
 }
@@ -0,0 +1,12 @@
+use std::env;
+
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(threads)");
+    // Work is spread across threads unless the single-threaded feature overrides parallel, which
+    // may have been enabled by another crate depending on oxipng with its default features
+    if env::var_os("CARGO_FEATURE_PARALLEL").is_some()
+        && env::var_os("CARGO_FEATURE_SINGLE_THREADED").is_none()
+    {
+        println!("cargo:rustc-cfg=threads");
+    }
+}
@@ -0,0 +1,104 @@
+//! Conversion of samples between bit depths
+
+use alloc::vec::Vec;
+
+/// Scale a 16-bit sample to 8 bits, rounding to the nearest value
+#[must_use]
+pub fn scale_sample(val: u16) -> u8 {
+    // See: http://www.libpng.org/pub/png/spec/1.2/PNG-Decoders.html#D.Sample-depth-rescaling
+    // This allows values such as 0x00FF to be rounded to 0x01 rather than truncated to 0x00
+    // The exact quotient is never halfway between two integers, so this is the same as rounding
+    ((u32::from(val) * 255 + 32767) / 65535) as u8
+}
+
+/// Expand a sample of `bits` bits to 8 bits by repeating its bits, as for grayscale
+#[must_use]
+pub fn replicate_bits(mut val: u8, bits: u32) -> u8 {
+    let mut depth = bits;
+    while depth < 8 {
+        val = val << depth | val;
+        depth <<= 1;
+    }
+    val
+}
+
+/// The lowest bit depth of 1, 2, 4 or 8 that can represent every 8-bit grayscale sample exactly,
+/// as the repetition of its bits
+#[must_use]
+pub fn minimum_gray_bit_depth<'a>(samples: impl IntoIterator<Item = &'a u8>) -> u32 {
+    let mut minimum_bits = 1;
+    let mut mask = 1;
+    let mut divisions = 1..8;
+    for &b in samples {
+        if b == 0 || b == 255 {
+            continue;
+        }
+        'try_depth: loop {
+            // Align the first pixel division with the mask
+            let mut byte = b.rotate_left(minimum_bits);
+            // Each potential division of this pixel must be identical to successfully reduce
+            let compare = byte & mask;
+            for _ in divisions.clone() {
+                // Align the next division with the mask
+                byte = byte.rotate_left(minimum_bits);
+                if byte & mask != compare {
+                    // This depth is not possible, try the next one up
+                    minimum_bits <<= 1;
+                    if minimum_bits == 8 {
+                        return 8;
+                    }
+                    mask = (1 << minimum_bits) - 1;
+                    divisions = 1..(8 / minimum_bits);
+                    continue 'try_depth;
+                }
+            }
+            break;
+        }
+    }
+    minimum_bits
+}
+
+/// Pack the low `bits` bits of each 8-bit sample of a line, appending the result to `out`
+///
+/// The last byte is padded with zeros if the samples don't fill it.
+pub fn pack_line(samples: &[u8], bits: u32, out: &mut Vec<u8>) {
+    let mask = (1 << bits) - 1;
+    // Loop over the data in chunks that will produce 1 byte of output
+    for chunk in samples.chunks(8 / bits as usize) {
+        let mut new_byte = 0;
+        let mut shift = 8;
+        for byte in chunk {
+            shift -= bits;
+            // Take the low bits of the pixel and shift them into the output byte
+            new_byte |= (byte & mask) << shift;
+        }
+        out.push(new_byte);
+    }
+}
+
+/// Unpack `num_samples` samples of `bits` bits from a line to 8 bits each, appending the result
+/// to `out`
+///
+/// If `replicate` is set the samples are scaled to the full range, as for grayscale, rather
+/// than left as values such as palette indices.
+pub fn unpack_line(data: &[u8], bits: u32, num_samples: usize, replicate: bool, out: &mut Vec<u8>) {
+    let length = out.len() + num_samples;
+    // Calculate the current number of pixels per byte
+    let ppb = 8 / bits;
+    let mask = (1 << bits) - 1;
+    for &(mut byte) in data {
+        // Loop over each pixel in the byte
+        for _ in 0..ppb {
+            // Align the current pixel with the mask
+            byte = byte.rotate_left(bits);
+            let val = byte & mask;
+            out.push(if replicate {
+                replicate_bits(val, bits)
+            } else {
+                val
+            });
+        }
+    }
+    // Trim any overflow
+    out.truncate(length);
+}
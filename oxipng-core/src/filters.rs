@@ -0,0 +1,289 @@
+//! The five filter types of the PNG format, applied to and reversed from a single line
+
+use alloc::vec::Vec;
+use core::{fmt, fmt::Display};
+
+#[cfg(target_arch = "aarch64")]
+mod neon;
+#[cfg(target_arch = "x86_64")]
+mod simd;
+
+/// A filter type defined by the PNG format
+///
+/// The value of each is the filter type byte that starts a filtered line.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub enum FilterType {
+    None,
+    Sub,
+    Up,
+    Average,
+    Paeth,
+}
+
+impl TryFrom<u8> for FilterType {
+    type Error = InvalidFilterType;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Self::ALL
+            .get(value as usize)
+            .copied()
+            .ok_or(InvalidFilterType(value))
+    }
+}
+
+impl Display for FilterType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(
+            match self {
+                Self::None => "None",
+                Self::Sub => "Sub",
+                Self::Up => "Up",
+                Self::Average => "Average",
+                Self::Paeth => "Paeth",
+            },
+            f,
+        )
+    }
+}
+
+/// A filter type byte with no filter type defined for it
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct InvalidFilterType(pub u8);
+
+impl Display for InvalidFilterType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid filter type: {}", self.0)
+    }
+}
+
+impl FilterType {
+    /// Every filter type, in the order of their values
+    pub const ALL: [Self; 5] = [Self::None, Self::Sub, Self::Up, Self::Average, Self::Paeth];
+
+    /// Filter a line, writing the filter type byte followed by the filtered data to `buf`
+    ///
+    /// `prev_line` is the unfiltered previous line, or zeros for the first line. If
+    /// `alpha_bytes` is not 0, the color of fully transparent pixels, whose last `alpha_bytes`
+    /// bytes of each `bpp` are zero, is first changed in `data` to whatever filters best.
+    pub fn filter_line(
+        self,
+        bpp: usize,
+        data: &mut [u8],
+        prev_line: &[u8],
+        buf: &mut Vec<u8>,
+        alpha_bytes: usize,
+    ) {
+        assert!(data.len() >= bpp);
+        assert_eq!(data.len(), prev_line.len());
+
+        if alpha_bytes != 0 {
+            self.optimize_alpha(bpp, data, prev_line, bpp - alpha_bytes);
+        }
+
+        buf.clear();
+        buf.reserve(data.len() + 1);
+        buf.push(self as u8);
+        match self {
+            Self::None => {
+                buf.extend_from_slice(data);
+            }
+            Self::Sub => {
+                buf.extend_from_slice(&data[0..bpp]);
+                buf.extend(
+                    data.iter()
+                        .skip(bpp)
+                        .zip(data.iter())
+                        .map(|(cur, last)| cur.wrapping_sub(*last)),
+                );
+            }
+            Self::Up => {
+                buf.extend(
+                    data.iter()
+                        .zip(prev_line.iter())
+                        .map(|(cur, last)| cur.wrapping_sub(*last)),
+                );
+            }
+            #[cfg(target_arch = "x86_64")]
+            Self::Average => simd::filter_average(bpp, data, prev_line, buf),
+            // Safety: NEON support was checked at runtime
+            #[cfg(target_arch = "aarch64")]
+            Self::Average if neon::is_available() => unsafe {
+                neon::filter_average(bpp, data, prev_line, buf)
+            },
+            #[cfg(not(target_arch = "x86_64"))]
+            Self::Average => {
+                for (i, byte) in data.iter().enumerate() {
+                    buf.push(match i.checked_sub(bpp) {
+                        Some(x) => byte.wrapping_sub(
+                            ((u16::from(data[x]) + u16::from(prev_line[i])) >> 1) as u8,
+                        ),
+                        None => byte.wrapping_sub(prev_line[i] >> 1),
+                    });
+                }
+            }
+            #[cfg(target_arch = "x86_64")]
+            Self::Paeth => simd::filter_paeth(bpp, data, prev_line, buf),
+            // Safety: NEON support was checked at runtime
+            #[cfg(target_arch = "aarch64")]
+            Self::Paeth if neon::is_available() => unsafe {
+                neon::filter_paeth(bpp, data, prev_line, buf)
+            },
+            #[cfg(not(target_arch = "x86_64"))]
+            Self::Paeth => {
+                for (i, byte) in data.iter().enumerate() {
+                    buf.push(match i.checked_sub(bpp) {
+                        Some(x) => {
+                            byte.wrapping_sub(paeth_predictor(data[x], prev_line[i], prev_line[x]))
+                        }
+                        None => byte.wrapping_sub(prev_line[i]),
+                    });
+                }
+            }
+        }
+    }
+
+    // Optimize fully transparent pixels of a scanline such that they will be zeroed when filtered
+    #[allow(clippy::needless_range_loop)]
+    fn optimize_alpha(self, bpp: usize, data: &mut [u8], prev_line: &[u8], color_bytes: usize) {
+        if self == Self::None {
+            // Assume transparent pixels already set to 0
+            return;
+        }
+
+        let mut pixels: Vec<_> = data.chunks_mut(bpp).collect();
+        let prev_pixels: Vec<_> = prev_line.chunks(bpp).collect();
+        for i in 0..pixels.len() {
+            if pixels[i].iter().skip(color_bytes).all(|b| *b == 0) {
+                // If the first pixel in the row is transparent, find the next non-transparent pixel and pretend
+                // it is the previous one. This can help improve effectiveness of the Sub and Paeth filters.
+                let prev = match i {
+                    0 => pixels
+                        .iter()
+                        .position(|px| px.iter().skip(color_bytes).any(|b| *b != 0))
+                        .unwrap_or(i),
+                    _ => i - 1,
+                };
+                match self {
+                    Self::Sub => {
+                        for j in 0..color_bytes {
+                            pixels[i][j] = pixels[prev][j];
+                        }
+                    }
+                    Self::Up => {
+                        pixels[i][0..color_bytes].copy_from_slice(&prev_pixels[i][0..color_bytes]);
+                    }
+                    Self::Average => {
+                        for j in 0..color_bytes {
+                            pixels[i][j] = match i {
+                                0 => prev_pixels[i][j] >> 1,
+                                _ => {
+                                    ((u16::from(pixels[i - 1][j]) + u16::from(prev_pixels[i][j]))
+                                        >> 1) as u8
+                                }
+                            };
+                        }
+                    }
+                    Self::Paeth => {
+                        for j in 0..color_bytes {
+                            pixels[i][j] = match i {
+                                0 => pixels[prev][j].min(prev_pixels[i][j]),
+                                _ => paeth_predictor(
+                                    pixels[i - 1][j],
+                                    prev_pixels[i][j],
+                                    prev_pixels[i - 1][j],
+                                ),
+                            };
+                        }
+                    }
+                    Self::None => unreachable!(),
+                }
+            }
+        }
+    }
+
+    /// Reverse the filter on a line, without its filter type byte, writing the result to `buf`
+    ///
+    /// `prev_line` is the unfiltered previous line, or zeros for the first line.
+    pub fn unfilter_line(self, bpp: usize, data: &[u8], prev_line: &[u8], buf: &mut Vec<u8>) {
+        buf.clear();
+        buf.reserve(data.len());
+        assert!(data.len() >= bpp);
+        assert_eq!(data.len(), prev_line.len());
+        match self {
+            Self::None => {
+                buf.extend_from_slice(data);
+            }
+            Self::Sub => {
+                for (i, &cur) in data.iter().enumerate() {
+                    let prev_byte = i.checked_sub(bpp).and_then(|x| buf.get(x).copied());
+                    buf.push(match prev_byte {
+                        Some(b) => cur.wrapping_add(b),
+                        None => cur,
+                    });
+                }
+            }
+            Self::Up => {
+                buf.extend(
+                    data.iter()
+                        .zip(prev_line)
+                        .map(|(&cur, &last)| cur.wrapping_add(last)),
+                );
+            }
+            #[cfg(target_arch = "x86_64")]
+            Self::Average if bpp == 4 => simd::unfilter_average_4(data, prev_line, buf),
+            // Safety: NEON support was checked at runtime
+            #[cfg(target_arch = "aarch64")]
+            Self::Average if bpp == 4 && neon::is_available() => unsafe {
+                neon::unfilter_average_4(data, prev_line, buf)
+            },
+            Self::Average => {
+                for (i, (&cur, &last)) in data.iter().zip(prev_line).enumerate() {
+                    let prev_byte = i.checked_sub(bpp).and_then(|x| buf.get(x).copied());
+                    buf.push(match prev_byte {
+                        Some(b) => cur.wrapping_add(((u16::from(b) + u16::from(last)) >> 1) as u8),
+                        None => cur.wrapping_add(last >> 1),
+                    });
+                }
+            }
+            #[cfg(target_arch = "x86_64")]
+            Self::Paeth if bpp == 4 => simd::unfilter_paeth_4(data, prev_line, buf),
+            // Safety: NEON support was checked at runtime
+            #[cfg(target_arch = "aarch64")]
+            Self::Paeth if bpp == 4 && neon::is_available() => unsafe {
+                neon::unfilter_paeth_4(data, prev_line, buf)
+            },
+            Self::Paeth => {
+                for (i, (&cur, &up)) in data.iter().zip(prev_line).enumerate() {
+                    buf.push(
+                        match i
+                            .checked_sub(bpp)
+                            .map(|x| (buf.get(x).copied(), prev_line.get(x).copied()))
+                        {
+                            Some((Some(left), Some(left_up))) => {
+                                cur.wrapping_add(paeth_predictor(left, up, left_up))
+                            }
+                            _ => cur.wrapping_add(up),
+                        },
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// The Paeth predictor of a byte from the bytes to its left, above, and above left
+#[must_use]
+pub fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
+    let p = i32::from(a) + i32::from(b) - i32::from(c);
+    let pa = (p - i32::from(a)).abs();
+    let pb = (p - i32::from(b)).abs();
+    let pc = (p - i32::from(c)).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
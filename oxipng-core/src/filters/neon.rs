@@ -0,0 +1,169 @@
+//! NEON implementations of the Average and Paeth filters.
+//! NEON is not guaranteed on every AArch64 target, so callers must check `is_available` first.
+//! With the `std` feature, the detection runs once, on first use, and the standard library caches
+//! the result; later checks are a single atomic load, cheap enough to make once per line. Without
+//! it, NEON is only used when it is enabled at compile time.
+
+use alloc::vec::Vec;
+use core::arch::aarch64::*;
+
+/// Whether the CPU supports NEON
+#[cfg(feature = "std")]
+#[inline]
+pub(super) fn is_available() -> bool {
+    std::arch::is_aarch64_feature_detected!("neon")
+}
+
+/// Whether the CPU supports NEON
+#[cfg(not(feature = "std"))]
+#[inline]
+pub(super) fn is_available() -> bool {
+    cfg!(target_feature = "neon")
+}
+
+/// Apply the Average filter to a line, appending the result to `buf`
+///
+/// # Safety
+/// NEON must be available, as checked by `is_available`
+#[target_feature(enable = "neon")]
+pub(super) unsafe fn filter_average(bpp: usize, data: &[u8], prev_line: &[u8], buf: &mut Vec<u8>) {
+    let len = data.len();
+    buf.extend(
+        data.iter()
+            .zip(prev_line)
+            .take(bpp)
+            .map(|(cur, up)| cur.wrapping_sub(up >> 1)),
+    );
+    let mut i = bpp;
+    while i + 16 <= len {
+        // All loads are within the bounds checked above
+        let cur = load_128(data, i);
+        let left = load_128(data, i - bpp);
+        let up = load_128(prev_line, i);
+        // The halving add rounds down, as the filter requires
+        store_128(buf, vsubq_u8(cur, vhaddq_u8(left, up)));
+        i += 16;
+    }
+    for j in i..len {
+        let avg = (u16::from(data[j - bpp]) + u16::from(prev_line[j])) >> 1;
+        buf.push(data[j].wrapping_sub(avg as u8));
+    }
+}
+
+/// Apply the Paeth filter to a line, appending the result to `buf`
+///
+/// # Safety
+/// NEON must be available, as checked by `is_available`
+#[target_feature(enable = "neon")]
+pub(super) unsafe fn filter_paeth(bpp: usize, data: &[u8], prev_line: &[u8], buf: &mut Vec<u8>) {
+    let len = data.len();
+    buf.extend(
+        data.iter()
+            .zip(prev_line)
+            .take(bpp)
+            .map(|(cur, up)| cur.wrapping_sub(*up)),
+    );
+    let mut i = bpp;
+    while i + 16 <= len {
+        // All loads are within the bounds checked above
+        let cur = load_128(data, i);
+        let left = load_128(data, i - bpp);
+        let up = load_128(prev_line, i);
+        let up_left = load_128(prev_line, i - bpp);
+        let pred_lo = paeth_predictor(
+            vmovl_u8(vget_low_u8(left)),
+            vmovl_u8(vget_low_u8(up)),
+            vmovl_u8(vget_low_u8(up_left)),
+        );
+        let pred_hi = paeth_predictor(
+            vmovl_high_u8(left),
+            vmovl_high_u8(up),
+            vmovl_high_u8(up_left),
+        );
+        let pred = vcombine_u8(vmovn_u16(pred_lo), vmovn_u16(pred_hi));
+        store_128(buf, vsubq_u8(cur, pred));
+        i += 16;
+    }
+    for j in i..len {
+        let pred = super::paeth_predictor(data[j - bpp], prev_line[j], prev_line[j - bpp]);
+        buf.push(data[j].wrapping_sub(pred));
+    }
+}
+
+/// Reverse the Average filter on a line with 4 bytes per pixel, appending the result to `buf`
+///
+/// # Safety
+/// NEON must be available, as checked by `is_available`
+#[target_feature(enable = "neon")]
+pub(super) unsafe fn unfilter_average_4(data: &[u8], prev_line: &[u8], buf: &mut Vec<u8>) {
+    // A zero left pixel gives the correct result for the first pixel
+    let mut left = vdup_n_u8(0);
+    for (cur, up) in data.chunks_exact(4).zip(prev_line.chunks_exact(4)) {
+        let out = vadd_u8(load_32(cur), vhadd_u8(left, load_32(up)));
+        buf.extend_from_slice(&store_32(out));
+        left = out;
+    }
+}
+
+/// Reverse the Paeth filter on a line with 4 bytes per pixel, appending the result to `buf`
+///
+/// # Safety
+/// NEON must be available, as checked by `is_available`
+#[target_feature(enable = "neon")]
+pub(super) unsafe fn unfilter_paeth_4(data: &[u8], prev_line: &[u8], buf: &mut Vec<u8>) {
+    // Zero left and upper left pixels give the correct result for the first pixel
+    let mut left = vdupq_n_u16(0);
+    let mut up_left = left;
+    for (cur, up) in data.chunks_exact(4).zip(prev_line.chunks_exact(4)) {
+        let up = vmovl_u8(load_32(up));
+        let pred = paeth_predictor(left, up, up_left);
+        let out = vadd_u8(load_32(cur), vmovn_u16(pred));
+        buf.extend_from_slice(&store_32(out));
+        left = vmovl_u8(out);
+        up_left = up;
+    }
+}
+
+/// Paeth predictor of 8 values widened to 16-bit lanes
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn paeth_predictor(a: uint16x8_t, b: uint16x8_t, c: uint16x8_t) -> uint16x8_t {
+    let b_c = vreinterpretq_s16_u16(vsubq_u16(b, c));
+    let a_c = vreinterpretq_s16_u16(vsubq_u16(a, c));
+    let pa = vabsq_s16(b_c);
+    let pb = vabsq_s16(a_c);
+    let pc = vabsq_s16(vaddq_s16(b_c, a_c));
+    let use_a = vandq_u16(vcleq_s16(pa, pb), vcleq_s16(pa, pc));
+    let use_b = vcleq_s16(pb, pc);
+    vbslq_u16(use_a, a, vbslq_u16(use_b, b, c))
+}
+
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn load_128(data: &[u8], offset: usize) -> uint8x16_t {
+    debug_assert!(offset + 16 <= data.len());
+    vld1q_u8(data.as_ptr().add(offset))
+}
+
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn store_128(buf: &mut Vec<u8>, value: uint8x16_t) {
+    let mut bytes = [0u8; 16];
+    vst1q_u8(bytes.as_mut_ptr(), value);
+    buf.extend_from_slice(&bytes);
+}
+
+/// Load 4 bytes into the low lanes, with the high lanes repeating them
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn load_32(data: &[u8]) -> uint8x8_t {
+    vreinterpret_u8_u32(vdup_n_u32(u32::from_le_bytes([
+        data[0], data[1], data[2], data[3],
+    ])))
+}
+
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn store_32(value: uint8x8_t) -> [u8; 4] {
+    vget_lane_u32::<0>(vreinterpret_u32_u8(value)).to_le_bytes()
+}
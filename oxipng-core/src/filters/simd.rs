@@ -0,0 +1,161 @@
+//! SSE2 implementations of the Average and Paeth filters.
+//! SSE2 is part of the x86_64 baseline, so no runtime feature detection is needed.
+
+use alloc::vec::Vec;
+use core::arch::x86_64::*;
+
+/// Apply the Average filter to a line, appending the result to `buf`
+pub(super) fn filter_average(bpp: usize, data: &[u8], prev_line: &[u8], buf: &mut Vec<u8>) {
+    let len = data.len();
+    buf.extend(
+        data.iter()
+            .zip(prev_line)
+            .take(bpp)
+            .map(|(cur, up)| cur.wrapping_sub(up >> 1)),
+    );
+    let mut i = bpp;
+    while i + 16 <= len {
+        // Safety: all loads are within the bounds checked above
+        unsafe {
+            let cur = load_128(data, i);
+            let left = load_128(data, i - bpp);
+            let up = load_128(prev_line, i);
+            store_128(buf, _mm_sub_epi8(cur, average(left, up)));
+        }
+        i += 16;
+    }
+    for j in i..len {
+        let avg = (u16::from(data[j - bpp]) + u16::from(prev_line[j])) >> 1;
+        buf.push(data[j].wrapping_sub(avg as u8));
+    }
+}
+
+/// Apply the Paeth filter to a line, appending the result to `buf`
+pub(super) fn filter_paeth(bpp: usize, data: &[u8], prev_line: &[u8], buf: &mut Vec<u8>) {
+    let len = data.len();
+    buf.extend(
+        data.iter()
+            .zip(prev_line)
+            .take(bpp)
+            .map(|(cur, up)| cur.wrapping_sub(*up)),
+    );
+    let mut i = bpp;
+    while i + 16 <= len {
+        // Safety: all loads are within the bounds checked above
+        unsafe {
+            let zero = _mm_setzero_si128();
+            let cur = load_128(data, i);
+            let left = load_128(data, i - bpp);
+            let up = load_128(prev_line, i);
+            let up_left = load_128(prev_line, i - bpp);
+            let pred_lo = paeth_predictor(
+                _mm_unpacklo_epi8(left, zero),
+                _mm_unpacklo_epi8(up, zero),
+                _mm_unpacklo_epi8(up_left, zero),
+            );
+            let pred_hi = paeth_predictor(
+                _mm_unpackhi_epi8(left, zero),
+                _mm_unpackhi_epi8(up, zero),
+                _mm_unpackhi_epi8(up_left, zero),
+            );
+            let pred = _mm_packus_epi16(pred_lo, pred_hi);
+            store_128(buf, _mm_sub_epi8(cur, pred));
+        }
+        i += 16;
+    }
+    for j in i..len {
+        let pred = super::paeth_predictor(data[j - bpp], prev_line[j], prev_line[j - bpp]);
+        buf.push(data[j].wrapping_sub(pred));
+    }
+}
+
+/// Reverse the Average filter on a line with 4 bytes per pixel, appending the result to `buf`
+pub(super) fn unfilter_average_4(data: &[u8], prev_line: &[u8], buf: &mut Vec<u8>) {
+    // Safety: only SSE2 intrinsics are used, which are always available on x86_64
+    unsafe {
+        // A zero left pixel gives the correct result for the first pixel
+        let mut left = _mm_setzero_si128();
+        for (cur, up) in data.chunks_exact(4).zip(prev_line.chunks_exact(4)) {
+            let out = _mm_add_epi8(load_32(cur), average(left, load_32(up)));
+            buf.extend_from_slice(&store_32(out));
+            left = out;
+        }
+    }
+}
+
+/// Reverse the Paeth filter on a line with 4 bytes per pixel, appending the result to `buf`
+pub(super) fn unfilter_paeth_4(data: &[u8], prev_line: &[u8], buf: &mut Vec<u8>) {
+    // Safety: only SSE2 intrinsics are used, which are always available on x86_64
+    unsafe {
+        let zero = _mm_setzero_si128();
+        // Zero left and upper left pixels give the correct result for the first pixel
+        let mut left = zero;
+        let mut up_left = zero;
+        for (cur, up) in data.chunks_exact(4).zip(prev_line.chunks_exact(4)) {
+            let up = _mm_unpacklo_epi8(load_32(up), zero);
+            let pred = paeth_predictor(left, up, up_left);
+            let out = _mm_add_epi8(load_32(cur), _mm_packus_epi16(pred, zero));
+            buf.extend_from_slice(&store_32(out));
+            left = _mm_unpacklo_epi8(out, zero);
+            up_left = up;
+        }
+    }
+}
+
+/// Average of unsigned bytes, rounded down
+#[inline]
+unsafe fn average(a: __m128i, b: __m128i) -> __m128i {
+    // `_mm_avg_epu8` rounds up, so subtract 1 where the sum is odd
+    let odd = _mm_and_si128(_mm_xor_si128(a, b), _mm_set1_epi8(1));
+    _mm_sub_epi8(_mm_avg_epu8(a, b), odd)
+}
+
+/// Paeth predictor of 8 values widened to 16-bit lanes
+#[inline]
+unsafe fn paeth_predictor(a: __m128i, b: __m128i, c: __m128i) -> __m128i {
+    let b_c = _mm_sub_epi16(b, c);
+    let a_c = _mm_sub_epi16(a, c);
+    let pa = abs_16(b_c);
+    let pb = abs_16(a_c);
+    let pc = abs_16(_mm_add_epi16(b_c, a_c));
+    let not_a = _mm_or_si128(_mm_cmpgt_epi16(pa, pb), _mm_cmpgt_epi16(pa, pc));
+    let not_b = _mm_cmpgt_epi16(pb, pc);
+    select(not_a, select(not_b, c, b), a)
+}
+
+#[inline]
+unsafe fn abs_16(x: __m128i) -> __m128i {
+    _mm_max_epi16(x, _mm_sub_epi16(_mm_setzero_si128(), x))
+}
+
+/// Choose lanes from `if_set` where `mask` is set, otherwise from `if_clear`
+#[inline]
+unsafe fn select(mask: __m128i, if_set: __m128i, if_clear: __m128i) -> __m128i {
+    _mm_or_si128(
+        _mm_and_si128(mask, if_set),
+        _mm_andnot_si128(mask, if_clear),
+    )
+}
+
+#[inline]
+unsafe fn load_128(data: &[u8], offset: usize) -> __m128i {
+    debug_assert!(offset + 16 <= data.len());
+    _mm_loadu_si128(data.as_ptr().add(offset).cast())
+}
+
+#[inline]
+unsafe fn store_128(buf: &mut Vec<u8>, value: __m128i) {
+    let mut bytes = [0u8; 16];
+    _mm_storeu_si128(bytes.as_mut_ptr().cast(), value);
+    buf.extend_from_slice(&bytes);
+}
+
+#[inline]
+unsafe fn load_32(data: &[u8]) -> __m128i {
+    _mm_cvtsi32_si128(i32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+}
+
+#[inline]
+unsafe fn store_32(value: __m128i) -> [u8; 4] {
+    _mm_cvtsi128_si32(value).to_le_bytes()
+}
@@ -0,0 +1,20 @@
+//! The pure pixel math behind oxipng: PNG filters and bit depth conversion.
+//!
+//! This crate is `no_std`, needing only `alloc`, so it can be used where oxipng itself can't,
+//! such as embedded targets or WebAssembly without file access. oxipng builds its optimizations on
+//! top of it, adding the file handling, compression and threading.
+
+#![no_std]
+#![warn(trivial_casts, trivial_numeric_casts, unused_import_braces)]
+#![deny(missing_debug_implementations, missing_copy_implementations)]
+#![warn(clippy::match_same_arms)]
+#![warn(clippy::needless_continue)]
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod bit_depth;
+pub mod filters;
+
+pub use crate::filters::{FilterType, InvalidFilterType};
@@ -0,0 +1,65 @@
+//! Built without the standard library, other than what the test harness itself needs, to check
+//! that the crate is usable from `no_std` code
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use oxipng_core::bit_depth::{pack_line, scale_sample, unpack_line};
+use oxipng_core::FilterType;
+
+#[test]
+fn filter_round_trip() {
+    let bpp = 4;
+    let lines: Vec<Vec<u8>> = (0..3u8)
+        .map(|y| {
+            (0..40u8)
+                .map(|x| x.wrapping_mul(37) ^ y.wrapping_mul(91))
+                .collect()
+        })
+        .collect();
+    for filter in FilterType::ALL {
+        let mut prev_line = vec![0; 40];
+        let mut filtered = Vec::new();
+        let mut unfiltered = Vec::new();
+        for line in &lines {
+            let mut data = line.clone();
+            filter.filter_line(bpp, &mut data, &prev_line, &mut filtered, 0);
+            assert_eq!(filtered[0], filter as u8);
+            assert_eq!(FilterType::try_from(filtered[0]), Ok(filter));
+            filter.unfilter_line(bpp, &filtered[1..], &prev_line, &mut unfiltered);
+            assert_eq!(&unfiltered, line, "{}", filter);
+            prev_line = unfiltered.clone();
+        }
+    }
+    assert!(FilterType::try_from(5).is_err());
+}
+
+#[test]
+fn bit_depth_round_trip() {
+    let samples = [0, 1, 0, 1, 1, 1, 0, 0, 1, 0, 1];
+    let mut packed = Vec::new();
+    pack_line(&samples, 1, &mut packed);
+    assert_eq!(packed, [0b0101_1100, 0b1010_0000]);
+    let mut unpacked = Vec::new();
+    unpack_line(&packed, 1, samples.len(), false, &mut unpacked);
+    assert_eq!(unpacked, samples);
+    unpacked.clear();
+    unpack_line(&packed, 1, samples.len(), true, &mut unpacked);
+    assert!(unpacked.iter().zip(samples).all(|(&v, s)| v == s * 255));
+
+    assert_eq!(scale_sample(0x00FF), 1);
+    assert_eq!(scale_sample(0xFFFF), 255);
+    for val in 0..=u16::MAX {
+        let nearest = u32::from(val) * 255 / 65535;
+        let remainder = u32::from(val) * 255 % 65535;
+        let expected = if remainder * 2 > 65535 {
+            nearest + 1
+        } else {
+            nearest
+        };
+        assert_eq!(u32::from(scale_sample(val)), expected);
+    }
+}
@@ -0,0 +1,311 @@
+//! Optimization of animated PNGs.
+//! All frames of an animation must share the same color type and bit depth,
+//! so reductions are evaluated on all of them together.
+
+use crate::atomicmin::AtomicMin;
+use crate::deflate;
+use crate::evaluate::Evaluator;
+use crate::filters::RowFilter;
+use crate::headers::*;
+use crate::interlace::Interlacing;
+use crate::png::{PngData, PngImage};
+use crate::reduction::perform_reductions;
+use crate::{optimize_raw, report_format, verify_idat, Deadline, Options, PngError, PngResult};
+use indexmap::indexset;
+use log::debug;
+use std::iter::once;
+use std::sync::Arc;
+
+/// A frame of an animation, other than the default image
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// The headers from the fcTL chunk of the frame
+    pub control: FrameControl,
+    /// The uncompressed, unfiltered data of the frame
+    pub image: PngImage,
+    /// The filtered and compressed data from the fdAT chunks of the frame
+    pub fdat_data: Vec<u8>,
+}
+
+/// Decode all frames stored in fdAT chunks of an animated PNG
+pub fn decode_frames(png: &PngData) -> PngResult<Vec<Frame>> {
+    let ihdr = &png.raw.ihdr;
+    let mut frames: Vec<(FrameControl, Vec<u8>)> = Vec::new();
+    let mut seen_idat = false;
+    for chunk in &png.aux_chunks {
+        match &chunk.name {
+            b"IDAT" => seen_idat = true,
+            b"fcTL" => {
+                let control = parse_fctl_chunk(&chunk.data)?;
+                let fits = |offset: u32, size: u32, max: u32| {
+                    size > 0 && offset.checked_add(size).map_or(false, |end| end <= max)
+                };
+                if !fits(control.x_offset, control.width, ihdr.width)
+                    || !fits(control.y_offset, control.height, ihdr.height)
+                {
+                    return Err(PngError::new("Invalid frame dimensions in fcTL chunk"));
+                }
+                // A frame control before the IDAT describes the default image
+                if seen_idat {
+                    frames.push((control, Vec::new()));
+                }
+            }
+            b"fdAT" => {
+                let (_, data) = frames.last_mut().ok_or(PngError::ChunkMissing("fcTL"))?;
+                data.extend_from_slice(chunk.data.get(4..).ok_or(PngError::TruncatedData)?);
+            }
+            _ => {}
+        }
+    }
+
+    frames
+        .into_iter()
+        .map(|(control, fdat_data)| {
+            let frame_ihdr = IhdrData {
+                width: control.width,
+                height: control.height,
+                ..ihdr.clone()
+            };
+            let raw_data = deflate::inflate(&fdat_data, frame_ihdr.raw_data_size())?;
+            if raw_data.len() != frame_ihdr.raw_data_size() {
+                return Err(PngError::TruncatedData);
+            }
+            let mut image = PngImage {
+                ihdr: frame_ihdr,
+                data: raw_data,
+            };
+            image.data = image.unfilter_image()?;
+            Ok(Frame {
+                control,
+                image,
+                fdat_data,
+            })
+        })
+        .collect()
+}
+
+/// Perform optimization on all frames of an animated PNG, using the options provided
+///
+/// Returns the filter used for the default image, or `None` if it was left unchanged
+pub(crate) fn optimize_animation(
+    png: &mut PngData,
+    opts: &Options,
+    deadline: &Arc<Deadline>,
+) -> PngResult<Option<RowFilter>> {
+    let frames = decode_frames(png)?;
+    let orig_ihdr = png.raw.ihdr.clone();
+    debug!("    {} animation frames", frames.len());
+
+    // Stack all frames vertically into a single progressive image, so that any reduction found
+    // is valid for every frame
+    let images: Vec<PngImage> = once(&*png.raw)
+        .chain(frames.iter().map(|f| &f.image))
+        .map(|image| {
+            image
+                .change_interlacing(Interlacing::None)
+                .unwrap_or_else(|| image.clone())
+        })
+        .collect();
+    let mut reduce_opts = opts.clone();
+    reduce_opts.interlace = None;
+    let eval = Evaluator::new(
+        deadline.clone(),
+        indexset! {RowFilter::None, RowFilter::Bigrams},
+        5,
+        false,
+        opts.progress.clone(),
+        opts.buffer_pool(),
+    );
+    let mut reduced = perform_reductions(
+        Arc::new(stack_images(&images)),
+        &reduce_opts,
+        deadline,
+        &eval,
+    );
+    if let Some(result) = eval.get_best_candidate() {
+        reduced = result.image;
+    }
+    let format_changed = reduced.ihdr.color_type != orig_ihdr.color_type
+        || reduced.ihdr.bit_depth != orig_ihdr.bit_depth;
+    if format_changed {
+        report_format("Reducing animation to ", &reduced);
+    }
+    let interlacing = opts.interlace.unwrap_or(orig_ihdr.interlaced);
+    // If nothing changed, frames which can't be compressed any smaller can keep their original data
+    let recode = format_changed || interlacing != orig_ihdr.interlaced;
+
+    // Each frame is then compressed separately, without further reductions
+    let mut frame_opts = opts.clone();
+    frame_opts.interlace = None;
+    frame_opts.bit_depth_reduction = false;
+    frame_opts.color_type_reduction = false;
+    frame_opts.palette_reduction = false;
+    frame_opts.grayscale_reduction = false;
+    let original_data = once(&png.idat_data).chain(frames.iter().map(|f| &f.fdat_data));
+    let mut encoded = Vec::with_capacity(images.len());
+    for (image, original) in split_image(&reduced, &images)
+        .into_iter()
+        .zip(original_data)
+    {
+        let image = Arc::new(if interlacing == Interlacing::Adam7 {
+            image.change_interlacing(interlacing).unwrap_or(image)
+        } else {
+            image
+        });
+        let max_size = if recode {
+            None
+        } else {
+            Some(original.len() + image.key_chunks_size())
+        };
+        let result = optimize_raw(
+            image.clone(),
+            &frame_opts,
+            deadline.clone(),
+            max_size,
+            None,
+            &mut None,
+        );
+        encoded.push(match result {
+            None if recode => {
+                // We must have run out of time, but the frame still needs to be converted
+                let filtered = image.filter_image(RowFilter::None, false);
+                let idat_data = deflate::deflate(&filtered, 1, &AtomicMin::new(None))?;
+                Some((
+                    PngData {
+                        raw: image,
+                        idat_data,
+                        aux_chunks: Vec::new(),
+                    },
+                    RowFilter::None,
+                ))
+            }
+            result => result,
+        });
+        if let Some(Some((new, filter))) = encoded.last() {
+            verify_idat(new, *filter, opts)?;
+        }
+    }
+
+    // Write the new frames back out, renumbering the sequence as there is now one fdAT per frame
+    let mut encoded = encoded.into_iter();
+    let mut fdat_data = frames.into_iter().map(|f| f.fdat_data);
+    let filter = match encoded.next().flatten() {
+        Some((new, filter)) => {
+            png.raw = new.raw;
+            png.idat_data = new.idat_data;
+            Some(filter)
+        }
+        None => None,
+    };
+    let mut sequence_number = 0;
+    let mut seen_idat = false;
+    let mut chunks = Vec::with_capacity(png.aux_chunks.len());
+    for chunk in png.aux_chunks.drain(..) {
+        match &chunk.name {
+            b"IDAT" => {
+                seen_idat = true;
+                chunks.push(chunk);
+            }
+            b"fcTL" => {
+                let mut control = parse_fctl_chunk(&chunk.data)?;
+                control.sequence_number = sequence_number;
+                sequence_number += 1;
+                chunks.push(Chunk {
+                    name: *b"fcTL",
+                    data: control.chunk_data(),
+                });
+                if seen_idat {
+                    let original = fdat_data.next().unwrap_or_default();
+                    let new = encoded.next().flatten().map(|(new, _)| new.idat_data);
+                    let mut data = sequence_number.to_be_bytes().to_vec();
+                    data.extend_from_slice(&new.unwrap_or(original));
+                    sequence_number += 1;
+                    chunks.push(Chunk {
+                        name: *b"fdAT",
+                        data,
+                    });
+                }
+            }
+            // Replaced by the fdAT written after each fcTL
+            b"fdAT" => {}
+            _ => chunks.push(chunk),
+        }
+    }
+    png.aux_chunks = chunks;
+
+    Ok(filter)
+}
+
+/// Stack progressive images of the same format vertically into a single image.
+/// Narrower images are padded on the right by repeating their last pixel.
+fn stack_images(images: &[PngImage]) -> PngImage {
+    let ihdr = &images[0].ihdr;
+    let bpp = ihdr.bpp();
+    let row_bytes = |width: u32| (width as usize * bpp + 7) / 8;
+    let width = images.iter().map(|i| i.ihdr.width).max().unwrap_or(0);
+    let height = images.iter().map(|i| i.ihdr.height).sum();
+    let stacked_row_bytes = row_bytes(width);
+
+    let mut data = Vec::with_capacity(stacked_row_bytes * height as usize);
+    for image in images {
+        let image_width = image.ihdr.width as usize;
+        for row in image.data.chunks(row_bytes(image.ihdr.width)) {
+            let start = data.len();
+            data.extend_from_slice(row);
+            data.resize(start + stacked_row_bytes, 0);
+            let row = &mut data[start..];
+            for x in image_width..width as usize {
+                copy_pixel(row, image_width - 1, x, bpp);
+            }
+        }
+    }
+
+    PngImage {
+        ihdr: IhdrData {
+            width,
+            height,
+            interlaced: Interlacing::None,
+            ..ihdr.clone()
+        },
+        data,
+    }
+}
+
+/// Split a stacked image back into images of the original dimensions
+fn split_image(stacked: &PngImage, images: &[PngImage]) -> Vec<PngImage> {
+    let bpp = stacked.ihdr.bpp();
+    let row_bytes = |width: u32| (width as usize * bpp + 7) / 8;
+    let mut rows = stacked.data.chunks(row_bytes(stacked.ihdr.width));
+    images
+        .iter()
+        .map(|image| {
+            let len = row_bytes(image.ihdr.width);
+            let mut data = Vec::with_capacity(len * image.ihdr.height as usize);
+            for row in rows.by_ref().take(image.ihdr.height as usize) {
+                data.extend_from_slice(&row[..len]);
+            }
+            PngImage {
+                ihdr: IhdrData {
+                    width: image.ihdr.width,
+                    height: image.ihdr.height,
+                    ..stacked.ihdr.clone()
+                },
+                data,
+            }
+        })
+        .collect()
+}
+
+/// Copy a pixel within a row of unfiltered image data
+fn copy_pixel(row: &mut [u8], from: usize, to: usize, bpp: usize) {
+    if bpp >= 8 {
+        let bytes = bpp / 8;
+        row.copy_within(from * bytes..(from + 1) * bytes, to * bytes);
+    } else {
+        let mask = (1 << bpp) - 1;
+        let shift = |x: usize| 8 - bpp - (x * bpp) % 8;
+        let value = (row[from * bpp / 8] >> shift(from)) & mask;
+        let byte = &mut row[to * bpp / 8];
+        *byte = (*byte & !(mask << shift(to))) | (value << shift(to));
+    }
+}
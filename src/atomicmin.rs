@@ -1,6 +1,10 @@
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::SeqCst;
 
+/// A size limit that can be lowered by other threads, such as the size of the smallest result
+/// found so far
+///
+/// As the maximum size given to deflaters, an unset value means there is no limit.
 #[derive(Debug)]
 pub struct AtomicMin {
     val: AtomicUsize,
@@ -22,6 +26,14 @@ impl AtomicMin {
         }
     }
 
+    /// Check if the value is set and no larger than `target`, if there is a target
+    pub fn reached(&self, target: Option<usize>) -> bool {
+        match (self.get(), target) {
+            (Some(val), Some(target)) => val <= target,
+            _ => false,
+        }
+    }
+
     /// Unset value is usize_max
     pub fn as_atomic_usize(&self) -> &AtomicUsize {
         &self.val
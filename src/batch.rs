@@ -0,0 +1,120 @@
+//! Optimization of many files, or many configurations, at once.
+
+#[cfg(not(threads))]
+use crate::rayon;
+use crate::{in_thread_pool, optimize_from_memory, optimize_with_report};
+use crate::{InFile, OptimizationReport, Options, OutFile, PngError, PngResult};
+use log::warn;
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// Perform optimization on each of the input files using the options provided, returning a result
+/// for each file in the same order as the inputs
+///
+/// Files are processed in parallel, in the thread pool given in the options if there is one.
+/// If `out_dir` is given, each output is written to a file of the same name in that directory,
+/// otherwise each input file is overwritten. An error with one file does not stop the others from
+/// being optimized.
+pub fn optimize_many(
+    inputs: &[InFile],
+    out_dir: Option<&Path>,
+    opts: &Options,
+) -> Vec<PngResult<OptimizationReport>> {
+    in_thread_pool(opts, || {
+        inputs
+            .par_iter()
+            .with_max_len(1)
+            .map(|input| {
+                let output = output_for(input, out_dir, &OutFile::Path(None));
+                optimize_with_report(input, &output, opts)
+            })
+            .collect()
+    })
+}
+
+/// Optimize the PNG data with each of the options provided, returning the smallest output along
+/// with the index of the options that produced it
+///
+/// The configurations are optimized in parallel, each in the thread pool given in its options if
+/// there is one. Where several produce outputs of the same size, the first of them wins, so the
+/// result doesn't depend on which finishes first. Configurations that fail are ignored unless
+/// they all fail, in which case the error of the first is returned.
+pub fn optimize_best_of(data: &[u8], configs: &[Options]) -> PngResult<(Vec<u8>, usize)> {
+    let results: Vec<_> = configs
+        .par_iter()
+        .with_max_len(1)
+        .map(|opts| optimize_from_memory(data, opts))
+        .collect();
+    let mut best: Option<(Vec<u8>, usize)> = None;
+    let mut first_error = None;
+    for (i, result) in results.into_iter().enumerate() {
+        match result {
+            Ok(output) => {
+                if best.as_ref().map_or(true, |(b, _)| output.len() < b.len()) {
+                    best = Some((output, i));
+                }
+            }
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    best.ok_or_else(|| first_error.unwrap_or_else(|| PngError::new("No options were given")))
+}
+
+/// Work out where the output for an input file should be written
+///
+/// If `out_dir` is given, this is a file of the same name in that directory, otherwise `out_file`.
+pub fn output_for(input: &InFile, out_dir: Option<&Path>, out_file: &OutFile) -> OutFile {
+    match (out_dir, input) {
+        (Some(out_dir), InFile::Path(path)) => match path.file_name() {
+            Some(name) => OutFile::Path(Some(out_dir.join(name))),
+            None => out_file.clone(),
+        },
+        _ => out_file.clone(),
+    }
+}
+
+/// Collect the input files from a list of paths, along with where to write each of their outputs
+///
+/// Directories are searched for files if `recursive` is set, and skipped otherwise.
+/// If `allow_stdin` is set, a single path of `-` means that the input is read from stdin.
+pub fn collect_files(
+    files: Vec<PathBuf>,
+    out_dir: &Option<PathBuf>,
+    out_file: &OutFile,
+    recursive: bool,
+    allow_stdin: bool,
+) -> Vec<(InFile, OutFile)> {
+    let mut in_out_pairs = Vec::new();
+    let allow_stdin = allow_stdin && files.len() == 1;
+    for input in files {
+        let using_stdin = allow_stdin && input.to_str() == Some("-");
+        if !using_stdin && input.is_dir() {
+            if recursive {
+                match input.read_dir() {
+                    Ok(dir) => {
+                        let files = dir.filter_map(|x| x.ok().map(|x| x.path())).collect();
+                        in_out_pairs
+                            .extend(collect_files(files, out_dir, out_file, recursive, false));
+                    }
+                    Err(_) => {
+                        return Vec::new();
+                    }
+                }
+            } else {
+                warn!("{} is a directory, skipping", input.display());
+            }
+            continue;
+        };
+        // Output to a directory is named after the input, even when reading stdin
+        let out_file = output_for(&InFile::Path(input.clone()), out_dir.as_deref(), out_file);
+        let in_file = if using_stdin {
+            InFile::StdIn
+        } else {
+            InFile::Path(input)
+        };
+        in_out_pairs.push((in_file, out_file));
+    }
+    in_out_pairs
+}
@@ -0,0 +1,51 @@
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::Mutex;
+
+/// The most buffers a pool will hold on to
+const MAX_BUFFERS: usize = 32;
+
+/// A pool of byte buffers for the data of compression trials
+///
+/// A pool can be shared through `Options` by many optimizations, so that buffers are reused
+/// from one file to the next instead of being allocated again.
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    allocations: AtomicUsize,
+}
+
+impl BufferPool {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of times the pool had no suitable buffer, so a new one was allocated
+    pub fn allocations(&self) -> usize {
+        self.allocations.load(Relaxed)
+    }
+
+    /// Take an empty buffer with at least the given capacity
+    pub(crate) fn take(&self, capacity: usize) -> Vec<u8> {
+        let reused = {
+            let mut buffers = self.buffers.lock().unwrap_or_else(|e| e.into_inner());
+            buffers
+                .iter()
+                .position(|b| b.capacity() >= capacity)
+                .map(|i| buffers.swap_remove(i))
+        };
+        reused.unwrap_or_else(|| {
+            self.allocations.fetch_add(1, Relaxed);
+            Vec::with_capacity(capacity)
+        })
+    }
+
+    /// Return a buffer to the pool for reuse
+    pub(crate) fn give(&self, mut buffer: Vec<u8>) {
+        buffer.clear();
+        let mut buffers = self.buffers.lock().unwrap_or_else(|e| e.into_inner());
+        if buffers.len() < MAX_BUFFERS {
+            buffers.push(buffer);
+        }
+    }
+}
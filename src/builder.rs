@@ -0,0 +1,412 @@
+use crate::buffer_pool::BufferPool;
+use crate::colors::{BitDepth, ColorType};
+use crate::deflate::Deflaters;
+use crate::filters::RowFilter;
+use crate::headers::{ChunkFilter, StripChunks};
+use crate::interlace::Interlacing;
+use crate::progress::ProgressCallback;
+use crate::reduction::ReductionFilter;
+use crate::{Options, PhaseTimeouts, PngError, PngResult};
+use indexmap::IndexSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A builder for `Options`
+///
+/// Settings that are not given keep their default, or the value from the preset the builder was
+/// created with. The `Options` fields may still be set directly after building.
+///
+/// ```
+/// use oxipng::{indexset, Deflaters, OptionsBuilder, RowFilter, StripChunks};
+/// use std::time::Duration;
+///
+/// let opts = OptionsBuilder::from_preset(3)
+///     .filters(indexset! {RowFilter::None, RowFilter::Bigrams})
+///     .deflate(Deflaters::Libdeflater { compression: 12 })
+///     .strip(StripChunks::Safe)
+///     .interlace(None)
+///     .timeout(Duration::from_secs(10))
+///     .build()
+///     .unwrap();
+/// assert_eq!(opts.filter.len(), 2);
+/// ```
+///
+/// Conflicting settings are rejected when building:
+///
+/// ```
+/// use oxipng::OptionsBuilder;
+///
+/// let result = OptionsBuilder::new()
+///     .grayscale_reduction(false)
+///     .grayscale_tolerance(Some(2))
+///     .build();
+/// assert!(result.is_err());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct OptionsBuilder {
+    opts: Options,
+}
+
+impl OptionsBuilder {
+    /// Start from the default options
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from the options of a preset level, as in `Options::from_preset`
+    #[must_use]
+    pub fn from_preset(level: u8) -> Self {
+        Self {
+            opts: Options::from_preset(level),
+        }
+    }
+
+    /// Check the settings for conflicts and return the finished options
+    pub fn build(self) -> PngResult<Options> {
+        let opts = self.opts;
+        if opts.lossless_only {
+            if let Some(name) = opts.lossy_options().first() {
+                return Err(PngError::new(&format!(
+                    "{} is lossy, which requires lossless_only to be disabled",
+                    name
+                )));
+            }
+        }
+        if opts.dither_16_to_8 && !opts.scale_16 {
+            return Err(PngError::new("Dithering 16-bit images requires scale_16"));
+        }
+        if opts.grayscale_tolerance.is_some()
+            && !(opts.color_type_reduction && opts.grayscale_reduction)
+        {
+            return Err(PngError::new(
+                "A grayscale tolerance requires grayscale reduction to be enabled",
+            ));
+        }
+        if matches!(opts.max_palette_colors, Some(0 | 257..)) {
+            return Err(PngError::new(
+                "max_palette_colors must be between 1 and 256",
+            ));
+        }
+        if let Some((color_type, bit_depth)) = &opts.force_color_type {
+            if !color_type.allows_bit_depth(*bit_depth) {
+                return Err(PngError::InvalidDepthForType(
+                    *bit_depth,
+                    color_type.clone(),
+                ));
+            }
+            if opts
+                .force_bit_depth
+                .map_or(false, |depth| depth != *bit_depth)
+            {
+                return Err(PngError::new(
+                    "force_bit_depth must match the bit depth of force_color_type",
+                ));
+            }
+        }
+        if matches!(opts.trial_compression, Some(0 | 13..)) {
+            return Err(PngError::new("trial_compression must be between 1 and 12"));
+        }
+        if opts.max_idat_size == Some(0) {
+            return Err(PngError::new("max_idat_size must be at least 1"));
+        }
+        if opts.check_only_fast && opts.force {
+            return Err(PngError::new("check_only_fast cannot be used with force"));
+        }
+        Ok(opts)
+    }
+
+    /// Which row filters to try on the file
+    #[must_use]
+    pub fn filters(mut self, filters: IndexSet<RowFilter>) -> Self {
+        self.opts.filter = filters;
+        self
+    }
+
+    /// A filter to use for the whole image without evaluating any others
+    #[must_use]
+    pub fn fixed_filter(mut self, filter: Option<RowFilter>) -> Self {
+        self.opts.fixed_filter = filter;
+        self
+    }
+
+    /// Which DEFLATE algorithm to use
+    #[must_use]
+    pub fn deflate(mut self, deflate: Deflaters) -> Self {
+        self.opts.deflate = deflate;
+        self
+    }
+
+    /// Which DEFLATE algorithm to use for compressed ancillary chunks, instead of `deflate`
+    #[must_use]
+    pub fn ancillary_deflate(mut self, deflate: Deflaters) -> Self {
+        self.opts.ancillary_deflate = Some(deflate);
+        self
+    }
+
+    /// The libdeflate compression level at which to compare filters, with only the best one
+    /// compressed using `deflate`
+    #[must_use]
+    pub fn trial_compression(mut self, trial_compression: Option<u8>) -> Self {
+        self.opts.trial_compression = trial_compression;
+        self
+    }
+
+    /// The maximum size of the data in each IDAT chunk
+    #[must_use]
+    pub fn max_idat_size(mut self, max_idat_size: Option<usize>) -> Self {
+        self.opts.max_idat_size = max_idat_size;
+        self
+    }
+
+    /// Which chunks to strip from the PNG file
+    #[must_use]
+    pub fn strip(mut self, strip: StripChunks) -> Self {
+        self.opts.strip = strip;
+        self
+    }
+
+    /// A predicate to decide which other ancillary chunks to strip
+    #[must_use]
+    pub fn chunk_filter(mut self, filter: ChunkFilter) -> Self {
+        self.opts.chunk_filter = Some(filter);
+        self
+    }
+
+    /// The largest size allowed for the data of an ancillary chunk
+    #[must_use]
+    pub fn max_chunk_size(mut self, size: usize) -> Self {
+        self.opts.max_chunk_size = Some(size);
+        self
+    }
+
+    /// Whether to strip chunks larger than the maximum size rather than returning an error
+    #[must_use]
+    pub fn strip_oversized_chunks(mut self, enabled: bool) -> Self {
+        self.opts.strip_oversized_chunks = enabled;
+        self
+    }
+
+    /// Which interlacing mode to use, or `None` to keep the existing one
+    #[must_use]
+    pub fn interlace(mut self, interlace: Option<Interlacing>) -> Self {
+        self.opts.interlace = interlace;
+        self
+    }
+
+    /// Try the image both with and without interlacing, keeping whichever is smaller
+    #[must_use]
+    pub fn interlace_auto(mut self, interlace_auto: bool) -> Self {
+        self.opts.interlace_auto = interlace_auto;
+        self
+    }
+
+    /// Maximum amount of time to spend on optimizations
+    #[must_use]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.opts.timeout = Some(timeout);
+        self
+    }
+
+    /// Separate time budgets for the phases of optimization, within the overall timeout
+    #[must_use]
+    pub fn phase_timeouts(mut self, phase_timeouts: PhaseTimeouts) -> Self {
+        self.opts.phase_timeouts = Some(phase_timeouts);
+        self
+    }
+
+    /// An output size in bytes at which to stop trying for a smaller result
+    #[must_use]
+    pub fn target_size(mut self, size: usize) -> Self {
+        self.opts.target_size = Some(size);
+        self
+    }
+
+    /// Whether to write the output even if it is larger than the input
+    #[must_use]
+    pub fn force(mut self, force: bool) -> Self {
+        self.opts.force = force;
+        self
+    }
+
+    /// Whether to fix errors when decoding the input file rather than failing
+    #[must_use]
+    pub fn fix_errors(mut self, fix_errors: bool) -> Self {
+        self.opts.fix_errors = fix_errors;
+        self
+    }
+
+    /// Whether to allow the color values of transparent pixels to be altered
+    #[must_use]
+    pub fn optimize_alpha(mut self, optimize_alpha: bool) -> Self {
+        self.opts.optimize_alpha = optimize_alpha;
+        self
+    }
+
+    /// Whether to search for the color of transparent pixels that compresses best, rather than
+    /// setting them to black. This implies `optimize_alpha`.
+    #[must_use]
+    pub fn optimize_alpha_aggressive(mut self, aggressive: bool) -> Self {
+        self.opts.optimize_alpha_aggressive = aggressive;
+        if aggressive {
+            self.opts.optimize_alpha = true;
+        }
+        self
+    }
+
+    /// Whether to refuse the options which change pixel values, such as `scale_16`
+    #[must_use]
+    pub fn lossless_only(mut self, enabled: bool) -> Self {
+        self.opts.lossless_only = enabled;
+        self
+    }
+
+    /// Whether to attempt bit depth reduction
+    #[must_use]
+    pub fn bit_depth_reduction(mut self, enabled: bool) -> Self {
+        self.opts.bit_depth_reduction = enabled;
+        self
+    }
+
+    /// Whether to attempt color type reduction
+    #[must_use]
+    pub fn color_type_reduction(mut self, enabled: bool) -> Self {
+        self.opts.color_type_reduction = enabled;
+        self
+    }
+
+    /// Whether to attempt palette reduction
+    #[must_use]
+    pub fn palette_reduction(mut self, enabled: bool) -> Self {
+        self.opts.palette_reduction = enabled;
+        self
+    }
+
+    /// Whether to attempt grayscale reduction
+    #[must_use]
+    pub fn grayscale_reduction(mut self, enabled: bool) -> Self {
+        self.opts.grayscale_reduction = enabled;
+        self
+    }
+
+    /// How far channels of a pixel may differ for the image to be reduced to grayscale
+    #[must_use]
+    pub fn grayscale_tolerance(mut self, tolerance: Option<u8>) -> Self {
+        self.opts.grayscale_tolerance = tolerance;
+        self
+    }
+
+    /// Whether to discard the bits that an sBIT chunk marks as insignificant
+    #[must_use]
+    pub fn use_significant_bits(mut self, enabled: bool) -> Self {
+        self.opts.use_significant_bits = enabled;
+        self
+    }
+
+    /// Whether to add the color of a bKGD chunk to the palette when reductions remove it
+    #[must_use]
+    pub fn add_bkgd_to_palette(mut self, enabled: bool) -> Self {
+        self.opts.add_bkgd_to_palette = enabled;
+        self
+    }
+
+    /// A predicate to decide which kinds of reduction may be attempted
+    #[must_use]
+    pub fn reduction_filter(mut self, filter: ReductionFilter) -> Self {
+        self.opts.reduction_filter = Some(filter);
+        self
+    }
+
+    /// Whether to allow grayscale reduction when it means removing a kept ICC profile
+    #[must_use]
+    pub fn drop_incompatible_icc(mut self, enabled: bool) -> Self {
+        self.opts.drop_incompatible_icc = enabled;
+        self
+    }
+
+    /// The greatest number of colors the output may have, quantizing the image if necessary
+    #[must_use]
+    pub fn max_palette_colors(mut self, max_colors: Option<u16>) -> Self {
+        self.opts.max_palette_colors = max_colors;
+        self
+    }
+
+    /// The color type and bit depth the output must have, converting the image to them
+    #[must_use]
+    pub fn force_color_type(mut self, format: Option<(ColorType, BitDepth)>) -> Self {
+        self.opts.force_color_type = format;
+        self
+    }
+
+    /// The bit depth the output must have, keeping the color type it would otherwise have
+    #[must_use]
+    pub fn force_bit_depth(mut self, bit_depth: Option<BitDepth>) -> Self {
+        self.opts.force_bit_depth = bit_depth;
+        self
+    }
+
+    /// Whether to scale 16-bit images to 8-bit, and whether to dither them while doing so
+    #[must_use]
+    pub fn scale_16(mut self, scale: bool, dither: bool) -> Self {
+        self.opts.scale_16 = scale;
+        self.opts.dither_16_to_8 = dither;
+        self
+    }
+
+    /// Whether to cheaply check if the image data is already optimal first
+    #[must_use]
+    pub fn check_only_fast(mut self, check_only_fast: bool) -> Self {
+        self.opts.check_only_fast = check_only_fast;
+        self
+    }
+
+    /// Whether to remove empty deflate blocks from the end of the original image data, if it is
+    /// kept
+    #[must_use]
+    pub fn trim_idat_padding(mut self, trim: bool) -> Self {
+        self.opts.trim_idat_padding = trim;
+        self
+    }
+
+    /// Whether to check that the compressed image data inflates back to the original
+    #[must_use]
+    pub fn verify_output(mut self, verify_output: bool) -> Self {
+        self.opts.verify_output = verify_output;
+        self
+    }
+
+    /// Whether to record the filter type and score of each scanline in the report
+    #[must_use]
+    pub fn collect_filter_stats(mut self, collect_filter_stats: bool) -> Self {
+        self.opts.collect_filter_stats = collect_filter_stats;
+        self
+    }
+
+    /// Whether to record the departures from the specification that are tolerated in the report
+    #[must_use]
+    pub fn collect_warnings(mut self, collect_warnings: bool) -> Self {
+        self.opts.collect_warnings = collect_warnings;
+        self
+    }
+
+    /// Whether to record the time spent in each stage of the optimization in the report
+    #[must_use]
+    pub fn collect_timings(mut self, collect_timings: bool) -> Self {
+        self.opts.collect_timings = collect_timings;
+        self
+    }
+
+    /// A callback to receive progress events
+    #[must_use]
+    pub fn progress(mut self, progress: ProgressCallback) -> Self {
+        self.opts.progress = Some(progress);
+        self
+    }
+
+    /// A pool of buffers to share with other optimizations
+    #[must_use]
+    pub fn buffer_pool(mut self, pool: Arc<BufferPool>) -> Self {
+        self.opts.buffer_pool = Some(pool);
+        self
+    }
+}
@@ -0,0 +1,674 @@
+//! Conversion between `Options` and the command line arguments of the `oxipng` binary.
+
+#[cfg(feature = "binary")]
+use crate::RowFilter;
+use crate::{Deflaters, Interlacing, Options, PngError, PngResult, StripChunks};
+#[cfg(feature = "binary")]
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use indexmap::IndexSet;
+#[cfg(all(feature = "binary", feature = "zopfli"))]
+use std::num::NonZeroU8;
+#[cfg(feature = "binary")]
+use std::path::PathBuf;
+#[cfg(feature = "binary")]
+use std::time::Duration;
+
+/// The command line arguments of the `oxipng` binary
+#[cfg(feature = "binary")]
+pub fn command() -> Command {
+    Command::new("oxipng")
+    .version(env!("CARGO_PKG_VERSION"))
+    .author("Joshua Holmer <jholmer.in@gmail.com>")
+    .about("Losslessly improves compression of PNG files")
+    .arg(
+        Arg::new("files")
+            .help("File(s) to compress (use \"-\" for stdin)")
+            .index(1)
+            .num_args(1..)
+            .use_value_delimiter(false)
+            .required(true)
+            .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+        Arg::new("optimization")
+            .help("Optimization level - Default: 2")
+            .short('o')
+            .long("opt")
+            .value_name("level")
+            .value_parser(["0", "1", "2", "3", "4", "5", "6", "max"]),
+    )
+    .arg(
+        Arg::new("backup")
+            .help("Back up modified files")
+            .short('b')
+            .long("backup")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("recursive")
+            .help("Recurse into subdirectories")
+            .short('r')
+            .long("recursive")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("output_dir")
+            .help("Write output file(s) to <directory>")
+            .long("dir")
+            .value_name("directory")
+            .value_parser(value_parser!(PathBuf))
+            .conflicts_with("output_file")
+            .conflicts_with("stdout"),
+    )
+    .arg(
+        Arg::new("output_file")
+            .help("Write output file to <file>")
+            .long("out")
+            .value_name("file")
+            .value_parser(value_parser!(PathBuf))
+            .conflicts_with("output_dir")
+            .conflicts_with("stdout"),
+    )
+    .arg(
+        Arg::new("stdout")
+            .help("Write output to stdout")
+            .long("stdout")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("output_dir")
+            .conflicts_with("output_file"),
+    )
+    .arg(
+        Arg::new("preserve")
+            .help("Preserve file attributes if possible")
+            .short('p')
+            .long("preserve")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("preserve-mtime")
+            .help("Preserve the access and modification times of files, but not other attributes")
+            .long("preserve-mtime")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("atomic")
+            .help("Write each output to a temporary file first, then rename it into place")
+            .long("atomic")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("check")
+            .help(
+                "Do not write any files, exit with status 2 if any could be optimized further. \
+                This runs the full optimization, rather than only parsing the files as it used to",
+            )
+            .short('c')
+            .long("check")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("pretend")
+            .help("Do not write any files, only calculate compression gains")
+            .short('P')
+            .long("pretend")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("strip-safe")
+            .help("Strip safely-removable metadata objects")
+            .short('s')
+            .action(ArgAction::SetTrue)
+            .conflicts_with("strip"),
+    )
+    .arg(
+        Arg::new("strip")
+            .help("Strip metadata objects ['safe', 'safe-private', 'all', or comma-separated list]\n'safe-private' is 'safe' but keeps private chunks which are safe to copy\nCAUTION: stripping 'all' will convert APNGs to standard PNGs")
+            .long("strip")
+            .value_name("mode")
+            .conflicts_with("strip-safe"),
+    )
+    .arg(
+        Arg::new("keep")
+            .help("Strip all optional metadata except objects in the comma-separated list")
+            .long("keep")
+            .value_name("list")
+            .conflicts_with("strip")
+            .conflicts_with("strip-safe"),
+    )
+    .arg(
+        Arg::new("alpha")
+            .help("Perform additional alpha optimizations")
+            .short('a')
+            .long("alpha")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("interlace")
+            .help("PNG interlace type (auto tries both and keeps the smaller) - Default: 0")
+            .short('i')
+            .long("interlace")
+            .value_name("type")
+            .value_parser(["0", "1", "keep", "auto"]),
+    )
+    .arg(
+        Arg::new("scale16")
+            .help("Forcibly reduce 16-bit images to 8-bit")
+            .long("scale16")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("verbose")
+            .help("Run in verbose mode (use multiple times to increase verbosity)")
+            .short('v')
+            .long("verbose")
+            .action(ArgAction::Count)
+            .conflicts_with("quiet"),
+    )
+    .arg(
+        Arg::new("quiet")
+            .help("Run in quiet mode")
+            .short('q')
+            .long("quiet")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("verbose"),
+    )
+    .arg(
+        Arg::new("filters")
+            .help(format!("PNG delta filters (0-{})", RowFilter::LAST))
+            .short('f')
+            .long("filters")
+            .value_parser(|x: &str| {
+                parse_numeric_range_opts(x, 0, RowFilter::LAST)
+                    .map_err(|_| "Invalid option for filters")
+            }),
+    )
+    .arg(
+        Arg::new("fixed_filter")
+            .help(format!(
+                "Use one filter (0-{}) for the whole image, instead of evaluating --filters",
+                RowFilter::LAST
+            ))
+            .long("fixed-filter")
+            .value_name("filter")
+            .value_parser(0..=i64::from(RowFilter::LAST)),
+    )
+    .arg(
+        Arg::new("fast")
+            .help("Use fast filter evaluation")
+            .long("fast")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("compression")
+            .help("zlib compression level (1-12)")
+            .long("zc")
+            .value_name("level")
+            .value_parser(1..=12)
+            .conflicts_with("zopfli"),
+    )
+    .arg(
+        Arg::new("trial_compression")
+            .help("zlib compression level (1-12) for comparing filters, before the final one")
+            .long("trial-zc")
+            .value_name("level")
+            .value_parser(1..=12),
+    )
+    .arg(
+        Arg::new("no-bit-reduction")
+            .help("No bit depth reduction")
+            .long("nb")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("no-color-reduction")
+            .help("No color type reduction")
+            .long("nc")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("no-palette-reduction")
+            .help("No palette reduction")
+            .long("np")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("no-grayscale-reduction")
+            .help("No grayscale reduction")
+            .long("ng")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("no-reductions")
+            .help("No reductions")
+            .long("nx")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("no-recoding")
+            .help("No IDAT recoding unless necessary")
+            .long("nz")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("fix")
+            .help("Enable error recovery")
+            .long("fix")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("force")
+            .help("Write the output even if it is larger than the input")
+            .long("force")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("zopfli")
+            .help("Use the slower but better compressing Zopfli algorithm")
+            .short('Z')
+            .long("zopfli")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("timeout")
+            .help("Maximum amount of time, in seconds, to spend on optimizations")
+            .value_name("secs")
+            .long("timeout")
+            .value_parser(value_parser!(u64)),
+    )
+    .arg(
+        Arg::new("threads")
+            .help("Set number of threads to use - Default: num CPU cores")
+            .long("threads")
+            .short('t')
+            .value_name("num")
+            .value_parser(value_parser!(usize)),
+    )
+    .args(cfg!(feature = "json").then(|| {
+        Arg::new("log_json")
+            .help("Write a line of JSON describing each optimized file to <file>")
+            .long("log-json")
+            .value_name("file")
+            .value_parser(value_parser!(PathBuf))
+    }))
+    .after_help(
+        "Optimization levels:
+-o 0   =>  --zc 5 --fast                (1 trial, determined heuristically)
+-o 1   =>  --zc 10 --fast               (1 trial, determined heuristically)
+-o 2   =>  --zc 11 -f 0,1,6,7 --fast    (1 trial, determined by fast evaluation)
+-o 3   =>  --zc 11 -f 0,7,8,9           (4 trials)
+-o 4   =>  --zc 12 -f 0,7,8,9           (4 trials; same as `-o 3` for zopfli)
+-o 5   =>  --zc 12 -f 0,1,2,5,6,7,8,9   (8 trials)
+-o 6   =>  --zc 12 -f 0-9               (10 trials)
+-o max =>                               (stable alias for the max compression)
+
+Manually specifying a compression option (zc, f, etc.) will override the optimization preset,
+regardless of the order you write the arguments.
+
+PNG delta filters:
+0  =>  None
+1  =>  Sub
+2  =>  Up
+3  =>  Average
+4  =>  Paeth
+Heuristic filter selection strategies:
+5  =>  MinSum    Minimum sum of absolute differences
+6  =>  Entropy   Highest Shannon entropy
+7  =>  Bigrams   Lowest count of distinct bigrams
+8  =>  BigEnt    Highest Shannon entropy of bigrams
+9  =>  Brute     Smallest compressed size (slow)
+10 =>  WeightedMsad  Minimum sum of absolute differences, favoring zeros
+11 =>  LookBack  MinSum ignoring bytes repeated from the previous line",
+    )
+}
+
+/// Build the options given by the command line arguments, other than those for logging,
+/// input and output files, and threads, which are handled by the binary
+#[cfg(feature = "binary")]
+pub fn parse_options(matches: &ArgMatches) -> Result<Options, String> {
+    let mut opts = match matches.get_one::<String>("optimization") {
+        None => Options::default(),
+        Some(x) if x == "max" => Options::max_compression(),
+        Some(level) => Options::from_preset(level.parse::<u8>().unwrap()),
+    };
+
+    if let Some(x) = matches.get_one::<String>("interlace") {
+        if x == "auto" {
+            opts.interlace_auto = true;
+        } else {
+            opts.interlace = if x == "keep" {
+                None
+            } else {
+                x.parse::<u8>().unwrap().try_into().ok()
+            };
+        }
+    }
+
+    if let Some(x) = matches.get_one::<IndexSet<u8>>("filters") {
+        opts.filter.clear();
+        for &f in x {
+            opts.filter.insert(f.try_into().unwrap());
+        }
+    }
+
+    if let Some(&f) = matches.get_one::<i64>("fixed_filter") {
+        opts.fixed_filter = Some((f as u8).try_into().unwrap());
+    }
+
+    if let Some(&num) = matches.get_one::<u64>("timeout") {
+        opts.timeout = Some(Duration::from_secs(num));
+    }
+
+    opts.optimize_alpha = matches.get_flag("alpha");
+
+    opts.scale_16 = matches.get_flag("scale16");
+    // Asking for a lossy option is enough to allow it
+    opts.lossless_only = !opts.scale_16;
+
+    opts.fast_evaluation = matches.get_flag("fast");
+
+    opts.trial_compression = matches
+        .get_one::<i64>("trial_compression")
+        .map(|&x| x as u8);
+
+    opts.backup = matches.get_flag("backup");
+
+    opts.force = matches.get_flag("force");
+
+    opts.fix_errors = matches.get_flag("fix");
+
+    opts.pretend = matches.get_flag("pretend");
+
+    opts.preserve_attrs = matches.get_flag("preserve");
+    opts.preserve_mtime = matches.get_flag("preserve-mtime");
+    opts.atomic_write = matches.get_flag("atomic");
+
+    opts.bit_depth_reduction = !matches.get_flag("no-bit-reduction");
+
+    opts.color_type_reduction = !matches.get_flag("no-color-reduction");
+
+    opts.palette_reduction = !matches.get_flag("no-palette-reduction");
+
+    opts.grayscale_reduction = !matches.get_flag("no-grayscale-reduction");
+
+    if matches.get_flag("no-reductions") {
+        opts.bit_depth_reduction = false;
+        opts.color_type_reduction = false;
+        opts.palette_reduction = false;
+        opts.grayscale_reduction = false;
+    }
+
+    opts.idat_recoding = !matches.get_flag("no-recoding");
+
+    if let Some(keep) = matches.get_one::<String>("keep") {
+        let names = keep
+            .split(',')
+            .map(parse_chunk_name)
+            .collect::<Result<_, _>>()?;
+        opts.strip = StripChunks::Keep(names)
+    }
+
+    if let Some(strip) = matches.get_one::<String>("strip") {
+        if strip == "safe" {
+            opts.strip = StripChunks::Safe;
+        } else if strip == "safe-private" {
+            opts.strip = StripChunks::SafeKeepPrivate;
+        } else if strip == "all" {
+            opts.strip = StripChunks::All;
+        } else {
+            const FORBIDDEN_CHUNKS: [[u8; 4]; 5] =
+                [*b"IHDR", *b"IDAT", *b"tRNS", *b"PLTE", *b"IEND"];
+            let names = strip
+                .split(',')
+                .map(|x| {
+                    if x == "safe" || x == "safe-private" || x == "all" {
+                        return Err(
+                            "'safe', 'safe-private' or 'all' presets for --strip should be used by themselves"
+                                .to_owned(),
+                        );
+                    }
+                    let name = parse_chunk_name(x)?;
+                    if FORBIDDEN_CHUNKS.contains(&name) {
+                        return Err(format!("{} chunk is not allowed to be stripped", x));
+                    }
+                    Ok(name)
+                })
+                .collect::<Result<_, _>>()?;
+            opts.strip = StripChunks::Strip(names);
+        }
+    }
+
+    if matches.get_flag("strip-safe") {
+        opts.strip = StripChunks::Safe;
+    }
+
+    if matches.get_flag("zopfli") {
+        #[cfg(feature = "zopfli")]
+        if let Some(iterations) = NonZeroU8::new(15) {
+            opts.deflate = Deflaters::Zopfli { iterations };
+        }
+    } else if let Deflaters::Libdeflater { compression } = &mut opts.deflate {
+        if let Some(x) = matches.get_one::<i64>("compression") {
+            *compression = *x as u8;
+        }
+    }
+
+    Ok(opts)
+}
+
+/// Serialize the options into the command line arguments that would reproduce them
+pub(crate) fn to_cli_args(opts: &Options) -> PngResult<Vec<String>> {
+    let mut args = Vec::new();
+    let mut push = |arg: &str| args.push(arg.to_owned());
+
+    // Palette order optimization and an empty filter set are only available from presets.
+    // All of the other settings of the preset are overridden by the arguments below.
+    let preset = if opts.filter.is_empty() {
+        push("-o0");
+        0
+    } else if opts.optimize_palette_order {
+        push("-o5");
+        5
+    } else {
+        2
+    };
+    check_cli_expressible(opts, &Options::from_preset(preset))?;
+
+    match opts.deflate {
+        Deflaters::Libdeflater { compression } => push(&format!("--zc={}", compression)),
+        // The binary always uses 15 iterations
+        #[cfg(feature = "zopfli")]
+        Deflaters::Zopfli { iterations } if iterations.get() == 15 => push("--zopfli"),
+        ref deflater => {
+            return Err(PngError::new(&format!(
+                "The deflater {} can't be given on the command line",
+                deflater
+            )))
+        }
+    }
+    // The binary only turns off lossless_only to allow --scale16
+    if opts.lossless_only == opts.scale_16 {
+        return Err(PngError::new(
+            "lossless_only can only be turned off on the command line by --scale16",
+        ));
+    }
+    if !opts.filter.is_empty() {
+        let filters: Vec<_> = opts.filter.iter().map(|&f| (f as u8).to_string()).collect();
+        push(&format!("--filters={}", filters.join(",")));
+    }
+    if let Some(filter) = opts.fixed_filter {
+        push(&format!("--fixed-filter={}", filter as u8));
+    }
+    if opts.fast_evaluation {
+        push("--fast");
+    }
+    if let Some(level) = opts.trial_compression {
+        push(&format!("--trial-zc={}", level));
+    }
+    push(match opts.interlace {
+        _ if opts.interlace_auto => "--interlace=auto",
+        Some(Interlacing::None) => "--interlace=0",
+        Some(Interlacing::Adam7) => "--interlace=1",
+        None => "--interlace=keep",
+    });
+    let chunk_list = |names: &IndexSet<[u8; 4]>| {
+        names
+            .iter()
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    match &opts.strip {
+        StripChunks::None => {}
+        StripChunks::Strip(names) => push(&format!("--strip={}", chunk_list(names))),
+        StripChunks::Safe => push("--strip=safe"),
+        StripChunks::SafeKeepPrivate => push("--strip=safe-private"),
+        StripChunks::Keep(names) => push(&format!("--keep={}", chunk_list(names))),
+        StripChunks::All => push("--strip=all"),
+    }
+    if let Some(timeout) = opts.timeout {
+        if timeout.subsec_nanos() != 0 {
+            return Err(PngError::new(
+                "The timeout can only be given in whole seconds on the command line",
+            ));
+        }
+        push(&format!("--timeout={}", timeout.as_secs()));
+    }
+
+    let flags = [
+        (opts.optimize_alpha, "--alpha"),
+        (opts.scale_16, "--scale16"),
+        (opts.backup, "--backup"),
+        (opts.force, "--force"),
+        (opts.fix_errors, "--fix"),
+        (opts.pretend, "--pretend"),
+        (opts.preserve_attrs, "--preserve"),
+        (opts.preserve_mtime, "--preserve-mtime"),
+        (opts.atomic_write, "--atomic"),
+        (!opts.bit_depth_reduction, "--nb"),
+        (!opts.color_type_reduction, "--nc"),
+        (!opts.palette_reduction, "--np"),
+        (!opts.grayscale_reduction, "--ng"),
+        (!opts.idat_recoding, "--nz"),
+    ];
+    for (enabled, flag) in flags {
+        if enabled {
+            push(flag);
+        }
+    }
+    Ok(args)
+}
+
+/// Check that the settings which have no command line argument are those of the preset
+///
+/// Callbacks, the thread pool, the cancellation flag and the buffer pool aren't checked, as they
+/// only make sense within a program using the library.
+fn check_cli_expressible(opts: &Options, preset: &Options) -> PngResult<()> {
+    macro_rules! check_fields {
+        ($($field:ident),* $(,)?) => {
+            $(
+                if format!("{:?}", opts.$field) != format!("{:?}", preset.$field) {
+                    return Err(PngError::new(concat!(
+                        "The ",
+                        stringify!($field),
+                        " setting can't be given on the command line"
+                    )));
+                }
+            )*
+        };
+    }
+    check_fields!(
+        parse_only,
+        optimize_alpha_aggressive,
+        optimize_palette_order,
+        grayscale_tolerance,
+        use_significant_bits,
+        max_palette_colors,
+        force_color_type,
+        force_bit_depth,
+        add_bkgd_to_palette,
+        drop_incompatible_icc,
+        max_idat_size,
+        dither_16_to_8,
+        max_chunk_size,
+        strip_oversized_chunks,
+        ancillary_deflate,
+        phase_timeouts,
+        target_size,
+        optimize_animation,
+        strip_exif_except_orientation,
+        optimize_icc,
+        dedup_text,
+        physical_dimensions,
+        time,
+        chunk_order,
+        check_only_fast,
+        trim_idat_padding,
+        verify_output,
+        collect_filter_stats,
+        collect_warnings,
+        collect_timings,
+    );
+    Ok(())
+}
+
+#[cfg(feature = "binary")]
+fn parse_chunk_name(name: &str) -> Result<[u8; 4], String> {
+    name.trim()
+        .as_bytes()
+        .try_into()
+        .map_err(|_| format!("Invalid chunk name {}", name))
+}
+
+#[cfg(feature = "binary")]
+fn parse_numeric_range_opts(
+    input: &str,
+    min_value: u8,
+    max_value: u8,
+) -> Result<IndexSet<u8>, String> {
+    const ERROR_MESSAGE: &str = "Not a valid input";
+    let mut items = IndexSet::new();
+
+    // one value
+    if let Ok(one_value) = input.parse::<u8>() {
+        if (min_value <= one_value) && (one_value <= max_value) {
+            items.insert(one_value);
+            return Ok(items);
+        }
+    }
+
+    // a range ("A-B")
+    let range_values = input.split('-').collect::<Vec<&str>>();
+    if range_values.len() == 2 {
+        let first_opt = range_values[0].parse::<u8>();
+        let second_opt = range_values[1].parse::<u8>();
+        if let (Ok(first), Ok(second)) = (first_opt, second_opt) {
+            if min_value <= first && first < second && second <= max_value {
+                for i in first..=second {
+                    items.insert(i);
+                }
+                return Ok(items);
+            }
+        }
+        return Err(ERROR_MESSAGE.to_owned());
+    }
+
+    // a list ("A,B[,…]")
+    let list_items = input.split(',').collect::<Vec<&str>>();
+    if list_items.len() > 1 {
+        for value in list_items {
+            if let Ok(value_int) = value.parse::<u8>() {
+                if (min_value <= value_int)
+                    && (value_int <= max_value)
+                    && !items.contains(&value_int)
+                {
+                    items.insert(value_int);
+                    continue;
+                }
+            }
+            return Err(ERROR_MESSAGE.to_owned());
+        }
+        return Ok(items);
+    }
+
+    Err(ERROR_MESSAGE.to_owned())
+}
@@ -4,6 +4,7 @@ use std::{fmt, fmt::Display};
 use crate::PngError;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 /// The color type used to represent this image
 pub enum ColorType {
     /// Grayscale, with one color channel
@@ -83,6 +84,16 @@ impl ColorType {
         matches!(self, ColorType::GrayscaleAlpha | ColorType::RGBA)
     }
 
+    /// Whether the PNG specification allows this color type to have the given bit depth
+    #[inline]
+    pub(crate) fn allows_bit_depth(&self, bit_depth: BitDepth) -> bool {
+        match self {
+            ColorType::Grayscale { .. } => true,
+            ColorType::Indexed { .. } => bit_depth <= BitDepth::Eight,
+            _ => bit_depth >= BitDepth::Eight,
+        }
+    }
+
     #[inline]
     pub(crate) fn has_trns(&self) -> bool {
         match self {
@@ -93,8 +104,19 @@ impl ColorType {
     }
 }
 
+/// The number of entries a tRNS chunk needs for the given palette.
+/// Trailing opaque entries are left out, as they are implied by a shorter chunk.
+#[inline]
+pub(crate) fn trns_len(palette: &[RGBA8]) -> usize {
+    palette
+        .iter()
+        .rposition(|px| px.a != 255)
+        .map_or(0, |i| i + 1)
+}
+
 #[repr(u8)]
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 /// The number of bits to be used per channel per pixel
 pub enum BitDepth {
     /// One bit per channel per pixel
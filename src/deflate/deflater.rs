@@ -1,22 +1,104 @@
 use crate::atomicmin::AtomicMin;
 use crate::{PngError, PngResult};
 use libdeflater::*;
+use std::fmt;
+use std::io::{self, Write};
 
+/// Compress the data with libdeflate into a zlib stream, at a level from 1 to 12
+///
+/// If `max_size` is set, it is a hard cap: the output is never larger, and if it can't fit,
+/// `PngError::DeflatedDataTooLong` is returned with the cap, never partial output. libdeflate
+/// gives up as soon as its output buffer is full, so a cap that is far too small is cheap. The
+/// value of `max_size` is read once at the start, and is not updated with the size of the result.
 pub fn deflate(data: &[u8], level: u8, max_size: &AtomicMin) -> PngResult<Vec<u8>> {
+    let mut dest = Vec::new();
+    deflate_into(data, level, max_size, &mut dest)?;
+    Ok(dest)
+}
+
+/// Compress the data into `dest`, reusing its allocation where possible, following the contract
+/// of `deflate`
+///
+/// On failure, `dest` is left empty.
+pub fn deflate_into(
+    data: &[u8],
+    level: u8,
+    max_size: &AtomicMin,
+    dest: &mut Vec<u8>,
+) -> PngResult<()> {
     let mut compressor = Compressor::new(CompressionLvl::new(level.into()).unwrap());
+    let max = max_size.get();
     // If adhering to a max_size we need to include at least 9 extra bytes of slack space (as specified in docs).
+    let capacity = max.unwrap_or_else(|| compressor.zlib_compress_bound(data.len())) + 9;
+    dest.clear();
+    dest.resize(capacity, 0);
+    match compressor.zlib_compress(data, dest) {
+        // The slack space may hold output just over the cap, which must be rejected too
+        Ok(len) if max.map_or(true, |max| len <= max) => {
+            dest.truncate(len);
+            Ok(())
+        }
+        Ok(_) | Err(CompressionError::InsufficientSpace) => {
+            dest.clear();
+            Err(PngError::DeflatedDataTooLong(capacity - 9))
+        }
+    }
+}
+
+#[cfg(feature = "zlib-ng")]
+pub fn zlib_ng_deflate(data: &[u8], level: u8, max_size: &AtomicMin) -> PngResult<Vec<u8>> {
+    use libz_ng_sys::{compress2, compressBound, Z_BUF_ERROR, Z_OK};
+
+    // Unlike libdeflate, zlib-ng needs no slack space to detect that the output won't fit
     let capacity = max_size
         .get()
-        .unwrap_or_else(|| compressor.zlib_compress_bound(data.len()))
-        + 9;
+        .unwrap_or_else(|| unsafe { compressBound(data.len()) });
     let mut dest = vec![0; capacity];
-    let len = compressor
-        .zlib_compress(data, &mut dest)
-        .map_err(|err| match err {
-            CompressionError::InsufficientSpace => PngError::DeflatedDataTooLong(capacity - 9),
-        })?;
-    dest.truncate(len);
-    Ok(dest)
+    let mut len = capacity;
+    let result = unsafe {
+        compress2(
+            dest.as_mut_ptr(),
+            &mut len,
+            data.as_ptr(),
+            data.len(),
+            level.into(),
+        )
+    };
+    match result {
+        Z_OK => {
+            dest.truncate(len);
+            Ok(dest)
+        }
+        Z_BUF_ERROR => Err(PngError::DeflatedDataTooLong(capacity)),
+        _ => Err(PngError::new("Failed to compress in zlib-ng")),
+    }
+}
+
+/// Compress the data with miniz_oxide into a zlib stream, at a level from 0 to 10
+///
+/// This follows the contract of `deflate`. miniz_oxide hands over its output a buffer at a time,
+/// so it gives up once a buffer takes the output past `max_size`, rather than straight away.
+#[cfg(feature = "miniz")]
+pub fn miniz_oxide_deflate(data: &[u8], level: u8, max_size: &AtomicMin) -> PngResult<Vec<u8>> {
+    use miniz_oxide::deflate::core::{
+        compress_to_output, create_comp_flags_from_zip_params, CompressorOxide, TDEFLFlush,
+        TDEFLStatus,
+    };
+
+    let max = max_size.get();
+    // A positive window size asks for the zlib header and checksum
+    let flags = create_comp_flags_from_zip_params(level.into(), 15, 0);
+    let mut compressor = CompressorOxide::new(flags);
+    let mut dest = Vec::new();
+    let (status, _) = compress_to_output(&mut compressor, data, TDEFLFlush::Finish, |buf| {
+        dest.extend_from_slice(buf);
+        max.map_or(true, |max| dest.len() <= max)
+    });
+    match (status, max) {
+        (TDEFLStatus::Done, _) => Ok(dest),
+        (TDEFLStatus::PutBufFailed, Some(max)) => Err(PngError::DeflatedDataTooLong(max)),
+        _ => Err(PngError::new("Failed to compress in miniz_oxide")),
+    }
 }
 
 pub fn inflate(data: &[u8], out_size: usize) -> PngResult<Vec<u8>> {
@@ -32,8 +114,59 @@ pub fn inflate(data: &[u8], out_size: usize) -> PngResult<Vec<u8>> {
     Ok(dest)
 }
 
+/// The CRC-32 of the data, as stored at the end of each PNG chunk
+///
+/// This is libdeflate's implementation, which uses carry-less multiplication instructions where
+/// the CPU has them. It runs at several GB/s, so checksums are a negligible part of writing the
+/// output compared to compression, and they are always computed in full as the specification
+/// requires.
 pub fn crc32(data: &[u8]) -> u32 {
     let mut crc = Crc::new();
     crc.update(data);
     crc.sum()
 }
+
+/// A writer which passes data on to another writer, while accumulating the CRC-32 of everything
+/// written, so that chunks can be written without first being copied into one buffer
+pub struct Crc32Writer<W> {
+    inner: W,
+    crc: Crc,
+}
+
+impl<W: Write> Crc32Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            crc: Crc::new(),
+        }
+    }
+
+    /// The CRC-32 of the data written so far
+    pub fn crc(&self) -> u32 {
+        self.crc.sum()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Crc32Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.crc.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W> fmt::Debug for Crc32Writer<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Crc32Writer")
+            .field("crc", &self.crc.sum())
+            .finish_non_exhaustive()
+    }
+}
@@ -1,19 +1,93 @@
 mod deflater;
+mod trim;
 use crate::AtomicMin;
 use crate::{PngError, PngResult};
 pub use deflater::crc32;
 pub use deflater::deflate;
+pub(crate) use deflater::deflate_into;
 pub use deflater::inflate;
+#[cfg(feature = "miniz")]
+pub use deflater::miniz_oxide_deflate;
+#[cfg(feature = "zlib-ng")]
+pub use deflater::zlib_ng_deflate;
+pub use deflater::Crc32Writer;
+use std::sync::Arc;
 use std::{fmt, fmt::Display};
+pub use trim::trim_empty_blocks;
 
 #[cfg(feature = "zopfli")]
 use std::num::NonZeroU8;
 #[cfg(feature = "zopfli")]
 mod zopfli_oxipng;
 #[cfg(feature = "zopfli")]
+pub use zopfli_oxipng::compresses_in_parallel as zopfli_compresses_in_parallel;
+#[cfg(feature = "zopfli")]
 pub use zopfli_oxipng::deflate as zopfli_deflate;
+#[cfg(feature = "zopfli")]
+pub use zopfli_oxipng::deflate_raw as zopfli_deflate_raw;
+#[cfg(feature = "zopfli")]
+pub use zopfli_oxipng::deflate_raw_sequential as zopfli_deflate_raw_sequential;
+#[cfg(feature = "zopfli")]
+pub use zopfli_oxipng::{ZopfliDeflater, DEFAULT_MAX_BLOCK_SPLITS};
+
+/// The FLEVEL value of a zlib header for the slowest, maximum compression
+pub const ZLIB_FLEVEL_MAXIMUM: u8 = 3;
+
+/// Build the header of a zlib stream with a 32K window and no preset dictionary,
+/// declaring the given compression level (FLEVEL, from 0 for fastest to 3 for maximum)
+pub fn zlib_header(flevel: u8) -> [u8; 2] {
+    assert!(flevel <= ZLIB_FLEVEL_MAXIMUM, "FLEVEL must be 0-3");
+    // Compression method 8 (DEFLATE) with a window size of 2^(7+8)
+    let cmf: u8 = 0x78;
+    let fdict: u8 = 0;
+    let flg = flevel << 6 | fdict << 5;
+    // The check bits make the header a multiple of 31
+    let fcheck = (31 - u16::from_be_bytes([cmf, flg]) % 31) % 31;
+    [cmf, flg | fcheck as u8]
+}
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// A DEFLATE implementation which can be plugged into oxipng with `Deflaters::Custom`
+///
+/// The `Display` implementation is used to describe the algorithm in log messages.
+///
+/// ```
+/// use oxipng::{AtomicMin, Deflater, Deflaters, Options, PngResult};
+/// use std::fmt;
+/// use std::sync::Arc;
+///
+/// #[derive(Debug)]
+/// struct Fast;
+///
+/// impl fmt::Display for Fast {
+///     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+///         f.write_str("fast")
+///     }
+/// }
+///
+/// impl Deflater for Fast {
+///     fn deflate(&self, data: &[u8], max_size: &AtomicMin) -> PngResult<Vec<u8>> {
+///         Deflaters::Libdeflater { compression: 1 }.deflate(data, max_size)
+///     }
+/// }
+///
+/// let opts = Options {
+///     deflate: Deflaters::Custom(Arc::new(Fast)),
+///     ..Options::default()
+/// };
+/// ```
+pub trait Deflater: fmt::Debug + Display + Send + Sync {
+    /// Compress the data into a zlib stream
+    ///
+    /// `max_size` holds the size of the smallest result found so far, which may be lowered by
+    /// other threads while this runs. Once the output is known to be larger than this, compression
+    /// may be abandoned by returning `PngError::DeflatedDataTooLong`. Output larger than the
+    /// maximum is discarded anyway, so need not be avoided. The caller updates `max_size` with
+    /// the size of successful results, so the deflater should not.
+    fn deflate(&self, data: &[u8], max_size: &AtomicMin) -> PngResult<Vec<u8>>;
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 /// DEFLATE algorithms supported by oxipng
 pub enum Deflaters {
     /// Use libdeflater.
@@ -29,14 +103,101 @@ pub enum Deflaters {
         /// less iterations, or else they will be too slow.
         iterations: NonZeroU8,
     },
+    #[cfg(feature = "zlib-ng")]
+    /// Use a zlib-ng library linked into the binary
+    ZlibNg {
+        /// Which compression level to use on the file (1-9)
+        level: u8,
+    },
+    #[cfg(feature = "miniz")]
+    /// Use the pure Rust miniz_oxide library
+    ///
+    /// Its output is typically larger than that of libdeflate at a similar level, and it is
+    /// slower. This doesn't remove the C dependency on libdeflate, which is still used to
+    /// decompress and verify the data and to compute checksums.
+    MinizOxide {
+        /// Which compression level to use on the file (0-10)
+        level: u8,
+    },
+    /// Use a DEFLATE implementation supplied by the user
+    Custom(
+        #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_custom"))]
+        Arc<dyn Deflater>,
+    ),
+}
+
+/// Custom deflaters are serialized as their description
+#[cfg(feature = "serde")]
+fn serialize_custom<S: serde::Serializer>(
+    deflater: &Arc<dyn Deflater>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(deflater)
 }
 
+impl PartialEq for Deflaters {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Libdeflater { compression: a }, Self::Libdeflater { compression: b }) => a == b,
+            #[cfg(feature = "zopfli")]
+            (Self::Zopfli { iterations: a }, Self::Zopfli { iterations: b }) => a == b,
+            #[cfg(feature = "zlib-ng")]
+            (Self::ZlibNg { level: a }, Self::ZlibNg { level: b }) => a == b,
+            #[cfg(feature = "miniz")]
+            (Self::MinizOxide { level: a }, Self::MinizOxide { level: b }) => a == b,
+            // Custom deflaters are only known to be the same if they are the same instance
+            (Self::Custom(a), Self::Custom(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Deflaters {}
+
 impl Deflaters {
-    pub(crate) fn deflate(self, data: &[u8], max_size: &AtomicMin) -> PngResult<Vec<u8>> {
+    /// Compress the data with this algorithm, following the contract of `Deflater::deflate`
+    ///
+    /// This can be used outside of PNG optimization to compress a zlib stream under a size
+    /// limit. If `max_size` is set, the output is never larger: if it doesn't fit,
+    /// `PngError::DeflatedDataTooLong` is returned with the limit, and no partial output.
+    /// libdeflate and zlib-ng stop as soon as the output can't fit, and miniz_oxide soon after.
+    /// Zopfli can only stop between the sections of large data it compresses in parallel, and
+    /// other algorithms only check the size once done. The limit is read again when
+    /// compression is done, so output that fit the limit it started with is still rejected if
+    /// another thread has since lowered it. `max_size` is never updated with the size of the
+    /// result.
+    ///
+    /// ```
+    /// use oxipng::{AtomicMin, Deflaters, PngError};
+    ///
+    /// let data = vec![7; 10_000];
+    /// let deflater = Deflaters::Libdeflater { compression: 6 };
+    /// let compressed = deflater.deflate(&data, &AtomicMin::new(None)).unwrap();
+    ///
+    /// // The exact size fits, but a byte less doesn't
+    /// let limit = AtomicMin::new(Some(compressed.len()));
+    /// assert_eq!(deflater.deflate(&data, &limit).unwrap(), compressed);
+    /// let limit = AtomicMin::new(Some(compressed.len() - 1));
+    /// assert!(matches!(
+    ///     deflater.deflate(&data, &limit),
+    ///     Err(PngError::DeflatedDataTooLong(size)) if size == compressed.len() - 1
+    /// ));
+    /// assert_eq!(limit.get(), Some(compressed.len() - 1));
+    /// ```
+    pub fn deflate(&self, data: &[u8], max_size: &AtomicMin) -> PngResult<Vec<u8>> {
         let compressed = match self {
-            Self::Libdeflater { compression } => deflate(data, compression, max_size)?,
+            Self::Libdeflater { compression } => deflate(data, *compression, max_size)?,
             #[cfg(feature = "zopfli")]
-            Self::Zopfli { iterations } => zopfli_deflate(data, iterations)?,
+            Self::Zopfli { iterations } => ZopfliDeflater {
+                iterations: *iterations,
+                max_block_splits: DEFAULT_MAX_BLOCK_SPLITS,
+            }
+            .deflate(data, max_size)?,
+            #[cfg(feature = "zlib-ng")]
+            Self::ZlibNg { level } => zlib_ng_deflate(data, *level, max_size)?,
+            #[cfg(feature = "miniz")]
+            Self::MinizOxide { level } => miniz_oxide_deflate(data, *level, max_size)?,
+            Self::Custom(deflater) => deflater.deflate(data, max_size)?,
         };
         if let Some(max) = max_size.get() {
             if compressed.len() > max {
@@ -45,6 +206,46 @@ impl Deflaters {
         }
         Ok(compressed)
     }
+
+    /// The FLEVEL value of the zlib header written by this algorithm, which each library derives
+    /// from its own compression level, or `None` for a custom deflater
+    pub fn zlib_flevel(&self) -> Option<u8> {
+        Some(match self {
+            // libdeflate counts levels 6 and 7 as the default
+            Self::Libdeflater { compression } => match compression {
+                0 | 1 => 0,
+                2..=5 => 1,
+                6 | 7 => 2,
+                _ => ZLIB_FLEVEL_MAXIMUM,
+            },
+            #[cfg(feature = "zopfli")]
+            Self::Zopfli { .. } => ZLIB_FLEVEL_MAXIMUM,
+            #[cfg(feature = "zlib-ng")]
+            Self::ZlibNg { level } => match level {
+                0 | 1 => 0,
+                2..=5 => 1,
+                6 => 2,
+                _ => ZLIB_FLEVEL_MAXIMUM,
+            },
+            #[cfg(feature = "miniz")]
+            Self::MinizOxide { level } => match level {
+                0 | 1 => 0,
+                2 | 3 => 1,
+                4..=8 => 2,
+                _ => ZLIB_FLEVEL_MAXIMUM,
+            },
+            Self::Custom(_) => return None,
+        })
+    }
+}
+
+/// Check that the compressed data inflates back to exactly the expected data
+pub fn verify_deflated(compressed: &[u8], expected: &[u8]) -> PngResult<()> {
+    // One extra byte of space is enough to detect data that inflates too long
+    match inflate(compressed, expected.len() + 1) {
+        Ok(inflated) if inflated == expected => Ok(()),
+        _ => Err(PngError::VerificationFailed),
+    }
 }
 
 impl Display for Deflaters {
@@ -54,6 +255,11 @@ impl Display for Deflaters {
             Self::Libdeflater { compression } => Display::fmt(compression, f),
             #[cfg(feature = "zopfli")]
             Self::Zopfli { .. } => Display::fmt("zopfli", f),
+            #[cfg(feature = "zlib-ng")]
+            Self::ZlibNg { level } => write!(f, "zlib-ng:{}", level),
+            #[cfg(feature = "miniz")]
+            Self::MinizOxide { level } => write!(f, "miniz:{}", level),
+            Self::Custom(deflater) => Display::fmt(deflater, f),
         }
     }
 }
@@ -0,0 +1,231 @@
+//! Removal of empty blocks from the end of a zlib stream, such as those left by flushing
+
+use super::{inflate, verify_deflated};
+
+/// The extra bits of the length codes 257 to 285
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// The extra bits of the distance codes 0 to 29
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order in which the code lengths of the code length alphabet are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Remove the empty blocks that follow the last block holding any data, which becomes the final
+/// block, returning the shorter stream
+///
+/// Returns `None` if there is nothing to remove, if the stream can't be parsed, or if the trimmed
+/// stream doesn't inflate to the same `inflated_size` bytes as the original.
+pub fn trim_empty_blocks(data: &[u8], inflated_size: usize) -> Option<Vec<u8>> {
+    // A preset dictionary is never used in PNG, and the checksum must follow the stream
+    if data.len() < 6 || data[1] & 0x20 != 0 {
+        return None;
+    }
+    let (stream, adler) = data[2..].split_at(data.len() - 6);
+    let (start, end) = last_data_block(stream)?;
+    let length = (end + 7) / 8;
+    if length >= stream.len() {
+        return None;
+    }
+
+    let mut trimmed = Vec::with_capacity(length + 6);
+    trimmed.extend_from_slice(&data[..2]);
+    trimmed.extend_from_slice(&stream[..length]);
+    // Set the BFINAL bit of the block and clear the bits after its end, which belonged to the
+    // empty blocks
+    trimmed[2 + start / 8] |= 1 << (start % 8);
+    if end % 8 != 0 {
+        trimmed[1 + length] &= (1 << (end % 8)) - 1;
+    }
+    // The checksum is of the inflated data, which is unchanged
+    trimmed.extend_from_slice(adler);
+
+    let original = inflate(data, inflated_size).ok()?;
+    verify_deflated(&trimmed, &original).ok()?;
+    Some(trimmed)
+}
+
+/// Find the bit positions of the start and the end of the last block holding any data in a
+/// deflate stream, up to its final block
+fn last_data_block(stream: &[u8]) -> Option<(usize, usize)> {
+    let mut bits = Bits {
+        data: stream,
+        pos: 0,
+    };
+    let mut last = None;
+    loop {
+        let start = bits.pos;
+        let is_final = bits.read(1)? == 1;
+        let has_data = match bits.read(2)? {
+            0 => {
+                bits.align();
+                let len = bits.read(16)?;
+                if bits.read(16)? != !len & 0xFFFF {
+                    return None;
+                }
+                bits.skip(len as usize * 8)?;
+                len > 0
+            }
+            1 => {
+                let mut lengths = [8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                let litlen = Huffman::new(&lengths)?;
+                let distance = Huffman::new(&[5; 30])?;
+                skip_codes(&mut bits, &litlen, &distance)?
+            }
+            2 => {
+                let (litlen, distance) = read_dynamic_codes(&mut bits)?;
+                skip_codes(&mut bits, &litlen, &distance)?
+            }
+            _ => return None,
+        };
+        if has_data {
+            last = Some((start, bits.pos));
+        }
+        if is_final {
+            break;
+        }
+    }
+    last
+}
+
+/// Read the codes of a block compressed with dynamic Huffman codes
+fn read_dynamic_codes(bits: &mut Bits) -> Option<(Huffman, Huffman)> {
+    let num_litlen = bits.read(5)? as usize + 257;
+    let num_distance = bits.read(5)? as usize + 1;
+    let num_code_lengths = bits.read(4)? as usize + 4;
+    if num_litlen > 286 || num_distance > 30 {
+        return None;
+    }
+    let mut code_lengths = [0; 19];
+    for &i in &CODE_LENGTH_ORDER[..num_code_lengths] {
+        code_lengths[i] = bits.read(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths)?;
+
+    let mut lengths = vec![0; num_litlen + num_distance];
+    let mut i = 0;
+    while i < lengths.len() {
+        let (value, repeat) = match code_length_code.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.get(i.checked_sub(1)?)?, 3 + bits.read(2)?),
+            17 => (0, 3 + bits.read(3)?),
+            _ => (0, 11 + bits.read(7)?),
+        };
+        let repeated = lengths.get_mut(i..i + repeat as usize)?;
+        repeated.fill(value);
+        i += repeat as usize;
+    }
+    // A block can't be ended without a code for the end of block symbol
+    if lengths[256] == 0 {
+        return None;
+    }
+    Some((
+        Huffman::new(&lengths[..num_litlen])?,
+        Huffman::new(&lengths[num_litlen..])?,
+    ))
+}
+
+/// Read the codes of a compressed block up to its end, returning whether it held any data
+fn skip_codes(bits: &mut Bits, litlen: &Huffman, distance: &Huffman) -> Option<bool> {
+    let mut has_data = false;
+    loop {
+        match litlen.decode(bits)? {
+            0..=255 => {}
+            256 => return Some(has_data),
+            symbol => {
+                bits.read(u32::from(*LENGTH_EXTRA_BITS.get(symbol as usize - 257)?))?;
+                let symbol = distance.decode(bits)?;
+                bits.read(u32::from(*DISTANCE_EXTRA_BITS.get(symbol as usize)?))?;
+            }
+        }
+        has_data = true;
+    }
+}
+
+/// A reader of the bits of a deflate stream, from the least significant bit of each byte
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Bits<'_> {
+    fn read(&mut self, count: u32) -> Option<u32> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self.data.get(self.pos / 8)?;
+            value |= u32::from(byte >> (self.pos % 8) & 1) << i;
+            self.pos += 1;
+        }
+        Some(value)
+    }
+
+    fn skip(&mut self, count: usize) -> Option<()> {
+        self.pos += count;
+        (self.pos <= self.data.len() * 8).then_some(())
+    }
+
+    fn align(&mut self) {
+        self.pos = (self.pos + 7) / 8 * 8;
+    }
+}
+
+/// A canonical Huffman code, decoded a bit at a time
+struct Huffman {
+    /// The number of codes of each length
+    counts: [u16; 16],
+    /// The symbols, ordered by their codes
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Option<Self> {
+        let mut counts = [0; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        // Reject codes with more codes of a length than there is room for
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return None;
+            }
+        }
+        let mut offsets = [0; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Some(Self { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Option<u16> {
+        let mut code = 0;
+        let mut first = 0;
+        let mut index = 0;
+        for &count in &self.counts[1..] {
+            code |= bits.read(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return self.symbols.get((index + code - first) as usize).copied();
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
@@ -1,18 +1,310 @@
-use crate::{PngError, PngResult};
+use super::{zlib_header, Deflater, ZLIB_FLEVEL_MAXIMUM};
+#[cfg(not(threads))]
+use crate::rayon;
+use crate::{AtomicMin, PngError, PngResult};
+use std::fmt;
 use std::num::NonZeroU8;
 
+/// The limit on block splits used by `Deflaters::Zopfli`
+pub const DEFAULT_MAX_BLOCK_SPLITS: u16 = 15;
+
+/// Zopfli compression with control over block splitting, for use with `Deflaters::Custom`
+///
+/// `Deflaters::Zopfli` is equivalent to this with `DEFAULT_MAX_BLOCK_SPLITS`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ZopfliDeflater {
+    /// The number of compression iterations to do
+    pub iterations: NonZeroU8,
+    /// The maximum number of blocks to split the data into, or 0 for no limit
+    pub max_block_splits: u16,
+}
+
+impl Deflater for ZopfliDeflater {
+    fn deflate(&self, data: &[u8], max_size: &AtomicMin) -> PngResult<Vec<u8>> {
+        let output = zlib_compress(data, self.iterations, self.max_block_splits, max_size)?;
+        // zopfli can't be stopped partway through a section, so this is only checked between
+        // sections and once done
+        match max_size.get() {
+            Some(max) if output.len() > max => Err(PngError::DeflatedDataTooLong(max)),
+            _ => Ok(output),
+        }
+    }
+}
+
+impl fmt::Display for ZopfliDeflater {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "zopfli:{}", self.max_block_splits)
+    }
+}
+
+/// Compress to a zlib stream, with a header declaring maximum compression
 pub fn deflate(data: &[u8], iterations: NonZeroU8) -> PngResult<Vec<u8>> {
+    zlib_compress(
+        data,
+        iterations,
+        DEFAULT_MAX_BLOCK_SPLITS,
+        &AtomicMin::new(None),
+    )
+}
+
+/// Compress to a raw DEFLATE stream, without the zlib header and adler32 checksum
+pub fn deflate_raw(data: &[u8], iterations: NonZeroU8) -> PngResult<Vec<u8>> {
+    compress(
+        data,
+        iterations,
+        DEFAULT_MAX_BLOCK_SPLITS,
+        &AtomicMin::new(None),
+    )
+}
+
+/// Compress to a raw DEFLATE stream as a single section on the current thread, for comparison
+/// with `deflate_raw`
+pub fn deflate_raw_sequential(data: &[u8], iterations: NonZeroU8) -> PngResult<Vec<u8>> {
+    compress_sequential(data, &options(iterations, DEFAULT_MAX_BLOCK_SPLITS))
+}
+
+fn zlib_compress(
+    data: &[u8],
+    iterations: NonZeroU8,
+    max_block_splits: u16,
+    max_size: &AtomicMin,
+) -> PngResult<Vec<u8>> {
+    let raw = compress(data, iterations, max_block_splits, max_size)?;
+    let mut output = Vec::with_capacity(raw.len() + 6);
+    output.extend_from_slice(&zlib_header(ZLIB_FLEVEL_MAXIMUM));
+    output.extend_from_slice(&raw);
+    output.extend_from_slice(&libdeflater::adler32(data).to_be_bytes());
+    Ok(output)
+}
+
+fn options(iterations: NonZeroU8, max_block_splits: u16) -> zopfli::Options {
+    zopfli::Options {
+        iteration_count: iterations,
+        maximum_block_splits: max_block_splits,
+    }
+}
+
+#[cfg_attr(not(threads), allow(unused_variables))]
+fn compress(
+    data: &[u8],
+    iterations: NonZeroU8,
+    max_block_splits: u16,
+    max_size: &AtomicMin,
+) -> PngResult<Vec<u8>> {
+    let options = options(iterations, max_block_splits);
+    #[cfg(threads)]
+    if compresses_in_parallel(data.len()) {
+        if let Some(output) = parallel::compress(data, &options, max_size)? {
+            return Ok(output);
+        }
+    }
+    compress_sequential(data, &options)
+}
+
+/// Whether data of this length is compressed in sections on separate threads, rather than as a
+/// whole. This needs more than one section, and more than one thread in the current pool, as
+/// splitting the data only costs time and compression on a single thread.
+pub fn compresses_in_parallel(data_len: usize) -> bool {
+    data_len > MASTER_BLOCK_SIZE && rayon::current_num_threads() > 1
+}
+
+fn compress_sequential(data: &[u8], options: &zopfli::Options) -> PngResult<Vec<u8>> {
     use std::cmp::max;
 
     let mut output = Vec::with_capacity(max(1024, data.len() / 20));
-    let options = zopfli::Options {
-        iteration_count: iterations,
-        ..Default::default()
-    };
-    match zopfli::compress(&options, &zopfli::Format::Zlib, data, &mut output) {
+    match zopfli::compress(options, &zopfli::Format::Deflate, data, &mut output) {
         Ok(_) => (),
         Err(_) => return Err(PngError::new("Failed to compress in zopfli")),
     };
     output.shrink_to_fit();
     Ok(output)
 }
+
+/// The size of the sections which are compressed in parallel. This is the size zopfli itself
+/// splits streamed input into, as sections this large lose little compression from being split
+/// into blocks separately.
+const MASTER_BLOCK_SIZE: usize = 1_000_000;
+
+#[cfg(threads)]
+mod parallel {
+    use super::MASTER_BLOCK_SIZE;
+    use crate::{AtomicMin, PngError, PngResult};
+    use rayon::prelude::*;
+    use std::io::Write;
+    use std::ops::Range;
+    use zopfli::{BlockType, DeflateEncoder};
+
+    /// The size of the DEFLATE back-reference window
+    const WINDOW_SIZE: usize = 32768;
+
+    /// Compress each section of the data on a separate thread, then join the streams.
+    ///
+    /// Returns `None` if a stream could not be joined, which should not happen with the zopfli
+    /// version in use, in which case the data must be compressed sequentially instead. Sections
+    /// not yet started are abandoned once one section alone is longer than `max_size`.
+    pub fn compress(
+        data: &[u8],
+        options: &zopfli::Options,
+        max_size: &AtomicMin,
+    ) -> PngResult<Option<Vec<u8>>> {
+        let sections = (data.len() + MASTER_BLOCK_SIZE - 1) / MASTER_BLOCK_SIZE;
+        let streams = (0..sections)
+            .into_par_iter()
+            .map(|i| {
+                let start = i * MASTER_BLOCK_SIZE;
+                let end = data.len().min(start + MASTER_BLOCK_SIZE);
+                let section = compress_section(data, start, end, options)?;
+                if let (Some((_, bits)), Some(max)) = (&section, max_size.get()) {
+                    if (bits.end - bits.start) / 8 > max {
+                        return Err(PngError::DeflatedDataTooLong(max));
+                    }
+                }
+                Ok(section)
+            })
+            .collect::<PngResult<Option<Vec<_>>>>()?;
+        let streams = match streams {
+            Some(streams) => streams,
+            None => return Ok(None),
+        };
+
+        let mut output = BitConcat::default();
+        for (stream, bits) in &streams {
+            // Stored blocks are padded to a byte boundary, so the section must be at the same
+            // position within a byte as in its own stream
+            output.pad_to(bits.start % 8);
+            output.append(stream, bits.start, bits.end);
+        }
+        Ok(Some(output.bytes))
+    }
+
+    /// Compress the section of the data from `start` to `end`, using the preceding data as the
+    /// dictionary, returning the stream and the range of bits which encode the section.
+    ///
+    /// The final block bit is only set if the section is at the end of the data.
+    fn compress_section(
+        data: &[u8],
+        start: usize,
+        end: usize,
+        options: &zopfli::Options,
+    ) -> PngResult<Option<(Vec<u8>, Range<usize>)>> {
+        // The dictionary is also encoded and must be skipped. Padding can only be added in
+        // multiples of 2 bits, so its length is varied until it encodes to an even number of bits.
+        let mut window = &[][..];
+        let mut start_bit = 0;
+        for shorten in 0..8 {
+            let window_start = start.saturating_sub(WINDOW_SIZE) + shorten;
+            if window_start >= start {
+                break;
+            }
+            let encoded = encode(options, &[&data[window_start..start]], false)?;
+            match bits_before_empty_final_block(&encoded) {
+                Some(bits) if bits % 2 == 0 => {
+                    window = &data[window_start..start];
+                    start_bit = bits;
+                    break;
+                }
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+
+        let last = end == data.len();
+        let stream = encode(options, &[window, &data[start..end]], last)?;
+        let end_bit = if last {
+            Some(stream.len() * 8)
+        } else {
+            bits_before_empty_final_block(&stream)
+        };
+        Ok(end_bit.map(|end_bit| (stream, start_bit..end_bit)))
+    }
+
+    /// Encode each part as a separate chunk, with the earlier parts as the dictionary for later
+    /// ones. Unless `last` is set, the stream is ended with an empty final block, so that the
+    /// blocks of the data are not marked as final.
+    fn encode(options: &zopfli::Options, parts: &[&[u8]], last: bool) -> PngResult<Vec<u8>> {
+        let error = |_| PngError::new("Failed to compress in zopfli");
+        let mut encoder = DeflateEncoder::new(*options, BlockType::Dynamic, Vec::new());
+        for part in parts.iter().filter(|part| !part.is_empty()) {
+            // The encoder compresses each write once it knows whether it is the last
+            encoder.write_all(part).map_err(error)?;
+        }
+        if !last {
+            // An empty write forces the previous chunk to be compressed as non-final
+            #[allow(clippy::unused_io_amount)]
+            encoder.write(&[]).map_err(error)?;
+        }
+        encoder.finish().map_err(error)
+    }
+
+    /// Find the length in bits of a stream, excluding the empty final block and padding it ends
+    /// with after finishing an encoder which has no data left to compress
+    fn bits_before_empty_final_block(stream: &[u8]) -> Option<usize> {
+        // The empty block is the bits 1 (final), 1 0 (fixed codes) and 0000000 (end of block),
+        // followed by zero padding, so its second bit is the last one set
+        let last_byte = stream.iter().rposition(|&byte| byte != 0)?;
+        let last_bit = last_byte * 8 + 7 - stream[last_byte].leading_zeros() as usize;
+        let start = last_bit.checked_sub(1)?;
+        let final_bit_set = stream[start / 8] >> (start % 8) & 1 == 1;
+        if final_bit_set && (start + 10 + 7) / 8 == stream.len() {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    /// A buffer which bit ranges of DEFLATE streams can be appended to
+    #[derive(Default)]
+    struct BitConcat {
+        bytes: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitConcat {
+        /// Append the bits from `start` to `end` of the stream, which are numbered from the
+        /// least significant bit of each byte, as in DEFLATE
+        fn append(&mut self, stream: &[u8], start: usize, end: usize) {
+            self.bytes.reserve((end - start) / 8 + 1);
+            let shift = self.bits % 8;
+            let mut bit = start;
+            // Copy a byte at a time, merging it into any partial byte at the end
+            while bit < end {
+                let offset = bit % 8;
+                let mut byte = stream[bit / 8] >> offset;
+                if offset != 0 && bit / 8 + 1 < stream.len() {
+                    byte |= stream[bit / 8 + 1] << (8 - offset);
+                }
+                let count = (end - bit).min(8);
+                if count < 8 {
+                    byte &= (1 << count) - 1;
+                }
+                if shift == 0 {
+                    self.bytes.push(byte);
+                } else {
+                    *self.bytes.last_mut().unwrap() |= byte << shift;
+                    if count > 8 - shift {
+                        self.bytes.push(byte >> (8 - shift));
+                    }
+                }
+                bit += count;
+                self.bits += count;
+            }
+        }
+
+        /// Move to an even position within a byte by adding empty, non-final blocks
+        fn pad_to(&mut self, position: usize) {
+            if (self.bits + position) % 2 != 0 {
+                // A stored block, with its header of 3 zero bits padded to the next byte,
+                // then a length of 0 followed by its complement
+                if self.bits % 8 > 5 {
+                    self.bytes.push(0);
+                }
+                self.bits = self.bytes.len() * 8;
+                self.append(&[0, 0, 0xFF, 0xFF], 0, 32);
+            }
+            while self.bits % 8 != position {
+                // A block with fixed codes, which is the header 0 1 0 and the end of block code
+                self.append(&[0b010, 0], 0, 10);
+            }
+        }
+    }
+}
@@ -7,13 +7,35 @@ use std::fmt;
 pub enum PngError {
     DeflatedDataTooLong(usize),
     TimedOut,
+    Cancelled,
     NotPNG,
+    /// The data has the signature of the named format related to PNG, such as MNG or JNG, which
+    /// can't be optimized
+    UnsupportedFormat(&'static str),
     APNGNotSupported,
     InvalidData,
     TruncatedData,
+    /// The named chunk extends past the end of the data, needing `expected` bytes of data and CRC
+    /// where only `got` remain
+    TruncatedChunk {
+        chunk: [u8; 4],
+        expected: usize,
+        got: usize,
+    },
     ChunkMissing(&'static str),
     InvalidDepthForType(BitDepth, ColorType),
     IncorrectDataLength(usize, usize),
+    VerificationFailed,
+    /// The stored CRC of the named chunk does not match its contents
+    CRCMismatch([u8; 4]),
+    /// The named chunk appears in a position the specification does not allow
+    ChunkOutOfOrder([u8; 4]),
+    /// The IHDR chunk is malformed or describes an invalid image
+    InvalidHeader(&'static str),
+    /// The named chunk breaks a rule of the specification, as described
+    InvalidChunk([u8; 4], &'static str),
+    /// The named chunk has this many bytes of data, more than `Options::max_chunk_size` allows
+    ChunkTooLarge([u8; 4], usize),
     Other(Box<str>),
 }
 
@@ -26,11 +48,30 @@ impl fmt::Display for PngError {
         match *self {
             PngError::DeflatedDataTooLong(_) => f.write_str("deflated data too long"),
             PngError::TimedOut => f.write_str("timed out"),
+            PngError::Cancelled => f.write_str("cancelled"),
             PngError::NotPNG => f.write_str("Invalid header detected; Not a PNG file"),
+            PngError::UnsupportedFormat(s) => {
+                write!(
+                    f,
+                    "{} files are not supported; Only PNG files can be optimized",
+                    s
+                )
+            }
             PngError::InvalidData => f.write_str("Invalid data found; unable to read PNG file"),
             PngError::TruncatedData => {
                 f.write_str("Missing data in the file; the file is truncated")
             }
+            PngError::TruncatedChunk {
+                chunk,
+                expected,
+                got,
+            } => write!(
+                f,
+                "Chunk {} is truncated; {} bytes expected but only {} found",
+                String::from_utf8_lossy(&chunk),
+                expected,
+                got
+            ),
             PngError::APNGNotSupported => f.write_str("APNG files are not (yet) supported"),
             PngError::ChunkMissing(s) => write!(f, "Chunk {} missing or empty", s),
             PngError::InvalidDepthForType(d, ref c) => {
@@ -41,6 +82,31 @@ impl fmt::Display for PngError {
                 "Data length {} does not match the expected length {}",
                 l1, l2
             ),
+            PngError::VerificationFailed => {
+                f.write_str("Compressed image data does not match the uncompressed data")
+            }
+            PngError::CRCMismatch(name) => write!(
+                f,
+                "CRC Mismatch in {} chunk; May be recoverable by using --fix",
+                String::from_utf8_lossy(&name)
+            ),
+            PngError::ChunkOutOfOrder(name) => {
+                write!(
+                    f,
+                    "Chunk {} is out of order",
+                    String::from_utf8_lossy(&name)
+                )
+            }
+            PngError::InvalidHeader(s) => write!(f, "Invalid IHDR chunk: {}", s),
+            PngError::InvalidChunk(name, s) => {
+                write!(f, "Invalid {} chunk: {}", String::from_utf8_lossy(&name), s)
+            }
+            PngError::ChunkTooLarge(name, size) => write!(
+                f,
+                "Chunk {} of {} bytes is larger than the maximum allowed",
+                String::from_utf8_lossy(&name),
+                size
+            ),
             PngError::Other(ref s) => f.write_str(s),
         }
     }
@@ -52,3 +118,74 @@ impl PngError {
         PngError::Other(description.into())
     }
 }
+
+/// A departure from the PNG specification that is tolerated or repaired when optimizing, rather
+/// than treated as an error
+///
+/// These are only collected if `Options::collect_warnings` is set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub enum PngWarning {
+    /// The named chunk appears in a position the specification does not allow
+    ChunkOutOfOrder([u8; 4]),
+    /// The named chunk may only appear once, but appears again
+    DuplicateChunk([u8; 4]),
+    /// The stored CRC of the named chunk does not match its contents
+    CRCMismatch([u8; 4]),
+    /// The named chunk breaks a rule of the specification, as described, so it was removed
+    InvalidChunk([u8; 4], &'static str),
+    /// A cHRM chunk describes the colors, but there is no gAMA chunk to go with it, nor a chunk
+    /// that overrides both
+    MissingGamma,
+    /// The tRNS chunk has more entries than the palette, and the extra ones were ignored
+    TransparencyTooLong { palette: usize, transparency: usize },
+    /// Pixels use palette indices beyond the end of the palette, which are taken to be opaque
+    /// black
+    PaletteIndexOutOfRange { palette: usize, index: u8 },
+    /// There are this many bytes of data before the PNG signature, which were ignored
+    LeadingData(usize),
+    /// There are this many bytes of data after the IEND chunk, which were ignored
+    TrailingData(usize),
+    /// The named chunk has this many bytes of data, more than `Options::max_chunk_size` allows
+    OversizedChunk([u8; 4], usize),
+}
+
+impl fmt::Display for PngWarning {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = |name: &[u8; 4]| String::from_utf8_lossy(name).into_owned();
+        match *self {
+            PngWarning::ChunkOutOfOrder(ref n) => write!(f, "Chunk {} is out of order", name(n)),
+            PngWarning::DuplicateChunk(ref n) => {
+                write!(f, "Chunk {} may only appear once", name(n))
+            }
+            PngWarning::CRCMismatch(ref n) => write!(f, "CRC mismatch in {} chunk", name(n)),
+            PngWarning::InvalidChunk(ref n, s) => write!(f, "Invalid {} chunk: {}", name(n), s),
+            PngWarning::MissingGamma => f.write_str("cHRM chunk present without a gAMA chunk"),
+            PngWarning::TransparencyTooLong {
+                palette,
+                transparency,
+            } => write!(
+                f,
+                "tRNS chunk has {} entries, but the palette only has {}",
+                transparency, palette
+            ),
+            PngWarning::PaletteIndexOutOfRange { palette, index } => write!(
+                f,
+                "Pixels use palette index {}, but the palette only has {} entries",
+                index, palette
+            ),
+            PngWarning::LeadingData(n) => {
+                write!(f, "{} bytes of data before the PNG signature", n)
+            }
+            PngWarning::TrailingData(n) => write!(f, "{} bytes of data after the IEND chunk", n),
+            PngWarning::OversizedChunk(ref n, size) => write!(
+                f,
+                "Chunk {} of {} bytes is larger than the maximum allowed",
+                name(n),
+                size
+            ),
+        }
+    }
+}
@@ -2,34 +2,40 @@
 //! Works asynchronously when possible
 
 use crate::atomicmin::AtomicMin;
+use crate::buffer_pool::BufferPool;
 use crate::deflate;
 use crate::filters::RowFilter;
 use crate::png::PngImage;
-#[cfg(not(feature = "parallel"))]
+use crate::progress::{ProgressCallback, ProgressEvent};
+#[cfg(not(threads))]
 use crate::rayon;
 use crate::Deadline;
-use crate::PngError;
-#[cfg(feature = "parallel")]
+use crate::{PngError, PngResult};
+#[cfg(threads)]
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use indexmap::IndexSet;
 use log::trace;
 use rayon::prelude::*;
-#[cfg(not(feature = "parallel"))]
+#[cfg(not(threads))]
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::SeqCst;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 pub struct Candidate {
     pub image: Arc<PngImage>,
     pub idat_data: Vec<u8>,
     pub filtered: Vec<u8>,
     pub filter: RowFilter,
-    // first wins tie-breaker
+    // The order in which the image was entered for evaluation, as the last tie-breaker
     nth: usize,
 }
 
 impl Candidate {
+    /// Candidates are ordered by size, with ties broken by fixed properties of the candidate, so
+    /// that the best one doesn't depend on which thread finished first. No two candidates have the
+    /// same key, as each image is only evaluated once with each filter.
     fn cmp_key(&self) -> impl Ord {
         (
             self.idat_data.len() + self.image.key_chunks_size(),
@@ -41,19 +47,103 @@ impl Candidate {
     }
 }
 
+impl BufferPool {
+    /// Return the buffers of a candidate that lost to another
+    fn give_candidate(&self, candidate: Candidate) {
+        self.give(candidate.idat_data);
+        self.give(candidate.filtered);
+    }
+}
+
+/// The results of compressing filtered image data with a single deflater, so that a filter which
+/// produces the same data as one already tried, such as two heuristic strategies agreeing, doesn't
+/// compress it again. Each optimization uses its own cache.
+///
+/// Entries are keyed by the CRC and length of the filtered data. Successful results are checked
+/// by inflating them before use, so a collision can't produce the wrong image data. The data of
+/// results which have become too long to be used is dropped, keeping only their size, so the
+/// cache doesn't hold a copy of every trial's output.
+#[derive(Default)]
+pub(crate) struct DeflateCache {
+    results: Mutex<HashMap<(u32, usize), CachedDeflate>>,
+}
+
+/// The compressed data, or the maximum size it was found to exceed
+type CachedDeflate = Result<Arc<[u8]>, usize>;
+
+impl DeflateCache {
+    /// Compress the filtered data into `dest` using `compress`, unless the result is already known
+    /// for the current maximum size
+    pub fn deflate_into<F>(
+        &self,
+        filtered: &[u8],
+        max_size: &AtomicMin,
+        dest: &mut Vec<u8>,
+        compress: F,
+    ) -> PngResult<()>
+    where
+        F: FnOnce(&mut Vec<u8>) -> PngResult<()>,
+    {
+        let key = (deflate::crc32(filtered), filtered.len());
+        let max = max_size.get();
+        let cached = self.results.lock().unwrap().get(&key).cloned();
+        match cached {
+            Some(Ok(data))
+                if max.map_or(true, |max| data.len() <= max)
+                    && deflate::inflate(&data, filtered.len()).map_or(false, |d| d == filtered) =>
+            {
+                dest.clear();
+                dest.extend_from_slice(&data);
+                return Ok(());
+            }
+            // The maximum size only ever decreases, so it still can't be reached
+            Some(Err(exceeded)) if max.map_or(false, |max| max <= exceeded) => {
+                return Err(PngError::DeflatedDataTooLong(max.unwrap()));
+            }
+            _ => {}
+        }
+        let result = compress(dest);
+        let entry = match &result {
+            Ok(()) => Ok(Arc::from(dest.as_slice())),
+            Err(PngError::DeflatedDataTooLong(size)) => Err(*size),
+            Err(_) => return result,
+        };
+        let mut results = self.results.lock().unwrap();
+        if let Some(max) = max {
+            for cached in results.values_mut() {
+                if let Ok(data) = cached {
+                    if data.len() > max {
+                        *cached = Err(data.len() - 1);
+                    }
+                }
+            }
+        }
+        results.insert(key, entry);
+        result
+    }
+}
+
 /// Collect image versions and pick one that compresses best
 pub(crate) struct Evaluator {
     deadline: Arc<Deadline>,
     filters: IndexSet<RowFilter>,
     compression: u8,
     optimize_alpha: bool,
+    progress: Option<ProgressCallback>,
+    pool: Arc<BufferPool>,
     nth: AtomicUsize,
+    /// The smallest output size found so far, including the chunks other than the image data,
+    /// which is shared by all trials so that they can give up once they can't beat it
     best_candidate_size: Arc<AtomicMin>,
+    /// A size, comparable with `best_candidate_size`, which is small enough that no more trials
+    /// need to be started once it is reached
+    target_size: Option<usize>,
+    deflate_cache: Arc<DeflateCache>,
     /// images are sent to the caller thread for evaluation
-    #[cfg(feature = "parallel")]
+    #[cfg(threads)]
     eval_channel: (Sender<Candidate>, Receiver<Candidate>),
     // in non-parallel mode, images are evaluated synchronously
-    #[cfg(not(feature = "parallel"))]
+    #[cfg(not(threads))]
     eval_best_candidate: RefCell<Option<Candidate>>,
 }
 
@@ -63,45 +153,75 @@ impl Evaluator {
         filters: IndexSet<RowFilter>,
         compression: u8,
         optimize_alpha: bool,
+        progress: Option<ProgressCallback>,
+        pool: Arc<BufferPool>,
     ) -> Self {
-        #[cfg(feature = "parallel")]
+        #[cfg(threads)]
         let eval_channel = unbounded();
         Self {
             deadline,
             filters,
             compression,
             optimize_alpha,
+            progress,
+            pool,
             best_candidate_size: Arc::new(AtomicMin::new(None)),
+            target_size: None,
+            deflate_cache: Arc::new(DeflateCache::default()),
             nth: AtomicUsize::new(0),
-            #[cfg(feature = "parallel")]
+            #[cfg(threads)]
             eval_channel,
-            #[cfg(not(feature = "parallel"))]
+            #[cfg(not(threads))]
             eval_best_candidate: RefCell::new(None),
         }
     }
 
     /// Wait for all evaluations to finish and return smallest reduction
     /// Or `None` if the queue is empty.
-    #[cfg(feature = "parallel")]
+    #[cfg(threads)]
     pub fn get_best_candidate(self) -> Option<Candidate> {
         let (eval_send, eval_recv) = self.eval_channel;
         // Disconnect the sender, breaking the loop in the thread
         drop(eval_send);
         // Yield to ensure evaluations are finished - this can prevent deadlocks when run within an existing thread pool
         while let Some(rayon::Yield::Executed) = rayon::yield_local() {}
-        eval_recv.into_iter().min_by_key(Candidate::cmp_key)
+        let mut best: Option<Candidate> = None;
+        for candidate in eval_recv {
+            match best {
+                Some(ref prev) if prev.cmp_key() < candidate.cmp_key() => {
+                    self.pool.give_candidate(candidate)
+                }
+                _ => {
+                    if let Some(prev) = best.replace(candidate) {
+                        self.pool.give_candidate(prev);
+                    }
+                }
+            }
+        }
+        best
     }
 
-    #[cfg(not(feature = "parallel"))]
+    #[cfg(not(threads))]
     pub fn get_best_candidate(self) -> Option<Candidate> {
         self.eval_best_candidate.into_inner()
     }
 
-    /// Set best size, if known in advance
+    /// Whether no images have been entered for evaluation
+    pub fn is_empty(&self) -> bool {
+        self.nth.load(SeqCst) == 0
+    }
+
+    /// Set best output size, including `key_chunks_size`, if known in advance
     pub fn set_best_size(&self, size: usize) {
         self.best_candidate_size.set_min(size);
     }
 
+    /// Stop starting new trials once a candidate, including `key_chunks_size`, is no larger than
+    /// this size
+    pub fn set_target_size(&mut self, size: Option<usize>) {
+        self.target_size = size;
+    }
+
     /// Check if the image is smaller than others
     pub fn try_image(&self, image: Arc<PngImage>) {
         let nth = self.nth.fetch_add(1, SeqCst);
@@ -110,10 +230,14 @@ impl Evaluator {
         let filters = self.filters.clone();
         let compression = self.compression;
         let optimize_alpha = self.optimize_alpha;
+        let progress = self.progress.clone();
+        let pool = self.pool.clone();
         let best_candidate_size = self.best_candidate_size.clone();
+        let target_size = self.target_size;
+        let deflate_cache = self.deflate_cache.clone();
         // sends it off asynchronously for compression,
         // but results will be collected via the message queue
-        #[cfg(feature = "parallel")]
+        #[cfg(threads)]
         let eval_send = self.eval_channel.0.clone();
         rayon::spawn(move || {
             let filters_iter = filters.par_iter().with_max_len(1);
@@ -123,14 +247,37 @@ impl Evaluator {
             // Instead, only update (atomic) best size in real time,
             // and the best result later without need for locks.
             filters_iter.for_each(|&filter| {
-                if deadline.passed() {
+                if deadline.passed() || best_candidate_size.reached(target_size) {
                     return;
                 }
-                let filtered = image.filter_image(filter, optimize_alpha);
-                let idat_data = deflate::deflate(&filtered, compression, &best_candidate_size);
-                if let Ok(idat_data) = idat_data {
-                    let size = idat_data.len() + image.key_chunks_size();
+                let report = |event| {
+                    if let Some(progress) = &progress {
+                        progress.emit(event);
+                    }
+                };
+                report(ProgressEvent::FilterTrialStarted { filter });
+                let mut filtered = pool.take(image.data.len());
+                image.filter_image_into(filter, optimize_alpha, &mut filtered);
+                let mut idat_data = pool.take(filtered.len());
+                // The image data must be smaller than the best size less this image's other
+                // chunks, which may differ from those of the best candidate
+                let key_chunks_size = image.key_chunks_size();
+                let max_size = AtomicMin::new(
+                    best_candidate_size
+                        .get()
+                        .map(|best| best.saturating_sub(key_chunks_size)),
+                );
+                let result =
+                    deflate_cache.deflate_into(&filtered, &max_size, &mut idat_data, |dest| {
+                        deflate::deflate_into(&filtered, compression, &max_size, dest)
+                    });
+                if result.is_ok() {
+                    let size = idat_data.len() + key_chunks_size;
                     best_candidate_size.set_min(size);
+                    report(ProgressEvent::DeflateTrialCompleted {
+                        filter,
+                        size: Some(idat_data.len()),
+                    });
                     trace!(
                         "Eval: {}-bit {:20}  {:8}   {} bytes",
                         image.ihdr.bit_depth,
@@ -146,19 +293,30 @@ impl Evaluator {
                         nth,
                     };
 
-                    #[cfg(feature = "parallel")]
+                    #[cfg(threads)]
                     {
                         eval_send.send(new).expect("send");
                     }
 
-                    #[cfg(not(feature = "parallel"))]
+                    #[cfg(not(threads))]
                     {
                         match &mut *self.eval_best_candidate.borrow_mut() {
-                            Some(prev) if prev.cmp_key() < new.cmp_key() => {}
-                            best => *best = Some(new),
+                            Some(prev) if prev.cmp_key() < new.cmp_key() => {
+                                pool.give_candidate(new)
+                            }
+                            best => {
+                                if let Some(prev) = best.replace(new) {
+                                    pool.give_candidate(prev);
+                                }
+                            }
                         }
                     }
-                } else if let Err(PngError::DeflatedDataTooLong(size)) = idat_data {
+                    return;
+                }
+                pool.give(filtered);
+                pool.give(idat_data);
+                if let Err(PngError::DeflatedDataTooLong(size)) = result {
+                    report(ProgressEvent::DeflateTrialCompleted { filter, size: None });
                     trace!(
                         "Eval: {}-bit {:20}  {:8}  >{} bytes",
                         image.ihdr.bit_depth,
@@ -4,10 +4,14 @@ use crate::error::PngError;
 use crate::interlace::Interlacing;
 use crate::AtomicMin;
 use crate::Deflaters;
+use crate::Options;
 use crate::PngResult;
 use indexmap::IndexSet;
-use log::warn;
+use log::{trace, warn};
 use rgb::{RGB16, RGBA8};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone)]
 /// Headers from the IHDR chunk of the image
@@ -35,30 +39,45 @@ impl IhdrData {
     /// Byte length of IDAT that is correct for this IHDR
     #[must_use]
     pub fn raw_data_size(&self) -> usize {
+        let (pixels, lines) = self.data_size();
+        pixels + lines
+    }
+
+    /// Byte length of the image data once unfiltered, without the filter type of each line
+    #[must_use]
+    pub fn unfiltered_data_size(&self) -> usize {
+        self.data_size().0
+    }
+
+    /// The bytes of pixel data and the number of lines, each of which has a filter type
+    fn data_size(&self) -> (usize, usize) {
         let w = self.width as usize;
         let h = self.height as usize;
         let bpp = self.bpp();
 
-        fn bitmap_size(bpp: usize, w: usize, h: usize) -> usize {
-            ((w * bpp + 7) / 8) * h
-        }
+        // Passes with no pixels in a line have no lines at all
+        let bitmap_size = |w: usize, h: usize| {
+            if w == 0 {
+                (0, 0)
+            } else {
+                (((w * bpp + 7) / 8) * h, h)
+            }
+        };
 
         if self.interlaced == Interlacing::None {
-            bitmap_size(bpp, w, h) + h
+            bitmap_size(w, h)
         } else {
-            let mut size = bitmap_size(bpp, (w + 7) >> 3, (h + 7) >> 3) + ((h + 7) >> 3);
-            if w > 4 {
-                size += bitmap_size(bpp, (w + 3) >> 3, (h + 7) >> 3) + ((h + 7) >> 3);
-            }
-            size += bitmap_size(bpp, (w + 3) >> 2, (h + 3) >> 3) + ((h + 3) >> 3);
-            if w > 2 {
-                size += bitmap_size(bpp, (w + 1) >> 2, (h + 3) >> 2) + ((h + 3) >> 2);
-            }
-            size += bitmap_size(bpp, (w + 1) >> 1, (h + 1) >> 2) + ((h + 1) >> 2);
-            if w > 1 {
-                size += bitmap_size(bpp, w >> 1, (h + 1) >> 1) + ((h + 1) >> 1);
-            }
-            size + bitmap_size(bpp, w, h >> 1) + (h >> 1)
+            [
+                bitmap_size((w + 7) >> 3, (h + 7) >> 3),
+                bitmap_size((w + 3) >> 3, (h + 7) >> 3),
+                bitmap_size((w + 3) >> 2, (h + 3) >> 3),
+                bitmap_size((w + 1) >> 2, (h + 3) >> 2),
+                bitmap_size((w + 1) >> 1, (h + 1) >> 2),
+                bitmap_size(w >> 1, (h + 1) >> 1),
+                bitmap_size(w, h >> 1),
+            ]
+            .iter()
+            .fold((0, 0), |(pixels, lines), (p, l)| (pixels + p, lines + l))
         }
     }
 }
@@ -69,6 +88,364 @@ pub struct Chunk {
     pub data: Vec<u8>,
 }
 
+/// A chunk of a parsed PNG file, borrowed from its `PngData`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkRef<'a> {
+    /// The chunk type, such as `*b"tEXt"`
+    pub name: [u8; 4],
+    /// The data of the chunk, without its length, type or CRC
+    pub data: &'a [u8],
+}
+
+impl ChunkRef<'_> {
+    /// The number of bytes of data in the chunk
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Whether the chunk has no data
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Headers from the acTL chunk of an animation
+pub struct AnimationControl {
+    /// The number of frames, which must be the number of fcTL chunks
+    pub num_frames: u32,
+    /// The number of times to play the animation, or 0 to loop forever
+    pub num_plays: u32,
+}
+
+impl AnimationControl {
+    /// Construct the data of an acTL chunk with these headers
+    #[must_use]
+    pub fn chunk_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(8);
+        data.extend_from_slice(&self.num_frames.to_be_bytes());
+        data.extend_from_slice(&self.num_plays.to_be_bytes());
+        data
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Headers from the fcTL chunk of an animation frame
+pub struct FrameControl {
+    /// The sequence number of the chunk within the animation
+    pub sequence_number: u32,
+    /// The width of the frame in pixels
+    pub width: u32,
+    /// The height of the frame in pixels
+    pub height: u32,
+    /// The horizontal position of the frame within the image
+    pub x_offset: u32,
+    /// The vertical position of the frame within the image
+    pub y_offset: u32,
+    /// The numerator of the frame delay fraction
+    pub delay_num: u16,
+    /// The denominator of the frame delay fraction
+    pub delay_den: u16,
+    /// How the frame area is disposed of after rendering
+    pub dispose_op: u8,
+    /// How the frame is blended with the current output buffer
+    pub blend_op: u8,
+}
+
+impl FrameControl {
+    /// Construct the data of an fcTL chunk with these headers
+    #[must_use]
+    pub fn chunk_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(26);
+        data.extend_from_slice(&self.sequence_number.to_be_bytes());
+        data.extend_from_slice(&self.width.to_be_bytes());
+        data.extend_from_slice(&self.height.to_be_bytes());
+        data.extend_from_slice(&self.x_offset.to_be_bytes());
+        data.extend_from_slice(&self.y_offset.to_be_bytes());
+        data.extend_from_slice(&self.delay_num.to_be_bytes());
+        data.extend_from_slice(&self.delay_den.to_be_bytes());
+        data.push(self.dispose_op);
+        data.push(self.blend_op);
+        data
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Physical pixel dimensions from the pHYs chunk
+pub struct PhysicalDimensions {
+    /// Pixels per unit in the horizontal direction
+    pub x_pixels_per_unit: u32,
+    /// Pixels per unit in the vertical direction
+    pub y_pixels_per_unit: u32,
+    /// Whether the unit is the meter, otherwise only the aspect ratio is known
+    pub unit_is_meter: bool,
+}
+
+impl PhysicalDimensions {
+    /// Physical dimensions for the given resolution in dots per inch
+    ///
+    /// Returns an error if the resolution is zero or too large to be stored in a pHYs chunk
+    pub fn from_dpi(dpi: u32) -> PngResult<Self> {
+        // There are exactly 0.0254 meters in an inch
+        let ppm = (u64::from(dpi) * 10000 + 127) / 254;
+        match u32::try_from(ppm) {
+            Ok(ppm) if ppm > 0 => Ok(Self {
+                x_pixels_per_unit: ppm,
+                y_pixels_per_unit: ppm,
+                unit_is_meter: true,
+            }),
+            _ => Err(PngError::new(&format!(
+                "Resolution of {} DPI cannot be stored in a pHYs chunk",
+                dpi
+            ))),
+        }
+    }
+
+    /// Construct the data of a pHYs chunk with these dimensions
+    #[must_use]
+    pub fn chunk_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(9);
+        data.extend_from_slice(&self.x_pixels_per_unit.to_be_bytes());
+        data.extend_from_slice(&self.y_pixels_per_unit.to_be_bytes());
+        data.push(self.unit_is_meter as u8);
+        data
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Position of the image on a page or screen from the oFFs chunk
+pub struct ImageOffset {
+    /// Position of the left edge of the image
+    pub x: i32,
+    /// Position of the top edge of the image
+    pub y: i32,
+    /// Whether the unit is the micrometer, otherwise it is the pixel
+    pub unit_is_micrometer: bool,
+}
+
+impl ImageOffset {
+    /// Construct the data of an oFFs chunk with this offset
+    #[must_use]
+    pub fn chunk_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(9);
+        data.extend_from_slice(&self.x.to_be_bytes());
+        data.extend_from_slice(&self.y.to_be_bytes());
+        data.push(self.unit_is_micrometer as u8);
+        data
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Physical size of the pixels from the sCAL chunk
+pub struct PhysicalScale {
+    /// Whether the unit is the radian, otherwise it is the meter
+    pub unit_is_radian: bool,
+    /// Width of a pixel, as an ASCII floating-point number
+    pub pixel_width: String,
+    /// Height of a pixel, as an ASCII floating-point number
+    pub pixel_height: String,
+}
+
+impl PhysicalScale {
+    /// Construct the data of an sCAL chunk with this scale
+    #[must_use]
+    pub fn chunk_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(2 + self.pixel_width.len() + self.pixel_height.len());
+        data.push(if self.unit_is_radian { 2 } else { 1 });
+        data.extend_from_slice(self.pixel_width.as_bytes());
+        data.push(0);
+        data.extend_from_slice(self.pixel_height.as_bytes());
+        data
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Coding-independent code points identifying the color space, from the cICP chunk
+///
+/// The values are those of ITU-T H.273, as used for HDR images.
+pub struct CodingIndependentCodePoints {
+    /// The color primaries, such as 9 for BT.2020
+    pub color_primaries: u8,
+    /// The transfer function, such as 16 for PQ or 18 for HLG
+    pub transfer_function: u8,
+    /// The matrix coefficients, which must be 0 as PNG stores RGB
+    pub matrix_coefficients: u8,
+    /// Whether the samples use the full range, otherwise the narrow range used for video
+    pub video_full_range: bool,
+}
+
+impl CodingIndependentCodePoints {
+    /// Construct the data of a cICP chunk with these code points
+    #[must_use]
+    pub fn chunk_data(&self) -> Vec<u8> {
+        vec![
+            self.color_primaries,
+            self.transfer_function,
+            self.matrix_coefficients,
+            self.video_full_range as u8,
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The color volume of the display the image was mastered on, from the mDCV chunk
+pub struct MasteringDisplayColorVolume {
+    /// The chromaticity coordinates of the red, green and blue primaries, in units of 0.00002
+    pub primaries: [(u16, u16); 3],
+    /// The chromaticity coordinates of the white point, in units of 0.00002
+    pub white_point: (u16, u16),
+    /// The maximum luminance of the display, in units of 0.0001 cd/m²
+    pub max_luminance: u32,
+    /// The minimum luminance of the display, in units of 0.0001 cd/m²
+    pub min_luminance: u32,
+}
+
+impl MasteringDisplayColorVolume {
+    /// Construct the data of an mDCV chunk with this color volume
+    #[must_use]
+    pub fn chunk_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(24);
+        for (x, y) in self.primaries.iter().chain([&self.white_point]) {
+            data.extend_from_slice(&x.to_be_bytes());
+            data.extend_from_slice(&y.to_be_bytes());
+        }
+        data.extend_from_slice(&self.max_luminance.to_be_bytes());
+        data.extend_from_slice(&self.min_luminance.to_be_bytes());
+        data
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The light levels of the content of the image, from the cLLI chunk
+pub struct ContentLightLevel {
+    /// The maximum light level of any pixel, in units of 0.0001 cd/m²
+    pub max_content_light_level: u32,
+    /// The maximum average light level of any frame, in units of 0.0001 cd/m²
+    pub max_frame_average_light_level: u32,
+}
+
+impl ContentLightLevel {
+    /// Construct the data of a cLLI chunk with these light levels
+    #[must_use]
+    pub fn chunk_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(8);
+        data.extend_from_slice(&self.max_content_light_level.to_be_bytes());
+        data.extend_from_slice(&self.max_frame_average_light_level.to_be_bytes());
+        data
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Time of the last modification of the image from the tIME chunk, in UTC
+pub struct PngTime {
+    /// The complete year, such as 2023
+    pub year: u16,
+    /// The month, from 1 to 12
+    pub month: u8,
+    /// The day of the month, from 1 to 31
+    pub day: u8,
+    /// The hour, from 0 to 23
+    pub hour: u8,
+    /// The minute, from 0 to 59
+    pub minute: u8,
+    /// The second, from 0 to 60 to allow for leap seconds
+    pub second: u8,
+}
+
+impl PngTime {
+    /// The time a number of seconds after the Unix epoch
+    ///
+    /// Returns an error if the year would be too large to be stored in a tIME chunk
+    pub fn from_unix_time(secs: u64) -> PngResult<Self> {
+        let days = secs / 86400;
+        let time_of_day = secs % 86400;
+        // Convert the day count to a date in the proleptic Gregorian calendar, counting eras of
+        // 400 years from March 1st so that leap days come at the end of each year
+        let days = days + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_from_march = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+        let month = if month_from_march < 10 {
+            month_from_march + 3
+        } else {
+            month_from_march - 9
+        };
+        let year = era * 400 + year_of_era + u64::from(month <= 2);
+        match u16::try_from(year) {
+            Ok(year) => Ok(Self {
+                year,
+                month: month as u8,
+                day: day as u8,
+                hour: (time_of_day / 3600) as u8,
+                minute: (time_of_day / 60 % 60) as u8,
+                second: (time_of_day % 60) as u8,
+            }),
+            Err(_) => Err(PngError::new(&format!(
+                "Time of {} seconds cannot be stored in a tIME chunk",
+                secs
+            ))),
+        }
+    }
+
+    /// The time given by a value of the `SOURCE_DATE_EPOCH` environment variable, which is a
+    /// number of seconds after the Unix epoch
+    ///
+    /// Returns an error if the value is not a number or the time can't be stored in a tIME chunk
+    pub fn from_source_date_epoch(epoch: &str) -> PngResult<Self> {
+        match epoch.trim().parse() {
+            Ok(secs) => Self::from_unix_time(secs),
+            Err(_) => Err(PngError::new(&format!(
+                "Invalid SOURCE_DATE_EPOCH: {}",
+                epoch
+            ))),
+        }
+    }
+
+    /// The current time, or the time given by the `SOURCE_DATE_EPOCH` environment variable if it
+    /// is set, so that builds can be reproducible
+    pub fn current() -> Self {
+        if let Ok(epoch) = std::env::var("SOURCE_DATE_EPOCH") {
+            match Self::from_source_date_epoch(&epoch) {
+                Ok(time) => return time,
+                Err(_) => warn!("Ignoring invalid SOURCE_DATE_EPOCH: {}", epoch),
+            }
+        }
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        // The current year will fit for a long time yet
+        Self::from_unix_time(secs).unwrap()
+    }
+
+    /// Construct the data of a tIME chunk with this time
+    #[must_use]
+    pub fn chunk_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(7);
+        data.extend_from_slice(&self.year.to_be_bytes());
+        data.extend_from_slice(&[self.month, self.day, self.hour, self.minute, self.second]);
+        data
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// What to do with the tIME chunk, which records when the image was last modified
+pub enum TimeMode {
+    /// Keep any existing chunk, unless it is removed by the `strip` option
+    #[default]
+    Keep,
+    /// Remove any existing chunk
+    Strip,
+    /// Write a chunk with the given time, replacing any existing chunk even if it would be
+    /// stripped. With no time, `PngTime::current` is used.
+    Set(Option<PngTime>),
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 /// Options to use when stripping chunks
 pub enum StripChunks {
@@ -78,6 +455,9 @@ pub enum StripChunks {
     Strip(IndexSet<[u8; 4]>),
     /// Remove all chunks that won't affect rendering
     Safe,
+    /// Remove the same chunks as `Safe`, except private chunks which are marked as safe to copy,
+    /// as these can only be understood by the application that wrote them
+    SafeKeepPrivate,
     /// Remove all non-critical chunks except these
     Keep(IndexSet<[u8; 4]>),
     /// All non-critical chunks
@@ -86,8 +466,9 @@ pub enum StripChunks {
 
 impl StripChunks {
     /// List of chunks that will be kept when using the `Safe` option
-    pub const KEEP_SAFE: [[u8; 4]; 7] = [
-        *b"cICP", *b"iCCP", *b"sRGB", *b"pHYs", *b"acTL", *b"fcTL", *b"fdAT",
+    pub const KEEP_SAFE: [[u8; 4]; 11] = [
+        *b"cICP", *b"mDCV", *b"cLLI", *b"iCCP", *b"sRGB", *b"pHYs", *b"oFFs", *b"sCAL", *b"acTL",
+        *b"fcTL", *b"fdAT",
     ];
 
     pub(crate) fn keep(&self, name: &[u8; 4]) -> bool {
@@ -96,11 +477,103 @@ impl StripChunks {
             StripChunks::Keep(names) => names.contains(name),
             StripChunks::Strip(names) => !names.contains(name),
             StripChunks::Safe => Self::KEEP_SAFE.contains(name),
+            StripChunks::SafeKeepPrivate => {
+                Self::KEEP_SAFE.contains(name) || (is_private(name) && is_safe_to_copy(name))
+            }
             StripChunks::All => false,
         }
     }
 }
 
+/// Whether a chunk type is critical, so must be understood to display the image
+pub fn is_critical(name: &[u8; 4]) -> bool {
+    name[0].is_ascii_uppercase()
+}
+
+/// Whether a chunk type is private to an application, rather than defined by the specification
+pub fn is_private(name: &[u8; 4]) -> bool {
+    name[1].is_ascii_lowercase()
+}
+
+/// Whether a chunk may be copied to an image whose critical chunks have been modified, even if
+/// the chunk type is not recognized
+pub fn is_safe_to_copy(name: &[u8; 4]) -> bool {
+    name[3].is_ascii_lowercase()
+}
+
+/// A predicate deciding whether to keep an ancillary chunk, given its type and data
+///
+/// It is run after `StripChunks`, so it is only asked about chunks the preset would keep, and
+/// never about critical chunks.
+///
+/// ```
+/// use oxipng::{ChunkFilter, Options};
+///
+/// // Drop gAMA, and any private chunk larger than 10KB
+/// let opts = Options {
+///     chunk_filter: Some(ChunkFilter::new(|name, data| {
+///         let private = name[1].is_ascii_lowercase();
+///         name != b"gAMA" && !(private && data.len() > 10_000)
+///     })),
+///     ..Options::default()
+/// };
+/// ```
+#[derive(Clone)]
+pub struct ChunkFilter(Arc<ChunkPredicate>);
+
+type ChunkPredicate = dyn Fn(&[u8; 4], &[u8]) -> bool + Send + Sync;
+
+impl ChunkFilter {
+    pub fn new<F: Fn(&[u8; 4], &[u8]) -> bool + Send + Sync + 'static>(f: F) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn keep(&self, name: &[u8; 4], data: &[u8]) -> bool {
+        // Critical chunks are required to display the image
+        is_critical(name) || (self.0)(name, data)
+    }
+}
+
+impl fmt::Debug for ChunkFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ChunkFilter")
+    }
+}
+
+/// Check the size of an auxiliary chunk against `Options::max_chunk_size`, returning whether it
+/// should be kept
+pub(crate) fn check_chunk_size(name: &[u8; 4], data: &[u8], opts: &Options) -> PngResult<bool> {
+    match opts.max_chunk_size {
+        Some(max) if data.len() > max && name != b"fdAT" => {
+            if !opts.strip_oversized_chunks {
+                return Err(PngError::ChunkTooLarge(*name, data.len()));
+            }
+            warn!(
+                "Removing {} chunk of {} bytes, which is larger than the maximum",
+                String::from_utf8_lossy(name),
+                data.len()
+            );
+            Ok(false)
+        }
+        _ => Ok(true),
+    }
+}
+
+/// Determine the data of an auxiliary chunk to write to the output, or `None` if it should be stripped
+pub(crate) fn retained_chunk_data(name: &[u8; 4], data: &[u8], opts: &Options) -> Option<Vec<u8>> {
+    let data = if name == b"eXIf" && opts.strip_exif_except_orientation {
+        exif_orientation_only(data)?
+    } else if opts.strip.keep(name) {
+        data.to_owned()
+    } else {
+        return None;
+    };
+    match &opts.chunk_filter {
+        Some(filter) if !filter.keep(name, &data) => None,
+        _ => Some(data),
+    }
+}
+
 #[inline]
 pub fn file_header_is_valid(bytes: &[u8]) -> bool {
     let expected_header: [u8; 8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
@@ -114,6 +587,10 @@ pub struct RawChunk<'a> {
     pub data: &'a [u8],
 }
 
+/// Read the chunk at `byte_offset`, advancing past it, or return `None` on reaching IEND.
+///
+/// A chunk that extends past the end of the data is always an error, while a CRC mismatch is
+/// only an error if `fix_errors` is not set.
 pub fn parse_next_chunk<'a>(
     byte_data: &'a [u8],
     byte_offset: &mut usize,
@@ -136,25 +613,32 @@ pub fn parse_next_chunk<'a>(
     }
     *byte_offset += 4;
 
-    let data = byte_data
-        .get(*byte_offset..*byte_offset + length as usize)
-        .ok_or(PngError::TruncatedData)?;
+    // Once the name is known, a chunk cut short can be reported in detail
+    let expected = length as usize + 4;
+    let got = byte_data.len().saturating_sub(*byte_offset);
+    if got < expected {
+        return Err(PngError::TruncatedChunk {
+            chunk: chunk_name.try_into().unwrap(),
+            expected,
+            got,
+        });
+    }
+    let data = &byte_data[*byte_offset..*byte_offset + length as usize];
     *byte_offset += length as usize;
-    let crc = read_be_u32(
-        byte_data
-            .get(*byte_offset..*byte_offset + 4)
-            .ok_or(PngError::TruncatedData)?,
-    );
+    let crc = read_be_u32(&byte_data[*byte_offset..*byte_offset + 4]);
     *byte_offset += 4;
 
-    let chunk_bytes = byte_data
-        .get(chunk_start..chunk_start + 4 + length as usize)
-        .ok_or(PngError::TruncatedData)?;
-    if !fix_errors && crc32(chunk_bytes) != crc {
-        return Err(PngError::new(&format!(
-            "CRC Mismatch in {} chunk; May be recoverable by using --fix",
+    let chunk_bytes = &byte_data[chunk_start..chunk_start + 4 + length as usize];
+    if crc32(chunk_bytes) != crc {
+        // The length was consistent with the rest of the file, so only the CRC is suspect.
+        // A correct CRC will be written for the chunk on output.
+        if !fix_errors {
+            return Err(PngError::CRCMismatch(chunk_name.try_into().unwrap()));
+        }
+        warn!(
+            "CRC mismatch in {} chunk, accepting its data",
             String::from_utf8_lossy(chunk_name)
-        )));
+        );
     }
 
     let name: [u8; 4] = chunk_name.try_into().unwrap();
@@ -167,7 +651,10 @@ pub fn parse_ihdr_chunk(
     trns_data: Option<Vec<u8>>,
 ) -> PngResult<IhdrData> {
     // This eliminates bounds checks for the rest of the function
-    let interlaced = byte_data.get(12).copied().ok_or(PngError::TruncatedData)?;
+    let interlaced = byte_data
+        .get(12)
+        .copied()
+        .ok_or(PngError::InvalidHeader("the chunk must be 13 bytes long"))?;
     Ok(IhdrData {
         color_type: match byte_data[9] {
             0 => ColorType::Grayscale {
@@ -187,23 +674,365 @@ pub fn parse_ihdr_chunk(
             },
             4 => ColorType::GrayscaleAlpha,
             6 => ColorType::RGBA,
-            _ => return Err(PngError::new("Unexpected color type in header")),
+            _ => return Err(PngError::InvalidHeader("the color type is not valid")),
         },
-        bit_depth: byte_data[8].try_into()?,
+        bit_depth: byte_data[8]
+            .try_into()
+            .map_err(|_| PngError::InvalidHeader("the bit depth is not valid"))?,
         width: read_be_u32(&byte_data[0..4]),
         height: read_be_u32(&byte_data[4..8]),
-        interlaced: interlaced.try_into()?,
+        interlaced: interlaced
+            .try_into()
+            .map_err(|_| PngError::InvalidHeader("the interlace method is not valid"))?,
+    })
+}
+
+pub fn parse_actl_chunk(byte_data: &[u8]) -> PngResult<AnimationControl> {
+    if byte_data.len() != 8 {
+        return Err(PngError::InvalidChunk(
+            *b"acTL",
+            "the chunk must be 8 bytes long",
+        ));
+    }
+    Ok(AnimationControl {
+        num_frames: read_be_u32(&byte_data[0..4]),
+        num_plays: read_be_u32(&byte_data[4..8]),
+    })
+}
+
+/// Check the headers of an acTL chunk against the number of fcTL chunks in the file
+pub(crate) fn check_actl(control: &AnimationControl, num_fctl: usize) -> PngResult<()> {
+    if control.num_frames as usize != num_fctl {
+        return Err(PngError::InvalidChunk(
+            *b"acTL",
+            "num_frames does not match the number of fcTL chunks",
+        ));
+    }
+    // Like other PNG integers, it may not be larger than this
+    if control.num_plays > (1 << 31) - 1 {
+        return Err(PngError::InvalidChunk(*b"acTL", "num_plays is too large"));
+    }
+    Ok(())
+}
+
+/// Check that the acTL chunk matches the frames of the animation, if there is one, as described
+/// by `check_actl`
+///
+/// If `fix_errors` is set, a wrong number of frames is corrected, and a number of plays that is
+/// too large becomes 0, to loop forever, as it would in practice. An acTL chunk without any
+/// frames is removed.
+pub(crate) fn reconcile_actl(chunks: &mut Vec<Chunk>, fix_errors: bool) -> PngResult<()> {
+    let index = match chunks.iter().position(|c| &c.name == b"acTL") {
+        Some(index) => index,
+        None => return Ok(()),
+    };
+    let num_fctl = chunks.iter().filter(|c| &c.name == b"fcTL").count();
+    let mut control = parse_actl_chunk(&chunks[index].data)?;
+    match check_actl(&control, num_fctl) {
+        Ok(()) => return Ok(()),
+        Err(e) if !fix_errors => return Err(e),
+        Err(_) => {}
+    }
+
+    if num_fctl == 0 {
+        warn!("acTL chunk has no frames, removing it");
+        chunks.remove(index);
+        return Ok(());
+    }
+    if control.num_frames as usize != num_fctl {
+        warn!(
+            "acTL chunk gives {} frames, but there are {}, correcting it",
+            control.num_frames, num_fctl
+        );
+        control.num_frames = num_fctl as u32;
+    }
+    if check_actl(&control, num_fctl).is_err() {
+        warn!(
+            "acTL chunk gives {} plays, which is too many, looping forever instead",
+            control.num_plays
+        );
+        control.num_plays = 0;
+    }
+    chunks[index].data = control.chunk_data();
+    Ok(())
+}
+
+pub fn parse_fctl_chunk(byte_data: &[u8]) -> PngResult<FrameControl> {
+    // This eliminates bounds checks for the rest of the function
+    let blend_op = byte_data.get(25).copied().ok_or(PngError::InvalidChunk(
+        *b"fcTL",
+        "the chunk must be 26 bytes long",
+    ))?;
+    Ok(FrameControl {
+        sequence_number: read_be_u32(&byte_data[0..4]),
+        width: read_be_u32(&byte_data[4..8]),
+        height: read_be_u32(&byte_data[8..12]),
+        x_offset: read_be_u32(&byte_data[12..16]),
+        y_offset: read_be_u32(&byte_data[16..20]),
+        delay_num: u16::from_be_bytes([byte_data[20], byte_data[21]]),
+        delay_den: u16::from_be_bytes([byte_data[22], byte_data[23]]),
+        dispose_op: byte_data[24],
+        blend_op,
+    })
+}
+
+pub fn parse_phys_chunk(byte_data: &[u8]) -> PngResult<PhysicalDimensions> {
+    if byte_data.len() != 9 {
+        return Err(PngError::InvalidChunk(
+            *b"pHYs",
+            "the chunk must be 9 bytes long",
+        ));
+    }
+    Ok(PhysicalDimensions {
+        x_pixels_per_unit: read_be_u32(&byte_data[0..4]),
+        y_pixels_per_unit: read_be_u32(&byte_data[4..8]),
+        unit_is_meter: match byte_data[8] {
+            0 => false,
+            1 => true,
+            _ => return Err(PngError::InvalidChunk(*b"pHYs", "the unit is not valid")),
+        },
+    })
+}
+
+pub fn parse_time_chunk(byte_data: &[u8]) -> PngResult<PngTime> {
+    if byte_data.len() != 7 {
+        return Err(PngError::InvalidChunk(
+            *b"tIME",
+            "the chunk must be 7 bytes long",
+        ));
+    }
+    Ok(PngTime {
+        year: u16::from_be_bytes([byte_data[0], byte_data[1]]),
+        month: byte_data[2],
+        day: byte_data[3],
+        hour: byte_data[4],
+        minute: byte_data[5],
+        second: byte_data[6],
+    })
+}
+
+pub fn parse_offs_chunk(byte_data: &[u8]) -> PngResult<ImageOffset> {
+    if byte_data.len() != 9 {
+        return Err(PngError::InvalidChunk(
+            *b"oFFs",
+            "the chunk must be 9 bytes long",
+        ));
+    }
+    Ok(ImageOffset {
+        x: read_be_u32(&byte_data[0..4]) as i32,
+        y: read_be_u32(&byte_data[4..8]) as i32,
+        unit_is_micrometer: match byte_data[8] {
+            0 => false,
+            1 => true,
+            _ => return Err(PngError::InvalidChunk(*b"oFFs", "the unit is not valid")),
+        },
     })
 }
 
-/// Construct an RGBA palette from the raw palette and transparency data
+pub fn parse_scal_chunk(byte_data: &[u8]) -> PngResult<PhysicalScale> {
+    let (&unit, values) = byte_data
+        .split_first()
+        .ok_or(PngError::InvalidChunk(*b"sCAL", "the chunk is empty"))?;
+    let unit_is_radian = match unit {
+        1 => false,
+        2 => true,
+        _ => return Err(PngError::InvalidChunk(*b"sCAL", "the unit is not valid")),
+    };
+    let mut values = values.split(|&b| b == 0);
+    let mut next_value = || match values.next() {
+        Some(value) if is_positive_ascii_float(value) => {
+            Ok(String::from_utf8_lossy(value).into_owned())
+        }
+        _ => Err(PngError::InvalidChunk(
+            *b"sCAL",
+            "the pixel size is not valid",
+        )),
+    };
+    let pixel_width = next_value()?;
+    let pixel_height = next_value()?;
+    if values.next().is_some() {
+        return Err(PngError::InvalidChunk(
+            *b"sCAL",
+            "there is data after the pixel sizes",
+        ));
+    }
+    Ok(PhysicalScale {
+        unit_is_radian,
+        pixel_width,
+        pixel_height,
+    })
+}
+
+pub fn parse_cicp_chunk(byte_data: &[u8]) -> PngResult<CodingIndependentCodePoints> {
+    if byte_data.len() != 4 {
+        return Err(PngError::InvalidChunk(
+            *b"cICP",
+            "the chunk must be 4 bytes long",
+        ));
+    }
+    if byte_data[2] != 0 {
+        return Err(PngError::InvalidChunk(
+            *b"cICP",
+            "the matrix coefficients must be 0",
+        ));
+    }
+    Ok(CodingIndependentCodePoints {
+        color_primaries: byte_data[0],
+        transfer_function: byte_data[1],
+        matrix_coefficients: byte_data[2],
+        video_full_range: match byte_data[3] {
+            0 => false,
+            1 => true,
+            _ => {
+                return Err(PngError::InvalidChunk(
+                    *b"cICP",
+                    "the range flag is not valid",
+                ))
+            }
+        },
+    })
+}
+
+pub fn parse_mdcv_chunk(byte_data: &[u8]) -> PngResult<MasteringDisplayColorVolume> {
+    if byte_data.len() != 24 {
+        return Err(PngError::InvalidChunk(
+            *b"mDCV",
+            "the chunk must be 24 bytes long",
+        ));
+    }
+    let coordinates = |i: usize| {
+        (
+            u16::from_be_bytes([byte_data[i], byte_data[i + 1]]),
+            u16::from_be_bytes([byte_data[i + 2], byte_data[i + 3]]),
+        )
+    };
+    Ok(MasteringDisplayColorVolume {
+        primaries: [coordinates(0), coordinates(4), coordinates(8)],
+        white_point: coordinates(12),
+        max_luminance: read_be_u32(&byte_data[16..20]),
+        min_luminance: read_be_u32(&byte_data[20..24]),
+    })
+}
+
+pub fn parse_clli_chunk(byte_data: &[u8]) -> PngResult<ContentLightLevel> {
+    if byte_data.len() != 8 {
+        return Err(PngError::InvalidChunk(
+            *b"cLLI",
+            "the chunk must be 8 bytes long",
+        ));
+    }
+    Ok(ContentLightLevel {
+        max_content_light_level: read_be_u32(&byte_data[0..4]),
+        max_frame_average_light_level: read_be_u32(&byte_data[4..8]),
+    })
+}
+
+/// Check that a value is in the floating-point format used by the sCAL chunk, and greater than zero
+fn is_positive_ascii_float(value: &[u8]) -> bool {
+    let value = value.strip_prefix(b"+").unwrap_or(value);
+    let (mantissa, exponent) = match value.iter().position(|&b| b == b'e' || b == b'E') {
+        Some(i) => (&value[..i], Some(&value[i + 1..])),
+        None => (value, None),
+    };
+    let (integer, fraction) = match mantissa.iter().position(|&b| b == b'.') {
+        Some(i) => (&mantissa[..i], &mantissa[i + 1..]),
+        None => (mantissa, &[][..]),
+    };
+    let digits = |s: &[u8]| s.iter().all(u8::is_ascii_digit);
+    let exponent_valid = exponent.map_or(true, |e| {
+        let e = e
+            .strip_prefix(b"+")
+            .or_else(|| e.strip_prefix(b"-"))
+            .unwrap_or(e);
+        !e.is_empty() && digits(e)
+    });
+    let mantissa_valid = digits(integer) && digits(fraction) && integer.len() + fraction.len() > 0;
+    // Zero is not a valid size even when written with an exponent
+    mantissa_valid && exponent_valid && integer.iter().chain(fraction).any(|&b| b != b'0')
+}
+
+/// Check the structure of an ancillary chunk whose contents oxipng understands
+pub(crate) fn validate_chunk(name: &[u8; 4], data: &[u8]) -> PngResult<()> {
+    match name {
+        b"oFFs" => parse_offs_chunk(data).map(|_| ()),
+        b"sCAL" => parse_scal_chunk(data).map(|_| ()),
+        b"cICP" => parse_cicp_chunk(data).map(|_| ()),
+        b"mDCV" => parse_mdcv_chunk(data).map(|_| ()),
+        b"cLLI" => parse_clli_chunk(data).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+/// Remove duplicate pHYs chunks, and replace the dimensions if requested
+pub(crate) fn postprocess_phys(chunks: &mut Vec<Chunk>, target: Option<PhysicalDimensions>) {
+    let first = chunks.iter().position(|c| &c.name == b"pHYs");
+    if let Some(target) = target {
+        chunks.retain(|c| &c.name != b"pHYs");
+        // The chunk must come before the image data
+        let index = first.unwrap_or_else(|| {
+            chunks
+                .iter()
+                .position(|c| &c.name == b"IDAT")
+                .unwrap_or(chunks.len())
+        });
+        chunks.insert(
+            index,
+            Chunk {
+                name: *b"pHYs",
+                data: target.chunk_data(),
+            },
+        );
+    } else if let Some(first) = first {
+        // Broken encoders may write the same dimensions more than once
+        let dims = parse_phys_chunk(&chunks[first].data).ok();
+        let mut index = 0;
+        chunks.retain(|c| {
+            index += 1;
+            let duplicate = index > first + 1
+                && &c.name == b"pHYs"
+                && dims.is_some()
+                && parse_phys_chunk(&c.data).ok() == dims;
+            if duplicate {
+                trace!("Removing duplicate pHYs chunk");
+            }
+            !duplicate
+        });
+    }
+}
+
+/// Remove or replace the tIME chunk as requested
+pub(crate) fn postprocess_time(chunks: &mut Vec<Chunk>, mode: TimeMode) {
+    let time = match mode {
+        TimeMode::Keep => return,
+        TimeMode::Strip => {
+            chunks.retain(|c| &c.name != b"tIME");
+            return;
+        }
+        TimeMode::Set(time) => time.unwrap_or_else(PngTime::current),
+    };
+    let first = chunks.iter().position(|c| &c.name == b"tIME");
+    chunks.retain(|c| &c.name != b"tIME");
+    // The chunk may be placed anywhere, so keep the original position if there was one
+    let index = first.unwrap_or(chunks.len());
+    chunks.insert(
+        index,
+        Chunk {
+            name: *b"tIME",
+            data: time.chunk_data(),
+        },
+    );
+}
+
+/// Construct an RGBA palette from the raw palette and transparency data.
+/// The tRNS chunk may be shorter than the palette, leaving the remaining entries opaque,
+/// while any excess tRNS entries or incomplete palette entries are ignored.
 fn palette_to_rgba(
     palette_data: Option<Vec<u8>>,
     trns_data: Option<Vec<u8>>,
 ) -> Result<Vec<RGBA8>, PngError> {
-    let palette_data = palette_data.ok_or_else(|| PngError::new("no palette in indexed image"))?;
+    let palette_data = palette_data.ok_or(PngError::ChunkMissing("PLTE"))?;
     let mut palette: Vec<_> = palette_data
-        .chunks(3)
+        .chunks_exact(3)
         .map(|color| RGBA8::new(color[0], color[1], color[2], 255))
         .collect();
 
@@ -220,8 +1049,68 @@ fn read_be_u32(bytes: &[u8]) -> u32 {
     u32::from_be_bytes(bytes.try_into().unwrap())
 }
 
+/// Reduce the data of an eXIf chunk to just the Orientation tag
+///
+/// Returns `None` if there is no Orientation tag or the data is malformed
+pub fn exif_orientation_only(exif: &[u8]) -> Option<Vec<u8>> {
+    let big_endian = match exif.get(0..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let read_u16 = |offset: usize| {
+        let bytes = exif.get(offset..offset.checked_add(2)?)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let read_u32 = |offset: usize| {
+        let bytes = exif.get(offset..offset.checked_add(4)?)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+    if read_u16(2)? != 42 {
+        return None;
+    }
+
+    // Only the first IFD is searched, as this is where the Orientation tag belongs
+    let ifd = read_u32(4)? as usize;
+    let count = read_u16(ifd)? as usize;
+    let entry = (0..count)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| read_u16(entry) == Some(0x0112))?;
+    // The Orientation is a single SHORT value, which is stored within the entry itself
+    if read_u16(entry + 2)? != 3 || read_u32(entry + 4)? != 1 {
+        return None;
+    }
+    let entry = exif.get(entry..entry + 12)?;
+
+    let (ifd_offset, entry_count) = if big_endian {
+        (8u32.to_be_bytes(), 1u16.to_be_bytes())
+    } else {
+        (8u32.to_le_bytes(), 1u16.to_le_bytes())
+    };
+    let mut data = Vec::with_capacity(26);
+    data.extend_from_slice(&exif[0..4]); // Byte order and magic number
+    data.extend_from_slice(&ifd_offset);
+    data.extend_from_slice(&entry_count);
+    data.extend_from_slice(entry);
+    data.extend_from_slice(&[0; 4]); // No further IFDs
+    Some(data)
+}
+
 /// Extract and decompress the ICC profile from an iCCP chunk
 pub fn extract_icc(iccp: &Chunk) -> Option<Vec<u8>> {
+    extract_icc_limited(iccp, None)
+}
+
+/// Extract and decompress the ICC profile from an iCCP chunk, unless it is larger than `limit`
+pub fn extract_icc_limited(iccp: &Chunk, limit: Option<usize>) -> Option<Vec<u8>> {
     // Skip (useless) profile name
     let mut data = iccp.data.as_slice();
     loop {
@@ -237,7 +1126,7 @@ pub fn extract_icc(iccp: &Chunk) -> Option<Vec<u8>> {
         return None; // The profile is supposed to be compressed (method 0)
     }
     // The decompressed size is unknown so we have to guess the required buffer size
-    let max_size = compressed_data.len() * 2 + 1000;
+    let max_size = (compressed_data.len() * 2 + 1000).min(limit.unwrap_or(usize::MAX));
     match inflate(compressed_data, max_size) {
         Ok(icc) => Some(icc),
         Err(e) => {
@@ -249,8 +1138,10 @@ pub fn extract_icc(iccp: &Chunk) -> Option<Vec<u8>> {
 }
 
 /// Construct an iCCP chunk by compressing the ICC profile
-pub fn construct_iccp(icc: &[u8], deflater: Deflaters) -> PngResult<Chunk> {
-    let mut compressed = deflater.deflate(icc, &AtomicMin::new(None))?;
+///
+/// Returns an error if the compressed profile would be larger than `max_size`
+pub fn construct_iccp(icc: &[u8], deflater: &Deflaters, max_size: &AtomicMin) -> PngResult<Chunk> {
+    let mut compressed = deflater.deflate(icc, max_size)?;
     let mut data = Vec::with_capacity(compressed.len() + 5);
     data.extend(b"icc"); // Profile name - generally unused, can be anything
     data.extend([0, 0]); // Null separator, zlib compression method
@@ -261,29 +1152,52 @@ pub fn construct_iccp(icc: &[u8], deflater: Deflaters) -> PngResult<Chunk> {
     })
 }
 
-/// If the profile is sRGB, extracts the rendering intent value from it
-pub fn srgb_rendering_intent(icc_data: &[u8]) -> Option<u8> {
-    let rendering_intent = *icc_data.get(67)?;
-
-    // The known profiles are the same as in libpng's `png_sRGB_checks`.
-    // The Profile ID header of ICC has a fixed layout,
-    // and is supposed to contain MD5 of profile data at this offset
-    match icc_data.get(84..100)? {
-        b"\x29\xf8\x3d\xde\xaf\xf2\x55\xae\x78\x42\xfa\xe4\xca\x83\x39\x0d"
-        | b"\xc9\x5b\xd6\x37\xe9\x5d\x8a\x3b\x0d\xf3\x8f\x99\xc1\x32\x03\x89"
-        | b"\xfc\x66\x33\x78\x37\xe2\x88\x6b\xfd\x72\xe9\x83\x82\x28\xf1\xb8"
-        | b"\x34\x56\x2a\xbf\x99\x4c\xcd\x06\x6d\x2c\x57\x21\xd0\xd6\x8c\x5d" => {
-            Some(rendering_intent)
+/// Remove textual chunks which are byte-identical to an earlier one, and compress tEXt chunks
+/// to zTXt where that makes them smaller
+///
+/// Chunks are only considered duplicates if the whole chunk matches, so iTXt chunks with
+/// differing language tags or translated keywords are always kept.
+pub fn dedup_text_chunks(chunks: &mut Vec<Chunk>, opts: &Options) {
+    let is_text = |c: &Chunk| matches!(&c.name, b"tEXt" | b"zTXt" | b"iTXt");
+    let mut seen = IndexSet::new();
+    chunks.retain(|c| {
+        if !is_text(c) || seen.insert((c.name, c.data.clone())) {
+            return true;
         }
-        b"\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00" => {
-            // Known-bad profiles are identified by their CRC
-            match (crc32(icc_data), icc_data.len()) {
-                (0x5d51_29ce, 3024) | (0x182e_a552, 3144) | (0xf29e_526d, 3144) => {
-                    Some(rendering_intent)
-                }
-                _ => None,
-            }
+        trace!(
+            "Removing duplicate {} chunk",
+            std::str::from_utf8(&c.name).unwrap()
+        );
+        false
+    });
+
+    if !opts.strip.keep(b"zTXt") {
+        return;
+    }
+    for chunk in chunks.iter_mut().filter(|c| &c.name == b"tEXt") {
+        if let Some(ztxt) = compress_text(chunk, opts.ancillary_deflater()) {
+            trace!(
+                "Compressed tEXt chunk to zTXt ({} bytes decrease)",
+                chunk.data.len() - ztxt.data.len()
+            );
+            *chunk = ztxt;
         }
-        _ => None,
     }
 }
+
+/// Convert a tEXt chunk to zTXt, if the result would be smaller
+fn compress_text(text: &Chunk, deflater: &Deflaters) -> Option<Chunk> {
+    let keyword_len = text.data.iter().position(|&b| b == 0)?;
+    let (keyword, value) = text.data.split_at(keyword_len + 1);
+    // The zTXt chunk has an extra byte for the compression method
+    let max_size = AtomicMin::new(Some(value.len().checked_sub(2)?));
+    let compressed = deflater.deflate(value, &max_size).ok()?;
+    let mut data = Vec::with_capacity(keyword.len() + 1 + compressed.len());
+    data.extend_from_slice(keyword);
+    data.push(0); // zlib compression method
+    data.extend_from_slice(&compressed);
+    Some(Chunk {
+        name: *b"zTXt",
+        data,
+    })
+}
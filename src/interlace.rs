@@ -7,6 +7,7 @@ use bitvec::prelude::*;
 
 #[repr(u8)]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Interlacing {
     None,
     Adam7,
@@ -36,6 +37,57 @@ impl Display for Interlacing {
     }
 }
 
+/// Rearrange the pixels of a non-interlaced image into the seven passes of Adam7 interlacing
+///
+/// An image that is already interlaced is returned as it is. The pixels themselves are not
+/// changed, and the data is copied into a new image.
+///
+/// ```
+/// use oxipng::{deinterlace_adam7, interlace_adam7, Interlacing, PngImage};
+///
+/// let data = std::fs::read("tests/files/rgb_8_should_be_rgb_8.png").unwrap();
+/// let image = PngImage::from_slice(&data).unwrap();
+/// assert_eq!(image.interlacing(), Interlacing::None);
+///
+/// let interlaced = interlace_adam7(&image);
+/// assert_eq!(interlaced.interlacing(), Interlacing::Adam7);
+/// let restored = deinterlace_adam7(&interlaced);
+/// assert!(restored.scanlines().eq(image.scanlines()));
+/// ```
+#[must_use]
+pub fn interlace_adam7(image: &PngImage) -> PngImage {
+    match image.ihdr.interlaced {
+        Interlacing::None => interlace_image(image),
+        Interlacing::Adam7 => image.clone(),
+    }
+}
+
+/// Rearrange the pixels of an Adam7 interlaced image into rows from top to bottom
+///
+/// An image that is not interlaced is returned as it is. The pixels themselves are not changed,
+/// and the data is copied into a new image.
+///
+/// ```
+/// use oxipng::{deinterlace_adam7, interlace_adam7, Interlacing, PngImage};
+///
+/// let data = std::fs::read("tests/files/interlaced_rgb_8_should_be_rgb_8.png").unwrap();
+/// let image = PngImage::from_slice(&data).unwrap();
+/// assert_eq!(image.interlacing(), Interlacing::Adam7);
+///
+/// let progressive = deinterlace_adam7(&image);
+/// assert_eq!(progressive.interlacing(), Interlacing::None);
+/// assert_eq!(progressive.scanlines().count(), image.height() as usize);
+/// let restored = interlace_adam7(&progressive);
+/// assert!(restored.scanlines().eq(image.scanlines()));
+/// ```
+#[must_use]
+pub fn deinterlace_adam7(image: &PngImage) -> PngImage {
+    match image.ihdr.interlaced {
+        Interlacing::None => image.clone(),
+        Interlacing::Adam7 => deinterlace_image(image),
+    }
+}
+
 #[must_use]
 pub fn interlace_image(png: &PngImage) -> PngImage {
     let mut passes: Vec<BitVec<u8, Msb0>> = vec![BitVec::new(); 7];
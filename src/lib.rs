@@ -19,55 +19,92 @@
     allow(unreachable_patterns)
 )]
 
-#[cfg(feature = "parallel")]
+#[cfg(threads)]
 extern crate rayon;
-#[cfg(not(feature = "parallel"))]
+#[cfg(not(threads))]
 mod rayon;
 
-use crate::atomicmin::AtomicMin;
-use crate::evaluate::Evaluator;
+use crate::evaluate::{DeflateCache, Evaluator};
 use crate::headers::*;
-use crate::png::PngData;
-use crate::png::PngImage;
+use crate::reduction::bkgd::{palette_with_bkgd, updated_bkgd};
+use crate::reduction::convert::converted_image;
+use crate::reduction::depth::forced_bit_depth;
+use crate::reduction::icc::{
+    icc_is_gray, replace_srgb_gamma_chrm, replace_srgb_icc, srgb_rendering_intent,
+};
+use crate::reduction::palette::updated_hist;
+use crate::reduction::sbit::{significant_bits_replicated, updated_sbit};
 use crate::reduction::*;
 use log::{debug, info, trace, warn};
 use rayon::prelude::*;
 use std::borrow::Cow;
 use std::fmt;
-use std::fs::{copy, File, Metadata};
+use std::fs::{copy, remove_file, rename, File, Metadata, OpenOptions};
 use std::io::{stdin, stdout, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+pub use crate::atomicmin::AtomicMin;
+pub use crate::batch::{collect_files, optimize_best_of, optimize_many, output_for};
+pub use crate::buffer_pool::BufferPool;
+pub use crate::builder::OptionsBuilder;
 pub use crate::colors::{BitDepth, ColorType};
-pub use crate::deflate::Deflaters;
-pub use crate::error::PngError;
+pub use crate::deflate::{Deflater, Deflaters};
+#[cfg(feature = "zopfli")]
+pub use crate::deflate::{ZopfliDeflater, DEFAULT_MAX_BLOCK_SPLITS};
+pub use crate::error::{PngError, PngWarning};
+pub use crate::estimate::estimate_optimized_size;
 pub use crate::filters::RowFilter;
-pub use crate::headers::StripChunks;
-pub use crate::interlace::Interlacing;
+pub use crate::headers::{
+    ChunkFilter, ChunkRef, CodingIndependentCodePoints, ContentLightLevel, ImageOffset,
+    MasteringDisplayColorVolume, PhysicalDimensions, PhysicalScale, PngTime, StripChunks, TimeMode,
+};
+pub use crate::interlace::{deinterlace_adam7, interlace_adam7, Interlacing};
+#[cfg(feature = "mng")]
+pub use crate::png::extract_png_from_mng;
+pub use crate::png::{join_strips, split_strips, PngData, PngImage};
+pub use crate::progress::{ProgressCallback, ProgressEvent};
+pub use crate::reduction::{ReductionFilter, ReductionKind};
+#[cfg(feature = "json")]
+pub use crate::report::JSON_LOG_VERSION;
+pub use crate::report::{CheckResult, OptimizationReport, StageTimings};
+pub use crate::validate::{pixels_equal, validate_png};
 pub use indexmap::{indexset, IndexSet};
 pub use rgb::{RGB16, RGBA8};
 
+mod apng;
 mod atomicmin;
+mod batch;
+mod buffer_pool;
+mod builder;
+/// Private to oxipng; shared with the binary
+#[doc(hidden)]
+pub mod cli;
 mod colors;
 mod deflate;
 mod error;
+mod estimate;
 mod evaluate;
 mod filters;
 mod headers;
 mod interlace;
 mod png;
+mod progress;
 mod reduction;
+mod report;
 #[cfg(feature = "sanity-checks")]
 mod sanity_checks;
+mod validate;
 
 /// Private to oxipng; don't use outside tests and benches
 #[doc(hidden)]
 pub mod internal_tests {
+    pub use crate::apng::*;
     pub use crate::atomicmin::*;
     pub use crate::deflate::*;
+    pub use crate::headers::*;
     pub use crate::png::*;
     pub use crate::reduction::*;
     #[cfg(feature = "sanity-checks")]
@@ -123,6 +160,24 @@ impl<T: Into<PathBuf>> From<T> for InFile {
 
 pub type PngResult<T> = Result<T, PngError>;
 
+/// Separate time budgets for the phases of optimizing an image
+///
+/// Each phase starts its own clock when it begins, and is also cut short by the overall
+/// `Options::timeout` if that passes first. When a phase runs out of time, the best result found
+/// so far is kept and optimization continues with the next phase.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PhaseTimeouts {
+    /// Maximum amount of time to spend on the fast compression trials used to evaluate
+    /// reductions and filters
+    ///
+    /// If no filter has been evaluated when this runs out, one is picked without evaluation.
+    pub filter_evaluation: Option<Duration>,
+    /// Maximum amount of time to spend on the main compression trials
+    ///
+    /// A trial which has already started is always completed, but no further trials are started.
+    pub deflate: Option<Duration>,
+}
+
 #[derive(Clone, Debug)]
 /// Options controlling the output of the `optimize` function
 pub struct Options {
@@ -132,12 +187,17 @@ pub struct Options {
     pub backup: bool,
     /// Attempt to fix errors when decoding the input file rather than returning an `Err`.
     ///
+    /// This includes skipping any data before the PNG signature, if it isn't at the start.
+    ///
     /// Default: `false`
     pub fix_errors: bool,
     /// Don't actually run any optimizations, just parse the PNG file.
     ///
+    /// This was called `check`, which is now what the `check` function and the `--check` option
+    /// of the binary do instead: run the full optimization without writing any output.
+    ///
     /// Default: `false`
-    pub check: bool,
+    pub parse_only: bool,
     /// Don't actually write any output, just calculate the best results.
     ///
     /// Default: `false`
@@ -150,10 +210,35 @@ pub struct Options {
     ///
     /// Default: `false`
     pub preserve_attrs: bool,
+    /// Set the access and modification times of the output file to those of the input file,
+    /// without copying its permissions. This avoids spurious changes being seen by tools which
+    /// compare file times, such as build caches and rsync.
+    ///
+    /// This requires the `filetime` feature, without which a warning is logged instead.
+    ///
+    /// Default: `false`
+    pub preserve_mtime: bool,
+    /// Write the output to a temporary file in the same directory, then rename it over the
+    /// output file, so that the output file is never left partly written if writing fails or the
+    /// process is killed.
+    ///
+    /// On failure, the temporary file is removed. The output file is replaced rather than
+    /// overwritten, so other hard links to it keep the old data, although its permissions are
+    /// kept.
+    ///
+    /// Default: `false`
+    pub atomic_write: bool,
     /// Which RowFilters to try on the file
     ///
     /// Default: `None,Sub,Entropy,Bigrams`
     pub filter: IndexSet<RowFilter>,
+    /// A filter to use for the whole image without evaluating any others, in place of `filter`
+    ///
+    /// This is faster and reproducible, for debugging or for images known to favor one filter.
+    /// It only applies when the image data is recoded.
+    ///
+    /// Default: `None`
+    pub fixed_filter: Option<RowFilter>,
     /// Whether to change the interlacing type of the file.
     ///
     /// `None` will not change the current interlacing type.
@@ -162,8 +247,30 @@ pub struct Options {
     ///
     /// Default: `Some(None)`
     pub interlace: Option<Interlacing>,
+    /// Whether to try the image both with and without interlacing, keeping whichever is smaller.
+    ///
+    /// This takes about twice as long. When set, `interlace` is ignored, except for animations.
+    ///
+    /// Default: `false`
+    pub interlace_auto: bool,
     /// Whether to allow transparent pixels to be altered to improve compression.
     pub optimize_alpha: bool,
+    /// Whether to search for the color of transparent pixels that compresses best, by trying the
+    /// color of neighbouring pixels, rather than just setting them to black
+    ///
+    /// This is slower, and has no effect unless `optimize_alpha` is enabled.
+    ///
+    /// Default: `false`
+    pub optimize_alpha_aggressive: bool,
+    /// Whether to allow only optimizations that keep every pixel value exactly
+    ///
+    /// While this is set, `OptionsBuilder::build` refuses the options that change pixel values:
+    /// `scale_16`, `dither_16_to_8`, a nonzero `grayscale_tolerance`, `max_palette_colors` and
+    /// `use_significant_bits`. Options made any other way have them ignored with a warning. Unset
+    /// this to use any of them.
+    ///
+    /// Default: `true`
+    pub lossless_only: bool,
     /// Whether to attempt bit depth reduction
     ///
     /// Default: `true`
@@ -176,10 +283,85 @@ pub struct Options {
     ///
     /// Default: `true`
     pub palette_reduction: bool,
+    /// Whether to try several orderings of the palette and keep the one that compresses best,
+    /// rather than only sorting by luma
+    ///
+    /// Has no effect unless `palette_reduction` is enabled.
+    ///
+    /// Default: `false`
+    pub optimize_palette_order: bool,
     /// Whether to attempt grayscale reduction
     ///
     /// Default: `true`
     pub grayscale_reduction: bool,
+    /// Maximum difference between the color channels of a pixel for it to be considered gray
+    ///
+    /// When set, RGB images where every pixel is within the tolerance are reduced to grayscale
+    /// using the luma of each pixel. This is lossy, except that a tolerance of 0 is the same as
+    /// the default lossless behavior. Has no effect unless `grayscale_reduction` is enabled.
+    ///
+    /// Default: `None`
+    pub grayscale_tolerance: Option<u8>,
+    /// Whether to discard the bits that an sBIT chunk marks as insignificant
+    ///
+    /// The insignificant bits of each sample are replaced with copies of the significant bits,
+    /// which may allow the bit depth to be reduced. This changes the stored pixel values, though
+    /// only in the bits the image declares to be meaningless.
+    ///
+    /// Default: `false`
+    pub use_significant_bits: bool,
+    /// The greatest number of colors the output may have
+    ///
+    /// Images with more colors are quantized to a palette of at most this many colors, which is
+    /// lossy. Valid values are 1 to 256.
+    ///
+    /// Default: `None`
+    pub max_palette_colors: Option<u16>,
+    /// The color type and bit depth the output must have, such as for a pipeline that requires
+    /// one format, regardless of which would be smallest
+    ///
+    /// The image is converted to it, and no reductions that would change it are tried.
+    /// Optimization fails if the image can't be converted without loss, or if the combination
+    /// isn't valid in PNG. The palette or transparent color given with the color type is ignored,
+    /// and worked out from the image instead. A converted image is written even if it is larger
+    /// than the input, as with `force`. Animated images can't be converted.
+    ///
+    /// Default: `None`
+    pub force_color_type: Option<(ColorType, BitDepth)>,
+    /// The bit depth the output must have, keeping the color type it would otherwise have, such
+    /// as 8-bit for a pipeline that can't read packed pixels
+    ///
+    /// The image is converted to it, and no reductions that would change it are tried. Reducing
+    /// it to a depth too low for its pixels fails, unless it is 16-bit and lossy options allow
+    /// it to be scaled to 8-bit with `scale_16`. It must be valid for the color type of the
+    /// input, and match the bit depth of `force_color_type` if that is also set. As with
+    /// `force_color_type`, a converted image is written even if it is larger, and animated images
+    /// can't be converted.
+    ///
+    /// Default: `None`
+    pub force_bit_depth: Option<BitDepth>,
+    /// Whether to add the color of a bKGD chunk to the palette when reductions remove it
+    ///
+    /// Otherwise the bKGD chunk is dropped, as it must refer to an entry of the palette.
+    ///
+    /// Default: `false`
+    pub add_bkgd_to_palette: bool,
+    /// Whether to allow grayscale reductions that leave a kept ICC profile describing the wrong
+    /// color space, removing the iCCP chunk when they are applied
+    ///
+    /// An ICC profile for RGB data can't be used on a grayscale image, nor a gray profile on an
+    /// RGB or indexed image, so grayscale reduction is skipped while such a profile is kept.
+    /// Profiles that will be replaced with an sRGB chunk don't prevent it.
+    ///
+    /// Default: `false`
+    pub drop_incompatible_icc: bool,
+    /// A predicate to decide which kinds of reduction may be attempted.
+    ///
+    /// It is consulted before each reduction that the other options allow. Those it rejects are
+    /// skipped, while other reductions are still attempted.
+    ///
+    /// Default: `None`
+    pub reduction_filter: Option<ReductionFilter>,
     /// Whether to perform IDAT recoding
     ///
     /// If any type of reduction is performed, IDAT recoding will be performed
@@ -187,26 +369,208 @@ pub struct Options {
     ///
     /// Default: `true`
     pub idat_recoding: bool,
+    /// The maximum size of the data in each IDAT chunk, with larger image data split across
+    /// several chunks
+    ///
+    /// `None` writes a single IDAT chunk, unless the data is too large for one.
+    ///
+    /// Default: `None`
+    pub max_idat_size: Option<usize>,
     /// Whether to forcibly reduce 16-bit to 8-bit by scaling
     ///
     /// Default: `false`
     pub scale_16: bool,
+    /// Whether to apply dithering when forcibly reducing 16-bit to 8-bit, to avoid banding
+    ///
+    /// This has no effect unless `scale_16` is set, and images which can be reduced losslessly
+    /// are never dithered.
+    ///
+    /// Default: `false`
+    pub dither_16_to_8: bool,
     /// Which chunks to strip from the PNG file, if any
     ///
     /// Default: `None`
     pub strip: StripChunks,
+    /// A predicate to decide which other ancillary chunks to strip.
+    ///
+    /// It is applied to each chunk that `strip` would keep. Critical chunks are always kept.
+    ///
+    /// Default: `None`
+    pub chunk_filter: Option<ChunkFilter>,
+    /// The largest size in bytes allowed for the data of an ancillary chunk, other than the image
+    /// data of animation frames
+    ///
+    /// Larger chunks, such as text chunks that would take excessive time or memory to process,
+    /// are an error unless `strip_oversized_chunks` is set. ICC profiles are also not
+    /// decompressed beyond this size.
+    ///
+    /// Default: `None`
+    pub max_chunk_size: Option<usize>,
+    /// Whether to strip chunks larger than `max_chunk_size` rather than returning an error
+    ///
+    /// Default: `false`
+    pub strip_oversized_chunks: bool,
     /// Which DEFLATE algorithm to use
     ///
     /// Default: `Libdeflater`
     pub deflate: Deflaters,
+    /// Which DEFLATE algorithm to use for compressed ancillary chunks, such as iCCP and zTXt,
+    /// if not the same as `deflate`
+    ///
+    /// These chunks are usually small, so a slower algorithm may be worth using on them even
+    /// when it isn't for the image data.
+    ///
+    /// Default: `None`
+    pub ancillary_deflate: Option<Deflaters>,
     /// Whether to use fast evaluation to pick the best filter
     ///
     /// Default: `true`
     pub fast_evaluation: bool,
+    /// The libdeflate compression level (1-12) at which to compare filters and reductions
+    ///
+    /// When several filters are tried without fast evaluation, each is compressed at this level
+    /// and only the winner is compressed with `deflate`. Levels below 5 are much less
+    /// representative of the final size. `None` uses level 5 for evaluation and `deflate` for
+    /// every full trial.
+    ///
+    /// Default: `None`
+    pub trial_compression: Option<u8>,
 
     /// Maximum amount of time to spend on optimizations.
     /// Further potential optimizations are skipped if the timeout is exceeded.
     pub timeout: Option<Duration>,
+    /// Time budgets for the individual phases of optimization, within the overall `timeout`.
+    ///
+    /// Default: `None`
+    pub phase_timeouts: Option<PhaseTimeouts>,
+    /// An output size in bytes that is small enough, once reached, to stop trying for smaller.
+    ///
+    /// No further filter trials, evaluations or main compressions are started once a result is
+    /// estimated to be within the target. If several trials running at once reach it, which one is
+    /// kept may vary between runs. When the target can't be met, optimization carries on with full
+    /// effort, and `OptimizationReport::target_size_met` tells whether it was.
+    ///
+    /// Default: `None`
+    pub target_size: Option<usize>,
+    /// A callback to receive progress events during optimization.
+    ///
+    /// See `ProgressCallback` for the threading contract.
+    ///
+    /// Default: `None`
+    pub progress: Option<ProgressCallback>,
+    /// A thread pool to run the optimization in, instead of the global rayon pool.
+    ///
+    /// The pool may be shared by multiple concurrent optimizations, in which case they will
+    /// compete for its threads. It is ignored if the `single-threaded` feature is enabled.
+    ///
+    /// Default: `None`
+    #[cfg(feature = "parallel")]
+    pub thread_pool: Option<Arc<::rayon::ThreadPool>>,
+    /// A flag which may be set from another thread to abort the optimization.
+    ///
+    /// Once set, the optimization stops as soon as possible and returns `PngError::Cancelled`,
+    /// without writing any output.
+    ///
+    /// Default: `None`
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Whether to optimize the frames of animated PNGs.
+    ///
+    /// All frames are reduced to a common format and then filtered and compressed individually.
+    /// If disabled, reductions are skipped for animated PNGs and only the existing frame data is
+    /// recompressed.
+    ///
+    /// Default: `false`
+    pub optimize_animation: bool,
+    /// Whether to reduce any eXIf chunk to just its Orientation tag.
+    ///
+    /// This drops other metadata such as GPS location and camera details, while still allowing
+    /// the image to be displayed the right way up. It takes priority over `strip`, so the
+    /// Orientation is kept even if eXIf chunks would otherwise be stripped.
+    ///
+    /// Default: `false`
+    pub strip_exif_except_orientation: bool,
+    /// Whether to replace an iCCP chunk with an sRGB chunk when it holds a known sRGB profile.
+    ///
+    /// Only profiles which exactly match one of the standard sRGB profiles are replaced, so the
+    /// colors of the image are unchanged. Likewise, gAMA and cHRM chunks whose values are those
+    /// of sRGB are replaced by a single sRGB chunk. This always happens when `strip` is not `None`.
+    ///
+    /// Default: `false`
+    pub optimize_icc: bool,
+    /// Whether to remove duplicate tEXt, zTXt and iTXt chunks.
+    ///
+    /// Large tEXt chunks are also converted to zTXt where compression makes them smaller,
+    /// unless zTXt chunks would be stripped.
+    ///
+    /// Default: `false`
+    pub dedup_text: bool,
+    /// Physical pixel dimensions to write to the pHYs chunk, replacing any existing dimensions.
+    ///
+    /// Use `PhysicalDimensions::from_dpi` to set a consistent output density.
+    ///
+    /// Default: `None`
+    pub physical_dimensions: Option<PhysicalDimensions>,
+    /// Whether to keep, remove or update the tIME chunk recording the modification time.
+    ///
+    /// `TimeMode::Set(None)` uses the time given by `SOURCE_DATE_EPOCH` if it is set.
+    ///
+    /// Default: `TimeMode::Keep`
+    pub time: TimeMode,
+    /// The order in which to write ancillary chunks, by name
+    ///
+    /// Chunks not in the list are written after the listed ones, in their original order.
+    /// The positions of critical chunks are unaffected, and chunks are never moved to the other
+    /// side of the image data.
+    ///
+    /// Default: `None`
+    pub chunk_order: Option<Vec<[u8; 4]>>,
+    /// A pool of buffers to reuse for compression trials.
+    ///
+    /// Sharing one pool between the optimizations of many files avoids allocating new buffers
+    /// for each of them. If `None`, a new pool is used for each file.
+    ///
+    /// Default: `None`
+    pub buffer_pool: Option<Arc<BufferPool>>,
+    /// Whether to first check cheaply if the image data is already optimal.
+    ///
+    /// If no reductions apply, the existing filtering matches one of the selected filters, and a
+    /// single compression trial doesn't beat the existing IDAT, the image data is kept as it is
+    /// and `OptimizationReport::already_optimal` is set. This is much faster for files that were
+    /// optimized before with the same options, e.g. in pre-commit hooks.
+    ///
+    /// Default: `false`
+    pub check_only_fast: bool,
+    /// Whether to remove empty deflate blocks from the end of the image data when the original
+    /// image data is kept, as recompressing it gave no improvement.
+    ///
+    /// Some encoders leave such blocks, which hold no data, after flushing. The trimmed data is
+    /// checked to inflate to the same image first.
+    ///
+    /// Default: `true`
+    pub trim_idat_padding: bool,
+    /// Whether to inflate the compressed image data again and check that it matches the data
+    /// that was compressed, failing with `PngError::VerificationFailed` if not.
+    ///
+    /// This guards against bugs in the compressor at the cost of some extra time.
+    ///
+    /// Default: `false`
+    pub verify_output: bool,
+    /// Whether to record the filter type and heuristic score of each scanline of the output in
+    /// `OptimizationReport::filter_stats`, for tuning filter strategies.
+    ///
+    /// Default: `false`
+    pub collect_filter_stats: bool,
+    /// Whether to record the departures from the PNG specification that are tolerated or
+    /// repaired in `OptimizationReport::warnings`, such as out-of-order chunks, to help clean up
+    /// the source files.
+    ///
+    /// Default: `false`
+    pub collect_warnings: bool,
+    /// Whether to record the time spent in each stage of the optimization in
+    /// `OptimizationReport::timings`, such as reductions or compression, for profiling.
+    ///
+    /// Default: `false`
+    pub collect_timings: bool,
 }
 
 impl Options {
@@ -231,6 +595,58 @@ impl Options {
         Options::from_preset(6)
     }
 
+    /// The arguments to the `oxipng` binary that would optimize files with these options
+    ///
+    /// Parsing the arguments gives back equivalent options, other than callbacks, the thread pool,
+    /// the cancellation flag and the buffer pool, which are left out. Returns an error for any
+    /// other setting the command line can't express: a deflater other than libdeflate or Zopfli
+    /// with 15 iterations, `lossless_only` off without `scale_16`, a timeout that isn't a whole
+    /// number of seconds, or a setting without an argument that differs from its preset value.
+    pub fn to_cli_args(&self) -> PngResult<Vec<String>> {
+        cli::to_cli_args(self)
+    }
+
+    /// The filters to try on the image, which is only the fixed filter if there is one
+    pub(crate) fn filters(&self) -> IndexSet<RowFilter> {
+        match self.fixed_filter {
+            Some(filter) => indexset! {filter},
+            None => self.filter.clone(),
+        }
+    }
+
+    /// The configured buffer pool, or a new one if there is none
+    pub(crate) fn buffer_pool(&self) -> Arc<BufferPool> {
+        self.buffer_pool.clone().unwrap_or_default()
+    }
+
+    /// The names of the enabled options which change pixel values
+    pub(crate) fn lossy_options(&self) -> Vec<&'static str> {
+        [
+            (self.scale_16, "scale_16"),
+            (self.dither_16_to_8, "dither_16_to_8"),
+            (
+                self.grayscale_tolerance.map_or(false, |t| t > 0),
+                "grayscale_tolerance",
+            ),
+            (self.max_palette_colors.is_some(), "max_palette_colors"),
+            (self.use_significant_bits, "use_significant_bits"),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, name)| enabled.then_some(name))
+        .collect()
+    }
+
+    /// The DEFLATE algorithm for compressed ancillary chunks
+    pub(crate) fn ancillary_deflater(&self) -> &Deflaters {
+        self.ancillary_deflate.as_ref().unwrap_or(&self.deflate)
+    }
+
+    pub(crate) fn report_progress(&self, event: ProgressEvent) {
+        if let Some(progress) = &self.progress {
+            progress.emit(event);
+        }
+    }
+
     // The following methods make assumptions that they are operating
     // on an `Options` struct generated by the `default` method.
     fn apply_preset_0(mut self) -> Self {
@@ -273,6 +689,7 @@ impl Options {
 
     fn apply_preset_5(mut self) -> Self {
         self.fast_evaluation = false;
+        self.optimize_palette_order = true;
         self.filter.insert(RowFilter::Up);
         self.filter.insert(RowFilter::MinSum);
         self.filter.insert(RowFilter::BigEnt);
@@ -295,24 +712,66 @@ impl Default for Options {
         // Default settings based on -o 2 from the CLI interface
         Options {
             backup: false,
-            check: false,
+            parse_only: false,
             pretend: false,
             fix_errors: false,
             force: false,
             preserve_attrs: false,
+            preserve_mtime: false,
+            atomic_write: false,
             filter: indexset! {RowFilter::None, RowFilter::Sub, RowFilter::Entropy, RowFilter::Bigrams},
+            fixed_filter: None,
             interlace: Some(Interlacing::None),
+            interlace_auto: false,
             optimize_alpha: false,
+            optimize_alpha_aggressive: false,
+            lossless_only: true,
             bit_depth_reduction: true,
             color_type_reduction: true,
             palette_reduction: true,
+            optimize_palette_order: false,
             grayscale_reduction: true,
+            grayscale_tolerance: None,
+            use_significant_bits: false,
+            max_palette_colors: None,
+            force_color_type: None,
+            force_bit_depth: None,
+            add_bkgd_to_palette: false,
+            drop_incompatible_icc: false,
+            reduction_filter: None,
             idat_recoding: true,
+            max_idat_size: None,
             scale_16: false,
+            dither_16_to_8: false,
             strip: StripChunks::None,
+            chunk_filter: None,
+            max_chunk_size: None,
+            strip_oversized_chunks: false,
             deflate: Deflaters::Libdeflater { compression: 11 },
+            ancillary_deflate: None,
             fast_evaluation: true,
+            trial_compression: None,
             timeout: None,
+            phase_timeouts: None,
+            target_size: None,
+            progress: None,
+            #[cfg(feature = "parallel")]
+            thread_pool: None,
+            cancel: None,
+            optimize_animation: false,
+            strip_exif_except_orientation: false,
+            optimize_icc: false,
+            dedup_text: false,
+            physical_dimensions: None,
+            time: TimeMode::Keep,
+            chunk_order: None,
+            buffer_pool: None,
+            check_only_fast: false,
+            trim_idat_padding: true,
+            verify_output: false,
+            collect_filter_stats: false,
+            collect_warnings: false,
+            collect_timings: false,
         }
     }
 }
@@ -340,12 +799,7 @@ impl RawImage {
         data: Vec<u8>,
     ) -> Result<Self, PngError> {
         // Validate bit depth
-        let valid_depth = match color_type {
-            ColorType::Grayscale { .. } => true,
-            ColorType::Indexed { .. } => (bit_depth as u8) <= 8,
-            _ => (bit_depth as u8) >= 8,
-        };
-        if !valid_depth {
+        if !color_type.allows_bit_depth(bit_depth) {
             return Err(PngError::InvalidDepthForType(bit_depth, color_type));
         }
 
@@ -372,6 +826,20 @@ impl RawImage {
         })
     }
 
+    /// Construct a raw image definition from 8-bit RGBA pixel data, such as a rendered frame
+    ///
+    /// The image will still be reduced to the smallest color type and bit depth that can represent
+    /// it when optimized.
+    pub fn from_rgba8(width: u32, height: u32, data: &[u8]) -> Result<Self, PngError> {
+        Self::new(
+            width,
+            height,
+            ColorType::RGBA,
+            BitDepth::Eight,
+            data.to_vec(),
+        )
+    }
+
     /// Add a png chunk, such as "iTXt", to be included in the output
     pub fn add_png_chunk(&mut self, name: [u8; 4], data: Vec<u8>) {
         self.aux_chunks.push(Chunk { name, data });
@@ -381,103 +849,255 @@ impl RawImage {
     pub fn add_icc_profile(&mut self, data: &[u8]) {
         // Compress with fastest compression level - will be recompressed during optimization
         let deflater = Deflaters::Libdeflater { compression: 1 };
-        if let Ok(iccp) = construct_iccp(data, deflater) {
+        if let Ok(iccp) = construct_iccp(data, &deflater, &AtomicMin::new(None)) {
             self.aux_chunks.push(iccp);
         }
     }
 
     /// Create an optimized png from the raw image data using the options provided
     pub fn create_optimized_png(&self, opts: &Options) -> PngResult<Vec<u8>> {
-        let deadline = Arc::new(Deadline::new(opts.timeout));
-        let mut png = optimize_raw(self.png.clone(), opts, deadline.clone(), None)
-            .ok_or_else(|| PngError::new("Failed to optimize input data"))?;
-
-        // Process aux chunks
-        png.aux_chunks = self
-            .aux_chunks
-            .iter()
-            .filter(|c| opts.strip.keep(&c.name))
-            .cloned()
-            .collect();
-        postprocess_chunks(&mut png, opts, deadline, &self.png.ihdr);
+        let lossless_opts = without_lossy_options(Cow::Borrowed(opts));
+        let opts: &Options = &lossless_opts;
+        let deadline = Arc::new(Deadline::new(opts.timeout, opts.cancel.clone()));
+        in_thread_pool(opts, || {
+            // Process aux chunks
+            let aux_chunks: Vec<_> = self
+                .aux_chunks
+                .iter()
+                .filter_map(|c| {
+                    retained_chunk_data(&c.name, &c.data, opts)
+                        .map(|data| Chunk { name: c.name, data })
+                })
+                .collect();
+            let reduction_opts =
+                without_incompatible_reductions(&self.png.ihdr, &aux_chunks, Cow::Borrowed(opts));
+            let converts = needs_forced_conversion(&self.png.ihdr, opts)?;
+            let reduction_opts = without_forced_reductions(reduction_opts, converts);
+            let image = with_forced_color_type(self.png.clone(), &reduction_opts)?;
+
+            // The signature, IHDR, IDAT and IEND chunks are needed besides the aux chunks
+            let overhead =
+                8 + 25 + 12 + 12 + aux_chunks.iter().map(|c| 12 + c.data.len()).sum::<usize>();
+            let target_size = opts.target_size.map(|size| size.saturating_sub(overhead));
+            let result = optimize_raw(
+                image,
+                &reduction_opts,
+                deadline.clone(),
+                None,
+                target_size,
+                &mut None,
+            );
+            if deadline.cancelled() {
+                return Err(PngError::Cancelled);
+            }
+            let (mut png, _) =
+                result.ok_or_else(|| PngError::new("Failed to optimize input data"))?;
+            png.aux_chunks = aux_chunks;
+            postprocess_chunks(&mut png, opts, deadline.clone(), &self.png);
+            validate_hist(&mut png, opts.fix_errors)?;
+            if deadline.cancelled() {
+                return Err(PngError::Cancelled);
+            }
 
-        Ok(png.output())
+            Ok(png.output_with_max_idat_size(opts.max_idat_size))
+        })
     }
 }
 
 /// Perform optimization on the input file using the options provided
 pub fn optimize(input: &InFile, output: &OutFile, opts: &Options) -> PngResult<()> {
+    optimize_with_report(input, output, opts).map(|_| ())
+}
+
+/// Perform optimization on the input file using the options provided, returning a report of what
+/// was done
+pub fn optimize_with_report(
+    input: &InFile,
+    output: &OutFile,
+    opts: &Options,
+) -> PngResult<OptimizationReport> {
     // Read in the file and try to decode as PNG.
     info!("Processing: {}", input);
 
-    let deadline = Arc::new(Deadline::new(opts.timeout));
-
     // grab metadata before even opening input file to preserve atime
-    let opt_metadata_preserved;
-    let in_data = match *input {
-        InFile::Path(ref input_path) => {
-            if opts.preserve_attrs {
-                opt_metadata_preserved = input_path
-                    .metadata()
-                    .map_err(|err| {
-                        // Fail if metadata cannot be preserved
-                        PngError::new(&format!(
-                            "Unable to read metadata from input file {:?}: {}",
-                            input_path, err
-                        ))
-                    })
-                    .map(Some)?;
-                trace!("preserving metadata: {:?}", opt_metadata_preserved);
-            } else {
-                opt_metadata_preserved = None;
-            }
-            PngData::read_file(input_path)?
-        }
+    let opt_metadata_preserved = match *input {
+        InFile::Path(ref input_path) => preserved_metadata(input_path, opts)?,
+        InFile::StdIn => None,
+    };
+    let in_data = read_input(input)?;
+
+    optimize_input_data(input, &in_data, opt_metadata_preserved, output, opts)
+}
+
+/// Run the full optimization on the input file using the options provided, without writing any
+/// output, and report how much smaller the file would become
+///
+/// As with `optimize`, the output is never larger than the input unless `Options::force` is set.
+pub fn check(input: &InFile, opts: &Options) -> PngResult<CheckResult> {
+    info!("Checking: {}", input);
+
+    let in_data = read_input(input)?;
+    let output = optimize_from_memory(&in_data, opts)?;
+    Ok(CheckResult {
+        original_size: in_data.len(),
+        optimized_size: output.len(),
+    })
+}
+
+/// Read all of the data of the input file
+fn read_input(input: &InFile) -> PngResult<Vec<u8>> {
+    match *input {
+        InFile::Path(ref input_path) => PngData::read_file(input_path),
         InFile::StdIn => {
-            opt_metadata_preserved = None;
             let mut data = Vec::new();
             stdin()
                 .read_to_end(&mut data)
                 .map_err(|e| PngError::new(&format!("Error reading stdin: {}", e)))?;
-            data
+            Ok(data)
         }
-    };
+    }
+}
 
-    let mut png = PngData::from_slice(&in_data, opts)?;
+/// Perform optimization on the input file using the options provided, reading the file through a
+/// memory map rather than loading it all into memory first
+///
+/// If the file cannot be mapped, it is read normally instead.
+///
+/// The file must not be modified by another process while it is being optimized. Depending on the
+/// platform, this could otherwise cause corrupt output or a crash, as the mapped memory is read
+/// directly from the file.
+#[cfg(feature = "mmap")]
+pub fn optimize_from_mmap(input: &Path, output: &OutFile, opts: &Options) -> PngResult<()> {
+    let in_file = InFile::Path(input.to_owned());
+    info!("Processing: {}", in_file);
 
-    if opts.check {
-        info!("Running in check mode, not optimizing");
-        return Ok(());
+    // grab metadata before even opening input file to preserve atime
+    let opt_metadata_preserved = preserved_metadata(input, opts)?;
+    let file = File::open(input)
+        .map_err(|err| PngError::new(&format!("Unable to open {}: {}", input.display(), err)))?;
+    // Safety: the caller is responsible for the file not being modified while it is mapped
+    match unsafe { memmap2::Mmap::map(&file) } {
+        Ok(in_data) => {
+            optimize_input_data(&in_file, &in_data, opt_metadata_preserved, output, opts)
+                .map(|_| ())
+        }
+        Err(err) => {
+            warn!(
+                "Unable to map {}, reading it instead: {}",
+                input.display(),
+                err
+            );
+            optimize(&in_file, output, opts)
+        }
+    }
+}
+
+/// Get the metadata of the input file if it needs to be preserved
+fn preserved_metadata(input_path: &Path, opts: &Options) -> PngResult<Option<Metadata>> {
+    if !opts.preserve_attrs && !opts.preserve_mtime {
+        return Ok(None);
+    }
+    let metadata = input_path.metadata().map_err(|err| {
+        // Fail if metadata cannot be preserved
+        PngError::new(&format!(
+            "Unable to read metadata from input file {:?}: {}",
+            input_path, err
+        ))
+    })?;
+    trace!("preserving metadata: {:?}", metadata);
+    Ok(Some(metadata))
+}
+
+/// Optimize the data read from the input and write it to the output
+fn optimize_input_data(
+    input: &InFile,
+    in_data: &[u8],
+    opt_metadata_preserved: Option<Metadata>,
+    output: &OutFile,
+    opts: &Options,
+) -> PngResult<OptimizationReport> {
+    let deadline = Arc::new(Deadline::new(opts.timeout, opts.cancel.clone()));
+
+    let mut timings = opts.collect_timings.then(StageTimings::default);
+    let mut png = timed(
+        &mut timings,
+        |t| &mut t.parse,
+        || PngData::from_slice(in_data, opts),
+    )?;
+    let original_ihdr = png.raw.ihdr.clone();
+    let unchanged =
+        OptimizationReport::unchanged(in_data.len(), &original_ihdr, opts.deflate.clone());
+
+    if opts.parse_only {
+        info!("Only parsing the input, not optimizing");
+        return Ok(OptimizationReport {
+            timings,
+            ..unchanged
+        });
     }
 
     // Run the optimizer on the decoded PNG.
-    let mut optimized_output = optimize_png(&mut png, &in_data, opts, deadline)?;
+    let (mut optimized_output, mut report) = in_thread_pool(opts, || {
+        optimize_png(&mut png, in_data, opts, deadline, timings)
+    })?;
 
-    if is_fully_optimized(in_data.len(), optimized_output.len(), opts) {
+    if is_fully_optimized(in_data.len(), optimized_output.len(), &original_ihdr, opts) {
         info!("File already optimized");
+        report = OptimizationReport {
+            already_optimal: report.already_optimal,
+            no_improvement: true,
+            target_size_met: opts.target_size.map(|size| in_data.len() <= size),
+            warnings: report.warnings,
+            timings: report.timings,
+            ..unchanged
+        };
         match (output, input) {
             // if p is None, it also means same as the input path
             (OutFile::Path(ref p), InFile::Path(ref input_path))
                 if p.as_ref().map_or(true, |p| p == input_path) =>
             {
-                return Ok(());
+                return Ok(report);
             }
             _ => {
-                optimized_output = in_data;
+                optimized_output = in_data.to_vec();
             }
         }
     }
 
     if opts.pretend {
         info!("Running in pretend mode, no output");
-        return Ok(());
+        return Ok(report);
     }
 
+    timed(
+        &mut report.timings,
+        |t| &mut t.write,
+        || {
+            write_output(
+                &optimized_output,
+                input,
+                output,
+                opt_metadata_preserved.as_ref(),
+                opts,
+            )
+        },
+    )?;
+    Ok(report)
+}
+
+/// Write the optimized data to the output, which may be the input file itself
+fn write_output(
+    optimized_output: &[u8],
+    input: &InFile,
+    output: &OutFile,
+    opt_metadata_preserved: Option<&Metadata>,
+    opts: &Options,
+) -> PngResult<()> {
     match (output, input) {
         (&OutFile::StdOut, _) | (&OutFile::Path(None), &InFile::StdIn) => {
             let mut buffer = BufWriter::new(stdout());
             buffer
-                .write_all(&optimized_output)
+                .write_all(optimized_output)
                 .map_err(|e| PngError::new(&format!("Unable to write to stdout: {}", e)))?;
         }
         (OutFile::Path(ref output_path), _) => {
@@ -488,32 +1108,32 @@ pub fn optimize(input: &InFile, output: &OutFile, opts: &Options) -> PngResult<(
             if opts.backup {
                 perform_backup(output_path)?;
             }
-            let out_file = File::create(output_path).map_err(|err| {
-                PngError::new(&format!(
-                    "Unable to write to file {}: {}",
-                    output_path.display(),
-                    err
-                ))
-            })?;
-            if let Some(metadata_input) = &opt_metadata_preserved {
-                copy_permissions(metadata_input, &out_file)?;
+            if opts.atomic_write {
+                let temp_path = temp_path_for(output_path);
+                // A replaced file keeps its permissions, unless those of the input are wanted
+                let existing = std::fs::metadata(output_path).ok();
+                let permissions = opt_metadata_preserved
+                    .filter(|_| opts.preserve_attrs)
+                    .or(existing.as_ref());
+                let result =
+                    write_file(&temp_path, optimized_output, permissions, true).and_then(|()| {
+                        rename(&temp_path, output_path).map_err(|e| {
+                            PngError::new(&format!(
+                                "Unable to write to {}: {}",
+                                output_path.display(),
+                                e
+                            ))
+                        })
+                    });
+                if result.is_err() {
+                    remove_file(&temp_path).ok();
+                }
+                result?;
+            } else {
+                let permissions = opt_metadata_preserved.filter(|_| opts.preserve_attrs);
+                write_file(output_path, optimized_output, permissions, false)?;
             }
-
-            let mut buffer = BufWriter::new(out_file);
-            buffer
-                .write_all(&optimized_output)
-                // flush BufWriter so IO errors don't get swallowed silently on close() by drop!
-                .and_then(|()| buffer.flush())
-                .map_err(|e| {
-                    PngError::new(&format!(
-                        "Unable to write to {}: {}",
-                        output_path.display(),
-                        e
-                    ))
-                })?;
-            // force drop and thereby closing of file handle before modifying any timestamp
-            std::mem::drop(buffer);
-            if let Some(metadata_input) = &opt_metadata_preserved {
+            if let Some(metadata_input) = opt_metadata_preserved {
                 copy_times(metadata_input, output_path)?;
             }
             info!("Output: {}", output_path.display());
@@ -522,37 +1142,195 @@ pub fn optimize(input: &InFile, output: &OutFile, opts: &Options) -> PngResult<(
     Ok(())
 }
 
+/// Write the data to a file, with the given permissions
+///
+/// If `temp` is set, a new file is created, and synced to disk so that it can then be renamed
+/// over the output without a crash leaving an empty or partial file in its place.
+fn write_file(
+    path: &Path,
+    data: &[u8],
+    permissions: Option<&Metadata>,
+    temp: bool,
+) -> PngResult<()> {
+    let out_file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(!temp)
+        .create_new(temp)
+        .open(path)
+        .map_err(|err| {
+            PngError::new(&format!(
+                "Unable to write to file {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+    if let Some(metadata) = permissions {
+        copy_permissions(metadata, &out_file)?;
+    }
+
+    // The file is closed when the writer is dropped on return, before any timestamp is modified
+    let mut buffer = BufWriter::new(out_file);
+    buffer
+        .write_all(data)
+        // flush BufWriter so IO errors don't get swallowed silently on close() by drop!
+        .and_then(|()| buffer.flush())
+        .and_then(|()| {
+            if temp {
+                buffer.get_ref().sync_all()
+            } else {
+                Ok(())
+            }
+        })
+        .map_err(|e| PngError::new(&format!("Unable to write to {}: {}", path.display(), e)))
+}
+
+/// A unique path for a temporary file in the same directory as the output, so that it can be
+/// renamed over it
+fn temp_path_for(output_path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let name = output_path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    output_path.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
 /// Perform optimization on the input file using the options provided, where the file is already
 /// loaded in-memory
 pub fn optimize_from_memory(data: &[u8], opts: &Options) -> PngResult<Vec<u8>> {
+    optimize_from_memory_with_report(data, opts).map(|(output, _)| output)
+}
+
+/// Perform optimization on the input file using the options provided, where the file is already
+/// loaded in-memory, returning a report of what was done along with the output
+pub fn optimize_from_memory_with_report(
+    data: &[u8],
+    opts: &Options,
+) -> PngResult<(Vec<u8>, OptimizationReport)> {
     // Read in the file and try to decode as PNG.
     info!("Processing from memory");
 
-    let deadline = Arc::new(Deadline::new(opts.timeout));
+    let deadline = Arc::new(Deadline::new(opts.timeout, opts.cancel.clone()));
 
     let original_size = data.len();
-    let mut png = PngData::from_slice(data, opts)?;
+    let mut timings = opts.collect_timings.then(StageTimings::default);
+    let mut png = timed(
+        &mut timings,
+        |t| &mut t.parse,
+        || PngData::from_slice(data, opts),
+    )?;
+    let original_ihdr = png.raw.ihdr.clone();
 
     // Run the optimizer on the decoded PNG.
-    let optimized_output = optimize_png(&mut png, data, opts, deadline)?;
+    let (optimized_output, report) = in_thread_pool(opts, || {
+        optimize_png(&mut png, data, opts, deadline, timings)
+    })?;
 
-    if is_fully_optimized(original_size, optimized_output.len(), opts) {
+    if is_fully_optimized(original_size, optimized_output.len(), &original_ihdr, opts) {
         info!("Image already optimized");
-        Ok(data.to_vec())
+        let report = OptimizationReport {
+            already_optimal: report.already_optimal,
+            no_improvement: true,
+            target_size_met: opts.target_size.map(|size| original_size <= size),
+            warnings: report.warnings,
+            timings: report.timings,
+            ..OptimizationReport::unchanged(original_size, &original_ihdr, opts.deflate.clone())
+        };
+        Ok((data.to_vec(), report))
     } else {
-        Ok(optimized_output)
+        Ok((optimized_output, report))
+    }
+}
+
+/// Optimize a PNG held in memory, such as one embedded in another format, returning just the
+/// bytes of the optimized PNG
+///
+/// This is the entry point for tools that optimize the PNG images inside containers such as
+/// ICO or ICNS icons, or game asset archives. It does no file IO, and accepts any PNG that
+/// `optimize_from_memory` does, including APNG. Such tools should:
+///
+/// 1. Locate each embedded PNG, using the offsets recorded by the container or by searching for
+///    the 8-byte PNG signature, and pass the data from its signature onwards. Anything after the
+///    IEND chunk, such as the rest of the container, is ignored, so the end need not be known.
+/// 2. Replace the PNG with the returned bytes, and update any offsets and sizes the container
+///    records for it, as the length is likely to change.
+///
+/// The returned bytes are the input PNG, without anything after it, if it couldn't be made
+/// smaller, unless `Options::force` is set. With `Options::fix_errors`, data before the
+/// signature is also skipped, so a search for the signature is then unnecessary.
+pub fn optimize_png_slice(data: &[u8], opts: &Options) -> PngResult<Vec<u8>> {
+    let start = png::signature_offset(data, opts.fix_errors)?;
+    let data = &data[start..];
+    optimize_from_memory(&data[..datastream_len(data)], opts)
+}
+
+/// The length of the PNG datastream at the start of the data, up to the end of its IEND chunk
+///
+/// If the chunks can't be followed to the IEND chunk, this is the length of all of the data, so
+/// that the problem is reported when it is parsed.
+fn datastream_len(data: &[u8]) -> usize {
+    let mut offset = 8;
+    while let Some(header) = data.get(offset..offset + 8) {
+        let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        offset = offset.saturating_add(12).saturating_add(length);
+        if &header[4..] == b"IEND" {
+            return offset.min(data.len());
+        }
     }
+    data.len()
+}
+
+/// Perform optimization on a PNG read from `reader` using the options provided, writing the
+/// result to `writer`
+///
+/// The input is buffered internally, as the whole image must be decoded before optimizing it.
+pub fn optimize_from_reader<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    opts: &Options,
+) -> PngResult<()> {
+    let mut data = Vec::new();
+    reader
+        .read_to_end(&mut data)
+        .map_err(|e| PngError::new(&format!("Error reading input: {}", e)))?;
+
+    let output = optimize_from_memory(&data, opts)?;
+
+    writer
+        .write_all(&output)
+        .and_then(|()| writer.flush())
+        .map_err(|e| PngError::new(&format!("Unable to write output: {}", e)))
 }
 
 type TrialResult = (RowFilter, Vec<u8>);
 
-/// Perform optimization on the input PNG object using the options provided
+/// Run `f` in the thread pool given in the options, or the global pool if there is none
+#[cfg_attr(not(threads), allow(unused_variables))]
+fn in_thread_pool<T: Send>(opts: &Options, f: impl FnOnce() -> T + Send) -> T {
+    #[cfg(threads)]
+    if let Some(pool) = &opts.thread_pool {
+        return pool.install(f);
+    }
+    f()
+}
+
+/// Perform optimization on the input PNG object using the options provided, adding the time of
+/// each stage to `timings` for the report if they are being collected
 fn optimize_png(
     png: &mut PngData,
     original_data: &[u8],
     opts: &Options,
     deadline: Arc<Deadline>,
-) -> PngResult<Vec<u8>> {
+    timings: Option<StageTimings>,
+) -> PngResult<(Vec<u8>, OptimizationReport)> {
+    let lossless_opts = without_lossy_options(Cow::Borrowed(opts));
+    let opts: &Options = &lossless_opts;
     // Print png info
     let file_original_size = original_data.len();
     let idat_original_size = png.idat_data.len();
@@ -565,8 +1343,35 @@ fn optimize_png(
     debug!("    IDAT size = {} bytes", idat_original_size);
     debug!("    File size = {} bytes", file_original_size);
 
+    let mut report =
+        OptimizationReport::unchanged(file_original_size, &raw.ihdr, opts.deflate.clone());
+    report.timings = timings;
+    if opts.collect_warnings {
+        report.warnings = validate::lint_png(original_data, opts.max_chunk_size);
+        report
+            .warnings
+            .extend(reduction::palette::out_of_range_indices(&raw));
+    }
+
     // Check for APNG by presence of acTL chunk
-    let opts = if png.aux_chunks.iter().any(|c| &c.name == b"acTL") {
+    let is_apng = png.aux_chunks.iter().any(|c| &c.name == b"acTL");
+    let converts = needs_forced_conversion(&raw.ihdr, opts)?;
+    if is_apng && converts {
+        return Err(PngError::new(
+            "Animated images can't be converted to a forced color type or bit depth",
+        ));
+    }
+    let mut animation_optimized = false;
+    if is_apng && opts.optimize_animation {
+        match apng::optimize_animation(png, opts, &deadline) {
+            Ok(filter) => {
+                report.filter = filter;
+                animation_optimized = true;
+            }
+            Err(e) => warn!("Unable to optimize animation frames: {}", e),
+        }
+    }
+    let opts = if is_apng && !animation_optimized {
         warn!("APNG detected, disabling all reductions");
         let mut opts = opts.to_owned();
         opts.interlace = None;
@@ -574,23 +1379,117 @@ fn optimize_png(
         opts.color_type_reduction = false;
         opts.palette_reduction = false;
         opts.grayscale_reduction = false;
+        // Existing fdAT data will be recompressed instead
+        opts.optimize_animation = false;
         Cow::Owned(opts)
     } else {
         Cow::Borrowed(opts)
     };
-    let max_size = if opts.force {
-        None
+    // The precision of HDR images is part of what the metadata describes, so it is kept
+    let is_hdr = png
+        .aux_chunks
+        .iter()
+        .any(|c| matches!(&c.name, b"cICP" | b"mDCV" | b"cLLI"));
+    let opts = if is_hdr && opts.scale_16 && raw.ihdr.bit_depth == BitDepth::Sixteen {
+        warn!("HDR metadata detected, not scaling 16-bit image to 8-bit");
+        let mut opts = opts.into_owned();
+        opts.scale_16 = false;
+        opts.dither_16_to_8 = false;
+        Cow::Owned(opts)
     } else {
-        Some(png.estimated_output_size())
+        opts
     };
-    if let Some(new_png) = optimize_raw(raw.clone(), &opts, deadline.clone(), max_size) {
-        png.raw = new_png.raw;
-        png.idat_data = new_png.idat_data;
+    let opts = without_incompatible_reductions(&raw.ihdr, &png.aux_chunks, opts);
+    let opts = without_forced_reductions(opts, converts);
+    if !animation_optimized
+        && opts.check_only_fast
+        && !is_apng
+        && !opts.force
+        && is_already_optimal(png, &opts, &deadline)
+    {
+        info!("Image data already optimal, skipping to chunk processing");
+        report.already_optimal = true;
+    }
+    if !animation_optimized && !report.already_optimal {
+        let max_size = if opts.force {
+            None
+        } else {
+            Some(png.estimated_output_size())
+        };
+        let sbit = png.aux_chunks.iter().find(|c| &c.name == b"sBIT");
+        let image = match sbit {
+            Some(sbit) if opts.use_significant_bits => {
+                significant_bits_replicated(&raw, &sbit.data).map_or(raw.clone(), Arc::new)
+            }
+            _ => raw.clone(),
+        };
+        let image = with_forced_color_type(image, &opts)?;
+        // The target is for the whole file, so the other chunks, as they are now, are excluded
+        let overhead = file_original_size.saturating_sub(png.estimated_output_size());
+        let target_size = opts.target_size.map(|size| size.saturating_sub(overhead));
+        let timings = &mut report.timings;
+        let result = if opts.interlace_auto {
+            optimize_raw_auto_interlacing(
+                image,
+                &opts,
+                deadline.clone(),
+                max_size,
+                target_size,
+                timings,
+            )
+        } else {
+            optimize_raw(
+                image,
+                &opts,
+                deadline.clone(),
+                max_size,
+                target_size,
+                timings,
+            )
+        };
+        if let Some((new_png, filter)) = result {
+            verify_idat(&new_png, filter, &opts)?;
+            png.raw = new_png.raw;
+            png.idat_data = new_png.idat_data;
+            report.filter = Some(filter);
+        }
+    }
+    // Nothing replaced the original image data, but any empty blocks at its end can still go
+    if opts.trim_idat_padding && report.filter.is_none() {
+        let raw_size = png.raw.ihdr.raw_data_size();
+        if let Some(trimmed) = deflate::trim_empty_blocks(&png.idat_data, raw_size) {
+            debug!(
+                "    Removed {} bytes of empty blocks from the image data",
+                png.idat_data.len() - trimmed.len()
+            );
+            png.idat_data = trimmed;
+        }
+    }
+    if opts.collect_filter_stats {
+        report.filter_stats = report
+            .filter
+            .map(|filter| png.raw.filter_stats(filter, opts.optimize_alpha));
     }
 
-    postprocess_chunks(png, &opts, deadline, &raw.ihdr);
-
-    let output = png.output();
+    let output = timed(
+        &mut report.timings,
+        |t| &mut t.write,
+        || {
+            postprocess_chunks(png, &opts, deadline.clone(), &raw);
+            validate_hist(png, opts.fix_errors)?;
+            if deadline.cancelled() {
+                return Err(PngError::Cancelled);
+            }
+            Ok(png.output_with_max_idat_size(opts.max_idat_size))
+        },
+    )?;
+    report.set_output(output.len(), &raw.ihdr, &png.raw.ihdr);
+    if let Some(target_size) = opts.target_size {
+        report.target_size_met = Some(output.len() <= target_size);
+        if output.len() > target_size {
+            info!("Target size of {} bytes was not met", target_size);
+        }
+    }
 
     if idat_original_size >= png.idat_data.len() {
         debug!(
@@ -624,29 +1523,47 @@ fn optimize_png(
     #[cfg(feature = "sanity-checks")]
     assert!(sanity_checks::validate_output(&output, original_data));
 
-    Ok(output)
+    Ok((output, report))
 }
 
 /// Perform optimization on the input image data using the options provided
+///
+/// Once a result no larger than `target_size` is found, counted like `max_size` in the size of the
+/// image data and its key chunks, no further trials are started. The time of each stage is added
+/// to `timings` if they are being collected.
 fn optimize_raw(
     image: Arc<PngImage>,
     opts: &Options,
     deadline: Arc<Deadline>,
     max_size: Option<usize>,
-) -> Option<PngData> {
+    target_size: Option<usize>,
+    timings: &mut Option<StageTimings>,
+) -> Option<(PngData, RowFilter)> {
     // Must use normal (lazy) compression, as faster ones (greedy) are not representative
-    let eval_compression = 5;
+    let eval_compression = opts.trial_compression.unwrap_or(5);
     // None and Bigrams work well together, especially for alpha reductions
     let eval_filters = indexset! {RowFilter::None, RowFilter::Bigrams};
+    let pool = opts.buffer_pool();
+    let phase_timeouts = opts.phase_timeouts.unwrap_or_default();
+    let eval_deadline = deadline.phase("Filter evaluation", phase_timeouts.filter_evaluation);
     // This will collect all versions of images and pick one that compresses best
-    let eval = Evaluator::new(
-        deadline.clone(),
+    let mut eval = Evaluator::new(
+        eval_deadline.clone(),
         eval_filters.clone(),
         eval_compression,
         false,
+        opts.progress.clone(),
+        pool.clone(),
+    );
+    eval.set_target_size(target_size);
+    let (mut png, mut eval_result) = timed(
+        timings,
+        |t| &mut t.reduction,
+        || {
+            let png = perform_reductions(image.clone(), opts, &deadline, &eval);
+            (png, eval.get_best_candidate())
+        },
     );
-    let mut png = perform_reductions(image.clone(), opts, &deadline, &eval);
-    let mut eval_result = eval.get_best_candidate();
     if let Some(ref result) = eval_result {
         png = result.image.clone();
     }
@@ -658,75 +1575,179 @@ fn optimize_raw(
         report_format("Reducing image to ", &png);
     }
 
+    // Trials compress only the image data, so the other chunks of this image are excluded from
+    // the size they must beat
+    let max_idat_size = max_size.map(|size| size.saturating_sub(png.key_chunks_size()));
+    let target_idat_size = target_size.map(|size| size.saturating_sub(png.key_chunks_size()));
+    let meets_target = |idat_data: &[u8]| target_idat_size.map_or(false, |t| idat_data.len() <= t);
     if opts.idat_recoding || reduction_occurred {
-        let mut filters = opts.filter.clone();
-        let fast_eval = opts.fast_evaluation && (filters.len() > 1 || eval_result.is_some());
-        let best: Option<TrialResult> = if fast_eval {
+        let mut filters = opts.filters();
+        // A fixed filter is used as it is, rather than compared with those already evaluated
+        let fast_eval = opts.fixed_filter.is_none()
+            && opts.fast_evaluation
+            && (filters.len() > 1 || eval_result.is_some());
+        if fast_eval {
             // Perform a fast evaluation of selected filters followed by a single main compression trial
+            timed(
+                timings,
+                |t| &mut t.filter_evaluation,
+                || {
+                    if eval_result.is_some() {
+                        // Some filters have already been evaluated, we don't need to try them again
+                        filters = filters.difference(&eval_filters).cloned().collect();
+                    }
 
-            if eval_result.is_some() {
-                // Some filters have already been evaluated, we don't need to try them again
-                filters = filters.difference(&eval_filters).cloned().collect();
-            }
-
-            if !filters.is_empty() {
-                trace!("Evaluating: {} filters", filters.len());
-                let eval = Evaluator::new(deadline, filters, eval_compression, opts.optimize_alpha);
-                if let Some(ref result) = eval_result {
-                    eval.set_best_size(result.idat_data.len());
-                }
-                eval.try_image(png.clone());
-                if let Some(result) = eval.get_best_candidate() {
-                    eval_result = Some(result);
-                }
-            }
-            // We should have a result here - fail if not (e.g. deadline passed)
-            let result = eval_result?;
-
-            match opts.deflate {
-                Deflaters::Libdeflater { compression } if compression <= eval_compression => {
-                    // No further compression required
-                    Some((result.filter, result.idat_data))
-                }
-                _ => {
-                    debug!("Trying: {}", result.filter);
-                    let best_size = AtomicMin::new(max_size);
-                    perform_trial(&result.filtered, opts, result.filter, &best_size)
-                }
-            }
-        } else {
-            // Perform full compression trials of selected filters and determine the best
-
-            if filters.is_empty() {
-                // Pick a filter automatically
-                if png.ihdr.bit_depth as u8 >= 8 {
-                    // Bigrams is the best all-rounder when there's at least one byte per pixel
-                    filters.insert(RowFilter::Bigrams);
-                } else {
-                    // Otherwise delta filters generally don't work well, so just stick with None
-                    filters.insert(RowFilter::None);
-                }
-            }
+                    if !filters.is_empty() {
+                        trace!("Evaluating: {} filters", filters.len());
+                        let mut eval = Evaluator::new(
+                            eval_deadline,
+                            filters.clone(),
+                            eval_compression,
+                            opts.optimize_alpha,
+                            opts.progress.clone(),
+                            pool.clone(),
+                        );
+                        eval.set_target_size(target_size);
+                        if let Some(ref result) = eval_result {
+                            eval.set_best_size(
+                                result.idat_data.len() + result.image.key_chunks_size(),
+                            );
+                        }
+                        eval.try_image(png.clone());
+                        if let Some(result) = eval.get_best_candidate() {
+                            if let Some(prev) = eval_result.replace(result) {
+                                pool.give(prev.idat_data);
+                                pool.give(prev.filtered);
+                            }
+                        }
+                    }
+                },
+            );
+        }
 
-            debug!("Trying: {} filters", filters.len());
+        let deflate_deadline = deadline.phase("Compression", phase_timeouts.deflate);
+        let best: Option<TrialResult> = timed(
+            timings,
+            |t| &mut t.deflate,
+            || match eval_result.filter(|_| fast_eval) {
+                Some(result) => match opts.deflate {
+                    Deflaters::Libdeflater { compression } if compression <= eval_compression => {
+                        // No further compression required
+                        pool.give(result.filtered);
+                        Some((result.filter, result.idat_data))
+                    }
+                    _ if meets_target(&result.idat_data) => {
+                        debug!("Target size reached, skipping main compression");
+                        pool.give(result.filtered);
+                        Some((result.filter, result.idat_data))
+                    }
+                    _ => {
+                        debug!("Trying: {}", result.filter);
+                        opts.report_progress(ProgressEvent::FilterTrialStarted {
+                            filter: result.filter,
+                        });
+                        let best_size = AtomicMin::new(max_idat_size);
+                        let best = perform_trial(
+                            &result.filtered,
+                            opts,
+                            &opts.deflate,
+                            result.filter,
+                            &best_size,
+                            None,
+                        );
+                        pool.give(result.filtered);
+                        pool.give(result.idat_data);
+                        best
+                    }
+                },
+                // The evaluation may have found nothing if its phase ran out of time, in which case we
+                // carry on with a single filter rather than giving up
+                None if fast_eval && deadline.passed() => None,
+                None => {
+                    // Perform full compression trials of selected filters and determine the best
+
+                    if fast_eval {
+                        filters = filters.into_iter().take(1).collect();
+                    }
+                    if filters.is_empty() {
+                        // Pick a filter automatically
+                        if png.ihdr.bit_depth as u8 >= 8 {
+                            // Bigrams is the best all-rounder when there's at least one byte per pixel
+                            filters.insert(RowFilter::Bigrams);
+                        } else {
+                            // Otherwise delta filters generally don't work well, so just stick with None
+                            filters.insert(RowFilter::None);
+                        }
+                    }
 
-            let best_size = AtomicMin::new(max_size);
-            let results_iter = filters.into_par_iter().with_max_len(1);
-            let best = results_iter.filter_map(|filter| {
-                if deadline.passed() {
-                    return None;
-                }
-                let filtered = &png.filter_image(filter, opts.optimize_alpha);
-                perform_trial(filtered, opts, filter, &best_size)
-            });
-            best.reduce_with(|i, j| {
-                if i.1.len() < j.1.len() || (i.1.len() == j.1.len() && i.0 < j.0) {
-                    i
-                } else {
-                    j
+                    debug!("Trying: {} filters", filters.len());
+
+                    // Filters may be compared at a different level than the final compression, in
+                    // which case the trial sizes can't be limited by the final maximum size
+                    let trial_deflater = match opts.trial_compression {
+                        Some(compression) if filters.len() > 1 => {
+                            Some(Deflaters::Libdeflater { compression })
+                        }
+                        _ => None,
+                    };
+                    let best_size =
+                        AtomicMin::new(max_idat_size.filter(|_| trial_deflater.is_none()));
+                    let deflate_cache = DeflateCache::default();
+                    let results_iter = filters.into_par_iter().with_max_len(1);
+                    let best = results_iter.filter_map(|filter| {
+                        if deflate_deadline.passed() || best_size.reached(target_idat_size) {
+                            return None;
+                        }
+                        opts.report_progress(ProgressEvent::FilterTrialStarted { filter });
+                        let mut filtered = pool.take(png.data.len());
+                        png.filter_image_into(filter, opts.optimize_alpha, &mut filtered);
+                        let deflater = trial_deflater.as_ref().unwrap_or(&opts.deflate);
+                        let result = perform_trial(
+                            &filtered,
+                            opts,
+                            deflater,
+                            filter,
+                            &best_size,
+                            Some(&deflate_cache),
+                        );
+                        pool.give(filtered);
+                        result
+                    });
+                    // Ties are broken by filter rather than by which trial finished first
+                    let best = best.reduce_with(|i, j| {
+                        if i.1.len() < j.1.len() || (i.1.len() == j.1.len() && i.0 < j.0) {
+                            i
+                        } else {
+                            j
+                        }
+                    });
+                    match (best, trial_deflater) {
+                        (Some((filter, trial_idat)), Some(_)) if meets_target(&trial_idat) => {
+                            debug!("Target size reached, skipping main compression");
+                            Some((filter, trial_idat))
+                        }
+                        (Some((filter, trial_idat)), Some(_)) => {
+                            debug!("Trying: {}", filter);
+                            pool.give(trial_idat);
+                            let mut filtered = pool.take(png.data.len());
+                            png.filter_image_into(filter, opts.optimize_alpha, &mut filtered);
+                            let best_size = AtomicMin::new(max_idat_size);
+                            let result = perform_trial(
+                                &filtered,
+                                opts,
+                                &opts.deflate,
+                                filter,
+                                &best_size,
+                                None,
+                            );
+                            pool.give(filtered);
+                            result
+                        }
+                        (best, _) => best,
+                    }
                 }
-            })
-        };
+            },
+        );
 
         if let Some((filter, idat_data)) = best {
             let image = PngData {
@@ -742,12 +1763,13 @@ fn optimize_raw(
                     filter,
                     image.idat_data.len()
                 );
-                return Some(image);
+                return Some((image, filter));
             }
         }
     } else if let Some(result) = eval_result {
         // If idat_recoding is off and reductions were attempted but ended up choosing the baseline,
         // we should still check if the evaluator compressed the baseline smaller than the original.
+        pool.give(result.filtered);
         let image = PngData {
             raw: result.image,
             idat_data: result.idat_data,
@@ -761,39 +1783,147 @@ fn optimize_raw(
                 result.filter,
                 image.idat_data.len()
             );
-            return Some(image);
+            return Some((image, result.filter));
         }
     }
 
     None
 }
 
-/// Execute a compression trial
+/// Perform optimization on the input image data both with and without interlacing, keeping
+/// whichever is smaller, or the non-interlaced one if they are the same size
+fn optimize_raw_auto_interlacing(
+    image: Arc<PngImage>,
+    opts: &Options,
+    deadline: Arc<Deadline>,
+    mut max_size: Option<usize>,
+    target_size: Option<usize>,
+    timings: &mut Option<StageTimings>,
+) -> Option<(PngData, RowFilter)> {
+    let mut best = None;
+    for interlacing in [Interlacing::None, Interlacing::Adam7] {
+        let mut opts = opts.clone();
+        opts.interlace = Some(interlacing);
+        // Only a strictly smaller result will be returned
+        let result = optimize_raw(
+            image.clone(),
+            &opts,
+            deadline.clone(),
+            max_size,
+            target_size,
+            timings,
+        );
+        if let Some(result) = result {
+            max_size = Some(result.0.estimated_output_size());
+            best = Some(result);
+            if max_size <= target_size {
+                debug!("Target size reached, skipping other interlacing");
+                break;
+            }
+        }
+    }
+    if let Some((png, _)) = &best {
+        debug!("Interlacing chosen: {}", png.raw.ihdr.interlaced);
+    }
+    best
+}
+
+/// Check cheaply whether `optimize_raw` would be unable to improve on the existing image data
+fn is_already_optimal(png: &PngData, opts: &Options, deadline: &Arc<Deadline>) -> bool {
+    // Any reduction that applies could give a smaller result. The evaluator has no filters, so
+    // images entered into it are not compressed, and the palette order is taken as-is to avoid
+    // trial compressions.
+    let mut reduce_opts = opts.clone();
+    reduce_opts.progress = None;
+    reduce_opts.optimize_palette_order = false;
+    let eval = Evaluator::new(
+        deadline.clone(),
+        IndexSet::new(),
+        5,
+        false,
+        None,
+        opts.buffer_pool(),
+    );
+    let reduced = perform_reductions(png.raw.clone(), &reduce_opts, deadline, &eval);
+    if !Arc::ptr_eq(&reduced, &png.raw) || !eval.is_empty() {
+        return false;
+    }
+
+    // The existing filtering must be what one of the selected filters produces
+    let filtered = match deflate::inflate(&png.idat_data, png.raw.ihdr.raw_data_size()) {
+        Ok(filtered) => filtered,
+        Err(_) => return false,
+    };
+    let mut filters = opts.filters();
+    if filters.is_empty() {
+        filters.insert(if png.raw.ihdr.bit_depth as u8 >= 8 {
+            RowFilter::Bigrams
+        } else {
+            RowFilter::None
+        });
+    }
+    let filter = filters
+        .into_iter()
+        .find(|&f| png.raw.filter_image(f, opts.optimize_alpha) == filtered);
+
+    // And a single trial must not be able to compress it any smaller
+    let best_size = AtomicMin::new(png.idat_data.len().checked_sub(1));
+    filter.map_or(false, |filter| {
+        !deadline.passed()
+            && perform_trial(&filtered, opts, &opts.deflate, filter, &best_size, None).is_none()
+    })
+}
+
+/// Run `f`, adding the time it takes to the stage of `timings` given by `stage`, if they are being
+/// collected
+fn timed<T>(
+    timings: &mut Option<StageTimings>,
+    stage: fn(&mut StageTimings) -> &mut Duration,
+    f: impl FnOnce() -> T,
+) -> T {
+    match timings {
+        Some(timings) => {
+            let start = Instant::now();
+            let result = f();
+            *stage(timings) += start.elapsed();
+            result
+        }
+        None => f(),
+    }
+}
+
+/// Execute a compression trial, reusing the result for the same data if a cache is given
 fn perform_trial(
     filtered: &[u8],
     opts: &Options,
+    deflater: &Deflaters,
     filter: RowFilter,
     best_size: &AtomicMin,
+    cache: Option<&DeflateCache>,
 ) -> Option<TrialResult> {
-    match opts.deflate.deflate(filtered, best_size) {
-        Ok(new_idat) => {
+    let compress = |dest: &mut Vec<u8>| {
+        *dest = deflater.deflate(filtered, best_size)?;
+        Ok(())
+    };
+    let mut new_idat = Vec::new();
+    let result = match cache {
+        Some(cache) => cache.deflate_into(filtered, best_size, &mut new_idat, compress),
+        None => compress(&mut new_idat),
+    };
+    match result {
+        Ok(()) => {
             let bytes = new_idat.len();
             best_size.set_min(bytes);
-            trace!(
-                "    zc = {}  f = {:8}  {} bytes",
-                opts.deflate,
+            opts.report_progress(ProgressEvent::DeflateTrialCompleted {
                 filter,
-                bytes
-            );
+                size: Some(bytes),
+            });
+            trace!("    zc = {}  f = {:8}  {} bytes", deflater, filter, bytes);
             Some((filter, new_idat))
         }
         Err(PngError::DeflatedDataTooLong(bytes)) => {
-            trace!(
-                "    zc = {}  f = {:8} >{} bytes",
-                opts.deflate,
-                filter,
-                bytes,
-            );
+            trace!("    zc = {}  f = {:8} >{} bytes", deflater, filter, bytes,);
+            opts.report_progress(ProgressEvent::DeflateTrialCompleted { filter, size: None });
             None
         }
         Err(_) => None,
@@ -805,30 +1935,65 @@ struct DeadlineImp {
     start: Instant,
     timeout: Duration,
     print_message: AtomicBool,
+    phase: Option<&'static str>,
 }
 
-/// Keep track of processing timeout
+/// Keep track of processing timeout and cancellation
 #[doc(hidden)]
 #[derive(Debug)]
 pub struct Deadline {
     imp: Option<DeadlineImp>,
+    cancel: Option<Arc<AtomicBool>>,
+    parent: Option<Arc<Deadline>>,
 }
 
 impl Deadline {
-    pub fn new(timeout: Option<Duration>) -> Self {
+    pub fn new(timeout: Option<Duration>, cancel: Option<Arc<AtomicBool>>) -> Self {
         Self {
             imp: timeout.map(|timeout| DeadlineImp {
                 start: Instant::now(),
                 timeout,
                 print_message: AtomicBool::new(true),
+                phase: None,
+            }),
+            cancel,
+            parent: None,
+        }
+    }
+
+    /// Start a deadline for a phase of the optimization, which also passes when this one does
+    pub fn phase(self: &Arc<Self>, name: &'static str, timeout: Option<Duration>) -> Arc<Self> {
+        match timeout {
+            Some(timeout) => Arc::new(Self {
+                imp: Some(DeadlineImp {
+                    start: Instant::now(),
+                    timeout,
+                    print_message: AtomicBool::new(true),
+                    phase: Some(name),
+                }),
+                cancel: None,
+                parent: Some(self.clone()),
             }),
+            None => self.clone(),
         }
     }
 
-    /// True if the timeout has passed, and no new work should be done.
+    /// True if the optimization has been cancelled by the caller
+    pub fn cancelled(&self) -> bool {
+        self.parent.as_ref().map_or(false, |p| p.cancelled())
+            || self
+                .cancel
+                .as_ref()
+                .map_or(false, |cancel| cancel.load(Ordering::SeqCst))
+    }
+
+    /// True if the timeout has passed or the optimization was cancelled, and no new work should be done.
     ///
     /// If the verbose option is on, it also prints a timeout message once.
     pub fn passed(&self) -> bool {
+        if self.cancelled() || self.parent.as_ref().map_or(false, |p| p.passed()) {
+            return true;
+        }
         if let Some(imp) = &self.imp {
             let elapsed = imp.start.elapsed();
             if elapsed > imp.timeout {
@@ -840,7 +2005,12 @@ impl Deadline {
                 ) {
                     Ok(x) | Err(x) => x,
                 } {
-                    warn!("Timed out after {} second(s)", elapsed.as_secs());
+                    match imp.phase {
+                        Some(phase) => {
+                            warn!("{} timed out after {} second(s)", phase, elapsed.as_secs())
+                        }
+                        None => warn!("Timed out after {} second(s)", elapsed.as_secs()),
+                    }
                 }
                 return true;
             }
@@ -857,55 +2027,166 @@ fn report_format(prefix: &str, png: &PngImage) {
     );
 }
 
+/// Whether an iCCP chunk may be replaced with an sRGB chunk, or dropped in favor of an existing one
+fn may_replace_iccp(opts: &Options) -> bool {
+    (opts.optimize_icc || opts.strip != StripChunks::None) && opts.strip.keep(b"sRGB")
+}
+
+/// Whether the image has an ICC profile that will be kept and would no longer match the image if
+/// it were converted between grayscale and color
+///
+/// A profile that doesn't match the image to begin with can't be made any worse.
+fn icc_blocks_grayscale_reduction(ihdr: &IhdrData, aux_chunks: &[Chunk], opts: &Options) -> bool {
+    let iccp = match aux_chunks.iter().find(|c| &c.name == b"iCCP") {
+        Some(iccp) => iccp,
+        None => return false,
+    };
+    if may_replace_iccp(opts) && aux_chunks.iter().any(|c| &c.name == b"sRGB") {
+        return false;
+    }
+    extract_icc_limited(iccp, opts.max_chunk_size).map_or(false, |icc| {
+        icc_is_gray(&icc) == Some(ihdr.color_type.is_gray())
+            && !(may_replace_iccp(opts) && srgb_rendering_intent(&icc).is_some())
+    })
+}
+
+/// Disable the options which change pixel values, with a warning, if `lossless_only` is set
+fn without_lossy_options(opts: Cow<'_, Options>) -> Cow<'_, Options> {
+    if !opts.lossless_only {
+        return opts;
+    }
+    let lossy = opts.lossy_options();
+    if lossy.is_empty() {
+        return opts;
+    }
+    warn!(
+        "Ignoring lossy options, as only lossless optimization is allowed: {}",
+        lossy.join(", ")
+    );
+    let mut opts = opts.into_owned();
+    opts.scale_16 = false;
+    opts.dither_16_to_8 = false;
+    opts.grayscale_tolerance = None;
+    opts.max_palette_colors = None;
+    opts.use_significant_bits = false;
+    Cow::Owned(opts)
+}
+
+/// Whether the image has a kept cICP chunk for narrow-range samples
+///
+/// The narrow range is defined relative to the bit depth, so an image whose bit depth is changed
+/// would have its colors shifted. Removing the chunk would be worse, so there is no option to.
+fn cicp_blocks_bit_depth_reduction(aux_chunks: &[Chunk], opts: &Options) -> bool {
+    opts.strip.keep(b"cICP")
+        && aux_chunks
+            .iter()
+            .filter(|c| &c.name == b"cICP")
+            .any(|c| parse_cicp_chunk(&c.data).map_or(false, |cicp| !cicp.video_full_range))
+}
+
+/// Disable the reductions that would invalidate a kept color chunk
+///
+/// Grayscale reduction is disabled if a kept ICC profile only applies to the current color space,
+/// and bit depth reduction if a cICP chunk uses the narrow range. The sBIT chunk doesn't block
+/// anything, as it is converted to match the new color type and bit depth.
+fn without_incompatible_reductions<'a>(
+    ihdr: &IhdrData,
+    aux_chunks: &[Chunk],
+    opts: Cow<'a, Options>,
+) -> Cow<'a, Options> {
+    let block_grayscale = opts.grayscale_reduction
+        && !opts.drop_incompatible_icc
+        && icc_blocks_grayscale_reduction(ihdr, aux_chunks, &opts);
+    let block_bit_depth =
+        opts.bit_depth_reduction && cicp_blocks_bit_depth_reduction(aux_chunks, &opts);
+    if !block_grayscale && !block_bit_depth {
+        return opts;
+    }
+    let mut opts = opts.into_owned();
+    if block_grayscale {
+        debug!("ICC profile is tied to the color type, disabling grayscale reduction");
+        opts.grayscale_reduction = false;
+    }
+    if block_bit_depth {
+        debug!("cICP chunk uses the narrow range, disabling bit depth reduction");
+        opts.bit_depth_reduction = false;
+    }
+    Cow::Owned(opts)
+}
+
 /// Perform cleanup of certain chunks from the `PngData` object, after optimization has been completed
-fn postprocess_chunks(
-    png: &mut PngData,
-    opts: &Options,
-    deadline: Arc<Deadline>,
-    orig_ihdr: &IhdrData,
-) {
+fn postprocess_chunks(png: &mut PngData, opts: &Options, deadline: Arc<Deadline>, orig: &PngImage) {
+    let orig_ihdr = &orig.ihdr;
     if let Some(iccp_idx) = png.aux_chunks.iter().position(|c| &c.name == b"iCCP") {
         // See if we can replace an iCCP chunk with an sRGB chunk
-        let may_replace_iccp = opts.strip != StripChunks::None && opts.strip.keep(b"sRGB");
+        let may_replace_iccp = may_replace_iccp(opts);
         if may_replace_iccp && png.aux_chunks.iter().any(|c| &c.name == b"sRGB") {
             // Files aren't supposed to have both chunks, so we chose to honor sRGB
             trace!("Removing iCCP chunk due to conflict with sRGB chunk");
             png.aux_chunks.remove(iccp_idx);
-        } else if let Some(icc) = extract_icc(&png.aux_chunks[iccp_idx]) {
-            let intent = if may_replace_iccp {
-                srgb_rendering_intent(&icc)
-            } else {
-                None
-            };
+        } else if let Some(icc) =
+            extract_icc_limited(&png.aux_chunks[iccp_idx], opts.max_chunk_size)
+        {
             // sRGB-like profile can be replaced with an sRGB chunk with the same rendering intent
             // Otherwise try recompressing the profile
-            if let Some(intent) = intent {
+            if may_replace_iccp
+                && replace_srgb_icc(&mut png.aux_chunks, iccp_idx, &icc, &opts.strip)
+            {
                 trace!("Replacing iCCP chunk with equivalent sRGB chunk");
-                png.aux_chunks[iccp_idx] = Chunk {
-                    name: *b"sRGB",
-                    data: vec![intent],
-                };
-            } else if let Ok(iccp) = construct_iccp(&icc, opts.deflate) {
+            } else {
+                // The profile is being kept, so recompress it if that makes it any smaller
                 let cur_len = png.aux_chunks[iccp_idx].data.len();
-                let new_len = iccp.data.len();
-                if new_len < cur_len {
-                    debug!(
-                        "Recompressed iCCP chunk: {} ({} bytes decrease)",
-                        new_len,
-                        cur_len - new_len
-                    );
-                    png.aux_chunks[iccp_idx] = iccp;
+                // The new chunk has 5 bytes of profile name and compression method
+                let max_size = AtomicMin::new(cur_len.checked_sub(6));
+                if let Ok(iccp) = construct_iccp(&icc, opts.ancillary_deflater(), &max_size) {
+                    let new_len = iccp.data.len();
+                    if new_len < cur_len {
+                        debug!(
+                            "Recompressed iCCP chunk: {} ({} bytes decrease)",
+                            new_len,
+                            cur_len - new_len
+                        );
+                        png.aux_chunks[iccp_idx] = iccp;
+                    }
                 }
             }
         }
     }
 
-    // If the depth/color type has changed, some chunks may be invalid and should be dropped
-    // While these could potentially be converted, they have no known use case today and are
-    // generally more trouble than they're worth
+    if may_replace_iccp(opts) && replace_srgb_gamma_chrm(&mut png.aux_chunks) {
+        trace!("Replacing gAMA and cHRM chunks with equivalent sRGB chunk");
+    }
+
+    postprocess_phys(&mut png.aux_chunks, opts.physical_dimensions);
+    postprocess_time(&mut png.aux_chunks, opts.time);
+
+    if opts.dedup_text {
+        dedup_text_chunks(&mut png.aux_chunks, opts);
+    }
+
+    // If the depth/color type has changed, some chunks may be invalid and should be converted or
+    // dropped
     let ihdr = &png.raw.ihdr;
     if orig_ihdr.bit_depth != ihdr.bit_depth || orig_ihdr.color_type != ihdr.color_type {
-        png.aux_chunks.retain(|c| {
+        let mut with_bkgd = None;
+        png.aux_chunks.retain_mut(|c| {
+            let updated = match &c.name {
+                b"sBIT" => updated_sbit(&c.data, orig_ihdr, ihdr),
+                b"bKGD" => updated_bkgd(&c.data, orig_ihdr, ihdr).or_else(|| {
+                    if !opts.add_bkgd_to_palette {
+                        return None;
+                    }
+                    let (image, data) = palette_with_bkgd(&png.raw, &c.data, orig_ihdr)?;
+                    with_bkgd = Some(image);
+                    Some(data)
+                }),
+                b"hIST" => updated_hist(&c.data, orig, &png.raw),
+                _ => None,
+            };
+            if let Some(data) = updated {
+                c.data = data;
+                return true;
+            }
             let invalid = &c.name == b"bKGD" || &c.name == b"sBIT" || &c.name == b"hIST";
             if invalid {
                 warn!(
@@ -915,10 +2196,38 @@ fn postprocess_chunks(
             }
             !invalid
         });
+        if let Some(image) = with_bkgd {
+            debug!("Added the background color to the palette");
+            png.raw = Arc::new(image);
+            // No pixels use the new entry
+            if let Some(hist) = png.aux_chunks.iter_mut().find(|c| &c.name == b"hIST") {
+                hist.data.extend_from_slice(&[0, 0]);
+            }
+        }
+    }
+
+    // A profile for the wrong color space is invalid, which reductions only cause when allowed to
+    let is_gray = png.raw.ihdr.color_type.is_gray();
+    png.aux_chunks.retain(|c| {
+        let mismatched = &c.name == b"iCCP"
+            && orig_ihdr.color_type.is_gray() != is_gray
+            && extract_icc_limited(c, opts.max_chunk_size).and_then(|icc| icc_is_gray(&icc))
+                == Some(!is_gray);
+        if mismatched {
+            warn!("Removing iCCP chunk as it no longer matches the color type");
+        }
+        !mismatched
+    });
+
+    if let Some(order) = &opts.chunk_order {
+        png.sort_chunks(order);
     }
 
-    // Find fdAT chunks and attempt to recompress them
+    // Find fdAT chunks and attempt to recompress them, unless the animation was already optimized
     // Note if there are multiple fdATs per frame then decompression will fail and nothing will change
+    if opts.optimize_animation {
+        return;
+    }
     let mut fdat: Vec<_> = png
         .aux_chunks
         .iter_mut()
@@ -935,7 +2244,15 @@ fn postprocess_chunks(
                 }
                 if let Ok(mut data) = deflate::inflate(&c.data[4..], buffer_size).and_then(|data| {
                     let max_size = AtomicMin::new(Some(c.data.len() - 5));
-                    opts.deflate.deflate(&data, &max_size)
+                    let compressed = opts.deflate.deflate(&data, &max_size)?;
+                    if opts.verify_output {
+                        // The original data is kept if the new data is bad
+                        deflate::verify_deflated(&compressed, &data).map_err(|e| {
+                            warn!("fdAT #{}: {}", i, e);
+                            e
+                        })?;
+                    }
+                    Ok(compressed)
                 }) {
                     debug!(
                         "Recompressed fdAT #{:<2}: {} ({} bytes decrease)",
@@ -950,9 +2267,123 @@ fn postprocess_chunks(
     }
 }
 
+/// If requested, check that the image data inflates back to the filtered image
+fn verify_idat(png: &PngData, filter: RowFilter, opts: &Options) -> PngResult<()> {
+    if !opts.verify_output {
+        return Ok(());
+    }
+    let filtered = png.raw.filter_image(filter, opts.optimize_alpha);
+    deflate::verify_deflated(&png.idat_data, &filtered)
+}
+
+/// Check that any hIST chunk has one entry per palette entry, removing it if `fix_errors` is set
+fn validate_hist(png: &mut PngData, fix_errors: bool) -> PngResult<()> {
+    let palette_len = match &png.raw.ihdr.color_type {
+        ColorType::Indexed { palette } => palette.len(),
+        _ => 0,
+    };
+    let valid = |c: &Chunk| &c.name != b"hIST" || c.data.len() == palette_len * 2;
+    if png.aux_chunks.iter().all(valid) {
+        return Ok(());
+    }
+    if !fix_errors {
+        return Err(PngError::new("hIST chunk does not match the palette"));
+    }
+    warn!("Removing hIST chunk as it does not match the palette");
+    png.aux_chunks.retain(valid);
+    Ok(())
+}
+
 /// Check if an image was already optimized prior to oxipng's operations
-fn is_fully_optimized(original_size: usize, optimized_size: usize, opts: &Options) -> bool {
-    original_size <= optimized_size && !opts.force
+fn is_fully_optimized(
+    original_size: usize,
+    optimized_size: usize,
+    original_ihdr: &IhdrData,
+    opts: &Options,
+) -> bool {
+    // An image converted to a forced color type must be written even if it is larger
+    original_size <= optimized_size
+        && !opts.force
+        && !needs_forced_conversion(original_ihdr, opts).unwrap_or(false)
+}
+
+/// Whether the image must be converted to the color type and bit depth forced by the options,
+/// failing if they aren't a valid combination
+fn needs_forced_conversion(ihdr: &IhdrData, opts: &Options) -> PngResult<bool> {
+    if let Some(bit_depth) = opts.force_bit_depth {
+        match &opts.force_color_type {
+            Some((_, forced)) if *forced != bit_depth => {
+                return Err(PngError::new(
+                    "force_bit_depth must match the bit depth of force_color_type",
+                ))
+            }
+            Some(_) => {}
+            None if !ihdr.color_type.allows_bit_depth(bit_depth) => {
+                return Err(PngError::InvalidDepthForType(
+                    bit_depth,
+                    ihdr.color_type.clone(),
+                ))
+            }
+            None => return Ok(bit_depth != ihdr.bit_depth),
+        }
+    }
+    match &opts.force_color_type {
+        Some((color_type, bit_depth)) if !color_type.allows_bit_depth(*bit_depth) => Err(
+            PngError::InvalidDepthForType(*bit_depth, color_type.clone()),
+        ),
+        Some((color_type, bit_depth)) => Ok(color_type.png_header_code()
+            != ihdr.color_type.png_header_code()
+            || *bit_depth != ihdr.bit_depth),
+        None => Ok(false),
+    }
+}
+
+/// Disable the reductions that would change the color type and bit depth forced by the options,
+/// and make sure that an image converted to them is written
+fn without_forced_reductions(opts: Cow<'_, Options>, converts: bool) -> Cow<'_, Options> {
+    if opts.force_color_type.is_none() && opts.force_bit_depth.is_none() {
+        return opts;
+    }
+    let mut opts = opts.into_owned();
+    opts.bit_depth_reduction = false;
+    // The other reductions keep the bit depth, except for quantizing to a palette
+    if opts.force_color_type.is_some() {
+        opts.color_type_reduction = false;
+        opts.grayscale_reduction = false;
+    }
+    opts.max_palette_colors = None;
+    opts.force |= converts;
+    Cow::Owned(opts)
+}
+
+/// Convert the image to the color type or bit depth forced by the options, if it isn't already
+fn with_forced_color_type(image: Arc<PngImage>, opts: &Options) -> PngResult<Arc<PngImage>> {
+    if !needs_forced_conversion(&image.ihdr, opts)? {
+        return Ok(image);
+    }
+    if let Some((color_type, bit_depth)) = &opts.force_color_type {
+        return converted_image(&image, color_type, *bit_depth)
+            .map(Arc::new)
+            .ok_or_else(|| {
+                PngError::new(&format!(
+                    "The image can't be converted to color type {} with bit depth {} without loss",
+                    color_type.png_header_code(),
+                    bit_depth
+                ))
+            });
+    }
+    let bit_depth = match opts.force_bit_depth {
+        Some(bit_depth) => bit_depth,
+        None => return Ok(image),
+    };
+    forced_bit_depth(&image, bit_depth, opts.scale_16, opts.dither_16_to_8)
+        .map(Arc::new)
+        .ok_or_else(|| {
+            PngError::new(&format!(
+                "The image can't be converted to bit depth {} without loss",
+                bit_depth
+            ))
+        })
 }
 
 fn perform_backup(input_path: &Path) -> PngResult<()> {
@@ -981,7 +2412,14 @@ fn copy_permissions(metadata_input: &Metadata, out_file: &File) -> PngResult<()>
             ))
         })
         .and_then(|out_meta| {
-            out_meta.permissions().set_readonly(readonly_input);
+            let mut permissions = out_meta.permissions();
+            permissions.set_readonly(readonly_input);
+            out_file.set_permissions(permissions).map_err(|err_io| {
+                PngError::new(&format!(
+                    "unable to set permissions of output file: {}",
+                    err_io
+                ))
+            })?;
             out_file
                 .metadata()
                 .map_err(|err_io| {
@@ -1019,7 +2457,16 @@ fn copy_permissions(metadata_input: &Metadata, out_file: &File) -> PngResult<()>
             ))
         })
         .and_then(|out_meta| {
-            out_meta.permissions().set_mode(permissions);
+            let mut out_permissions = out_meta.permissions();
+            out_permissions.set_mode(permissions);
+            out_file
+                .set_permissions(out_permissions)
+                .map_err(|err_io| {
+                    PngError::new(&format!(
+                        "unable to set permissions of output file: {}",
+                        err_io
+                    ))
+                })?;
             out_file
                 .metadata()
                 .map_err(|err_io| {
@@ -1043,7 +2490,11 @@ fn copy_permissions(metadata_input: &Metadata, out_file: &File) -> PngResult<()>
 }
 
 #[cfg(not(feature = "filetime"))]
-fn copy_times(_: &Metadata, _: &Path) -> PngResult<()> {
+fn copy_times(_: &Metadata, out_path: &Path) -> PngResult<()> {
+    warn!(
+        "Unable to set file times on {:?}: oxipng was built without the filetime feature",
+        out_path
+    );
     Ok(())
 }
 
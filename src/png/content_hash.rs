@@ -0,0 +1,164 @@
+//! A hash of the decoded pixels of an image, independent of how they are encoded
+
+use crate::colors::{BitDepth, ColorType};
+use crate::interlace::{deinterlace_image, Interlacing};
+use crate::png::PngImage;
+use crate::reduction::bit_depth::expanded_bit_depth_to_8;
+use std::borrow::Cow;
+
+const PRIME_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME_3: u64 = 0x1656_67B1_9E37_79F9;
+
+/// Hash the dimensions and the pixels of the image, each normalized to 16-bit RGBA, so that the
+/// interlacing, filters, compression, bit depth and color type don't affect the result
+pub(crate) fn content_hash(png: &PngImage) -> [u8; 32] {
+    let mut png = Cow::Borrowed(png);
+    if png.ihdr.interlaced != Interlacing::None {
+        png = Cow::Owned(deinterlace_image(&png));
+    }
+    if let Some(expanded) = expanded_bit_depth_to_8(&png) {
+        png = Cow::Owned(expanded);
+    }
+
+    let mut hasher = Hasher::new();
+    hasher.update(&png.ihdr.width.to_be_bytes());
+    hasher.update(&png.ihdr.height.to_be_bytes());
+    let wide = png.ihdr.bit_depth == BitDepth::Sixteen;
+    let sample = |bytes: &[u8], i: usize| {
+        if wide {
+            u16::from_be_bytes([bytes[i * 2], bytes[i * 2 + 1]])
+        } else {
+            u16::from(bytes[i]) * 257
+        }
+    };
+    // The transparent color is given at the bit depth of the samples
+    let is_transparent = |trns: u16, value: u16| {
+        if wide {
+            trns == value
+        } else {
+            u32::from(trns) * 257 == u32::from(value)
+        }
+    };
+    let channels = png.channels_per_pixel();
+    let pixel_size = channels * if wide { 2 } else { 1 };
+    let mut row = Vec::new();
+    for line in png.data.chunks(png.ihdr.width as usize * pixel_size) {
+        row.clear();
+        for px in line.chunks(pixel_size) {
+            let rgba = match &png.ihdr.color_type {
+                ColorType::Grayscale { transparent_shade } => {
+                    let g = sample(px, 0);
+                    let transparent = transparent_shade.map_or(false, |t| is_transparent(t, g));
+                    [g, g, g, if transparent { 0 } else { 0xFFFF }]
+                }
+                ColorType::RGB { transparent_color } => {
+                    let rgb = [sample(px, 0), sample(px, 1), sample(px, 2)];
+                    let transparent = transparent_color.map_or(false, |t| {
+                        [t.r, t.g, t.b]
+                            .iter()
+                            .zip(rgb)
+                            .all(|(&t, c)| is_transparent(t, c))
+                    });
+                    [rgb[0], rgb[1], rgb[2], if transparent { 0 } else { 0xFFFF }]
+                }
+                ColorType::Indexed { palette } => {
+                    let color = palette.get(px[0] as usize).copied().unwrap_or_default();
+                    [color.r, color.g, color.b, color.a].map(|c| u16::from(c) * 257)
+                }
+                ColorType::GrayscaleAlpha => {
+                    let g = sample(px, 0);
+                    [g, g, g, sample(px, 1)]
+                }
+                ColorType::RGBA => [sample(px, 0), sample(px, 1), sample(px, 2), sample(px, 3)],
+            };
+            for c in rgba {
+                row.extend_from_slice(&c.to_be_bytes());
+            }
+        }
+        hasher.update(&row);
+    }
+    hasher.finish()
+}
+
+/// A fast, non-cryptographic 256-bit hash, with four lanes built from the rounds of xxHash64
+struct Hasher {
+    lanes: [u64; 4],
+    /// Bytes not yet making up a full stripe of 32
+    pending: Vec<u8>,
+    len: u64,
+}
+
+impl Hasher {
+    fn new() -> Self {
+        Self {
+            lanes: [
+                PRIME_1.wrapping_add(PRIME_2),
+                PRIME_2,
+                0,
+                PRIME_1.wrapping_neg(),
+            ],
+            pending: Vec::with_capacity(32),
+            len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        if !self.pending.is_empty() {
+            let needed = (32 - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..needed]);
+            data = &data[needed..];
+            if self.pending.len() < 32 {
+                return;
+            }
+            let stripe: [u8; 32] = self.pending[..].try_into().unwrap();
+            self.stripe(&stripe);
+            self.pending.clear();
+        }
+        let mut stripes = data.chunks_exact(32);
+        for stripe in &mut stripes {
+            self.stripe(stripe);
+        }
+        self.pending.extend_from_slice(stripes.remainder());
+    }
+
+    fn stripe(&mut self, stripe: &[u8]) {
+        for (lane, word) in self.lanes.iter_mut().zip(stripe.chunks_exact(8)) {
+            *lane = round(*lane, u64::from_le_bytes(word.try_into().unwrap()));
+        }
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        // The length is mixed in below, so zero padding can't make different inputs collide
+        if !self.pending.is_empty() {
+            let mut stripe = std::mem::take(&mut self.pending);
+            stripe.resize(32, 0);
+            self.stripe(&stripe);
+        }
+        let mixed = self
+            .lanes
+            .iter()
+            .fold(self.len, |acc, &lane| round(acc, lane));
+        let mut hash = [0; 32];
+        for (i, (out, lane)) in hash.chunks_exact_mut(8).zip(self.lanes).enumerate() {
+            let lane = lane ^ mixed.wrapping_add(PRIME_3.wrapping_mul(i as u64 + 1));
+            out.copy_from_slice(&avalanche(lane).to_be_bytes());
+        }
+        hash
+    }
+}
+
+fn round(acc: u64, word: u64) -> u64 {
+    acc.wrapping_add(word.wrapping_mul(PRIME_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME_1)
+}
+
+fn avalanche(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(PRIME_2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(PRIME_3);
+    h ^ (h >> 32)
+}
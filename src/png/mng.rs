@@ -0,0 +1,43 @@
+//! Extraction of the PNG images embedded in MNG files
+
+use super::{write_png_block, MNG_SIGNATURE};
+use crate::error::PngError;
+use crate::headers::{file_header_is_valid, parse_next_chunk};
+use crate::PngResult;
+
+/// Extract the first PNG image embedded in an MNG file as a standalone PNG file, which can then
+/// be optimized
+///
+/// The image is made of the chunks from its IHDR to its IEND, so any global chunks of the MNG
+/// file that it relies on, such as a shared palette, are not included. Data that is already a PNG
+/// file is returned as it is.
+pub fn extract_png_from_mng(data: &[u8]) -> PngResult<Vec<u8>> {
+    let header = data.get(..8).ok_or(PngError::TruncatedData)?;
+    if file_header_is_valid(header) {
+        return Ok(data.to_vec());
+    }
+    if header != MNG_SIGNATURE {
+        return Err(PngError::NotPNG);
+    }
+
+    let mut output = Vec::with_capacity(data.len());
+    let mut byte_offset = 8;
+    // The chunks share their layout with PNG, so the first IEND ends the embedded image
+    while let Some(chunk) = parse_next_chunk(data, &mut byte_offset, false)? {
+        match &chunk.name {
+            b"MEND" => break,
+            b"IHDR" => {
+                output.clear();
+                output.extend_from_slice(&[0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A]);
+            }
+            _ if output.is_empty() => continue,
+            _ => {}
+        }
+        write_png_block(&chunk.name, chunk.data, &mut output);
+    }
+    if output.is_empty() || data.get(byte_offset..byte_offset + 4) != Some(b"IEND") {
+        return Err(PngError::new("No embedded PNG image found in MNG file"));
+    }
+    write_png_block(b"IEND", &[], &mut output);
+    Ok(output)
+}
@@ -1,13 +1,15 @@
-use crate::colors::{BitDepth, ColorType};
+use crate::colors::{trns_len, BitDepth, ColorType};
 use crate::deflate;
 use crate::error::PngError;
 use crate::filters::*;
 use crate::headers::*;
 use crate::interlace::{deinterlace_image, interlace_image, Interlacing};
+use crate::rayon;
 use crate::Options;
 use bitvec::bitarr;
 use libdeflater::{CompressionLvl, Compressor};
 use log::warn;
+use rayon::prelude::*;
 use rgb::ComponentSlice;
 use rustc_hash::FxHashMap;
 use std::fs::File;
@@ -16,32 +18,84 @@ use std::iter::Iterator;
 use std::path::Path;
 use std::sync::Arc;
 
+mod content_hash;
+#[cfg(feature = "mng")]
+mod mng;
 pub(crate) mod scan_lines;
+mod strips;
 
-use self::scan_lines::ScanLines;
+#[cfg(feature = "mng")]
+pub use self::mng::extract_png_from_mng;
+pub use self::strips::{join_strips, split_strips};
+
+use self::scan_lines::{ScanLine, ScanLines};
 
 /// Compression level to use for the Brute filter strategy
 const BRUTE_LEVEL: i32 = 1; // 1 is fastest, 2-4 are not useful, 5 is slower but more effective
+/// The largest chunk length allowed by the PNG specification
+const MAX_CHUNK_SIZE: usize = (1 << 31) - 1;
+
 /// Number of lines to compress with the Brute filter strategy
 const BRUTE_LINES: usize = 4; // Values over 8 are generally not useful
 
+/// Approximate number of bytes of image data in each range of lines that is filtered in parallel
+const FILTER_TILE_SIZE: usize = 1 << 16;
+
+/// The signature of MNG files, which are made of chunks in the same way as PNG files
+const MNG_SIGNATURE: [u8; 8] = [0x8A, b'M', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+/// The signature of JNG files, which hold JPEG data in PNG-style chunks
+const JNG_SIGNATURE: [u8; 8] = [0x8B, b'J', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// Recognize the signature of a format related to PNG that can't be optimized, so that it can be
+/// reported specifically rather than as invalid data
+pub(crate) fn unsupported_format(header: &[u8]) -> Option<PngError> {
+    match header {
+        h if h == MNG_SIGNATURE => Some(PngError::UnsupportedFormat("MNG")),
+        h if h == JNG_SIGNATURE => Some(PngError::UnsupportedFormat("JNG")),
+        _ => None,
+    }
+}
+
+/// Find the PNG signature at the start of the data, or anywhere in it if `fix_errors` is set,
+/// returning its position
+///
+/// This allows recovering images that have been embedded in other data, or prefixed with junk.
+pub(crate) fn signature_offset(data: &[u8], fix_errors: bool) -> Result<usize, PngError> {
+    let header = data.get(0..8).ok_or(PngError::TruncatedData)?;
+    if file_header_is_valid(header) {
+        return Ok(0);
+    }
+    if let Some(err) = unsupported_format(header) {
+        return Err(err);
+    }
+    if fix_errors {
+        if let Some(offset) = data.windows(8).position(file_header_is_valid) {
+            return Ok(offset);
+        }
+    }
+    Err(PngError::NotPNG)
+}
+
+/// The decoded image data of a PNG file
+///
+/// The pixel data is available through `scanlines`, without the filter bytes of the encoded data.
 #[derive(Debug, Clone)]
 pub struct PngImage {
     /// The headers stored in the IHDR chunk
-    pub ihdr: IhdrData,
+    pub(crate) ihdr: IhdrData,
     /// The uncompressed, unfiltered data from the IDAT chunk
-    pub data: Vec<u8>,
+    pub(crate) data: Vec<u8>,
 }
 
 /// Contains all data relevant to a PNG image
 #[derive(Debug, Clone)]
 pub struct PngData {
     /// Uncompressed image data
-    pub raw: Arc<PngImage>,
+    pub(crate) raw: Arc<PngImage>,
     /// The filtered and compressed data of the IDAT chunk
-    pub idat_data: Vec<u8>,
+    pub(crate) idat_data: Vec<u8>,
     /// All non-critical chunks from the PNG are stored here
-    pub aux_chunks: Vec<Chunk>,
+    pub(crate) aux_chunks: Vec<Chunk>,
 }
 
 impl PngData {
@@ -63,10 +117,11 @@ impl PngData {
         // Check file for PNG header
         let mut header = [0; 8];
         if reader.read_exact(&mut header).is_err() {
-            return Err(PngError::new("Not a PNG file: too small"));
+            return Err(PngError::NotPNG);
         }
-        if !file_header_is_valid(&header) {
-            return Err(PngError::new("Invalid PNG header detected"));
+        // Other signatures are left to `from_slice`, which may find one later in the data
+        if let Some(err) = unsupported_format(&header) {
+            return Err(err);
         }
         // Read raw png data into memory
         let mut byte_data: Vec<u8> = Vec::with_capacity(file_len);
@@ -80,11 +135,13 @@ impl PngData {
 
     /// Create a new `PngData` struct by reading a slice
     pub fn from_slice(byte_data: &[u8], opts: &Options) -> Result<Self, PngError> {
-        let mut byte_offset: usize = 0;
         // Test that png header is valid
-        let header = byte_data.get(0..8).ok_or(PngError::TruncatedData)?;
-        if !file_header_is_valid(header) {
-            return Err(PngError::NotPNG);
+        let mut byte_offset = signature_offset(byte_data, opts.fix_errors)?;
+        if byte_offset > 0 {
+            warn!(
+                "Ignoring {} bytes of data before the PNG signature",
+                byte_offset
+            );
         }
         byte_offset += 8;
 
@@ -92,11 +149,15 @@ impl PngData {
         let mut idat_data: Vec<u8> = Vec::new();
         let mut key_chunks: FxHashMap<[u8; 4], Vec<u8>> = FxHashMap::default();
         let mut aux_chunks: Vec<Chunk> = Vec::new();
+        let mut seen_idat = false;
         while let Some(chunk) = parse_next_chunk(byte_data, &mut byte_offset, opts.fix_errors)? {
             match &chunk.name {
                 b"IDAT" => {
-                    if idat_data.is_empty() {
+                    // IDAT chunks may be empty or be interleaved with other chunks, which will be
+                    // moved after the image data. Only the concatenated stream has to be valid.
+                    if !seen_idat {
                         // Keep track of where the first IDAT sits relative to other chunks
+                        seen_idat = true;
                         aux_chunks.push(Chunk {
                             name: chunk.name,
                             data: Vec::new(),
@@ -108,10 +169,20 @@ impl PngData {
                     key_chunks.insert(chunk.name, chunk.data.to_owned());
                 }
                 _ => {
-                    if opts.strip.keep(&chunk.name) {
+                    if let Some(data) = retained_chunk_data(&chunk.name, chunk.data, opts) {
+                        if !check_chunk_size(&chunk.name, &data, opts)? {
+                            continue;
+                        }
+                        if let Err(e) = validate_chunk(&chunk.name, &data) {
+                            if !opts.fix_errors {
+                                return Err(e);
+                            }
+                            warn!("{}, removing it", e);
+                            continue;
+                        }
                         aux_chunks.push(Chunk {
                             name: chunk.name,
-                            data: chunk.data.to_owned(),
+                            data,
                         })
                     } else if chunk.name == *b"acTL" {
                         warn!(
@@ -122,6 +193,15 @@ impl PngData {
             }
         }
 
+        // Editing animations often leaves the frame count of the acTL chunk out of date
+        reconcile_actl(&mut aux_chunks, opts.fix_errors)?;
+
+        // The offset is left at the name of the IEND chunk, which is followed by its CRC
+        let trailing = byte_data.len().saturating_sub(byte_offset + 8);
+        if trailing > 0 {
+            warn!("Ignoring {} bytes of data after the IEND chunk", trailing);
+        }
+
         // Parse the chunks into our PngData
         if idat_data.is_empty() {
             return Err(PngError::ChunkMissing("IDAT"));
@@ -155,13 +235,110 @@ impl PngData {
         })
     }
 
+    /// The decoded image
+    #[inline]
+    pub fn raw(&self) -> &PngImage {
+        &self.raw
+    }
+
+    /// The filtered and compressed image data, as it will be written in the IDAT chunk
+    #[inline]
+    pub fn idat_data(&self) -> &[u8] {
+        &self.idat_data
+    }
+
+    /// The chunks other than IHDR, PLTE, tRNS and IEND, in the order they will be written
+    ///
+    /// The position of the image data is marked by an empty IDAT chunk. Use `chunks` to get the
+    /// image data along with the other chunks.
+    #[inline]
+    pub fn aux_chunks(&self) -> &[Chunk] {
+        &self.aux_chunks
+    }
+
+    /// A hash of the decoded image, for caches to detect whether it has really changed
+    ///
+    /// The dimensions and the color of every pixel are hashed, so two PNGs that decode to the same
+    /// pixels hash equally however they are encoded, regardless of interlacing, filters,
+    /// compression, bit depth or color type. Other chunks are not included. The hash is fast but
+    /// not cryptographic, so it must not be relied on to detect deliberate tampering.
+    #[must_use]
+    pub fn content_hash(&self) -> [u8; 32] {
+        content_hash::content_hash(&self.raw)
+    }
+
     /// Return an estimate of the output size which can help with evaluation of very small data
     pub fn estimated_output_size(&self) -> usize {
         self.idat_data.len() + self.raw.key_chunks_size()
     }
 
+    /// Return an iterator over the chunks that are kept as they were read, in the order they
+    /// will be written
+    ///
+    /// The image data is given as a single IDAT chunk, however many it was read from. The IHDR,
+    /// PLTE and tRNS chunks are not included, because they are decoded into `raw` and rebuilt on
+    /// output, nor is IEND. Chunks that were stripped when reading are gone.
+    ///
+    /// ```
+    /// use oxipng::{Options, PngData};
+    ///
+    /// let data = std::fs::read("tests/files/strip_headers_all.png").unwrap();
+    /// let png = PngData::from_slice(&data, &Options::default()).unwrap();
+    /// let names: Vec<_> = png
+    ///     .chunks()
+    ///     .map(|chunk| String::from_utf8_lossy(&chunk.name).into_owned())
+    ///     .collect();
+    /// assert_eq!(names, ["iCCP", "tEXt", "tEXt", "tEXt", "iTXt", "IDAT"]);
+    /// ```
+    pub fn chunks(&self) -> impl Iterator<Item = ChunkRef<'_>> {
+        let mut aux_split = self.aux_chunks.split(|c| &c.name == b"IDAT");
+        let aux_pre = aux_split.next().unwrap();
+        fn as_ref(c: &Chunk) -> ChunkRef<'_> {
+            ChunkRef {
+                name: c.name,
+                data: &c.data,
+            }
+        }
+        aux_pre
+            .iter()
+            .map(as_ref)
+            .chain(std::iter::once(ChunkRef {
+                name: *b"IDAT",
+                data: &self.idat_data,
+            }))
+            .chain(aux_split.flatten().map(as_ref))
+    }
+
+    /// Sort the ancillary chunks to follow the given priority list, with unlisted chunks
+    /// keeping their relative order after the listed ones.
+    /// Chunks never move between before and after the image data, and the animation chunks
+    /// fcTL and fdAT are always treated as unlisted so that frames stay in sequence.
+    pub fn sort_chunks(&mut self, order: &[[u8; 4]]) {
+        let priority = |c: &Chunk| {
+            if &c.name == b"fcTL" || &c.name == b"fdAT" {
+                return order.len();
+            }
+            order
+                .iter()
+                .position(|name| name == &c.name)
+                .unwrap_or(order.len())
+        };
+        for group in self.aux_chunks.split_mut(|c| &c.name == b"IDAT") {
+            group.sort_by_key(priority);
+        }
+    }
+
     /// Format the `PngData` struct into a valid PNG bytestream
     pub fn output(&self) -> Vec<u8> {
+        self.output_with_max_idat_size(None)
+    }
+
+    /// Format the `PngData` struct into a valid PNG bytestream, splitting the image data into
+    /// IDAT chunks of at most `max_idat_size` bytes each
+    ///
+    /// With `None`, the image data is written as a single IDAT chunk, unless it exceeds the
+    /// largest chunk the PNG specification allows.
+    pub fn output_with_max_idat_size(&self, max_idat_size: Option<usize>) -> Vec<u8> {
         // PNG header
         let mut output = vec![0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
         // IHDR
@@ -195,8 +372,9 @@ impl PngData {
                     palette_data.extend_from_slice(px.rgb().as_slice());
                 }
                 write_png_block(b"PLTE", &palette_data, &mut output);
-                if let Some(last_trns) = palette.iter().rposition(|px| px.a != 255) {
-                    let trns_data: Vec<_> = palette[0..=last_trns].iter().map(|px| px.a).collect();
+                let trns = trns_len(palette);
+                if trns != 0 {
+                    let trns_data: Vec<_> = palette[..trns].iter().map(|px| px.a).collect();
                     write_png_block(b"tRNS", &trns_data, &mut output);
                 }
             }
@@ -223,7 +401,13 @@ impl PngData {
             write_png_block(&chunk.name, &chunk.data, &mut output);
         }
         // IDAT data
-        write_png_block(b"IDAT", &self.idat_data, &mut output);
+        let max_idat_size = max_idat_size.map_or(MAX_CHUNK_SIZE, |size| size.min(MAX_CHUNK_SIZE));
+        if self.idat_data.is_empty() {
+            write_png_block(b"IDAT", &[], &mut output);
+        }
+        for idat in self.idat_data.chunks(max_idat_size.max(1)) {
+            write_png_block(b"IDAT", idat, &mut output);
+        }
         // Ancillary chunks that come after IDAT
         for aux_post in aux_split {
             for chunk in aux_post {
@@ -238,6 +422,107 @@ impl PngData {
 }
 
 impl PngImage {
+    /// Construct an image from its headers and its unfiltered data, as returned by `data`
+    ///
+    /// Returns an error if the bit depth isn't allowed for the color type, or if the length of
+    /// the data doesn't match the headers.
+    pub fn new(ihdr: IhdrData, data: Vec<u8>) -> Result<Self, PngError> {
+        if !ihdr.color_type.allows_bit_depth(ihdr.bit_depth) {
+            return Err(PngError::InvalidDepthForType(
+                ihdr.bit_depth,
+                ihdr.color_type,
+            ));
+        }
+        let expected = ihdr.unfiltered_data_size();
+        if data.len() != expected {
+            return Err(PngError::IncorrectDataLength(data.len(), expected));
+        }
+        Ok(Self { ihdr, data })
+    }
+
+    /// Decode the image data of a PNG file
+    pub fn from_slice(data: &[u8]) -> Result<Self, PngError> {
+        let png = PngData::from_slice(data, &Options::default())?;
+        Ok(Arc::try_unwrap(png.raw).unwrap_or_else(|raw| (*raw).clone()))
+    }
+
+    /// The width of the image in pixels
+    #[inline]
+    pub fn width(&self) -> u32 {
+        self.ihdr.width
+    }
+
+    /// The height of the image in pixels
+    #[inline]
+    pub fn height(&self) -> u32 {
+        self.ihdr.height
+    }
+
+    /// The color type of the image, including its palette or transparent color
+    #[inline]
+    pub fn color_type(&self) -> &ColorType {
+        &self.ihdr.color_type
+    }
+
+    /// The bit depth of each channel of the image
+    #[inline]
+    pub fn bit_depth(&self) -> BitDepth {
+        self.ihdr.bit_depth
+    }
+
+    /// The interlacing of the image
+    #[inline]
+    pub fn interlacing(&self) -> Interlacing {
+        self.ihdr.interlaced
+    }
+
+    /// The headers of the image, as stored in the IHDR chunk
+    #[inline]
+    pub fn ihdr(&self) -> &IhdrData {
+        &self.ihdr
+    }
+
+    /// The unfiltered pixel data of the image, which is every row of `scanlines` in turn
+    #[inline]
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Return an iterator over the unfiltered rows of pixel data, from top to bottom
+    ///
+    /// Pixels are packed as in the PNG format: channels of 16 bits are big-endian, and pixels
+    /// smaller than a byte fill each byte from its most significant bit, with each row starting
+    /// on a new byte. For interlaced images, the rows of each of the seven Adam7 passes are
+    /// returned in turn, so their widths vary.
+    ///
+    /// ```
+    /// use oxipng::{BitDepth, ColorType, Options, PngImage, RawImage};
+    ///
+    /// let red = [255, 0, 0];
+    /// let blue = [0, 0, 255];
+    /// let color_type = ColorType::RGB {
+    ///     transparent_color: None,
+    /// };
+    /// let raw = RawImage::new(2, 1, color_type, BitDepth::Eight, [red, blue].concat()).unwrap();
+    /// // Keep the color type so that the pixels are stored as RGB
+    /// let opts = Options {
+    ///     bit_depth_reduction: false,
+    ///     color_type_reduction: false,
+    ///     palette_reduction: false,
+    ///     grayscale_reduction: false,
+    ///     ..Options::default()
+    /// };
+    /// let png = raw.create_optimized_png(&opts).unwrap();
+    ///
+    /// let image = PngImage::from_slice(&png).unwrap();
+    /// assert_eq!((image.width(), image.height()), (2, 1));
+    /// let top_left = &image.scanlines().next().unwrap()[..3];
+    /// assert_eq!(top_left, red);
+    /// ```
+    pub fn scanlines(&self) -> impl Iterator<Item = &[u8]> {
+        self.scan_lines(false).map(|line| line.data)
+    }
+
     /// Convert the image to the specified interlacing type
     /// Returns true if the interlacing was changed, false otherwise
     /// The `interlace` parameter specifies the *new* interlacing mode
@@ -279,7 +564,7 @@ impl PngImage {
         match &self.ihdr.color_type {
             ColorType::Indexed { palette } => {
                 let plte = 12 + palette.len() * 3;
-                let trns = palette.iter().filter(|p| p.a != 255).count();
+                let trns = trns_len(palette);
                 if trns != 0 {
                     plte + 12 + trns
                 } else {
@@ -299,7 +584,7 @@ impl PngImage {
     }
 
     /// Reverse all filters applied on the image, returning an unfiltered IDAT bytestream
-    fn unfilter_image(&self) -> Result<Vec<u8>, PngError> {
+    pub(crate) fn unfilter_image(&self) -> Result<Vec<u8>, PngError> {
         let mut unfiltered = Vec::with_capacity(self.data.len());
         let bpp = self.bytes_per_channel() * self.channels_per_pixel();
         let mut last_line: Vec<u8> = Vec::new();
@@ -323,7 +608,39 @@ impl PngImage {
     /// Apply the specified filter type to all rows in the image
     pub fn filter_image(&self, filter: RowFilter, optimize_alpha: bool) -> Vec<u8> {
         let mut filtered = Vec::with_capacity(self.data.len());
-        let bpp = self.bytes_per_channel() * self.channels_per_pixel();
+        self.filter_image_into(filter, optimize_alpha, &mut filtered);
+        filtered
+    }
+
+    /// Apply the specified filter type to all rows in the image, replacing the contents of `filtered`
+    pub fn filter_image_into(
+        &self,
+        filter: RowFilter,
+        optimize_alpha: bool,
+        filtered: &mut Vec<u8>,
+    ) {
+        self.filter_image_with_stats(filter, optimize_alpha, filtered, None);
+    }
+
+    /// Apply the specified filter type to all rows in the image, returning the filter type chosen
+    /// for each scanline along with the score the heuristic gave it
+    ///
+    /// Scores are only comparable within a heuristic, and are 0 for the standard filters.
+    pub fn filter_stats(&self, filter: RowFilter, optimize_alpha: bool) -> Vec<(RowFilter, u64)> {
+        let mut stats = Vec::new();
+        let mut filtered = Vec::with_capacity(self.data.len());
+        self.filter_image_with_stats(filter, optimize_alpha, &mut filtered, Some(&mut stats));
+        stats
+    }
+
+    fn filter_image_with_stats(
+        &self,
+        filter: RowFilter,
+        optimize_alpha: bool,
+        filtered: &mut Vec<u8>,
+        mut stats: Option<&mut Vec<(RowFilter, u64)>>,
+    ) {
+        filtered.clear();
         // If alpha optimization is enabled, determine how many bytes of alpha there are per pixel
         let alpha_bytes = if optimize_alpha && self.ihdr.color_type.has_alpha() {
             self.bytes_per_channel()
@@ -331,12 +648,75 @@ impl PngImage {
             0
         };
 
-        let mut prev_line = Vec::new();
-        let mut prev_pass: Option<u8> = None;
+        // Unless alpha optimization alters the lines, or the strategy looks at earlier filtered
+        // lines, the filter a heuristic chooses for each line depends only on it and the line
+        // above. Ranges of lines can then be scored independently, each starting from the line
+        // before it, with the same result as filtering them in order.
+        let independent = filter > RowFilter::Paeth
+            && alpha_bytes == 0
+            && !matches!(filter, RowFilter::LookBack | RowFilter::Brute);
+        if !independent || self.data.len() < FILTER_TILE_SIZE * 2 {
+            self.filter_lines(
+                self.scan_lines(false),
+                None,
+                filter,
+                alpha_bytes,
+                filtered,
+                stats,
+            );
+            return;
+        }
+
+        let lines: Vec<_> = self.scan_lines(false).collect();
+        let tile_lines = (lines.len() * FILTER_TILE_SIZE / self.data.len()).max(1);
+        let starts: Vec<_> = (0..lines.len()).step_by(tile_lines).collect();
+        let want_stats = stats.is_some();
+        let tiles: Vec<_> = starts
+            .par_iter()
+            .map(|&start| {
+                let end = (start + tile_lines).min(lines.len());
+                let mut tile_filtered = Vec::new();
+                let mut tile_stats = Vec::new();
+                self.filter_lines(
+                    lines[start..end].iter().cloned(),
+                    start.checked_sub(1).map(|i| &lines[i]),
+                    filter,
+                    alpha_bytes,
+                    &mut tile_filtered,
+                    want_stats.then_some(&mut tile_stats),
+                );
+                (tile_filtered, tile_stats)
+            })
+            .collect();
+        for (tile_filtered, tile_stats) in tiles {
+            filtered.extend_from_slice(&tile_filtered);
+            if let Some(stats) = stats.as_deref_mut() {
+                stats.extend(tile_stats);
+            }
+        }
+    }
+
+    /// Filter the lines, appending them to `filtered`, the same as if they followed the line
+    /// `before` in the image
+    fn filter_lines<'a>(
+        &self,
+        lines: impl Iterator<Item = ScanLine<'a>>,
+        before: Option<&ScanLine>,
+        filter: RowFilter,
+        alpha_bytes: usize,
+        filtered: &mut Vec<u8>,
+        mut stats: Option<&mut Vec<(RowFilter, u64)>>,
+    ) {
+        let bpp = self.bytes_per_channel() * self.channels_per_pixel();
+        let mut prev_line = before.map_or_else(Vec::new, |line| line.data.to_vec());
+        // The previous line as filtered by a heuristic strategy, including the filter type byte
+        let mut prev_filtered = Vec::new();
+        let mut prev_pass: Option<u8> = before.and_then(|line| line.pass);
         let mut f_buf = Vec::new();
-        for line in self.scan_lines(false) {
+        for line in lines {
             if prev_pass != line.pass || line.data.len() != prev_line.len() {
                 prev_line = vec![0; line.data.len()];
+                prev_filtered.clear();
             }
             // Alpha optimisation may alter the line data, so we need a mutable copy of it
             let mut line_data = line.data.to_vec();
@@ -351,6 +731,9 @@ impl PngImage {
                 filter.filter_line(bpp, &mut line_data, &prev_line, &mut f_buf, alpha_bytes);
                 filtered.extend_from_slice(&f_buf);
                 prev_line = line_data;
+                if let Some(stats) = stats.as_deref_mut() {
+                    stats.push((filter, 0));
+                }
             } else {
                 // Heuristic filter selection strategies
                 let mut best_line = Vec::new();
@@ -361,7 +744,7 @@ impl PngImage {
                 } else {
                     RowFilter::SINGLE_LINE.iter()
                 };
-                match filter {
+                let score = match filter {
                     RowFilter::MinSum => {
                         // MSAD algorithm mentioned in libpng reference docs
                         // http://www.libpng.org/pub/png/book/chapter09.html
@@ -378,6 +761,61 @@ impl PngImage {
                                 best_line_raw = line_data.clone();
                             }
                         }
+                        best_size as u64
+                    }
+                    RowFilter::WeightedMsad => {
+                        // MSAD weighted by the proportion of non-zero bytes, as runs of zeros
+                        // compress especially well
+                        let mut best_size = u64::MAX;
+                        for f in try_filters {
+                            f.filter_line(bpp, &mut line_data, &prev_line, &mut f_buf, alpha_bytes);
+                            let (sum, zeros) =
+                                f_buf[1..].iter().fold((0, 0), |(sum, zeros), &x| {
+                                    let signed = x as i8;
+                                    (
+                                        sum + u64::from(signed.unsigned_abs()),
+                                        zeros + u64::from(x == 0),
+                                    )
+                                });
+                            let len = (f_buf.len() - 1) as u64;
+                            let size = sum * (len - zeros) / len;
+                            if size < best_size {
+                                best_size = size;
+                                std::mem::swap(&mut best_line, &mut f_buf);
+                                best_line_raw = line_data.clone();
+                            }
+                        }
+                        best_size
+                    }
+                    RowFilter::LookBack => {
+                        // MSAD where bytes repeating those of the previous filtered line are free,
+                        // as deflate is likely to find matches for them
+                        let mut best_size = usize::MAX;
+                        for f in try_filters {
+                            f.filter_line(bpp, &mut line_data, &prev_line, &mut f_buf, alpha_bytes);
+                            let size = if prev_filtered.len() == f_buf.len() {
+                                f_buf
+                                    .iter()
+                                    .zip(&prev_filtered)
+                                    .fold(0, |acc, (&x, &prev)| {
+                                        if x == prev {
+                                            acc
+                                        } else {
+                                            acc + (x as i8).unsigned_abs() as usize + 1
+                                        }
+                                    })
+                            } else {
+                                f_buf
+                                    .iter()
+                                    .fold(0, |acc, &x| acc + (x as i8).unsigned_abs() as usize)
+                            };
+                            if size < best_size {
+                                best_size = size;
+                                std::mem::swap(&mut best_line, &mut f_buf);
+                                best_line_raw = line_data.clone();
+                            }
+                        }
+                        best_size as u64
                     }
                     RowFilter::Entropy => {
                         // Shannon entropy algorithm, from LodePNG
@@ -401,6 +839,7 @@ impl PngImage {
                                 best_line_raw = line_data.clone();
                             }
                         }
+                        best_size as u64
                     }
                     RowFilter::Bigrams => {
                         // Count distinct bigrams, from pngwolf
@@ -420,6 +859,7 @@ impl PngImage {
                                 best_line_raw = line_data.clone();
                             }
                         }
+                        best_size as u64
                     }
                     RowFilter::BigEnt => {
                         // Bigram entropy, combined from Entropy and Bigrams filters
@@ -440,6 +880,7 @@ impl PngImage {
                                 best_line_raw = line_data.clone();
                             }
                         }
+                        best_size as u64
                     }
                     RowFilter::Brute => {
                         // Brute force by compressing each filter attempt
@@ -466,27 +907,33 @@ impl PngImage {
                             }
                         }
                         filtered.resize(line_start, 0);
+                        best_size as u64
                     }
                     _ => unreachable!(),
+                };
+                if let Some(stats) = stats.as_deref_mut() {
+                    // The line begins with the filter type byte of the standard filter chosen
+                    stats.push((RowFilter::try_from(best_line[0]).unwrap(), score));
                 }
                 filtered.extend_from_slice(&best_line);
+                prev_filtered = best_line;
                 prev_line = best_line_raw;
             }
 
             prev_pass = line.pass;
         }
-        filtered
     }
 }
 
 fn write_png_block(key: &[u8], chunk: &[u8], output: &mut Vec<u8>) {
-    let mut chunk_data = Vec::with_capacity(chunk.len() + 4);
-    chunk_data.extend_from_slice(key);
-    chunk_data.extend_from_slice(chunk);
-    output.reserve(chunk_data.len() + 8);
-    output.extend_from_slice(&(chunk_data.len() as u32 - 4).to_be_bytes());
-    let crc = deflate::crc32(&chunk_data);
-    output.append(&mut chunk_data);
+    output.reserve(chunk.len() + 12);
+    output.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+    // The CRC covers the name and data, which are written straight to the output
+    let mut writer = deflate::Crc32Writer::new(&mut *output);
+    // Writing to a Vec cannot fail
+    writer.write_all(key).unwrap();
+    writer.write_all(chunk).unwrap();
+    let crc = writer.crc();
     output.extend_from_slice(&crc.to_be_bytes());
 }
 
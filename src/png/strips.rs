@@ -0,0 +1,113 @@
+//! Splitting an image into strips of rows that can be optimized separately, such as on different
+//! machines, and joining them back together
+
+use crate::error::PngError;
+use crate::headers::IhdrData;
+use crate::interlace::Interlacing;
+use crate::png::PngImage;
+use crate::reduction::{ReductionFilter, ReductionKind};
+use crate::Options;
+use std::borrow::Cow;
+
+/// Split an image into `count` strips of consecutive rows, from top to bottom, returning them
+/// along with the options to optimize each of them with
+///
+/// The strips are as close to the same height as possible, and each is a valid image of the same
+/// width, color type and bit depth. Interlaced images are deinterlaced first.
+///
+/// The strips must all keep the same color type, bit depth and palette to be joined again, so
+/// the returned options are `opts` with every reduction that could change them disabled. The
+/// image should therefore be reduced as a whole before it is split. Optimizing the strips with
+/// other options may make them impossible to join.
+pub fn split_strips(
+    image: &PngImage,
+    count: usize,
+    opts: &Options,
+) -> Result<(Vec<PngImage>, Options), PngError> {
+    let height = image.ihdr.height as usize;
+    if count == 0 || count > height {
+        return Err(PngError::new(&format!(
+            "Can't split an image of {} rows into {} strips",
+            height, count
+        )));
+    }
+    let image = match image.change_interlacing(Interlacing::None) {
+        Some(deinterlaced) => Cow::Owned(deinterlaced),
+        None => Cow::Borrowed(image),
+    };
+    let row_bytes = image.data.len() / height;
+
+    let strips = (0..count)
+        .map(|i| {
+            let start = i * height / count;
+            let end = (i + 1) * height / count;
+            PngImage {
+                ihdr: IhdrData {
+                    height: (end - start) as u32,
+                    ..image.ihdr.clone()
+                },
+                data: image.data[start * row_bytes..end * row_bytes].to_vec(),
+            }
+        })
+        .collect();
+    Ok((strips, strip_options(opts)))
+}
+
+/// Join strips that were split from an image with `split_strips`, in order from top to bottom,
+/// into one image
+///
+/// The strips may have been optimized and decoded again, as long as they still have the same
+/// width, color type and bit depth, including the same palette or transparent color.
+pub fn join_strips(strips: &[PngImage]) -> Result<PngImage, PngError> {
+    let first = strips
+        .first()
+        .ok_or_else(|| PngError::new("There are no strips to join"))?;
+    let mut height: u32 = 0;
+    let mut data = Vec::with_capacity(strips.iter().map(|s| s.data.len()).sum());
+    for strip in strips {
+        if strip.ihdr.width != first.ihdr.width
+            || strip.ihdr.color_type != first.ihdr.color_type
+            || strip.ihdr.bit_depth != first.ihdr.bit_depth
+        {
+            return Err(PngError::new(
+                "The strips don't share the same width, color type and bit depth",
+            ));
+        }
+        height = height
+            .checked_add(strip.ihdr.height)
+            .ok_or_else(|| PngError::new("The joined image is too tall"))?;
+        match strip.change_interlacing(Interlacing::None) {
+            Some(deinterlaced) => data.extend_from_slice(&deinterlaced.data),
+            None => data.extend_from_slice(&strip.data),
+        }
+    }
+
+    Ok(PngImage {
+        ihdr: IhdrData {
+            height,
+            interlaced: Interlacing::None,
+            ..first.ihdr.clone()
+        },
+        data,
+    })
+}
+
+/// The options to optimize each strip with, which keep the color type, bit depth and palette
+/// that the strips share
+fn strip_options(opts: &Options) -> Options {
+    let mut opts = opts.clone();
+    // Cleaning the alpha channel only changes the colors of transparent pixels
+    opts.reduction_filter = Some(ReductionFilter::new(|kind| kind == ReductionKind::Alpha));
+    opts.bit_depth_reduction = false;
+    opts.color_type_reduction = false;
+    opts.palette_reduction = false;
+    opts.optimize_palette_order = false;
+    opts.grayscale_reduction = false;
+    opts.grayscale_tolerance = None;
+    opts.max_palette_colors = None;
+    opts.force_color_type = None;
+    opts.add_bkgd_to_palette = false;
+    opts.interlace = Some(Interlacing::None);
+    opts.interlace_auto = false;
+    opts
+}
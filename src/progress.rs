@@ -0,0 +1,46 @@
+use crate::filters::RowFilter;
+use crate::reduction::ReductionKind;
+use std::fmt;
+use std::sync::Arc;
+
+/// An event reported to the progress callback while optimizing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// A filter is about to be applied to the image for a compression trial
+    FilterTrialStarted { filter: RowFilter },
+    /// A compression trial has finished
+    ///
+    /// `size` is `None` if the trial was abandoned because it could not beat the best result so far
+    DeflateTrialCompleted {
+        filter: RowFilter,
+        size: Option<usize>,
+    },
+    /// A reduction produced a new version of the image
+    ///
+    /// The new version may still be discarded if it turns out not to compress better
+    ReductionApplied { kind: ReductionKind },
+}
+
+/// A callback receiving `ProgressEvent`s as optimization proceeds
+///
+/// The callback is invoked from rayon worker threads as well as the calling thread, possibly
+/// concurrently and in no particular order. It is called while no locks are held, but it should
+/// return quickly, as it blocks the work that reported the event.
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<dyn Fn(ProgressEvent) + Send + Sync>);
+
+impl ProgressCallback {
+    pub fn new<F: Fn(ProgressEvent) + Send + Sync + 'static>(f: F) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn emit(&self, event: ProgressEvent) {
+        (self.0)(event)
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
@@ -1,6 +1,9 @@
 use rgb::RGB16;
 
+use crate::atomicmin::AtomicMin;
 use crate::colors::{BitDepth, ColorType};
+use crate::deflate::deflate;
+use crate::filters::RowFilter;
 use crate::headers::IhdrData;
 use crate::png::PngImage;
 
@@ -28,6 +31,88 @@ pub fn cleaned_alpha_channel(png: &PngImage) -> Option<PngImage> {
     })
 }
 
+/// Clean the alpha channel by setting the color of all fully transparent pixels to whichever of
+/// black, the color to their left or the color above them compresses best
+///
+/// Only the color of fully transparent pixels is altered, so the image is visually unchanged.
+pub fn aggressively_cleaned_alpha_channel(png: &PngImage) -> Option<PngImage> {
+    let zeroed = cleaned_alpha_channel(png)?;
+    [FillFrom::Left, FillFrom::Above]
+        .into_iter()
+        .map(|fill| filled_alpha_channel(png, fill))
+        .chain(Some(zeroed))
+        .min_by_key(estimated_size)
+}
+
+/// The neighbour from which a fully transparent pixel takes its color
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FillFrom {
+    Left,
+    Above,
+}
+
+/// Set the color of all fully transparent pixels to the color of a neighbouring pixel,
+/// falling back to the other neighbour and then to black at the edges of the image
+fn filled_alpha_channel(png: &PngImage, fill: FillFrom) -> PngImage {
+    let byte_depth = png.bytes_per_channel();
+    let bpp = png.channels_per_pixel() * byte_depth;
+    let colored_bytes = bpp - byte_depth;
+
+    let mut filled = Vec::with_capacity(png.data.len());
+    let mut prev_line: Option<(usize, Option<u8>)> = None;
+    for line in png.scan_lines(false) {
+        let start = filled.len();
+        filled.extend_from_slice(line.data);
+        // The line above is only usable if it belongs to the same interlacing pass
+        let above = match prev_line {
+            Some((prev_start, pass))
+                if pass == line.pass && start - prev_start == line.data.len() =>
+            {
+                Some(prev_start)
+            }
+            _ => None,
+        };
+        for x in (0..line.data.len()).step_by(bpp) {
+            let pixel = start + x;
+            if filled[pixel + colored_bytes..pixel + bpp]
+                .iter()
+                .any(|b| *b != 0)
+            {
+                continue;
+            }
+            let left = if x >= bpp { Some(pixel - bpp) } else { None };
+            let up = above.map(|a| a + x);
+            let source = match fill {
+                FillFrom::Left => left.or(up),
+                FillFrom::Above => up.or(left),
+            };
+            match source {
+                Some(s) => filled.copy_within(s..s + colored_bytes, pixel),
+                None => filled[pixel..pixel + colored_bytes].fill(0),
+            }
+        }
+        prev_line = Some((start, line.pass));
+    }
+
+    PngImage {
+        data: filled,
+        ihdr: png.ihdr.clone(),
+    }
+}
+
+/// Estimate how well an image will compress by quickly compressing it with the filters for which
+/// the color of transparent pixels is not already chosen to match the predictor
+fn estimated_size(png: &PngImage) -> usize {
+    [RowFilter::None, RowFilter::Bigrams]
+        .into_iter()
+        .filter_map(|filter| {
+            deflate(&png.filter_image(filter, true), 1, &AtomicMin::new(None)).ok()
+        })
+        .map(|compressed| compressed.len())
+        .min()
+        .unwrap_or(usize::MAX)
+}
+
 #[must_use]
 pub fn reduced_alpha_channel(png: &PngImage, optimize_alpha: bool) -> Option<PngImage> {
     if !png.ihdr.color_type.has_alpha() {
@@ -36,17 +121,28 @@ pub fn reduced_alpha_channel(png: &PngImage, optimize_alpha: bool) -> Option<Png
     let byte_depth = png.bytes_per_channel();
     let bpp = png.channels_per_pixel() * byte_depth;
     let colored_bytes = bpp - byte_depth;
+    let is_transparent = |pixel: &[u8]| pixel.iter().skip(colored_bytes).all(|b| *b == 0);
 
-    // If alpha optimisation is enabled, see if the image contains only fully opaque and fully transparent pixels.
-    // In case this occurs, we want to try and find an unused color we can use for the tRNS chunk.
+    // See if the image contains only fully opaque and fully transparent pixels.
+    // In case this occurs, we want to find a color we can use for the tRNS chunk.
+    // If all transparent pixels share the same color, this can be used as long as no opaque pixel
+    // has it too. Otherwise, if alpha optimisation is enabled, we try to find an unused color.
     // Rather than an exhaustive search, we will just keep track of 256 shades of gray, which should cover many cases.
-    let mut has_transparency = false;
+    let mut transparent_color: Option<&[u8]> = None;
+    let mut uniform_transparency = true;
     let mut used_colors = vec![false; 256];
 
     for pixel in png.data.chunks(bpp) {
-        if optimize_alpha && pixel.iter().skip(colored_bytes).all(|b| *b == 0) {
+        if is_transparent(pixel) {
             // Fully transparent, we may be able to reduce with tRNS
-            has_transparency = true;
+            match transparent_color {
+                None => transparent_color = Some(&pixel[..colored_bytes]),
+                Some(color) if color != &pixel[..colored_bytes] => uniform_transparency = false,
+                _ => {}
+            }
+            if !uniform_transparency && !optimize_alpha {
+                return None;
+            }
         } else if pixel.iter().skip(colored_bytes).any(|b| *b != 255) {
             // Partially transparent, the image is not reducible
             return None;
@@ -56,42 +152,56 @@ pub fn reduced_alpha_channel(png: &PngImage, optimize_alpha: bool) -> Option<Png
         }
     }
 
-    let transparency_pixel = if has_transparency {
-        // For grayscale, start by checking 4 specific values in the hope that we may reduce depth
-        let unused = match png.ihdr.color_type {
-            ColorType::GrayscaleAlpha => [0x00, 0xFF, 0x55, 0xAA]
-                .into_iter()
-                .find(|&v| !used_colors[v as usize]),
-            _ => None,
+    // Check that no opaque pixel shares the color of the transparent ones
+    let existing_color = transparent_color.filter(|&color| {
+        uniform_transparency
+            && png
+                .data
+                .chunks(bpp)
+                .all(|pixel| is_transparent(pixel) || &pixel[..colored_bytes] != color)
+    });
+
+    let transparency_pixel: Option<Vec<u8>> = match transparent_color {
+        None => None,
+        Some(_) if !optimize_alpha => Some(existing_color?.to_vec()),
+        Some(_) => {
+            // For grayscale, start by checking 4 specific values in the hope that we may reduce depth
+            let unused = match png.ihdr.color_type {
+                ColorType::GrayscaleAlpha => [0x00, 0xFF, 0x55, 0xAA]
+                    .into_iter()
+                    .find(|&v| !used_colors[v as usize]),
+                _ => None,
+            };
+            let unused_gray = || used_colors.iter().position(|&u| !u).map(|v| v as u8);
+            // If no usable color was found we will have to fail here
+            Some(match (unused, existing_color) {
+                (Some(v), _) => vec![v; colored_bytes],
+                (None, Some(color)) => color.to_vec(),
+                (None, None) => vec![unused_gray()?; colored_bytes],
+            })
         }
-        .or_else(|| used_colors.iter().position(|&u| !u).map(|v| v as u8));
-        // If no unused color was found we will have to fail here
-        Some(unused?)
-    } else {
-        None
     };
 
     let mut raw_data = Vec::with_capacity(png.data.len());
     for pixel in png.data.chunks(bpp) {
-        match transparency_pixel {
-            Some(trns) if pixel.iter().skip(colored_bytes).all(|b| *b == 0) => {
-                raw_data.resize(raw_data.len() + colored_bytes, trns);
-            }
+        match &transparency_pixel {
+            Some(trns) if is_transparent(pixel) => raw_data.extend_from_slice(trns),
             _ => raw_data.extend_from_slice(&pixel[0..colored_bytes]),
         };
     }
 
     // Construct the color type with appropriate transparency data
-    let transparent = transparency_pixel.map(|trns| match png.ihdr.bit_depth {
-        BitDepth::Sixteen => (trns as u16) << 8 | trns as u16,
-        _ => trns as u16,
-    });
+    let channel = |trns: &[u8], c: usize| match png.ihdr.bit_depth {
+        BitDepth::Sixteen => u16::from_be_bytes([trns[c * 2], trns[c * 2 + 1]]),
+        _ => trns[c] as u16,
+    };
     let target_color_type = match png.ihdr.color_type {
         ColorType::GrayscaleAlpha => ColorType::Grayscale {
-            transparent_shade: transparent,
+            transparent_shade: transparency_pixel.map(|trns| channel(&trns, 0)),
         },
         _ => ColorType::RGB {
-            transparent_color: transparent.map(|t| RGB16::new(t, t, t)),
+            transparent_color: transparency_pixel
+                .map(|trns| RGB16::new(channel(&trns, 0), channel(&trns, 1), channel(&trns, 2))),
         },
     };
 
@@ -1,17 +1,48 @@
 use crate::colors::{BitDepth, ColorType};
 use crate::headers::IhdrData;
+use crate::interlace::Interlacing;
 use crate::png::PngImage;
+use oxipng_core::bit_depth::{
+    minimum_gray_bit_depth, pack_line, replicate_bits, scale_sample, unpack_line,
+};
+use rgb::ComponentMap;
 
 /// Attempt to reduce a 16-bit image to 8-bit, returning the reduced image if successful
+///
+/// The reduction is lossless where possible. Otherwise, if `force_scale` is set, the image is
+/// scaled instead.
 #[must_use]
 pub fn reduced_bit_depth_16_to_8(png: &PngImage, force_scale: bool) -> Option<PngImage> {
+    lossless_bit_depth_16_to_8(png).or_else(|| {
+        if force_scale {
+            scaled_bit_depth_16_to_8(png)
+        } else {
+            None
+        }
+    })
+}
+
+/// Reduce a 16-bit image to 8-bit only if no value changes, returning the reduced image if
+/// successful
+///
+/// This requires every sample, and the transparent color if there is one, to be a multiple of
+/// 257, so that the high and low bytes are equal and the 8-bit value represents it exactly.
+#[must_use]
+pub fn lossless_bit_depth_16_to_8(png: &PngImage) -> Option<PngImage> {
     if png.ihdr.bit_depth != BitDepth::Sixteen {
         return None;
     }
 
-    if force_scale {
-        return scaled_bit_depth_16_to_8(png);
-    }
+    let exact = |v: u16| v % 257 == 0;
+    let color_type = match png.ihdr.color_type {
+        ColorType::Grayscale {
+            transparent_shade: Some(trns),
+        } if !exact(trns) => return None,
+        ColorType::RGB {
+            transparent_color: Some(trns),
+        } if !trns.iter().all(exact) => return None,
+        ref color_type => color_type_16_to_8(color_type),
+    };
 
     // Reduce from 16 to 8 bits per channel per pixel
     if png.data.chunks(2).any(|pair| pair[0] != pair[1]) {
@@ -22,13 +53,66 @@ pub fn reduced_bit_depth_16_to_8(png: &PngImage, force_scale: bool) -> Option<Pn
     Some(PngImage {
         data: png.data.iter().step_by(2).cloned().collect(),
         ihdr: IhdrData {
-            color_type: png.ihdr.color_type.clone(),
+            color_type,
             bit_depth: BitDepth::Eight,
             ..png.ihdr
         },
     })
 }
 
+/// The color type of a 16-bit image reduced to 8-bit, with the transparent color scaled to match
+fn color_type_16_to_8(color_type: &ColorType) -> ColorType {
+    match *color_type {
+        ColorType::Grayscale { transparent_shade } => ColorType::Grayscale {
+            transparent_shade: transparent_shade.map(|t| scale_sample(t).into()),
+        },
+        ColorType::RGB { transparent_color } => ColorType::RGB {
+            transparent_color: transparent_color.map(|t| t.map(|c| scale_sample(c).into())),
+        },
+        ref color_type => color_type.clone(),
+    }
+}
+
+/// Make the pixels of a 16-bit image scaled to 8-bit `data` transparent exactly where they were
+/// before, as the transparent color is scaled too
+///
+/// Pixels of the transparent color are given its scaled value, even if dithered, while other
+/// pixels which were scaled to that value are moved one step away from it, towards their original
+/// value where possible.
+fn with_transparency_kept(png: &PngImage, mut data: Vec<u8>) -> Vec<u8> {
+    let transparent: Vec<u16> = match png.ihdr.color_type {
+        ColorType::Grayscale {
+            transparent_shade: Some(trns),
+        } => vec![trns],
+        ColorType::RGB {
+            transparent_color: Some(trns),
+        } => trns.iter().collect(),
+        _ => return data,
+    };
+    let scaled: Vec<u8> = transparent.iter().map(|&t| scale_sample(t)).collect();
+    let channels = transparent.len();
+    for (pixel, reduced) in png.data.chunks(channels * 2).zip(data.chunks_mut(channels)) {
+        let original = pixel
+            .chunks(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+        if original.clone().eq(transparent.iter().copied()) {
+            reduced.copy_from_slice(&scaled);
+        } else if reduced == scaled {
+            // Change the first channel that differs from the transparent color
+            let (i, value) = original
+                .enumerate()
+                .find(|&(i, value)| value != transparent[i])
+                .unwrap();
+            reduced[i] = if scaled[i] == 0 || (value > transparent[i] && scaled[i] < 255) {
+                scaled[i] + 1
+            } else {
+                scaled[i] - 1
+            };
+        }
+    }
+    data
+}
+
 /// Forcibly reduce a 16-bit image to 8-bit by scaling, returning the reduced image if successful
 #[must_use]
 pub fn scaled_bit_depth_16_to_8(png: &PngImage) -> Option<PngImage> {
@@ -44,17 +128,67 @@ pub fn scaled_bit_depth_16_to_8(png: &PngImage) -> Option<PngImage> {
             if pair[0] == pair[1] {
                 return pair[0];
             }
-            // See: http://www.libpng.org/pub/png/spec/1.2/PNG-Decoders.html#D.Sample-depth-rescaling
-            // This allows values such as 0x00FF to be rounded to 0x01 rather than truncated to 0x00
-            let val = u16::from_be_bytes([pair[0], pair[1]]) as f64;
-            (val * 255.0 / 65535.0).round() as u8
+            scale_sample(u16::from_be_bytes([pair[0], pair[1]]))
         })
         .collect();
 
     Some(PngImage {
-        data,
+        data: with_transparency_kept(png, data),
         ihdr: IhdrData {
-            color_type: png.ihdr.color_type.clone(),
+            color_type: color_type_16_to_8(&png.ihdr.color_type),
+            bit_depth: BitDepth::Eight,
+            ..png.ihdr
+        },
+    })
+}
+
+/// Forcibly reduce a 16-bit image to 8-bit by scaling with Floyd-Steinberg dithering,
+/// returning the reduced image if successful
+///
+/// Samples which can be reduced exactly are left undithered. The image is always traversed in
+/// the same order so the result is deterministic.
+#[must_use]
+pub fn dithered_bit_depth_16_to_8(png: &PngImage) -> Option<PngImage> {
+    if png.ihdr.bit_depth != BitDepth::Sixteen {
+        return None;
+    }
+    // Dithering must be done on the image as it is displayed, not the separate interlaced passes
+    if png.ihdr.interlaced != Interlacing::None {
+        let progressive = png.change_interlacing(Interlacing::None)?;
+        let reduced = dithered_bit_depth_16_to_8(&progressive)?;
+        return reduced.change_interlacing(png.ihdr.interlaced);
+    }
+
+    let samples_per_row = png.ihdr.width as usize * png.channels_per_pixel();
+    let channels = png.channels_per_pixel();
+    // Errors carried to the current and next rows, with a sample of padding on each side
+    let mut errors = vec![0f32; samples_per_row + 2 * channels];
+    let mut next_errors = errors.clone();
+    let mut data = Vec::with_capacity(png.data.len() / 2);
+    for row in png.data.chunks(samples_per_row * 2) {
+        for (i, pair) in row.chunks(2).enumerate() {
+            if pair[0] == pair[1] {
+                data.push(pair[0]);
+                continue;
+            }
+            let val = u16::from_be_bytes([pair[0], pair[1]]) as f32 * 255.0 / 65535.0;
+            let pos = i + channels;
+            let wanted = val + errors[pos];
+            let reduced = wanted.round().clamp(0.0, 255.0);
+            data.push(reduced as u8);
+            let error = wanted - reduced;
+            errors[pos + channels] += error * 7.0 / 16.0;
+            next_errors[pos - channels] += error * 3.0 / 16.0;
+            next_errors[pos] += error * 5.0 / 16.0;
+            next_errors[pos + channels] += error / 16.0;
+        }
+        errors = std::mem::replace(&mut next_errors, vec![0f32; errors.len()]);
+    }
+
+    Some(PngImage {
+        data: with_transparency_kept(png, data),
+        ihdr: IhdrData {
+            color_type: color_type_16_to_8(&png.ihdr.color_type),
             bit_depth: BitDepth::Eight,
             ..png.ihdr
         },
@@ -68,62 +202,30 @@ pub fn reduced_bit_depth_8_or_less(png: &PngImage) -> Option<PngImage> {
         return None;
     }
 
-    let mut minimum_bits = 1;
-
-    if let ColorType::Indexed { palette } = &png.ihdr.color_type {
+    let minimum_bits = if let ColorType::Indexed { palette } = &png.ihdr.color_type {
         // We can easily determine minimum depth by the palette size
-        minimum_bits = match palette.len() {
+        let minimum_bits = match palette.len() {
             0..=2 => 1,
             3..=4 => 2,
             5..=16 => 4,
             _ => return None,
         };
+        // Indices beyond the palette would be corrupted by masking them to the lower depth
+        if png.data.iter().any(|&b| b >> minimum_bits != 0) {
+            return None;
+        }
+        minimum_bits
     } else {
         // Finding minimum depth for grayscale is much more complicated
-        let mut mask = 1;
-        let mut divisions = 1..8;
-        for &b in &png.data {
-            if b == 0 || b == 255 {
-                continue;
-            }
-            'try_depth: loop {
-                // Align the first pixel division with the mask
-                let mut byte = b.rotate_left(minimum_bits as u32);
-                // Each potential division of this pixel must be identical to successfully reduce
-                let compare = byte & mask;
-                for _ in divisions.clone() {
-                    // Align the next division with the mask
-                    byte = byte.rotate_left(minimum_bits as u32);
-                    if byte & mask != compare {
-                        // This depth is not possible, try the next one up
-                        minimum_bits <<= 1;
-                        if minimum_bits == 8 {
-                            return None;
-                        }
-                        mask = (1 << minimum_bits) - 1;
-                        divisions = 1..(8 / minimum_bits);
-                        continue 'try_depth;
-                    }
-                }
-                break;
-            }
+        match minimum_gray_bit_depth(&png.data) {
+            8 => return None,
+            bits => bits,
         }
-    }
+    };
 
     let mut reduced = Vec::with_capacity(png.data.len());
-    let mask = (1 << minimum_bits) - 1;
     for line in png.scan_lines(false) {
-        // Loop over the data in chunks that will produce 1 byte of output
-        for chunk in line.data.chunks(8 / minimum_bits) {
-            let mut new_byte = 0;
-            let mut shift = 8;
-            for byte in chunk {
-                shift -= minimum_bits;
-                // Take the low bits of the pixel and shift them into the output byte
-                new_byte |= (byte & mask) << shift;
-            }
-            reduced.push(new_byte);
-        }
+        pack_line(line.data, minimum_bits, &mut reduced);
     }
 
     // If the image is grayscale we also need to reduce the transparency pixel
@@ -133,12 +235,7 @@ pub fn reduced_bit_depth_8_or_less(png: &PngImage) -> Option<PngImage> {
     {
         let reduced_trans = (trans & 0xFF) >> (8 - minimum_bits);
         // Verify the reduction is valid by restoring back to original bit depth
-        let mut check = reduced_trans;
-        let mut bits = minimum_bits;
-        while bits < 8 {
-            check = check << bits | check;
-            bits <<= 1;
-        }
+        let check = u16::from(replicate_bits(reduced_trans as u8, minimum_bits));
         // If the transparency doesn't fit the new bit depth it is therefore unused - set it to None
         ColorType::Grayscale {
             transparent_shade: if trans == check {
@@ -168,48 +265,23 @@ pub fn expanded_bit_depth_to_8(png: &PngImage) -> Option<PngImage> {
     if bit_depth >= 8 {
         return None;
     }
-    // Calculate the current number of pixels per byte
-    let ppb = 8 / bit_depth;
     let is_gray = matches!(png.ihdr.color_type, ColorType::Grayscale { .. });
 
     let mut reduced = Vec::with_capacity((png.ihdr.width * png.ihdr.height) as usize);
-    let mut length = 0;
-    let mask = (1 << bit_depth) - 1;
     for line in png.scan_lines(false) {
-        for &(mut byte) in line.data {
-            // Loop over each pixel in the byte
-            for _ in 0..ppb {
-                // Align the current pixel with the mask
-                byte = byte.rotate_left(bit_depth);
-                let mut val = byte & mask;
-                if is_gray {
-                    // Expand gray by repeating the bits
-                    let mut bits = bit_depth;
-                    while bits < 8 {
-                        val = val << bits | val;
-                        bits <<= 1;
-                    }
-                }
-                reduced.push(val);
-            }
-        }
-        // Trim any overflow
-        length += line.num_pixels;
-        reduced.truncate(length);
+        // Expand gray by repeating the bits
+        unpack_line(line.data, bit_depth, line.num_pixels, is_gray, &mut reduced);
     }
 
     // If the image is grayscale we also need to expand the transparency pixel
     let color_type = if let ColorType::Grayscale {
-        transparent_shade: Some(mut trans),
+        transparent_shade: Some(trans),
     } = png.ihdr.color_type
     {
-        let mut bits = bit_depth;
-        while bits < 8 {
-            trans = trans << bits | trans;
-            bits <<= 1;
-        }
+        // A shade which doesn't fit the bit depth matches no pixels, so is dropped
         ColorType::Grayscale {
-            transparent_shade: Some(trans),
+            transparent_shade: (trans >> bit_depth == 0)
+                .then(|| replicate_bits(trans as u8, bit_depth).into()),
         }
     } else {
         png.ihdr.color_type.clone()
@@ -0,0 +1,132 @@
+//! Handling of the bKGD chunk, which gives a background color for the image
+
+use crate::colors::{BitDepth, ColorType};
+use crate::headers::IhdrData;
+use crate::png::PngImage;
+use rgb::{RGB16, RGBA8};
+
+/// The factor to scale a sample of the given depth by to get a 16-bit sample
+fn scale_to_16(depth: BitDepth) -> u16 {
+    u16::MAX / ((1u32 << depth as u8) - 1) as u16
+}
+
+/// Parse the data of a bKGD chunk into a 16-bit color, or `None` if it is not valid for the image
+fn parse_bkgd(data: &[u8], ihdr: &IhdrData) -> Option<RGB16> {
+    let sample = |i: usize| {
+        let value = u16::from_be_bytes([*data.get(i)?, *data.get(i + 1)?]);
+        value.checked_mul(scale_to_16(ihdr.bit_depth))
+    };
+    match (&ihdr.color_type, data.len()) {
+        (ColorType::Grayscale { .. } | ColorType::GrayscaleAlpha, 2) => {
+            let gray = sample(0)?;
+            Some(RGB16::new(gray, gray, gray))
+        }
+        (ColorType::RGB { .. } | ColorType::RGBA, 6) => {
+            Some(RGB16::new(sample(0)?, sample(2)?, sample(4)?))
+        }
+        (ColorType::Indexed { palette }, 1) => {
+            let entry = palette.get(data[0] as usize)?;
+            Some(RGB16::new(
+                u16::from(entry.r) * 257,
+                u16::from(entry.g) * 257,
+                u16::from(entry.b) * 257,
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Encode a color as the data of a bKGD chunk for the image, or `None` if the color can't be
+/// represented exactly
+fn encode_bkgd(color: RGB16, ihdr: &IhdrData) -> Option<Vec<u8>> {
+    let sample = |value: u16, depth: BitDepth| {
+        let scale = scale_to_16(depth);
+        if value % scale == 0 {
+            Some((value / scale).to_be_bytes())
+        } else {
+            None
+        }
+    };
+    match &ihdr.color_type {
+        ColorType::Grayscale { .. } | ColorType::GrayscaleAlpha => {
+            if color.r != color.g || color.g != color.b {
+                return None;
+            }
+            Some(sample(color.r, ihdr.bit_depth)?.to_vec())
+        }
+        ColorType::RGB { .. } | ColorType::RGBA => {
+            let mut data = Vec::with_capacity(6);
+            for value in [color.r, color.g, color.b] {
+                data.extend_from_slice(&sample(value, ihdr.bit_depth)?);
+            }
+            Some(data)
+        }
+        ColorType::Indexed { palette } => {
+            let rgb = palette_color(color)?;
+            let index = palette
+                .iter()
+                .position(|entry| entry.r == rgb.r && entry.g == rgb.g && entry.b == rgb.b)?;
+            Some(vec![index as u8])
+        }
+    }
+}
+
+/// The color as an 8-bit palette entry, if it can be represented exactly
+fn palette_color(color: RGB16) -> Option<RGBA8> {
+    let channel = |value: u16| {
+        if value % 257 == 0 {
+            Some((value / 257) as u8)
+        } else {
+            None
+        }
+    };
+    Some(RGBA8::new(
+        channel(color.r)?,
+        channel(color.g)?,
+        channel(color.b)?,
+        255,
+    ))
+}
+
+/// Convert the data of a bKGD chunk to match a new format of the image
+///
+/// Returns `None` if the chunk was not valid for the original image, or if the background color
+/// can't be represented in the new format, in which case it should be dropped.
+#[must_use]
+pub fn updated_bkgd(data: &[u8], orig: &IhdrData, new: &IhdrData) -> Option<Vec<u8>> {
+    encode_bkgd(parse_bkgd(data, orig)?, new)
+}
+
+/// Add the background color of a bKGD chunk to the end of the palette of an indexed image,
+/// returning the new image and the bKGD data for it
+///
+/// Returns `None` if the image is not indexed, or if there is no room in the palette at its
+/// current bit depth.
+#[must_use]
+pub fn palette_with_bkgd(
+    png: &PngImage,
+    data: &[u8],
+    orig: &IhdrData,
+) -> Option<(PngImage, Vec<u8>)> {
+    let palette = match &png.ihdr.color_type {
+        ColorType::Indexed { palette } => palette,
+        _ => return None,
+    };
+    if palette.len() >= 1 << png.ihdr.bit_depth as u8 {
+        return None;
+    }
+    let entry = palette_color(parse_bkgd(data, orig)?)?;
+    let mut palette = palette.clone();
+    palette.push(entry);
+    let index = palette.len() - 1;
+    Some((
+        PngImage {
+            ihdr: IhdrData {
+                color_type: ColorType::Indexed { palette },
+                ..png.ihdr
+            },
+            data: png.data.clone(),
+        },
+        vec![index as u8],
+    ))
+}
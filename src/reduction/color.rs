@@ -47,8 +47,11 @@ pub fn reduced_to_indexed(png: &PngImage, allow_grayscale: bool) -> Option<PngIm
     let palette: Vec<_> = match png.ihdr.color_type {
         ColorType::Grayscale { transparent_shade } => {
             let pmap = build_palette(png.data.as_gray().iter().cloned(), &mut raw_data)?;
-            // Convert the Gray16 transparency to Gray8
-            let transparency_pixel = transparent_shade.map(|t| Gray::from(t as u8));
+            // Convert the Gray16 transparency to Gray8, where a shade which doesn't fit matches
+            // no pixels
+            let transparency_pixel = transparent_shade
+                .and_then(|t| u8::try_from(t).ok())
+                .map(Gray::from);
             pmap.into_iter()
                 .map(|px| {
                     RGB::from(px).alpha(if Some(px) != transparency_pixel {
@@ -61,8 +64,11 @@ pub fn reduced_to_indexed(png: &PngImage, allow_grayscale: bool) -> Option<PngIm
         }
         ColorType::RGB { transparent_color } => {
             let pmap = build_palette(png.data.as_rgb().iter().cloned(), &mut raw_data)?;
-            // Convert the RGB16 transparency to RGB8
-            let transparency_pixel = transparent_color.map(|t| t.map(|c| c as u8));
+            // Convert the RGB16 transparency to RGB8, where a color which doesn't fit matches no
+            // pixels
+            let transparency_pixel = transparent_color
+                .filter(|t| t.iter().all(|c| c <= 0xFF))
+                .map(|t| t.map(|c| c as u8));
             pmap.into_iter()
                 .map(|px| {
                     px.alpha(if Some(px) != transparency_pixel {
@@ -133,6 +139,68 @@ pub fn reduced_rgb_to_grayscale(png: &PngImage) -> Option<PngImage> {
     })
 }
 
+/// Attempt to convert RGB to grayscale where the channels of every pixel differ by no more than
+/// `tolerance`, collapsing each pixel to its luma value. This is lossy unless `tolerance` is 0.
+///
+/// For 16-bit images, the tolerance is scaled up to the 16-bit range.
+#[must_use]
+pub fn near_gray_rgb_to_grayscale(png: &PngImage, tolerance: u8) -> Option<PngImage> {
+    if tolerance == 0 {
+        return reduced_rgb_to_grayscale(png);
+    }
+    let transparent_color = match png.ihdr.color_type {
+        ColorType::RGB { transparent_color } => transparent_color,
+        ColorType::RGBA => None,
+        _ => return None,
+    };
+    // Collapsing colors could make opaque pixels match the transparent color
+    if transparent_color.is_some() {
+        return None;
+    }
+
+    let byte_depth = png.bytes_per_channel();
+    let tolerance = u32::from(tolerance) * if byte_depth == 1 { 1 } else { 257 };
+    let bpp = png.channels_per_pixel() * byte_depth;
+    let mut reduced = Vec::with_capacity(png.data.len());
+    for pixel in png.data.chunks(bpp) {
+        let channel = |i: usize| {
+            if byte_depth == 1 {
+                u32::from(pixel[i])
+            } else {
+                u32::from(u16::from_be_bytes([pixel[i * 2], pixel[i * 2 + 1]]))
+            }
+        };
+        let (r, g, b) = (channel(0), channel(1), channel(2));
+        if r.max(g).max(b) - r.min(g).min(b) > tolerance {
+            return None;
+        }
+        // Rec. 601 luma, which is exact when the channels are equal
+        let luma = (299 * r + 587 * g + 114 * b + 500) / 1000;
+        if byte_depth == 1 {
+            reduced.push(luma as u8);
+        } else {
+            reduced.extend_from_slice(&(luma as u16).to_be_bytes());
+        }
+        reduced.extend_from_slice(&pixel[3 * byte_depth..]);
+    }
+
+    let color_type = if png.ihdr.color_type.has_alpha() {
+        ColorType::GrayscaleAlpha
+    } else {
+        ColorType::Grayscale {
+            transparent_shade: None,
+        }
+    };
+
+    Some(PngImage {
+        data: reduced,
+        ihdr: IhdrData {
+            color_type,
+            ..png.ihdr
+        },
+    })
+}
+
 /// Attempt to convert indexed to a different color type, returning the resulting image if successful
 #[must_use]
 pub fn indexed_to_channels(png: &PngImage, allow_grayscale: bool) -> Option<PngImage> {
@@ -186,3 +254,66 @@ pub fn indexed_to_channels(png: &PngImage, allow_grayscale: bool) -> Option<PngI
         data,
     })
 }
+
+/// Attempt to convert indexed with a gray palette to grayscale, returning the resulting image if
+/// successful
+///
+/// Unlike `indexed_to_channels`, transparency is kept as a tRNS chunk, which is only possible if
+/// the transparent pixels all have the same shade, and no opaque pixel has it. Other alpha values
+/// aren't supported.
+#[must_use]
+pub fn indexed_to_grayscale(png: &PngImage) -> Option<PngImage> {
+    if png.ihdr.bit_depth != BitDepth::Eight {
+        return None;
+    }
+    let palette = match &png.ihdr.color_type {
+        ColorType::Indexed { palette } => palette,
+        _ => return None,
+    };
+
+    let mut used = [false; 256];
+    for &b in &png.data {
+        used[b as usize] = true;
+    }
+    // There are invalid files that use pixel indices beyond palette size
+    let black = RGBA::new(0, 0, 0, 255);
+    let colors = || {
+        used.iter()
+            .enumerate()
+            .filter(|&(_, &used)| used)
+            .map(|(i, _)| *palette.get(i).unwrap_or(&black))
+    };
+    if colors().any(|c| c.r != c.g || c.g != c.b || (c.a != 0 && c.a != 255)) {
+        return None;
+    }
+    let mut transparent_shade = None;
+    for c in colors().filter(|c| c.a == 0) {
+        if transparent_shade
+            .replace(c.r)
+            .map_or(false, |shade| shade != c.r)
+        {
+            return None;
+        }
+    }
+    if let Some(shade) = transparent_shade {
+        if colors().any(|c| c.a == 255 && c.r == shade) {
+            return None;
+        }
+    }
+
+    let data = png
+        .data
+        .iter()
+        .map(|&b| palette.get(b as usize).unwrap_or(&black).r)
+        .collect();
+
+    Some(PngImage {
+        ihdr: IhdrData {
+            color_type: ColorType::Grayscale {
+                transparent_shade: transparent_shade.map(u16::from),
+            },
+            ..png.ihdr
+        },
+        data,
+    })
+}
@@ -0,0 +1,196 @@
+//! Conversion of an image to a given color type and bit depth, for `Options::force_color_type`
+
+use crate::colors::{BitDepth, ColorType};
+use crate::headers::IhdrData;
+use crate::png::PngImage;
+use indexmap::IndexSet;
+use oxipng_core::bit_depth::{pack_line, unpack_line};
+use rgb::{RGB16, RGBA8};
+
+/// A pixel with each channel scaled to 16 bits
+pub type Rgba16 = [u16; 4];
+
+/// Convert the image to the given color type and bit depth, returning the converted image if
+/// every pixel can be represented exactly
+///
+/// The palette or transparent color of `color_type` is ignored, and worked out from the image
+/// instead. The combination must be valid in PNG.
+#[must_use]
+pub fn converted_image(
+    png: &PngImage,
+    color_type: &ColorType,
+    bit_depth: BitDepth,
+) -> Option<PngImage> {
+    let lines: Vec<Vec<Rgba16>> = png
+        .scan_lines(false)
+        .map(|line| decoded_line(&png.ihdr, line.data, line.num_pixels))
+        .collect();
+    let pixels = || lines.iter().flatten();
+
+    // Samples at lower bit depths are the 16-bit value divided by this, while the palette is
+    // always 8-bit
+    let is_indexed = matches!(color_type, ColorType::Indexed { .. });
+    let scale = if is_indexed {
+        257
+    } else {
+        (65535 / ((1u32 << bit_depth as u32) - 1)) as u16
+    };
+    if pixels().flatten().any(|&c| c % scale != 0) {
+        return None;
+    }
+    let is_gray = matches!(
+        color_type,
+        ColorType::Grayscale { .. } | ColorType::GrayscaleAlpha
+    );
+    if is_gray && pixels().any(|&[r, g, b, _]| r != g || g != b) {
+        return None;
+    }
+
+    // Without an alpha channel, all of the transparent pixels must have the same color, which no
+    // opaque pixel has
+    let needs_trns = matches!(
+        color_type,
+        ColorType::Grayscale { .. } | ColorType::RGB { .. }
+    );
+    let mut transparent = None;
+    if needs_trns {
+        if pixels().any(|&[.., a]| a != 0 && a != 65535) {
+            return None;
+        }
+        transparent = pixels().find(|&&[.., a]| a == 0).copied();
+        if let Some(trns) = transparent {
+            if pixels().any(|&[r, g, b, a]| ([r, g, b] == trns[..3]) != (a == 0)) {
+                return None;
+            }
+        }
+    }
+
+    let mut palette = IndexSet::new();
+    if is_indexed {
+        let max_colors = 1 << bit_depth as u32;
+        for &pixel in pixels() {
+            palette.insert(to_rgba8(pixel));
+            if palette.len() > max_colors {
+                return None;
+            }
+        }
+    }
+
+    let mut data = Vec::with_capacity(png.data.len());
+    let mut samples = Vec::new();
+    for line in &lines {
+        samples.clear();
+        for &pixel in line {
+            let [r, g, b, a] = pixel;
+            let channels: &[u16] = match color_type {
+                ColorType::Grayscale { .. } => &[r],
+                ColorType::RGB { .. } => &[r, g, b],
+                ColorType::Indexed { .. } => {
+                    samples.push(palette.get_index_of(&to_rgba8(pixel)).unwrap() as u16);
+                    continue;
+                }
+                ColorType::GrayscaleAlpha => &[r, a],
+                ColorType::RGBA => &[r, g, b, a],
+            };
+            samples.extend(channels.iter().map(|&c| c / scale));
+        }
+        match bit_depth {
+            BitDepth::Sixteen => data.extend(samples.iter().flat_map(|s| s.to_be_bytes())),
+            BitDepth::Eight => data.extend(samples.iter().map(|&s| s as u8)),
+            _ => {
+                let samples: Vec<u8> = samples.iter().map(|&s| s as u8).collect();
+                pack_line(&samples, bit_depth as u32, &mut data);
+            }
+        }
+    }
+
+    let trns = transparent.map(|[r, g, b, _]| RGB16::new(r / scale, g / scale, b / scale));
+    let color_type = match color_type {
+        ColorType::Grayscale { .. } => ColorType::Grayscale {
+            transparent_shade: trns.map(|c| c.r),
+        },
+        ColorType::RGB { .. } => ColorType::RGB {
+            transparent_color: trns,
+        },
+        ColorType::Indexed { .. } => ColorType::Indexed {
+            palette: palette.into_iter().collect(),
+        },
+        ColorType::GrayscaleAlpha => ColorType::GrayscaleAlpha,
+        ColorType::RGBA => ColorType::RGBA,
+    };
+    Some(PngImage {
+        ihdr: IhdrData {
+            color_type,
+            bit_depth,
+            ..png.ihdr
+        },
+        data,
+    })
+}
+
+/// Decode every pixel of a non-interlaced image to 16-bit RGBA, in order from the top left
+///
+/// Fully transparent pixels are all decoded as transparent black, since their color isn't seen.
+#[must_use]
+pub fn decoded_pixels(png: &PngImage) -> Vec<Rgba16> {
+    png.scan_lines(false)
+        .flat_map(|line| decoded_line(&png.ihdr, line.data, line.num_pixels))
+        .map(|pixel| if pixel[3] == 0 { [0; 4] } else { pixel })
+        .collect()
+}
+
+/// The 8-bit color of a pixel whose channels are all multiples of 257
+fn to_rgba8(pixel: Rgba16) -> RGBA8 {
+    let [r, g, b, a] = pixel.map(|c| (c / 257) as u8);
+    RGBA8::new(r, g, b, a)
+}
+
+/// Decode the pixels of an unfiltered line to 16-bit RGBA
+fn decoded_line(ihdr: &IhdrData, data: &[u8], num_pixels: usize) -> Vec<Rgba16> {
+    let bits = ihdr.bit_depth as u32;
+    let channels = ihdr.color_type.channels_per_pixel() as usize;
+    let samples: Vec<u16> = match ihdr.bit_depth {
+        BitDepth::Sixteen => data
+            .chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .collect(),
+        BitDepth::Eight => data.iter().map(|&b| u16::from(b)).collect(),
+        _ => {
+            let mut unpacked = Vec::with_capacity(num_pixels);
+            unpack_line(data, bits, num_pixels, false, &mut unpacked);
+            unpacked.into_iter().map(u16::from).collect()
+        }
+    };
+    // Samples are scaled up to 16 bits by this, which is the same as repeating their bits
+    let scale = (65535 / ((1u32 << bits) - 1)) as u16;
+
+    samples
+        .chunks_exact(channels)
+        .take(num_pixels)
+        .map(|px| match &ihdr.color_type {
+            ColorType::Grayscale { transparent_shade } => {
+                let a = if Some(px[0]) == *transparent_shade {
+                    0
+                } else {
+                    65535
+                };
+                let g = px[0] * scale;
+                [g, g, g, a]
+            }
+            ColorType::RGB { transparent_color } => {
+                let a = if Some(RGB16::new(px[0], px[1], px[2])) == *transparent_color {
+                    0
+                } else {
+                    65535
+                };
+                [px[0] * scale, px[1] * scale, px[2] * scale, a]
+            }
+            ColorType::Indexed { palette } => {
+                let color = palette.get(px[0] as usize).copied().unwrap_or_default();
+                [color.r, color.g, color.b, color.a].map(|c| u16::from(c) * 257)
+            }
+            ColorType::GrayscaleAlpha => [px[0], px[0], px[0], px[1]].map(|c| c * scale),
+            ColorType::RGBA => [px[0], px[1], px[2], px[3]].map(|c| c * scale),
+        })
+        .collect()
+}
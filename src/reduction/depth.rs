@@ -0,0 +1,33 @@
+//! Conversion of an image to a given bit depth, for `Options::force_bit_depth`
+
+use crate::colors::BitDepth;
+use crate::png::PngImage;
+use crate::reduction::bit_depth::{dithered_bit_depth_16_to_8, scaled_bit_depth_16_to_8};
+use crate::reduction::convert::converted_image;
+
+/// Convert the image to the given bit depth, keeping its color type, returning the converted
+/// image if every pixel can be represented exactly
+///
+/// A 16-bit image that can't be reduced to 8-bit exactly is scaled instead if `scale_16` is
+/// set, with dithering if `dither` is also set. The bit depth must be valid for the color type.
+#[must_use]
+pub fn forced_bit_depth(
+    png: &PngImage,
+    bit_depth: BitDepth,
+    scale_16: bool,
+    dither: bool,
+) -> Option<PngImage> {
+    if png.ihdr.bit_depth == bit_depth {
+        return Some(png.clone());
+    }
+    converted_image(png, &png.ihdr.color_type, bit_depth).or_else(|| {
+        if png.ihdr.bit_depth != BitDepth::Sixteen || bit_depth != BitDepth::Eight || !scale_16 {
+            return None;
+        }
+        if dither {
+            dithered_bit_depth_16_to_8(png)
+        } else {
+            scaled_bit_depth_16_to_8(png)
+        }
+    })
+}
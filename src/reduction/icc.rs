@@ -0,0 +1,136 @@
+//! Replacement of ICC profiles and gAMA/cHRM chunks which are known to describe sRGB
+
+use crate::deflate::crc32;
+use crate::headers::{Chunk, StripChunks};
+
+/// The gAMA value for sRGB, for decoders that don't support the sRGB chunk
+const SRGB_GAMMA: u32 = 45455;
+/// The cHRM values for sRGB: white point, then red, green and blue primaries
+const SRGB_CHROMATICITIES: [u32; 8] = [31270, 32900, 64000, 33000, 30000, 60000, 15000, 6000];
+/// How far a gAMA value may be from `SRGB_GAMMA` to be treated as sRGB, allowing for rounding
+/// by encoders which write 1/2.2 rather than the exact sRGB value
+const GAMMA_TOLERANCE: u32 = 100;
+/// How far each cHRM value may be from `SRGB_CHROMATICITIES` to be treated as sRGB
+const CHROMATICITY_TOLERANCE: u32 = 100;
+
+/// If the profile is sRGB, extracts the rendering intent value from it
+pub fn srgb_rendering_intent(icc_data: &[u8]) -> Option<u8> {
+    let rendering_intent = *icc_data.get(67)?;
+
+    // The known profiles are the same as in libpng's `png_sRGB_checks`.
+    // The Profile ID header of ICC has a fixed layout,
+    // and is supposed to contain MD5 of profile data at this offset
+    match icc_data.get(84..100)? {
+        b"\x29\xf8\x3d\xde\xaf\xf2\x55\xae\x78\x42\xfa\xe4\xca\x83\x39\x0d"
+        | b"\xc9\x5b\xd6\x37\xe9\x5d\x8a\x3b\x0d\xf3\x8f\x99\xc1\x32\x03\x89"
+        | b"\xfc\x66\x33\x78\x37\xe2\x88\x6b\xfd\x72\xe9\x83\x82\x28\xf1\xb8"
+        | b"\x34\x56\x2a\xbf\x99\x4c\xcd\x06\x6d\x2c\x57\x21\xd0\xd6\x8c\x5d" => {
+            Some(rendering_intent)
+        }
+        b"\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00" => {
+            // Known-bad profiles are identified by their CRC
+            match (crc32(icc_data), icc_data.len()) {
+                (0x5d51_29ce, 3024) | (0x182e_a552, 3144) | (0xf29e_526d, 3144) => {
+                    Some(rendering_intent)
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Whether the profile describes gray data, rather than RGB, or `None` if it is neither
+///
+/// This is the data color space field of the ICC header.
+pub fn icc_is_gray(icc_data: &[u8]) -> Option<bool> {
+    match icc_data.get(16..20)? {
+        b"GRAY" => Some(true),
+        b"RGB " => Some(false),
+        _ => None,
+    }
+}
+
+/// Replace the iCCP chunk at `index` with an sRGB chunk, if its profile is a known sRGB profile
+///
+/// gAMA and cHRM chunks describing sRGB are added after it for decoders which don't support sRGB,
+/// unless the image already has them or they would be stripped.
+/// Returns whether the chunk was replaced.
+pub(crate) fn replace_srgb_icc(
+    chunks: &mut Vec<Chunk>,
+    index: usize,
+    icc: &[u8],
+    strip: &StripChunks,
+) -> bool {
+    let intent = match srgb_rendering_intent(icc) {
+        Some(intent) => intent,
+        None => return false,
+    };
+    chunks[index] = Chunk {
+        name: *b"sRGB",
+        data: vec![intent],
+    };
+
+    let mut fallback = Vec::new();
+    let should_add = |name: &[u8; 4]| strip.keep(name) && !chunks.iter().any(|c| &c.name == name);
+    if should_add(b"gAMA") {
+        fallback.push(Chunk {
+            name: *b"gAMA",
+            data: SRGB_GAMMA.to_be_bytes().to_vec(),
+        });
+    }
+    if should_add(b"cHRM") {
+        fallback.push(Chunk {
+            name: *b"cHRM",
+            data: SRGB_CHROMATICITIES
+                .iter()
+                .flat_map(|v| v.to_be_bytes())
+                .collect(),
+        });
+    }
+    let after = index + 1;
+    chunks.splice(after..after, fallback);
+    true
+}
+
+/// Whether the data of a gAMA and a cHRM chunk together describe sRGB, within a small tolerance
+pub fn gamma_chrm_are_srgb(gama: &[u8], chrm: &[u8]) -> bool {
+    let value = |bytes: &[u8]| u32::from_be_bytes(bytes.try_into().unwrap());
+    let close = |value: u32, expected: u32, tolerance: u32| value.abs_diff(expected) <= tolerance;
+    gama.len() == 4
+        && chrm.len() == 32
+        && close(value(gama), SRGB_GAMMA, GAMMA_TOLERANCE)
+        && chrm
+            .chunks_exact(4)
+            .zip(SRGB_CHROMATICITIES)
+            .all(|(v, expected)| close(value(v), expected, CHROMATICITY_TOLERANCE))
+}
+
+/// Replace gAMA and cHRM chunks with a single sRGB chunk, if together they describe sRGB and
+/// there is no other color space information
+///
+/// The sRGB chunk takes the place of the first of the two, with the perceptual rendering intent.
+/// Returns whether the chunks were replaced.
+pub(crate) fn replace_srgb_gamma_chrm(chunks: &mut Vec<Chunk>) -> bool {
+    if chunks
+        .iter()
+        .any(|c| &c.name == b"sRGB" || &c.name == b"iCCP" || &c.name == b"cICP")
+    {
+        return false;
+    }
+    let gama = chunks.iter().position(|c| &c.name == b"gAMA");
+    let chrm = chunks.iter().position(|c| &c.name == b"cHRM");
+    let (gama, chrm) = match (gama, chrm) {
+        (Some(gama), Some(chrm)) => (gama, chrm),
+        _ => return false,
+    };
+    if !gamma_chrm_are_srgb(&chunks[gama].data, &chunks[chrm].data) {
+        return false;
+    }
+    chunks[gama.min(chrm)] = Chunk {
+        name: *b"sRGB",
+        data: vec![0],
+    };
+    chunks.remove(gama.max(chrm));
+    true
+}
@@ -1,17 +1,80 @@
+use crate::deflate::Deflaters;
 use crate::evaluate::Evaluator;
 use crate::png::PngImage;
+use crate::progress::ProgressEvent;
 use crate::Deadline;
 use crate::Options;
+use std::fmt;
 use std::sync::Arc;
 
 pub mod alpha;
 use crate::alpha::*;
 pub mod bit_depth;
+pub mod bkgd;
 use crate::bit_depth::*;
 pub mod color;
 use crate::color::*;
+pub mod convert;
+pub mod depth;
+pub mod icc;
 pub mod palette;
 use crate::palette::*;
+pub mod quantize;
+use crate::quantize::*;
+pub mod sbit;
+
+/// The kinds of reduction that may be performed on an image
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ReductionKind {
+    /// Reducing (or temporarily expanding) the bit depth of channels below 16 bits
+    BitDepth,
+    /// Converting between indexed and channel-based color types
+    ColorType,
+    /// Removing unused or duplicate palette entries, and sorting the palette
+    Palette,
+    /// Converting RGB to grayscale
+    Grayscale,
+    /// Removing or cleaning the alpha channel
+    Alpha,
+    /// Reducing 16-bit channels to 8-bit
+    BitDepth16To8,
+}
+
+/// A predicate deciding whether a kind of reduction may be attempted
+///
+/// It is asked before each reduction that the other options would attempt, so it can forbid
+/// reductions more finely than the options can.
+///
+/// ```
+/// use oxipng::{Options, ReductionFilter, ReductionKind};
+///
+/// // Keep the color type, but still allow the bit depth to be reduced
+/// let opts = Options {
+///     reduction_filter: Some(ReductionFilter::new(|kind| kind != ReductionKind::ColorType)),
+///     ..Options::default()
+/// };
+/// ```
+#[derive(Clone)]
+pub struct ReductionFilter(Arc<ReductionPredicate>);
+
+type ReductionPredicate = dyn Fn(ReductionKind) -> bool + Send + Sync;
+
+impl ReductionFilter {
+    pub fn new<F: Fn(ReductionKind) -> bool + Send + Sync + 'static>(f: F) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn allows(&self, kind: ReductionKind) -> bool {
+        (self.0)(kind)
+    }
+}
+
+impl fmt::Debug for ReductionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ReductionFilter")
+    }
+}
 
 pub(crate) fn perform_reductions(
     mut png: Arc<PngImage>,
@@ -20,6 +83,14 @@ pub(crate) fn perform_reductions(
     eval: &Evaluator,
 ) -> Arc<PngImage> {
     let mut evaluation_added = false;
+    let applied = |kind| opts.report_progress(ProgressEvent::ReductionApplied { kind });
+    let allowed = |kind| {
+        opts.reduction_filter
+            .as_ref()
+            .map_or(true, |filter| filter.allows(kind))
+    };
+    // Converting between indexed and channels may also convert to or from grayscale
+    let allow_grayscale = opts.grayscale_reduction && allowed(ReductionKind::Grayscale);
 
     // Interlacing must be processed first in order to evaluate the rest correctly
     if let Some(interlacing) = opts.interlace {
@@ -30,40 +101,70 @@ pub(crate) fn perform_reductions(
 
     // If alpha optimization is enabled, clean the alpha channel before continuing
     // This can allow some color type reductions which may not have been possible otherwise
-    if opts.optimize_alpha && !deadline.passed() {
-        if let Some(reduced) = cleaned_alpha_channel(&png) {
+    if opts.optimize_alpha && allowed(ReductionKind::Alpha) && !deadline.passed() {
+        let cleaned = if opts.optimize_alpha_aggressive {
+            aggressively_cleaned_alpha_channel(&png)
+        } else {
+            cleaned_alpha_channel(&png)
+        };
+        if let Some(reduced) = cleaned {
+            applied(ReductionKind::Alpha);
             png = Arc::new(reduced);
         }
     }
 
     // Attempt to reduce 16-bit to 8-bit
     // This is just removal of bytes and does not need to be evaluated
-    if opts.bit_depth_reduction && !deadline.passed() {
-        if let Some(reduced) = reduced_bit_depth_16_to_8(&png, opts.scale_16) {
+    if opts.bit_depth_reduction && allowed(ReductionKind::BitDepth16To8) && !deadline.passed() {
+        let reduced = if opts.scale_16 && opts.dither_16_to_8 {
+            reduced_bit_depth_16_to_8(&png, false).or_else(|| dithered_bit_depth_16_to_8(&png))
+        } else {
+            reduced_bit_depth_16_to_8(&png, opts.scale_16)
+        };
+        if let Some(reduced) = reduced {
+            applied(ReductionKind::BitDepth16To8);
             png = Arc::new(reduced);
         }
     }
 
+    // Quantize to a limited palette if requested
+    // This is lossy, so the result always replaces the image
+    if let Some(max_colors) = opts.max_palette_colors {
+        if allowed(ReductionKind::Palette) && !deadline.passed() {
+            if let Some(reduced) = quantized_palette(&png, max_colors) {
+                applied(ReductionKind::Palette);
+                png = Arc::new(reduced);
+            }
+        }
+    }
+
     // Attempt to reduce RGB to grayscale
     // This is just removal of bytes and does not need to be evaluated
-    if opts.color_type_reduction && opts.grayscale_reduction && !deadline.passed() {
-        if let Some(reduced) = reduced_rgb_to_grayscale(&png) {
+    if opts.color_type_reduction && allow_grayscale && !deadline.passed() {
+        let reduced = match opts.grayscale_tolerance {
+            Some(tolerance) => near_gray_rgb_to_grayscale(&png, tolerance),
+            None => reduced_rgb_to_grayscale(&png),
+        };
+        if let Some(reduced) = reduced {
+            applied(ReductionKind::Grayscale);
             png = Arc::new(reduced);
         }
     }
 
     // Attempt to expand the bit depth to 8
     // This does need to be evaluated but will be done so later when it gets reduced again
-    if opts.bit_depth_reduction && !deadline.passed() {
+    if opts.bit_depth_reduction && allowed(ReductionKind::BitDepth) && !deadline.passed() {
         if let Some(reduced) = expanded_bit_depth_to_8(&png) {
+            applied(ReductionKind::BitDepth);
             png = Arc::new(reduced);
         }
     }
 
     // Attempt to reduce the palette
     // This may change bytes but should always be beneficial
-    if opts.palette_reduction && !deadline.passed() {
+    if opts.palette_reduction && allowed(ReductionKind::Palette) && !deadline.passed() {
         if let Some(reduced) = reduced_palette(&png, opts.optimize_alpha) {
+            applied(ReductionKind::Palette);
             png = Arc::new(reduced);
         }
     }
@@ -73,8 +174,9 @@ pub(crate) fn perform_reductions(
     let mut baseline = png.clone();
 
     // Attempt alpha removal
-    if opts.color_type_reduction && !deadline.passed() {
+    if opts.color_type_reduction && allowed(ReductionKind::Alpha) && !deadline.passed() {
         if let Some(reduced) = reduced_alpha_channel(&png, opts.optimize_alpha) {
+            applied(ReductionKind::Alpha);
             png = Arc::new(reduced);
             // For small differences, if a tRNS chunk is required then enter this into the evaluator
             // Otherwise it is mostly just removal of bytes and should become the baseline
@@ -88,8 +190,15 @@ pub(crate) fn perform_reductions(
     }
 
     // Attempt to sort the palette
-    if opts.palette_reduction && !deadline.passed() {
-        if let Some(reduced) = sorted_palette(&png) {
+    if opts.palette_reduction && allowed(ReductionKind::Palette) && !deadline.passed() {
+        let reduced = if opts.optimize_palette_order {
+            // Trials use the same fast compression as the evaluator
+            optimized_palette_order(&png, Deflaters::Libdeflater { compression: 5 })
+        } else {
+            sorted_palette(&png)
+        };
+        if let Some(reduced) = reduced {
+            applied(ReductionKind::Palette);
             png = Arc::new(reduced);
             eval.try_image(png.clone());
             evaluation_added = true;
@@ -98,8 +207,20 @@ pub(crate) fn perform_reductions(
 
     // Attempt to convert from indexed to channels
     // This may give a better result due to dropping the PLTE chunk
-    if opts.color_type_reduction && !deadline.passed() {
-        if let Some(reduced) = indexed_to_channels(&png, opts.grayscale_reduction) {
+    if opts.color_type_reduction && allowed(ReductionKind::ColorType) && !deadline.passed() {
+        // A gray palette can become grayscale with a tRNS chunk, at the lowest bit depth it fits
+        let grayscale = allow_grayscale
+            .then(|| indexed_to_grayscale(&png))
+            .flatten()
+            .map(|reduced| {
+                if opts.bit_depth_reduction && allowed(ReductionKind::BitDepth) {
+                    reduced_bit_depth_8_or_less(&reduced).unwrap_or(reduced)
+                } else {
+                    reduced
+                }
+            });
+        if let Some(reduced) = grayscale.or_else(|| indexed_to_channels(&png, allow_grayscale)) {
+            applied(ReductionKind::ColorType);
             // This result should not be passed on to subsequent reductions
             eval.try_image(Arc::new(reduced));
             evaluation_added = true;
@@ -108,8 +229,9 @@ pub(crate) fn perform_reductions(
 
     // Attempt to reduce to indexed
     let mut indexed = None;
-    if opts.color_type_reduction && !deadline.passed() {
-        if let Some(reduced) = reduced_to_indexed(&png, opts.grayscale_reduction) {
+    if opts.color_type_reduction && allowed(ReductionKind::ColorType) && !deadline.passed() {
+        if let Some(reduced) = reduced_to_indexed(&png, allow_grayscale) {
+            applied(ReductionKind::ColorType);
             // Make sure the palette gets sorted (but don't bother evaluating both results)
             let new = Arc::new(sorted_palette(&reduced).unwrap_or(reduced));
             // For relatively small differences, enter this into the evaluator
@@ -125,12 +247,13 @@ pub(crate) fn perform_reductions(
     }
 
     // Attempt to reduce to a lower bit depth
-    if opts.bit_depth_reduction && !deadline.passed() {
+    if opts.bit_depth_reduction && allowed(ReductionKind::BitDepth) && !deadline.passed() {
         // Try reducing the previous png, falling back to the indexed one if it exists
         // This allows a grayscale depth reduction to be preferred over an indexed depth reduction
         let reduced = reduced_bit_depth_8_or_less(&png)
             .or_else(|| indexed.and_then(|png| reduced_bit_depth_8_or_less(&png)));
         if let Some(reduced) = reduced {
+            applied(ReductionKind::BitDepth);
             eval.try_image(Arc::new(reduced));
             evaluation_added = true;
         }
@@ -1,10 +1,42 @@
+use crate::atomicmin::AtomicMin;
 use crate::colors::{BitDepth, ColorType};
+use crate::deflate::Deflaters;
+use crate::error::PngWarning;
+use crate::filters::RowFilter;
 use crate::headers::IhdrData;
+use crate::interlace::Interlacing;
 use crate::png::PngImage;
+use crate::reduction::bit_depth::expanded_bit_depth_to_8;
 use indexmap::IndexSet;
 use rgb::RGBA8;
+use std::cmp::Reverse;
+
+/// Check whether the pixels use palette indices beyond the end of the palette, which are invalid
+/// but are read as opaque black by `reduced_palette`
+#[must_use]
+pub fn out_of_range_indices(png: &PngImage) -> Option<PngWarning> {
+    let palette = match &png.ihdr.color_type {
+        ColorType::Indexed { palette } => palette,
+        _ => return None,
+    };
+    let expanded = expanded_bit_depth_to_8(png);
+    let data = expanded.as_ref().map_or(&png.data, |png| &png.data);
+    let index = data.iter().copied().max()?;
+    if index as usize >= palette.len() {
+        Some(PngWarning::PaletteIndexOutOfRange {
+            palette: palette.len(),
+            index,
+        })
+    } else {
+        None
+    }
+}
 
 /// Attempt to reduce the number of colors in the palette, returning the reduced image if successful
+///
+/// Unused entries are dropped, and the pixels using duplicate entries are remapped to the first of
+/// them. Entries are only duplicates if their alpha matches as well as their color, except that
+/// fully transparent entries are all merged if `optimize_alpha` is set.
 #[must_use]
 pub fn reduced_palette(png: &PngImage, optimize_alpha: bool) -> Option<PngImage> {
     if png.ihdr.bit_depth != BitDepth::Eight {
@@ -69,44 +101,126 @@ fn add_color_to_set(mut color: RGBA8, set: &mut IndexSet<RGBA8>, optimize_alpha:
     idx as u8
 }
 
+/// Sort key for palette entries: ascending alpha and descending luma
+fn palette_sort_key(color: &RGBA8) -> i32 {
+    ((color.a as i32) << 18)
+    // These are coefficients for standard sRGB to luma conversion
+    - i32::from(color.r) * 299
+    - i32::from(color.g) * 587
+    - i32::from(color.b) * 114
+}
+
 /// Attempt to sort the colors in the palette, returning the sorted image if successful
 #[must_use]
 pub fn sorted_palette(png: &PngImage) -> Option<PngImage> {
-    if png.ihdr.bit_depth != BitDepth::Eight {
+    let palette = indexed_palette(png)?;
+    let mut order: Vec<_> = (0..palette.len()).collect();
+    order.sort_by_key(|&i| palette_sort_key(&palette[i]));
+    reordered_palette(png, &order)
+}
+
+/// Attempt to sort the colors in the palette by how often they are used, returning the sorted
+/// image if successful. Transparent entries are kept first to minimize the size of tRNS.
+#[must_use]
+pub fn sorted_palette_by_frequency(png: &PngImage) -> Option<PngImage> {
+    let palette = indexed_palette(png)?;
+    let mut counts = [0usize; 256];
+    for &byte in &png.data {
+        counts[byte as usize] += 1;
+    }
+    let mut order: Vec<_> = (0..palette.len()).collect();
+    order.sort_by_key(|&i| (palette[i].a == 255, Reverse(counts[i])));
+    reordered_palette(png, &order)
+}
+
+/// Attempt to order the colors in the palette by a greedy walk to the nearest remaining color,
+/// starting from the first color of the standard sort, returning the sorted image if successful
+#[must_use]
+pub fn sorted_palette_by_nearest_neighbor(png: &PngImage) -> Option<PngImage> {
+    let palette = indexed_palette(png)?;
+    let distance = |a: &RGBA8, b: &RGBA8| {
+        let d = |x: u8, y: u8| (i32::from(x) - i32::from(y)).pow(2);
+        d(a.r, b.r) + d(a.g, b.g) + d(a.b, b.b) + d(a.a, b.a)
+    };
+    let mut remaining: Vec<_> = (0..palette.len()).collect();
+    let start = (0..palette.len()).min_by_key(|&i| palette_sort_key(&palette[i]))?;
+    let mut order = Vec::with_capacity(palette.len());
+    let mut current = remaining.remove(start);
+    order.push(current);
+    while !remaining.is_empty() {
+        let (next, _) = remaining
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, &i)| distance(&palette[current], &palette[i]))?;
+        current = remaining.remove(next);
+        order.push(current);
+    }
+    reordered_palette(png, &order)
+}
+
+/// Try several orderings of the palette and return the one giving the smallest compressed data,
+/// using the given deflater for the trials. Returns `None` if no reordering is possible, or if
+/// none of them compresses better than the current order.
+#[must_use]
+pub fn optimized_palette_order(png: &PngImage, deflater: Deflaters) -> Option<PngImage> {
+    let candidates: Vec<_> = [
+        sorted_palette(png),
+        sorted_palette_by_frequency(png),
+        sorted_palette_by_nearest_neighbor(png),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    if candidates.is_empty() {
         return None;
     }
-    let palette = match &png.ihdr.color_type {
-        ColorType::Indexed { palette } => palette,
-        _ => return None,
+
+    let best_size = AtomicMin::new(None);
+    let trial_size = |image: &PngImage| {
+        // Palette images generally compress best without a filter
+        // Orderings that leave opaque entries before transparent ones need a longer tRNS
+        let filtered = image.filter_image(RowFilter::None, false);
+        let size = deflater.deflate(&filtered, &best_size).ok()?.len() + image.key_chunks_size();
+        best_size.set_min(size);
+        Some(size)
     };
+    // The current order is tried first, so that a reordering has to beat it
+    let original_size = trial_size(png)?;
+    candidates
+        .into_iter()
+        .filter_map(|candidate| Some((trial_size(&candidate)?, candidate)))
+        .filter(|(size, _)| *size < original_size)
+        // Ties go to the earlier candidate
+        .min_by_key(|(size, _)| *size)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Get the palette of an 8-bit indexed image
+fn indexed_palette(png: &PngImage) -> Option<&[RGBA8]> {
+    if png.ihdr.bit_depth != BitDepth::Eight {
+        return None;
+    }
+    match &png.ihdr.color_type {
+        ColorType::Indexed { palette } => Some(palette),
+        _ => None,
+    }
+}
 
-    let mut enumerated: Vec<_> = palette.iter().enumerate().collect();
-
-    // Sort the palette
-    enumerated.sort_by(|a, b| {
-        // Sort by ascending alpha and descending luma
-        let color_val = |color: &RGBA8| {
-            ((color.a as i32) << 18)
-            // These are coefficients for standard sRGB to luma conversion
-            - i32::from(color.r) * 299
-            - i32::from(color.g) * 587
-            - i32::from(color.b) * 114
-        };
-        color_val(a.1).cmp(&color_val(b.1))
-    });
-
-    // Extract the new palette and determine if anything changed
-    let (old_map, palette): (Vec<_>, Vec<RGBA8>) = enumerated.into_iter().unzip();
-    if old_map.iter().enumerate().all(|(a, b)| a == *b) {
+/// Rearrange the palette so that the entry at `order[i]` moves to index `i`,
+/// returning `None` if the order is unchanged
+fn reordered_palette(png: &PngImage, order: &[usize]) -> Option<PngImage> {
+    let palette = indexed_palette(png)?;
+    if order.iter().enumerate().all(|(a, b)| a == *b) {
         return None;
     }
 
     // Construct the new mapping and convert the data
     let mut byte_map = [0; 256];
-    for (i, &v) in old_map.iter().enumerate() {
+    for (i, &v) in order.iter().enumerate() {
         byte_map[v] = i as u8;
     }
     let data = png.data.iter().map(|&b| byte_map[b as usize]).collect();
+    let palette = order.iter().map(|&i| palette[i]).collect();
 
     Some(PngImage {
         ihdr: IhdrData {
@@ -116,3 +230,45 @@ pub fn sorted_palette(png: &PngImage) -> Option<PngImage> {
         data,
     })
 }
+
+/// The palette index of each pixel, in progressive order with one byte per pixel
+fn pixel_indices(png: &PngImage) -> Vec<u8> {
+    let progressive = png.change_interlacing(Interlacing::None);
+    let png = progressive.as_ref().unwrap_or(png);
+    expanded_bit_depth_to_8(png).map_or_else(|| png.data.clone(), |png| png.data)
+}
+
+/// Convert the data of an hIST chunk to match the new palette of the image, summing the
+/// frequencies of entries that were merged and giving added entries a frequency of zero
+///
+/// Each original entry is mapped to the new entry its pixels were converted to, so entries of the
+/// same color are only combined if the reduction merged them. An entry no pixel uses is mapped to
+/// the first new entry of its color, if there is one.
+///
+/// Returns `None` if the chunk was not valid for the original palette, or if the image is no
+/// longer indexed, in which case it should be dropped.
+#[must_use]
+pub fn updated_hist(data: &[u8], orig: &PngImage, new: &PngImage) -> Option<Vec<u8>> {
+    let (orig_palette, new_palette) = match (&orig.ihdr.color_type, &new.ihdr.color_type) {
+        (ColorType::Indexed { palette: orig }, ColorType::Indexed { palette: new }) => (orig, new),
+        _ => return None,
+    };
+    if data.len() != orig_palette.len() * 2 {
+        return None;
+    }
+
+    let mut index_map: [Option<u8>; 256] = [None; 256];
+    for (old, new) in pixel_indices(orig).into_iter().zip(pixel_indices(new)) {
+        index_map[old as usize].get_or_insert(new);
+    }
+    let mut frequencies = vec![0u16; new_palette.len()];
+    for (i, (color, pair)) in orig_palette.iter().zip(data.chunks(2)).enumerate() {
+        let new_index = index_map[i]
+            .map(usize::from)
+            .or_else(|| new_palette.iter().position(|entry| entry == color));
+        if let Some(sum) = new_index.and_then(|index| frequencies.get_mut(index)) {
+            *sum = sum.saturating_add(u16::from_be_bytes([pair[0], pair[1]]));
+        }
+    }
+    Some(frequencies.into_iter().flat_map(u16::to_be_bytes).collect())
+}
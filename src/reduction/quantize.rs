@@ -0,0 +1,184 @@
+//! Lossy reduction of an image to a limited number of colors, by median cut
+
+use crate::colors::{BitDepth, ColorType};
+use crate::headers::IhdrData;
+use crate::png::PngImage;
+use crate::reduction::bit_depth::{expanded_bit_depth_to_8, scaled_bit_depth_16_to_8};
+use indexmap::IndexMap;
+use rgb::RGBA8;
+use rustc_hash::FxHasher;
+use std::hash::BuildHasherDefault;
+
+type FxIndexMap<K, V> = IndexMap<K, V, BuildHasherDefault<FxHasher>>;
+
+/// Get the color of every pixel of an 8-bit image
+fn rgba_pixels(png: &PngImage) -> Vec<RGBA8> {
+    let data = &png.data;
+    match &png.ihdr.color_type {
+        ColorType::Grayscale { transparent_shade } => data
+            .iter()
+            .map(|&g| {
+                let a = if Some(u16::from(g)) == *transparent_shade {
+                    0
+                } else {
+                    255
+                };
+                RGBA8::new(g, g, g, a)
+            })
+            .collect(),
+        ColorType::RGB { transparent_color } => data
+            .chunks(3)
+            .map(|px| {
+                let color = rgb::RGB16::new(px[0].into(), px[1].into(), px[2].into());
+                let a = if Some(color) == *transparent_color {
+                    0
+                } else {
+                    255
+                };
+                RGBA8::new(px[0], px[1], px[2], a)
+            })
+            .collect(),
+        ColorType::Indexed { palette } => data
+            .iter()
+            .map(|&i| palette.get(i as usize).copied().unwrap_or_default())
+            .collect(),
+        ColorType::GrayscaleAlpha => data
+            .chunks(2)
+            .map(|px| RGBA8::new(px[0], px[0], px[0], px[1]))
+            .collect(),
+        ColorType::RGBA => data
+            .chunks(4)
+            .map(|px| RGBA8::new(px[0], px[1], px[2], px[3]))
+            .collect(),
+    }
+}
+
+fn channels(color: RGBA8) -> [u8; 4] {
+    [color.r, color.g, color.b, color.a]
+}
+
+/// A box of colors in the median cut, with the number of pixels of each color
+struct ColorBox {
+    colors: Vec<(RGBA8, usize)>,
+}
+
+impl ColorBox {
+    /// The channel with the widest range of values, and that range
+    fn widest_channel(&self) -> (usize, u8) {
+        (0..4)
+            .map(|c| {
+                let values = self.colors.iter().map(|&(color, _)| channels(color)[c]);
+                let min = values.clone().min().unwrap_or(0);
+                let max = values.max().unwrap_or(0);
+                (c, max - min)
+            })
+            .max_by_key(|&(c, range)| (range, std::cmp::Reverse(c)))
+            .unwrap_or((0, 0))
+    }
+
+    fn pixels(&self) -> usize {
+        self.colors.iter().map(|&(_, count)| count).sum()
+    }
+
+    /// Split the box at the pixel-weighted median of its widest channel
+    fn split(mut self) -> (Self, Self) {
+        let (channel, _) = self.widest_channel();
+        self.colors
+            .sort_by_key(|&(color, _)| (channels(color)[channel], channels(color)));
+        let half = self.pixels() / 2;
+        let mut seen = 0;
+        let mut at = self
+            .colors
+            .iter()
+            .position(|&(_, count)| {
+                seen += count;
+                seen > half
+            })
+            .unwrap_or(0);
+        // Both halves must have at least one color
+        at = at.clamp(1, self.colors.len() - 1);
+        let rest = self.colors.split_off(at);
+        (self, Self { colors: rest })
+    }
+
+    /// The pixel-weighted average color of the box
+    fn average(&self) -> RGBA8 {
+        let pixels = self.pixels().max(1);
+        let mut sums = [0usize; 4];
+        for &(color, count) in &self.colors {
+            for (sum, value) in sums.iter_mut().zip(channels(color)) {
+                *sum += value as usize * count;
+            }
+        }
+        let [r, g, b, a] = sums.map(|sum| ((sum + pixels / 2) / pixels) as u8);
+        RGBA8::new(r, g, b, a)
+    }
+}
+
+/// Reduce the image to an 8-bit indexed image of at most `max_colors` colors, returning `None` if
+/// it already has few enough colors
+///
+/// This is lossy. The colors are divided by median cut, repeatedly splitting the group of colors
+/// with the widest range of values, and each group is replaced by its average color.
+#[must_use]
+pub fn quantized_palette(png: &PngImage, max_colors: u16) -> Option<PngImage> {
+    let max_colors = max_colors.clamp(1, 256) as usize;
+    let png = match png.ihdr.bit_depth {
+        BitDepth::Sixteen => scaled_bit_depth_16_to_8(png)?,
+        BitDepth::Eight => png.clone(),
+        _ => expanded_bit_depth_to_8(png)?,
+    };
+    let pixels = rgba_pixels(&png);
+    let mut counts: FxIndexMap<RGBA8, usize> = FxIndexMap::default();
+    for &color in &pixels {
+        *counts.entry(color).or_insert(0) += 1;
+    }
+    if counts.len() <= max_colors {
+        return None;
+    }
+
+    let mut boxes = vec![ColorBox {
+        colors: counts
+            .iter()
+            .map(|(&color, &count)| (color, count))
+            .collect(),
+    }];
+    while boxes.len() < max_colors {
+        // Split the box with the widest range, weighted by how many pixels it covers
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.colors.len() > 1)
+            .max_by_key(|(i, b)| {
+                (
+                    b.widest_channel().1 as usize * b.pixels(),
+                    std::cmp::Reverse(*i),
+                )
+            })
+            .map(|(i, _)| i);
+        let (a, b) = match widest {
+            Some(widest) => boxes.swap_remove(widest).split(),
+            None => break,
+        };
+        boxes.push(a);
+        boxes.push(b);
+    }
+
+    let palette: Vec<RGBA8> = boxes.iter().map(ColorBox::average).collect();
+    let mut index_of: FxIndexMap<RGBA8, u8> = FxIndexMap::default();
+    for (i, b) in boxes.iter().enumerate() {
+        for &(color, _) in &b.colors {
+            index_of.insert(color, i as u8);
+        }
+    }
+    let data = pixels.iter().map(|color| index_of[color]).collect();
+
+    Some(PngImage {
+        data,
+        ihdr: IhdrData {
+            color_type: ColorType::Indexed { palette },
+            bit_depth: BitDepth::Eight,
+            ..png.ihdr
+        },
+    })
+}
@@ -0,0 +1,119 @@
+//! Handling of the sBIT chunk, which gives the number of significant bits in each channel
+
+use crate::colors::{BitDepth, ColorType};
+use crate::headers::IhdrData;
+use crate::png::PngImage;
+
+/// The greatest number of significant bits a channel can have in an image of this format
+fn sample_depth(ihdr: &IhdrData) -> u8 {
+    match ihdr.color_type {
+        // sBIT applies to the palette entries, which are always 8-bit
+        ColorType::Indexed { .. } => 8,
+        _ => ihdr.bit_depth as u8,
+    }
+}
+
+/// Parse the data of an sBIT chunk into significant bits for red, green, blue and alpha,
+/// or `None` if it is not valid for the image
+fn parse_sbit(data: &[u8], ihdr: &IhdrData) -> Option<([u8; 3], Option<u8>)> {
+    let depth = sample_depth(ihdr);
+    if data.iter().any(|&bits| bits == 0 || bits > depth) {
+        return None;
+    }
+    match (&ihdr.color_type, data) {
+        (ColorType::Grayscale { .. }, &[gray]) => Some(([gray; 3], None)),
+        (ColorType::GrayscaleAlpha, &[gray, alpha]) => Some(([gray; 3], Some(alpha))),
+        (ColorType::RGB { .. } | ColorType::Indexed { .. }, &[r, g, b]) => Some(([r, g, b], None)),
+        (ColorType::RGBA, &[r, g, b, alpha]) => Some(([r, g, b], Some(alpha))),
+        _ => None,
+    }
+}
+
+/// Convert the data of an sBIT chunk to match a new format of the image
+///
+/// Returns `None` if the chunk was not valid for the original image, in which case it should be
+/// dropped.
+#[must_use]
+pub fn updated_sbit(data: &[u8], orig: &IhdrData, new: &IhdrData) -> Option<Vec<u8>> {
+    let (rgb, alpha) = parse_sbit(data, orig)?;
+    let depth = sample_depth(new);
+    // A channel that had no significance recorded, such as alpha from tRNS, is fully significant
+    let alpha = alpha.unwrap_or(depth);
+    let gray = rgb.into_iter().max().unwrap_or(depth);
+    let sbit = match new.color_type {
+        ColorType::Grayscale { .. } => vec![gray],
+        ColorType::GrayscaleAlpha => vec![gray, alpha],
+        ColorType::RGB { .. } | ColorType::Indexed { .. } => rgb.to_vec(),
+        ColorType::RGBA => vec![rgb[0], rgb[1], rgb[2], alpha],
+    };
+    Some(sbit.into_iter().map(|bits| bits.min(depth)).collect())
+}
+
+/// Repeat the highest `from` bits of a value of `depth` bits to fill `to` bits
+fn replicate_bits(value: u16, depth: u32, from: u32, to: u32) -> u16 {
+    let significant = u32::from(value) >> (depth - from);
+    let mut result = 0;
+    let mut bits = 0;
+    while bits < to {
+        result = result << from | significant;
+        bits += from;
+    }
+    (result >> (bits - to)) as u16
+}
+
+/// Replace the bits an sBIT chunk marks as insignificant with copies of the significant bits,
+/// returning the new image if anything changed
+///
+/// The significant bits are first scaled to a whole power of two bits, so that a following bit
+/// depth reduction can take effect: for example an 8-bit grayscale image with 4 significant bits
+/// becomes reducible to 4-bit, and a 16-bit image with 8 significant bits to 8-bit.
+/// Images with a tRNS chunk are not changed, as the transparent color could become ambiguous.
+#[must_use]
+pub fn significant_bits_replicated(png: &PngImage, sbit: &[u8]) -> Option<PngImage> {
+    let depth = match png.ihdr.bit_depth {
+        BitDepth::Eight => 8,
+        BitDepth::Sixteen => 16,
+        // Indexed images don't store the samples directly, and lower depths can't go lower
+        _ => return None,
+    };
+    if matches!(png.ihdr.color_type, ColorType::Indexed { .. }) || png.ihdr.color_type.has_trns() {
+        return None;
+    }
+    // Check the chunk is valid for this image
+    parse_sbit(sbit, &png.ihdr)?;
+    if sbit.iter().all(|&bits| u32::from(bits) == depth) {
+        return None;
+    }
+
+    let scale = |value: u16, bits: u8| {
+        let bits = u32::from(bits);
+        let width = bits.next_power_of_two().min(depth);
+        let scaled = replicate_bits(value, depth, bits, width);
+        replicate_bits(scaled, width, width, depth)
+    };
+    let channels = sbit.len();
+    let data: Vec<u8> = if depth == 8 {
+        png.data
+            .iter()
+            .enumerate()
+            .map(|(i, &value)| scale(value.into(), sbit[i % channels]) as u8)
+            .collect()
+    } else {
+        png.data
+            .chunks(2)
+            .enumerate()
+            .flat_map(|(i, pair)| {
+                let value = u16::from_be_bytes([pair[0], pair[1]]);
+                scale(value, sbit[i % channels]).to_be_bytes()
+            })
+            .collect()
+    };
+    if data == png.data {
+        return None;
+    }
+
+    Some(PngImage {
+        data,
+        ihdr: png.ihdr.clone(),
+    })
+}
@@ -0,0 +1,220 @@
+use crate::colors::{BitDepth, ColorType};
+use crate::deflate::Deflaters;
+use crate::error::PngWarning;
+use crate::filters::RowFilter;
+use crate::headers::IhdrData;
+use crate::interlace::Interlacing;
+use crate::reduction::ReductionKind;
+#[cfg(feature = "json")]
+use crate::InFile;
+use std::time::Duration;
+
+/// The version of the schema produced by `OptimizationReport::to_json`.
+/// This is increased whenever a field is changed or removed, but not when one is added.
+#[cfg(feature = "json")]
+pub const JSON_LOG_VERSION: u32 = 1;
+
+/// A summary of what was done to an image during optimization
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct OptimizationReport {
+    /// Size of the input file in bytes
+    pub original_size: usize,
+    /// Size of the output file in bytes
+    pub final_size: usize,
+    /// The filter used to encode the output image data
+    ///
+    /// `None` if the original image data was kept
+    pub filter: Option<RowFilter>,
+    /// The color type of the output image
+    pub color_type: ColorType,
+    /// The bit depth of the output image
+    pub bit_depth: BitDepth,
+    /// The kinds of reduction that were applied to the output image
+    pub reductions: Vec<ReductionKind>,
+    /// The DEFLATE algorithm used for the output
+    pub deflate: Deflaters,
+    /// The interlacing mode of the output image
+    pub interlacing: Interlacing,
+    /// Whether the interlacing mode of the output differs from the input
+    pub interlacing_changed: bool,
+    /// Whether the image data was found to be already optimal by `Options::check_only_fast`,
+    /// so that only the other chunks were processed
+    pub already_optimal: bool,
+    /// Whether the optimized output was no smaller than the input, including any changes to
+    /// other chunks, so that the original data was returned unchanged instead
+    pub no_improvement: bool,
+    /// The filter type chosen for each scanline of the output and the score the heuristic gave
+    /// it, with the lines of each interlacing pass in turn
+    ///
+    /// Only recorded if `Options::collect_filter_stats` is set, and the image data was recoded.
+    /// Scores are only comparable within a heuristic, and are 0 for the standard filters.
+    pub filter_stats: Option<Vec<(RowFilter, u64)>>,
+    /// Whether the output is no larger than `Options::target_size`
+    ///
+    /// `None` if no target was given. When the target wasn't met, optimization ran with full
+    /// effort.
+    pub target_size_met: Option<bool>,
+    /// The departures from the PNG specification found in the input, which were tolerated or
+    /// repaired
+    ///
+    /// Only recorded if `Options::collect_warnings` is set.
+    pub warnings: Vec<PngWarning>,
+    /// The time spent in each stage of the optimization
+    ///
+    /// Only recorded if `Options::collect_timings` is set.
+    pub timings: Option<StageTimings>,
+}
+
+/// The time spent in each stage of the optimization, from `Options::collect_timings`
+///
+/// A stage that runs more than once, such as for each interlacing mode with
+/// `Options::interlace_auto`, has the sum of its runs. Work outside of these stages, such as
+/// checking `Options::check_only_fast`, isn't counted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StageTimings {
+    /// Decoding the input file
+    pub parse: Duration,
+    /// Trying the reductions of the image, including compressing each to compare them
+    pub reduction: Duration,
+    /// Comparing filters with fast compression, if `Options::fast_evaluation` is set
+    pub filter_evaluation: Duration,
+    /// The main compression of the image data, of each filter if they weren't evaluated first
+    pub deflate: Duration,
+    /// Processing the other chunks and writing the output
+    pub write: Duration,
+}
+
+impl OptimizationReport {
+    /// Create a report for an image that was left unchanged
+    pub(crate) fn unchanged(original_size: usize, ihdr: &IhdrData, deflate: Deflaters) -> Self {
+        Self {
+            original_size,
+            final_size: original_size,
+            filter: None,
+            color_type: ihdr.color_type.clone(),
+            bit_depth: ihdr.bit_depth,
+            reductions: Vec::new(),
+            deflate,
+            interlacing: ihdr.interlaced,
+            interlacing_changed: false,
+            already_optimal: false,
+            no_improvement: false,
+            filter_stats: None,
+            target_size_met: None,
+            warnings: Vec::new(),
+            timings: None,
+        }
+    }
+
+    /// Record the format of the output image and which reductions led to it
+    pub(crate) fn set_output(&mut self, final_size: usize, orig: &IhdrData, new: &IhdrData) {
+        self.final_size = final_size;
+        self.color_type = new.color_type.clone();
+        self.bit_depth = new.bit_depth;
+        self.reductions = reductions_between(orig, new);
+        self.interlacing = new.interlaced;
+        self.interlacing_changed = orig.interlaced != new.interlaced;
+    }
+}
+
+/// One entry of the JSON log, with a stable layout independent of the report's own fields
+#[cfg(feature = "json")]
+#[derive(serde::Serialize)]
+struct JsonLogEntry<'a> {
+    version: u32,
+    input: String,
+    original_size: usize,
+    final_size: usize,
+    /// The name of the filter, or null if the original image data was kept
+    filter: Option<String>,
+    /// The color type code from the PNG specification
+    color_type: u8,
+    bit_depth: u8,
+    /// The number of palette entries, for indexed images
+    palette_size: Option<usize>,
+    interlaced: bool,
+    reductions: &'a [ReductionKind],
+    deflate: String,
+    already_optimal: bool,
+    elapsed_ms: u64,
+}
+
+#[cfg(feature = "json")]
+impl OptimizationReport {
+    /// Serialize the report as a single line of JSON, for build systems to consume.
+    ///
+    /// The layout is versioned by `JSON_LOG_VERSION`, which is included as the `version` field,
+    /// along with the given input and the time it took to optimize.
+    pub fn to_json(&self, input: &InFile, elapsed: Duration) -> String {
+        let entry = JsonLogEntry {
+            version: JSON_LOG_VERSION,
+            input: input.to_string(),
+            original_size: self.original_size,
+            final_size: self.final_size,
+            filter: self.filter.map(|f| f.to_string()),
+            color_type: self.color_type.png_header_code(),
+            bit_depth: self.bit_depth as u8,
+            palette_size: match &self.color_type {
+                ColorType::Indexed { palette } => Some(palette.len()),
+                _ => None,
+            },
+            interlaced: self.interlacing == Interlacing::Adam7,
+            reductions: &self.reductions,
+            deflate: self.deflate.to_string(),
+            already_optimal: self.already_optimal,
+            elapsed_ms: elapsed.as_millis() as u64,
+        };
+        // Serializing a struct of plain values to a string cannot fail
+        serde_json::to_string(&entry).unwrap()
+    }
+}
+
+/// Work out which reductions must have been applied to get from one image format to another
+fn reductions_between(orig: &IhdrData, new: &IhdrData) -> Vec<ReductionKind> {
+    let mut reductions = Vec::new();
+    let was_indexed = matches!(orig.color_type, ColorType::Indexed { .. });
+    let is_indexed = matches!(new.color_type, ColorType::Indexed { .. });
+    if orig.bit_depth == BitDepth::Sixteen && new.bit_depth != BitDepth::Sixteen {
+        reductions.push(ReductionKind::BitDepth16To8);
+    }
+    if new.bit_depth < BitDepth::Eight && new.bit_depth != orig.bit_depth {
+        reductions.push(ReductionKind::BitDepth);
+    }
+    if was_indexed != is_indexed {
+        reductions.push(ReductionKind::ColorType);
+    }
+    if orig.color_type.is_rgb() && new.color_type.is_gray() {
+        reductions.push(ReductionKind::Grayscale);
+    }
+    if orig.color_type.has_alpha() && !new.color_type.has_alpha() {
+        reductions.push(ReductionKind::Alpha);
+    }
+    if was_indexed && is_indexed && orig.color_type != new.color_type {
+        reductions.push(ReductionKind::Palette);
+    }
+    reductions
+}
+
+/// The outcome of checking whether a file could be optimized, as returned by `check`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CheckResult {
+    /// Size of the input file in bytes
+    pub original_size: usize,
+    /// Size the file would have after optimization, in bytes
+    pub optimized_size: usize,
+}
+
+impl CheckResult {
+    /// Whether optimizing would make the file smaller
+    pub fn would_shrink(&self) -> bool {
+        self.optimized_size < self.original_size
+    }
+
+    /// The number of bytes optimizing would save
+    pub fn savings(&self) -> usize {
+        self.original_size.saturating_sub(self.optimized_size)
+    }
+}
@@ -0,0 +1,353 @@
+//! Strict checking of the structure of PNG files, following the rules of the specification
+//! rather than the leniency used when reading files to optimize.
+
+use crate::colors::ColorType;
+use crate::deflate::{crc32, inflate};
+use crate::error::{PngError, PngWarning};
+use crate::headers::*;
+use crate::interlace::Interlacing;
+use crate::png::{signature_offset, unsupported_format, PngData, PngImage};
+use crate::reduction::convert::decoded_pixels;
+use crate::{Options, PngResult};
+use indexmap::IndexSet;
+
+/// Chunks which must come before PLTE, if there is one, as well as before IDAT
+const BEFORE_PLTE: [&[u8; 4]; 8] = [
+    b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB", b"cICP", b"mDCV", b"cLLI",
+];
+/// Chunks which must come after PLTE, if there is one, but before IDAT
+const AFTER_PLTE: [&[u8; 4]; 3] = [b"bKGD", b"hIST", b"tRNS"];
+/// Other chunks which must come before IDAT
+const BEFORE_IDAT: [&[u8; 4]; 6] = [b"pHYs", b"sPLT", b"oFFs", b"sCAL", b"acTL", b"eXIf"];
+/// Chunks which may not appear more than once
+const UNIQUE: [&[u8; 4]; 19] = [
+    b"IHDR", b"PLTE", b"tRNS", b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB", b"cICP", b"mDCV",
+    b"cLLI", b"bKGD", b"hIST", b"pHYs", b"oFFs", b"sCAL", b"acTL", b"eXIf", b"tIME",
+];
+
+/// Decode two PNG files and check whether their images have the same pixels, whatever their
+/// color types, bit depths, interlacing and compression.
+///
+/// Every pixel is expanded to 16-bit RGBA before comparing, so an indexed image is equal to the
+/// same image stored as RGB. Fully transparent pixels are equal whatever their color, as oxipng
+/// may change it. Only the default image is compared, not the other frames of an APNG. Returns
+/// an error if either file can't be decoded.
+pub fn pixels_equal(a: &[u8], b: &[u8]) -> PngResult<bool> {
+    let opts = Options::default();
+    let a = PngData::from_slice(a, &opts)?;
+    let b = PngData::from_slice(b, &opts)?;
+    if a.raw.ihdr.width != b.raw.ihdr.width || a.raw.ihdr.height != b.raw.ihdr.height {
+        return Ok(false);
+    }
+    let pixels = |png: &PngImage| match png.change_interlacing(Interlacing::None) {
+        Some(deinterlaced) => decoded_pixels(&deinterlaced),
+        None => decoded_pixels(png),
+    };
+    Ok(pixels(&a.raw) == pixels(&b.raw))
+}
+
+/// Check that the data is a PNG file which conforms to the structure required by the
+/// specification, returning the first problem found.
+///
+/// This checks the signature, the name, CRC and position of every chunk, the values of the IHDR
+/// chunk, the lengths of the PLTE and tRNS chunks for the color type, the headers of the acTL
+/// chunk, and that the image data inflates to exactly the size given by the header with valid
+/// filter types. Unlike reading files to optimize, nothing is ignored or repaired.
+pub fn validate_png(data: &[u8]) -> PngResult<()> {
+    let header = data.get(..8).ok_or(PngError::NotPNG)?;
+    if !file_header_is_valid(header) {
+        return Err(unsupported_format(header).unwrap_or(PngError::NotPNG));
+    }
+    let mut byte_offset = 8;
+    let mut seen: IndexSet<[u8; 4]> = IndexSet::new();
+    let mut ihdr_data = None;
+    let mut palette_data = None;
+    let mut trns_data = None;
+    let mut idat_data = Vec::new();
+    let mut indexed = false;
+    let mut actl = None;
+    let mut num_fctl = 0;
+    while let Some(chunk) = parse_next_chunk(data, &mut byte_offset, false)? {
+        let name = chunk.name;
+        let valid_name = name.iter().all(u8::is_ascii_alphabetic) && name[2].is_ascii_uppercase();
+        if !valid_name {
+            return Err(PngError::InvalidChunk(name, "the chunk name is not valid"));
+        }
+        if is_out_of_order(&name, &seen, indexed) {
+            return Err(PngError::ChunkOutOfOrder(name));
+        }
+        if seen.contains(&name) && UNIQUE.contains(&&name) {
+            return Err(PngError::InvalidChunk(
+                name,
+                "the chunk may only appear once",
+            ));
+        }
+        // Move the name to the end, so that the last chunk seen can be checked
+        seen.shift_remove(&name);
+        seen.insert(name);
+
+        match &name {
+            b"IHDR" => {
+                indexed = chunk.data.get(9) == Some(&3);
+                ihdr_data = Some(chunk.data);
+            }
+            b"PLTE" => palette_data = Some(chunk.data),
+            b"tRNS" => trns_data = Some(chunk.data),
+            b"IDAT" => idat_data.extend_from_slice(chunk.data),
+            b"acTL" => actl = Some(parse_actl_chunk(chunk.data)?),
+            b"fcTL" => num_fctl += 1,
+            _ => validate_chunk(&name, chunk.data)?,
+        }
+    }
+
+    // The position is left at the name of the IEND chunk
+    let iend = data
+        .get(byte_offset - 4..byte_offset + 8)
+        .ok_or(PngError::TruncatedData)?;
+    if iend[..4] != [0; 4] {
+        return Err(PngError::InvalidChunk(*b"IEND", "the chunk must be empty"));
+    }
+    if iend[8..] != crc32(b"IEND").to_be_bytes() {
+        return Err(PngError::CRCMismatch(*b"IEND"));
+    }
+    if data.len() > byte_offset + 8 {
+        return Err(PngError::InvalidChunk(
+            *b"IEND",
+            "there is data after the chunk",
+        ));
+    }
+
+    if let Some(actl) = actl {
+        check_actl(&actl, num_fctl)?;
+    }
+
+    let ihdr_data = ihdr_data.ok_or(PngError::ChunkMissing("IHDR"))?;
+    validate_ihdr(ihdr_data)?;
+    let ihdr = parse_ihdr_chunk(
+        ihdr_data,
+        palette_data.map(<[u8]>::to_vec),
+        trns_data.map(<[u8]>::to_vec),
+    )?;
+    validate_palette(&ihdr, palette_data, trns_data)?;
+
+    if idat_data.is_empty() {
+        return Err(PngError::ChunkMissing("IDAT"));
+    }
+    let expected = ihdr.raw_data_size();
+    // One extra byte of space is enough to detect data that inflates too long
+    let raw_data = match inflate(&idat_data, expected + 1) {
+        Ok(raw_data) if raw_data.len() == expected => raw_data,
+        Ok(raw_data) if raw_data.len() < expected => {
+            return Err(PngError::IncorrectDataLength(raw_data.len(), expected))
+        }
+        Err(PngError::InvalidData) => return Err(PngError::InvalidData),
+        _ => {
+            return Err(PngError::InvalidChunk(
+                *b"IDAT",
+                "the image data is longer than the header allows",
+            ))
+        }
+    };
+    // This rejects unknown filter types
+    PngImage {
+        ihdr,
+        data: raw_data,
+    }
+    .unfilter_image()?;
+    Ok(())
+}
+
+/// Find the departures from the specification in the structure of a PNG file that reading it
+/// for optimization tolerates or repairs
+///
+/// Unlike `validate_png`, every problem found is reported rather than only the first, but the
+/// checks stop at the first problem that reading the file would not tolerate either.
+///
+/// Ancillary chunks larger than `max_chunk_size` are also reported.
+pub(crate) fn lint_png(data: &[u8], max_chunk_size: Option<usize>) -> Vec<PngWarning> {
+    let mut warnings = Vec::new();
+    let leading = signature_offset(data, true).unwrap_or(0);
+    if leading > 0 {
+        warnings.push(PngWarning::LeadingData(leading));
+    }
+    let mut byte_offset = leading + 8;
+    let mut seen: IndexSet<[u8; 4]> = IndexSet::new();
+    let mut indexed = false;
+    let mut palette_len = None;
+    let mut trns_len = None;
+    let mut actl = None;
+    let mut num_fctl = 0;
+    loop {
+        let chunk_start = byte_offset + 4;
+        let chunk = match parse_next_chunk(data, &mut byte_offset, true) {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(_) => return warnings,
+        };
+        let name = chunk.name;
+        let stored_crc = &data[byte_offset - 4..byte_offset];
+        if crc32(&data[chunk_start..byte_offset - 4]).to_be_bytes() != stored_crc {
+            warnings.push(PngWarning::CRCMismatch(name));
+        }
+        if is_out_of_order(&name, &seen, indexed) {
+            warnings.push(PngWarning::ChunkOutOfOrder(name));
+        }
+        if seen.contains(&name) && UNIQUE.contains(&&name) {
+            warnings.push(PngWarning::DuplicateChunk(name));
+        }
+        let is_ancillary = !is_critical(&name) && &name != b"fdAT";
+        if is_ancillary && max_chunk_size.map_or(false, |max| chunk.data.len() > max) {
+            warnings.push(PngWarning::OversizedChunk(name, chunk.data.len()));
+        }
+        seen.shift_remove(&name);
+        seen.insert(name);
+
+        match &name {
+            b"IHDR" => indexed = chunk.data.get(9) == Some(&3),
+            b"PLTE" => palette_len = Some(chunk.data.len() / 3),
+            b"tRNS" => trns_len = Some(chunk.data.len()),
+            b"IDAT" => (),
+            b"acTL" => actl = Some(parse_actl_chunk(chunk.data)),
+            b"fcTL" => num_fctl += 1,
+            _ => {
+                if let Err(PngError::InvalidChunk(name, s)) = validate_chunk(&name, chunk.data) {
+                    warnings.push(PngWarning::InvalidChunk(name, s));
+                }
+            }
+        }
+    }
+
+    if let Some(actl) = actl {
+        if let Err(PngError::InvalidChunk(name, s)) = actl.and_then(|c| check_actl(&c, num_fctl)) {
+            warnings.push(PngWarning::InvalidChunk(name, s));
+        }
+    }
+    if let (true, Some(palette), Some(transparency)) = (indexed, palette_len, trns_len) {
+        if transparency > palette {
+            warnings.push(PngWarning::TransparencyTooLong {
+                palette,
+                transparency,
+            });
+        }
+    }
+    let overrides_gamma = [b"gAMA", b"sRGB", b"iCCP", b"cICP"];
+    if seen.contains(b"cHRM") && !overrides_gamma.iter().any(|&name| seen.contains(name)) {
+        warnings.push(PngWarning::MissingGamma);
+    }
+    // The position is left at the name of the IEND chunk, which is followed by its CRC
+    let trailing = data.len().saturating_sub(byte_offset + 8);
+    if trailing > 0 {
+        warnings.push(PngWarning::TrailingData(trailing));
+    }
+    warnings
+}
+
+/// Check whether a chunk is in a position the specification does not allow, given the names of
+/// the chunks before it with the most recent last
+fn is_out_of_order(name: &[u8; 4], seen: &IndexSet<[u8; 4]>, indexed: bool) -> bool {
+    let seen_idat = seen.contains(b"IDAT");
+    let seen_plte = seen.contains(b"PLTE");
+    match name {
+        b"IHDR" => !seen.is_empty(),
+        _ if seen.is_empty() => true,
+        b"PLTE" => seen_idat,
+        // Image data must be in consecutive chunks
+        b"IDAT" => seen_idat && seen.last() != Some(b"IDAT"),
+        _ if BEFORE_PLTE.contains(&name) => seen_plte || seen_idat,
+        // In indexed images, these refer to palette entries
+        _ if AFTER_PLTE.contains(&name) => seen_idat || (indexed && !seen_plte),
+        _ if BEFORE_IDAT.contains(&name) => seen_idat,
+        _ => false,
+    }
+}
+
+/// Check the fields of the IHDR chunk against the allowed values
+fn validate_ihdr(data: &[u8]) -> PngResult<()> {
+    if data.len() != 13 {
+        return Err(PngError::InvalidHeader("the chunk must be 13 bytes long"));
+    }
+    let width = u32::from_be_bytes(data[0..4].try_into().unwrap());
+    let height = u32::from_be_bytes(data[4..8].try_into().unwrap());
+    if width == 0 || height == 0 || width > i32::MAX as u32 || height > i32::MAX as u32 {
+        return Err(PngError::InvalidHeader("the dimensions are out of range"));
+    }
+    let valid_depths: &[u8] = match data[9] {
+        0 => &[1, 2, 4, 8, 16],
+        3 => &[1, 2, 4, 8],
+        2 | 4 | 6 => &[8, 16],
+        _ => return Err(PngError::InvalidHeader("the color type is not valid")),
+    };
+    if !valid_depths.contains(&data[8]) {
+        return Err(PngError::InvalidHeader(
+            "the bit depth is not valid for the color type",
+        ));
+    }
+    if data[10] != 0 || data[11] != 0 {
+        return Err(PngError::InvalidHeader(
+            "the compression or filter method is not valid",
+        ));
+    }
+    if data[12] > 1 {
+        return Err(PngError::InvalidHeader("the interlace method is not valid"));
+    }
+    Ok(())
+}
+
+/// Check the presence and length of the PLTE and tRNS chunks against the color type
+fn validate_palette(
+    ihdr: &IhdrData,
+    palette_data: Option<&[u8]>,
+    trns_data: Option<&[u8]>,
+) -> PngResult<()> {
+    let palette_len = match palette_data {
+        Some(palette) => {
+            let entries = palette.len() / 3;
+            if palette.len() % 3 != 0 || entries == 0 || entries > 256 {
+                return Err(PngError::InvalidChunk(
+                    *b"PLTE",
+                    "the length must be a multiple of 3, with 1 to 256 entries",
+                ));
+            }
+            Some(entries)
+        }
+        None => None,
+    };
+    let trns_len = trns_data.map(<[u8]>::len);
+    match ihdr.color_type {
+        ColorType::Indexed { .. } => {
+            let palette_len = palette_len.ok_or(PngError::ChunkMissing("PLTE"))?;
+            if palette_len > 1 << ihdr.bit_depth as u8 {
+                return Err(PngError::InvalidChunk(
+                    *b"PLTE",
+                    "there are more entries than the bit depth allows",
+                ));
+            }
+            if trns_len.map_or(false, |len| len > palette_len) {
+                return Err(PngError::InvalidChunk(
+                    *b"tRNS",
+                    "there are more entries than in the palette",
+                ));
+            }
+        }
+        ColorType::Grayscale { .. } | ColorType::GrayscaleAlpha if palette_len.is_some() => {
+            return Err(PngError::InvalidChunk(
+                *b"PLTE",
+                "the chunk is not allowed for grayscale images",
+            ));
+        }
+        ColorType::Grayscale { .. } if trns_len.map_or(false, |len| len != 2) => {
+            return Err(PngError::InvalidChunk(*b"tRNS", "the length must be 2"));
+        }
+        ColorType::RGB { .. } if trns_len.map_or(false, |len| len != 6) => {
+            return Err(PngError::InvalidChunk(*b"tRNS", "the length must be 6"));
+        }
+        ColorType::GrayscaleAlpha | ColorType::RGBA if trns_len.is_some() => {
+            return Err(PngError::InvalidChunk(
+                *b"tRNS",
+                "the chunk is not allowed for images with an alpha channel",
+            ));
+        }
+        _ => {}
+    }
+    Ok(())
+}
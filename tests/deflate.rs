@@ -0,0 +1,247 @@
+#![cfg(feature = "zopfli")]
+
+use oxipng::internal_tests::*;
+use oxipng::PngError;
+use std::io::Write;
+use std::num::NonZeroU8;
+
+#[test]
+fn zopfli_raw_matches_zlib_body() {
+    let data: Vec<u8> = (0..4096u32).map(|i| (i * i % 251) as u8).collect();
+    let iterations = NonZeroU8::new(5).unwrap();
+
+    let zlib = zopfli_deflate(&data, iterations).unwrap();
+    let raw = zopfli_deflate_raw(&data, iterations).unwrap();
+
+    // A zlib stream is a 2-byte header, the raw deflate stream and a 4-byte adler32
+    assert_eq!(raw, &zlib[2..zlib.len() - 4]);
+    assert_eq!(inflate(&zlib, data.len()).unwrap(), data);
+}
+
+#[test]
+fn crc32_writer_matches_crc32() {
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut writer = Crc32Writer::new(Vec::new());
+    assert_eq!(writer.crc(), crc32(&[]));
+    // Write in uneven pieces to check the CRC accumulates correctly
+    for piece in data.chunks(999) {
+        writer.write_all(piece).unwrap();
+    }
+    assert_eq!(writer.crc(), crc32(&data));
+    assert_eq!(writer.into_inner(), data);
+}
+
+/// The CRC-32 used by PNG, computed a bit at a time as in the specification
+fn reference_crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[test]
+fn crc32_matches_reference() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+    // Accelerated implementations handle the start and end of the data separately, so check
+    // every alignment and length around the sizes they work in
+    let data: Vec<u8> = (0..1024u32).map(|i| (i * 131 % 256) as u8).collect();
+    for start in 0..16 {
+        for len in (0..300).chain([511, 512, 513, 1000]) {
+            let slice = &data[start..start + len];
+            assert_eq!(crc32(slice), reference_crc32(slice), "{}..+{}", start, len);
+        }
+    }
+}
+
+#[test]
+fn zlib_header_flevel() {
+    let data: Vec<u8> = (0..4096u32).map(|i| (i * i % 251) as u8).collect();
+
+    let zlib = zopfli_deflate(&data, NonZeroU8::new(5).unwrap()).unwrap();
+    assert_eq!(zlib[..2], [0x78, 0xDA]);
+    assert_eq!(zlib[..2], zlib_header(ZLIB_FLEVEL_MAXIMUM));
+    assert_eq!(inflate(&zlib, data.len()).unwrap(), data);
+
+    // The mapping of levels matches the header written by libdeflate
+    for compression in 1..=12 {
+        let deflater = Deflaters::Libdeflater { compression };
+        let zlib = deflater.deflate(&data, &AtomicMin::new(None)).unwrap();
+        let flevel = deflater.zlib_flevel().unwrap();
+        assert_eq!(zlib[..2], zlib_header(flevel), "level {}", compression);
+    }
+    assert_eq!(zlib_header(0), [0x78, 0x01]);
+    assert_eq!(zlib_header(1), [0x78, 0x5E]);
+    assert_eq!(zlib_header(2), [0x78, 0x9C]);
+}
+
+#[test]
+fn zopfli_max_block_splits() {
+    // Sections with very different statistics benefit from being split into separate blocks
+    let mut data = Vec::new();
+    for section in 0..8u32 {
+        data.extend((0..8192u32).map(|i| match section % 2 {
+            0 => (i.wrapping_mul(2_654_435_761) >> (section + 20)) as u8,
+            _ => b"abcd"[(i % 4) as usize] + section as u8,
+        }));
+    }
+    let iterations = NonZeroU8::new(2).unwrap();
+    let max_size = AtomicMin::new(None);
+    let unsplit = ZopfliDeflater {
+        iterations,
+        max_block_splits: 1,
+    }
+    .deflate(&data, &max_size)
+    .unwrap();
+    let split = ZopfliDeflater {
+        iterations,
+        max_block_splits: DEFAULT_MAX_BLOCK_SPLITS,
+    }
+    .deflate(&data, &max_size)
+    .unwrap();
+
+    assert!(split.len() < unsplit.len());
+    assert_eq!(split, zopfli_deflate(&data, iterations).unwrap());
+    assert_eq!(inflate(&unsplit, data.len()).unwrap(), data);
+    assert_eq!(inflate(&split, data.len()).unwrap(), data);
+
+    let max_size = AtomicMin::new(Some(split.len() - 1));
+    assert!(matches!(
+        ZopfliDeflater {
+            iterations,
+            max_block_splits: DEFAULT_MAX_BLOCK_SPLITS,
+        }
+        .deflate(&data, &max_size),
+        Err(PngError::DeflatedDataTooLong(max)) if max == split.len() - 1
+    ));
+}
+
+#[test]
+#[cfg(all(feature = "parallel", not(feature = "single-threaded")))]
+fn zopfli_parallel_matches_sequential() {
+    // Large enough to be split into sections which are compressed in parallel, with some
+    // incompressible data which is written in stored blocks
+    let mut state = 1u32;
+    let data: Vec<u8> = (0..2_100_000u32)
+        .map(|i| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            match i {
+                1_200_000..=1_300_000 => state as u8,
+                _ => (i / 3 % 64) as u8 ^ (state >> 29) as u8,
+            }
+        })
+        .collect();
+    let iterations = NonZeroU8::new(1).unwrap();
+    let pool = |threads| {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap()
+    };
+
+    let zlib = pool(4).install(|| {
+        assert!(zopfli_compresses_in_parallel(data.len()));
+        zopfli_deflate(&data, iterations).unwrap()
+    });
+    assert_eq!(inflate(&zlib, data.len()).unwrap(), data);
+    let sequential = zopfli_deflate_raw_sequential(&data, iterations).unwrap();
+    // Blocks are split separately in each section, which may cost a few bytes
+    assert!(zlib.len() - 6 <= sequential.len() + sequential.len() / 500);
+    assert_ne!(zlib[2..zlib.len() - 4], sequential);
+    // A section alone is longer than the limit, so the rest are abandoned
+    let deflater = Deflaters::Zopfli { iterations };
+    let too_long = pool(4).install(|| deflater.deflate(&data, &AtomicMin::new(Some(1000))));
+    assert!(matches!(too_long, Err(PngError::DeflatedDataTooLong(1000))));
+
+    // With a single thread, the data is compressed as a whole
+    let single = pool(1).install(|| {
+        assert!(!zopfli_compresses_in_parallel(data.len()));
+        zopfli_deflate_raw(&data, iterations).unwrap()
+    });
+    assert_eq!(single, sequential);
+    assert!(!zopfli_compresses_in_parallel(1000));
+}
+
+#[test]
+fn deflate_size_cap() {
+    let data: Vec<u8> = (0..4096u32).map(|i| (i * i % 251) as u8).collect();
+    let compressed = deflate(&data, 6, &AtomicMin::new(None)).unwrap();
+    let size = compressed.len();
+
+    // The cap is inclusive, and output just over it is rejected even though libdeflate has
+    // slack space for it
+    for cap in [size, size + 1] {
+        assert_eq!(
+            deflate(&data, 6, &AtomicMin::new(Some(cap))).unwrap(),
+            compressed
+        );
+    }
+    for cap in [size - 1, size - 8, 10, 0] {
+        let max_size = AtomicMin::new(Some(cap));
+        assert!(matches!(
+            deflate(&data, 6, &max_size),
+            Err(PngError::DeflatedDataTooLong(c)) if c == cap
+        ));
+        // The cap is left as it was
+        assert_eq!(max_size.get(), Some(cap));
+    }
+
+    // Zopfli can't stop partway through small data, but its output is checked against the cap
+    let zopfli = Deflaters::Zopfli {
+        iterations: NonZeroU8::new(1).unwrap(),
+    };
+    let compressed = zopfli.deflate(&data, &AtomicMin::new(None)).unwrap();
+    let max_size = AtomicMin::new(Some(compressed.len() - 1));
+    assert!(matches!(
+        zopfli.deflate(&data, &max_size),
+        Err(PngError::DeflatedDataTooLong(_))
+    ));
+}
+
+#[test]
+#[cfg(feature = "miniz")]
+fn miniz_oxide_round_trip() {
+    let data: Vec<u8> = (0..100_000u64).map(|i| (i * i % 251) as u8).collect();
+
+    for level in [0, 1, 6, 10] {
+        let deflater = Deflaters::MinizOxide { level };
+        let zlib = deflater.deflate(&data, &AtomicMin::new(None)).unwrap();
+        assert_eq!(inflate(&zlib, data.len()).unwrap(), data, "level {}", level);
+        assert_eq!(
+            zlib[..2],
+            zlib_header(deflater.zlib_flevel().unwrap()),
+            "level {}",
+            level
+        );
+    }
+
+    // The cap is inclusive, and output over it is rejected
+    let compressed = miniz_oxide_deflate(&data, 6, &AtomicMin::new(None)).unwrap();
+    let size = compressed.len();
+    assert_eq!(
+        miniz_oxide_deflate(&data, 6, &AtomicMin::new(Some(size))).unwrap(),
+        compressed
+    );
+    for cap in [size - 1, 10] {
+        assert!(matches!(
+            miniz_oxide_deflate(&data, 6, &AtomicMin::new(Some(cap))),
+            Err(PngError::DeflatedDataTooLong(c)) if c == cap
+        ));
+    }
+    // The binary has no option to use it
+    let opts = oxipng::Options {
+        deflate: Deflaters::MinizOxide { level: 6 },
+        ..oxipng::Options::default()
+    };
+    assert!(opts.to_cli_args().is_err());
+}
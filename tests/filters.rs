@@ -40,8 +40,8 @@ fn test_it_converts(
     let png = PngData::new(&input, &opts).unwrap();
     opts.filter = IndexSet::new();
     opts.filter.insert(filter);
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), color_type_in);
-    assert_eq!(png.raw.ihdr.bit_depth, bit_depth_in);
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), color_type_in);
+    assert_eq!(png.raw().ihdr().bit_depth, bit_depth_in);
 
     match oxipng::optimize(&InFile::Path(input), &output, &opts) {
         Ok(_) => (),
@@ -58,10 +58,13 @@ fn test_it_converts(
         }
     };
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), color_type_out);
-    assert_eq!(png.raw.ihdr.bit_depth, bit_depth_out);
-    if let ColorType::Indexed { palette } = &png.raw.ihdr.color_type {
-        assert!(palette.len() <= 1 << (png.raw.ihdr.bit_depth as u8));
+    assert_eq!(
+        png.raw().ihdr().color_type.png_header_code(),
+        color_type_out
+    );
+    assert_eq!(png.raw().ihdr().bit_depth, bit_depth_out);
+    if let ColorType::Indexed { palette } = &png.raw().ihdr().color_type {
+        assert!(palette.len() <= 1 << (png.raw().ihdr().bit_depth as u8));
     }
 
     remove_file(output).ok();
@@ -858,3 +861,189 @@ fn filter_5_for_palette_1() {
         BitDepth::One,
     );
 }
+
+/// Encode a PNG file of an image that isn't interlaced, from its already compressed image data
+fn encode_png(ihdr: &IhdrData, idat_data: &[u8]) -> Vec<u8> {
+    let mut header = ihdr.width.to_be_bytes().to_vec();
+    header.extend_from_slice(&ihdr.height.to_be_bytes());
+    header.extend_from_slice(&[
+        ihdr.bit_depth as u8,
+        ihdr.color_type.png_header_code(),
+        0,
+        0,
+        0,
+    ]);
+    let mut output = b"\x89PNG\r\n\x1a\n".to_vec();
+    for (name, data) in [(b"IHDR", &header[..]), (b"IDAT", idat_data), (b"IEND", &[])] {
+        output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = output.len();
+        output.extend_from_slice(name);
+        output.extend_from_slice(data);
+        let crc = crc32(&output[start..]);
+        output.extend_from_slice(&crc.to_be_bytes());
+    }
+    output
+}
+
+/// Reference implementation of the Average and Paeth filters, as given in the PNG specification
+fn reference_filter(filter: RowFilter, bpp: usize, data: &[u8], prev_line: &[u8]) -> Vec<u8> {
+    let paeth = |a: u8, b: u8, c: u8| {
+        let p = i16::from(a) + i16::from(b) - i16::from(c);
+        let (pa, pb, pc) = (
+            (p - i16::from(a)).abs(),
+            (p - i16::from(b)).abs(),
+            (p - i16::from(c)).abs(),
+        );
+        if pa <= pb && pa <= pc {
+            a
+        } else if pb <= pc {
+            b
+        } else {
+            c
+        }
+    };
+    let mut out = vec![filter as u8];
+    for i in 0..data.len() {
+        let left = if i >= bpp { data[i - bpp] } else { 0 };
+        let up_left = if i >= bpp { prev_line[i - bpp] } else { 0 };
+        let pred = match filter {
+            RowFilter::Average => ((u16::from(left) + u16::from(prev_line[i])) / 2) as u8,
+            RowFilter::Paeth => paeth(left, prev_line[i], up_left),
+            _ => unreachable!(),
+        };
+        out.push(data[i].wrapping_sub(pred));
+    }
+    out
+}
+
+/// The SIMD paths (SSE2 on x86_64, NEON on AArch64 where available) must agree byte-for-byte
+/// with the scalar reference, in both directions
+#[test]
+fn average_and_paeth_match_reference() {
+    let mut state = 12345u32;
+    let mut random = || {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (state >> 16) as u8
+    };
+    for (color_type, bit_depth) in [
+        (
+            ColorType::Grayscale {
+                transparent_shade: None,
+            },
+            BitDepth::Eight,
+        ),
+        (ColorType::GrayscaleAlpha, BitDepth::Eight),
+        (
+            ColorType::RGB {
+                transparent_color: None,
+            },
+            BitDepth::Eight,
+        ),
+        (ColorType::RGBA, BitDepth::Eight),
+        (
+            ColorType::RGB {
+                transparent_color: None,
+            },
+            BitDepth::Sixteen,
+        ),
+        (ColorType::RGBA, BitDepth::Sixteen),
+    ] {
+        for width in [1, 3, 5, 17, 40] {
+            let ihdr = IhdrData {
+                width,
+                height: 3,
+                color_type: color_type.clone(),
+                bit_depth,
+                interlaced: Interlacing::None,
+            };
+            let data: Vec<u8> = (0..ihdr.raw_data_size() - 3).map(|_| random()).collect();
+            let png = PngImage::new(ihdr, data).unwrap();
+            let bpp = png.channels_per_pixel() * png.bytes_per_channel();
+            let row_len = width as usize * bpp;
+
+            for filter in [RowFilter::Average, RowFilter::Paeth] {
+                let filtered = png.filter_image(filter, false);
+                let mut prev_line = vec![0; row_len];
+                for (row, line) in png.data().chunks(row_len).zip(filtered.chunks(row_len + 1)) {
+                    assert_eq!(line, reference_filter(filter, bpp, row, &prev_line));
+                    prev_line = row.to_vec();
+                }
+
+                // Unfiltering must give back the original data
+                let idat_data = deflate(&filtered, 1, &AtomicMin::new(None)).unwrap();
+                let output = encode_png(png.ihdr(), &idat_data);
+                let decoded = PngData::from_slice(&output, &Options::default()).unwrap();
+                assert_eq!(decoded.raw().data(), png.data());
+            }
+        }
+    }
+}
+
+/// Large images are scored by the heuristics in parallel ranges of lines, which must choose the
+/// same filters as scoring every line in order. Alpha optimization has no effect on opaque
+/// pixels, but keeps the lines in order.
+#[test]
+fn heuristics_match_across_tiles() {
+    let ihdr = IhdrData {
+        width: 256,
+        height: 300,
+        color_type: ColorType::RGBA,
+        bit_depth: BitDepth::Eight,
+        interlaced: Interlacing::None,
+    };
+    let data = (0..256 * 300 * 4)
+        .map(|i: u32| {
+            if i % 4 == 3 {
+                255
+            } else {
+                (i / 1024 + (i ^ (i >> 7)) % 13) as u8
+            }
+        })
+        .collect();
+    let png = PngImage::new(ihdr, data).unwrap();
+    let interlaced = png.change_interlacing(Interlacing::Adam7).unwrap();
+    for png in [png, interlaced] {
+        for filter in [
+            RowFilter::MinSum,
+            RowFilter::WeightedMsad,
+            RowFilter::Entropy,
+            RowFilter::Bigrams,
+            RowFilter::BigEnt,
+        ] {
+            assert_eq!(
+                png.filter_image(filter, false),
+                png.filter_image(filter, true),
+                "{} {:?}",
+                filter,
+                png.ihdr().interlaced
+            );
+            assert_eq!(
+                png.filter_stats(filter, false),
+                png.filter_stats(filter, true)
+            );
+        }
+    }
+}
+
+#[test]
+fn fixed_filter_applies_to_every_row() {
+    let input = PngData::read_file(Path::new("tests/files/filter_0_for_rgb_8.png")).unwrap();
+    let opts = Options {
+        force: true,
+        fixed_filter: Some(RowFilter::Paeth),
+        ..Default::default()
+    };
+    let (output, report) = optimize_from_memory_with_report(&input, &opts).unwrap();
+    assert_eq!(report.filter, Some(RowFilter::Paeth));
+
+    let png = PngData::from_slice(&output, &opts).unwrap();
+    let filtered = inflate(png.idat_data(), png.raw().ihdr().raw_data_size()).unwrap();
+    // Each row of the image, which isn't interlaced, starts with its filter type
+    let height = png.raw().height() as usize;
+    let filters: Vec<u8> = filtered
+        .chunks(filtered.len() / height)
+        .map(|row| row[0])
+        .collect();
+    assert_eq!(filters.len(), height);
+    assert!(filters.iter().all(|&f| f == 4));
+}
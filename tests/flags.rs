@@ -10,6 +10,7 @@ use std::num::NonZeroU8;
 use std::ops::Deref;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
 const GRAYSCALE: u8 = 0;
 const RGB: u8 = 2;
@@ -47,10 +48,10 @@ fn test_it_converts_callbacks<CBPRE, CBPOST>(
     CBPOST: FnMut(&Path),
     CBPRE: FnMut(&Path),
 {
-    let png = PngData::new(&input, &opts).unwrap();
+    let png = PngData::new(&input, opts).unwrap();
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), color_type_in);
-    assert_eq!(png.raw.ihdr.bit_depth, bit_depth_in);
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), color_type_in);
+    assert_eq!(png.raw().ihdr().bit_depth, bit_depth_in);
 
     callback_pre(&input);
 
@@ -63,7 +64,7 @@ fn test_it_converts_callbacks<CBPRE, CBPOST>(
 
     callback_post(output);
 
-    let png = match PngData::new(output, &opts) {
+    let png = match PngData::new(output, opts) {
         Ok(x) => x,
         Err(x) => {
             remove_file(output).ok();
@@ -71,8 +72,11 @@ fn test_it_converts_callbacks<CBPRE, CBPOST>(
         }
     };
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), color_type_out);
-    assert_eq!(png.raw.ihdr.bit_depth, bit_depth_out);
+    assert_eq!(
+        png.raw().ihdr().color_type.png_header_code(),
+        color_type_out
+    );
+    assert_eq!(png.raw().ihdr().bit_depth, bit_depth_out);
 
     remove_file(output).ok();
 }
@@ -116,7 +120,7 @@ fn verbose_mode() {
     // initialise it with Some(sender) only on threads spawned within
     // our test.
     thread_local! {
-        static VERBOSE_LOGS: RefCell<Option<Sender<String>>> = RefCell::new(None);
+        static VERBOSE_LOGS: RefCell<Option<Sender<String>>> = const { RefCell::new(None) };
     }
 
     struct LogTester;
@@ -194,7 +198,7 @@ fn verbose_mode() {
     for (i, log) in logs.into_iter().enumerate() {
         let expected_prefix = expected_prefixes[i];
         assert!(
-            log.starts_with(&expected_prefix),
+            log.starts_with(expected_prefix),
             "logs[{}] = {:?} doesn't start with {:?}",
             i,
             log,
@@ -204,12 +208,35 @@ fn verbose_mode() {
 }
 
 fn count_chunk(png: &PngData, name: &[u8; 4]) -> usize {
-    png.aux_chunks
+    png.aux_chunks()
         .iter()
         .filter(|chunk| &chunk.name == name)
         .count()
 }
 
+/// Encode a single gray pixel along with the given chunks
+fn gray_pixel_with_chunks(chunks: &[([u8; 4], Vec<u8>)], opts: &Options) -> Vec<u8> {
+    let mut raw = RawImage::new(
+        1,
+        1,
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Eight,
+        vec![0],
+    )
+    .unwrap();
+    for (name, data) in chunks {
+        raw.add_png_chunk(*name, data.clone());
+    }
+    raw.create_optimized_png(opts).unwrap()
+}
+
+fn optimize_gray_pixel_with_chunks(chunks: &[([u8; 4], Vec<u8>)], opts: &Options) -> PngData {
+    let output = gray_pixel_with_chunks(chunks, opts);
+    PngData::from_slice(&output, &Options::default()).unwrap()
+}
+
 #[test]
 fn strip_headers_list() {
     let input = PathBuf::from("tests/files/strip_headers_list.png");
@@ -278,6 +305,51 @@ fn strip_headers_safe() {
     remove_file(output).ok();
 }
 
+#[test]
+fn strip_headers_safe_keep_private() {
+    let mut input = std::fs::read("tests/files/strip_headers_safe.png").unwrap();
+    // Insert chunks with different case patterns before the IEND chunk
+    let mut chunks = Vec::new();
+    for name in [b"abCd", b"abCD", b"aBCd"] {
+        chunks.extend_from_slice(&4u32.to_be_bytes());
+        let start = chunks.len();
+        chunks.extend_from_slice(name);
+        chunks.extend_from_slice(b"data");
+        let crc = crc32(&chunks[start..]);
+        chunks.extend_from_slice(&crc.to_be_bytes());
+    }
+    let iend = input.len() - 12;
+    input.splice(iend..iend, chunks);
+
+    let mut opts = Options::from_preset(0);
+    opts.strip = StripChunks::SafeKeepPrivate;
+    let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+
+    // Private and safe to copy
+    assert_eq!(count_chunk(&png, b"abCd"), 1);
+    // Private but unsafe to copy, as it may depend on the image data
+    assert_eq!(count_chunk(&png, b"abCD"), 0);
+    // Public chunks are stripped as with `Safe`
+    assert_eq!(count_chunk(&png, b"aBCd"), 0);
+    assert_eq!(count_chunk(&png, b"tEXt"), 0);
+    assert_eq!(count_chunk(&png, b"sRGB"), 1);
+}
+
+#[test]
+fn chunk_type_properties() {
+    assert!(is_critical(b"IHDR"));
+    assert!(!is_private(b"IHDR"));
+    assert!(!is_safe_to_copy(b"IHDR"));
+    assert!(!is_critical(b"tEXt"));
+    assert!(!is_private(b"tEXt"));
+    assert!(is_safe_to_copy(b"tEXt"));
+    assert!(!is_safe_to_copy(b"sBIT") && !is_private(b"sBIT"));
+    assert!(is_private(b"abCd") && is_safe_to_copy(b"abCd"));
+    assert!(is_private(b"abCD") && !is_safe_to_copy(b"abCD"));
+    assert!(is_critical(b"AbCd") && is_private(b"AbCd"));
+}
+
 #[test]
 fn strip_headers_all() {
     let input = PathBuf::from("tests/files/strip_headers_all.png");
@@ -312,6 +384,53 @@ fn strip_headers_all() {
     remove_file(output).ok();
 }
 
+#[test]
+fn max_chunk_size() {
+    let input = std::fs::read("tests/files/strip_headers_all.png").unwrap();
+    // The iCCP chunk has 2627 bytes and the iTXt chunk 808, while the tEXt chunks are small
+    let mut opts = Options {
+        max_chunk_size: Some(100),
+        ..Options::default()
+    };
+    assert!(matches!(
+        oxipng::optimize_from_memory(&input, &opts),
+        Err(PngError::ChunkTooLarge(name, 2627)) if &name == b"iCCP"
+    ));
+
+    opts.strip_oversized_chunks = true;
+    opts.collect_warnings = true;
+    let (output, report) = oxipng::optimize_from_memory_with_report(&input, &opts).unwrap();
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    assert_eq!(count_chunk(&png, b"iCCP"), 0);
+    assert_eq!(count_chunk(&png, b"iTXt"), 0);
+    assert_eq!(count_chunk(&png, b"tEXt") + count_chunk(&png, b"zTXt"), 3);
+    assert!(report
+        .warnings
+        .contains(&PngWarning::OversizedChunk(*b"iCCP", 2627)));
+    assert!(report
+        .warnings
+        .contains(&PngWarning::OversizedChunk(*b"iTXt", 808)));
+
+    // The compressed profile is within the limit, but it isn't decompressed beyond it
+    opts.max_chunk_size = Some(3000);
+    let iccp = |data: &[u8]| {
+        PngData::from_slice(data, &Options::default())
+            .unwrap()
+            .aux_chunks()
+            .iter()
+            .find(|c| &c.name == b"iCCP")
+            .unwrap()
+            .clone()
+    };
+    let original = iccp(&input);
+    assert!(extract_icc(&original).unwrap().len() > 3000);
+    let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    assert_eq!(iccp(&output).data, original.data);
+    // Otherwise it would have been recompressed
+    let output = oxipng::optimize_from_memory(&input, &Options::default()).unwrap();
+    assert_ne!(iccp(&output).data, original.data);
+}
+
 #[test]
 fn strip_headers_none() {
     let input = PathBuf::from("tests/files/strip_headers_none.png");
@@ -346,6 +465,489 @@ fn strip_headers_none() {
     remove_file(output).ok();
 }
 
+#[test]
+fn strip_headers_predicate() {
+    let input = PathBuf::from("tests/files/strip_headers_none.png");
+    let (output, mut opts) = get_opts(&input);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen_by_filter = seen.clone();
+    opts.chunk_filter = Some(ChunkFilter::new(move |name, data| {
+        seen_by_filter.lock().unwrap().push(*name);
+        data.len() <= 100
+    }));
+
+    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    assert!(output.exists());
+
+    let png = match PngData::new(output, &Options::default()) {
+        Ok(x) => x,
+        Err(x) => {
+            remove_file(output).ok();
+            panic!("{}", x)
+        }
+    };
+
+    assert_eq!(count_chunk(&png, b"tEXt"), 3);
+    assert_eq!(count_chunk(&png, b"iTXt"), 0);
+    assert_eq!(count_chunk(&png, b"iCCP"), 0);
+    assert!(seen
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|name| name[0].is_ascii_lowercase()));
+
+    remove_file(output).ok();
+}
+
+#[test]
+fn strip_headers_predicate_after_preset() {
+    let input = PathBuf::from("tests/files/strip_headers_none.png");
+    let (output, mut opts) = get_opts(&input);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen_by_filter = seen.clone();
+    // The preset strips tEXt, which the predicate would keep, and keeps iCCP, which it wouldn't
+    opts.strip = StripChunks::Strip(indexset![*b"tEXt"]);
+    opts.chunk_filter = Some(ChunkFilter::new(move |name, _| {
+        seen_by_filter.lock().unwrap().push(*name);
+        name != b"iCCP"
+    }));
+
+    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    assert!(output.exists());
+
+    let png = match PngData::new(output, &Options::default()) {
+        Ok(x) => x,
+        Err(x) => {
+            remove_file(output).ok();
+            panic!("{}", x)
+        }
+    };
+
+    assert_eq!(count_chunk(&png, b"tEXt"), 0);
+    assert_eq!(count_chunk(&png, b"iTXt"), 1);
+    assert_eq!(count_chunk(&png, b"iCCP"), 0);
+    assert!(!seen.lock().unwrap().contains(b"tEXt"));
+
+    remove_file(output).ok();
+}
+
+#[test]
+fn optimize_icc() {
+    let input = PathBuf::from("tests/files/badsrgb.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.optimize_icc = true;
+
+    let png = PngData::new(&input, &Options::default()).unwrap();
+
+    assert_eq!(count_chunk(&png, b"iCCP"), 1);
+    assert_eq!(count_chunk(&png, b"sRGB"), 0);
+
+    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    assert!(output.exists());
+
+    let png = match PngData::new(output, &opts) {
+        Ok(x) => x,
+        Err(x) => {
+            remove_file(output).ok();
+            panic!("{}", x)
+        }
+    };
+
+    assert_eq!(count_chunk(&png, b"iCCP"), 0);
+    assert_eq!(count_chunk(&png, b"sRGB"), 1);
+    assert_eq!(count_chunk(&png, b"gAMA"), 1);
+    assert_eq!(count_chunk(&png, b"cHRM"), 1);
+
+    remove_file(output).ok();
+}
+
+#[test]
+fn optimize_srgb_gamma_chrm() {
+    let srgb_chrm = [31270, 32900, 64000, 33000, 30000, 60000, 15000, 6000];
+    let output = |gamma: u32, chrm: [u32; 8], optimize_icc: bool| {
+        let chunks = [
+            (*b"gAMA", gamma.to_be_bytes().to_vec()),
+            (
+                *b"cHRM",
+                chrm.iter().flat_map(|v| v.to_be_bytes()).collect(),
+            ),
+        ];
+        let opts = Options {
+            optimize_icc,
+            ..Default::default()
+        };
+        optimize_gray_pixel_with_chunks(&chunks, &opts)
+    };
+    let names = |png: &PngData| -> Vec<[u8; 4]> {
+        png.aux_chunks()
+            .iter()
+            .map(|c| c.name)
+            .filter(|n| n != b"IDAT")
+            .collect()
+    };
+
+    let png = output(45455, srgb_chrm, true);
+    assert_eq!(names(&png), [*b"sRGB"]);
+    assert_eq!(png.aux_chunks()[0].data, [0]);
+    // Values rounded by the encoder are still sRGB
+    let mut rounded = srgb_chrm;
+    rounded[2] = 64010;
+    assert_eq!(names(&output(45454, rounded, true)), [*b"sRGB"]);
+
+    assert_eq!(
+        names(&output(45455, srgb_chrm, false)),
+        [*b"gAMA", *b"cHRM"]
+    );
+    // A gamma of 0.45 is not sRGB
+    assert_eq!(names(&output(45000, srgb_chrm, true)), [*b"gAMA", *b"cHRM"]);
+    let mut wide = srgb_chrm;
+    wide[2] = 73470;
+    assert_eq!(names(&output(45455, wide, true)), [*b"gAMA", *b"cHRM"]);
+
+    assert!(!icc::gamma_chrm_are_srgb(&45455u32.to_be_bytes(), &[0; 32]));
+}
+
+#[test]
+fn recompress_icc() {
+    // Not a known sRGB profile, so it must be kept
+    let mut state = 1u32;
+    let icc: Vec<u8> = (0..4000)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 28) as u8
+        })
+        .collect();
+    let fast = construct_iccp(
+        &icc,
+        &Deflaters::Libdeflater { compression: 1 },
+        &AtomicMin::new(None),
+    )
+    .unwrap();
+    let png =
+        optimize_gray_pixel_with_chunks(&[(*b"iCCP", fast.data.clone())], &Options::default());
+
+    let iccp = png
+        .aux_chunks()
+        .iter()
+        .find(|c| &c.name == b"iCCP")
+        .unwrap();
+    assert!(iccp.data.len() < fast.data.len());
+    assert_eq!(extract_icc(iccp).unwrap(), icc);
+}
+
+#[test]
+fn icc_prevents_grayscale_reduction() {
+    // An uncompressible profile for RGB data, which isn't a known sRGB profile
+    let mut icc = vec![0; 132];
+    icc[16..20].copy_from_slice(b"RGB ");
+    let mut raw = RawImage::new(
+        2,
+        1,
+        ColorType::RGB {
+            transparent_color: None,
+        },
+        BitDepth::Eight,
+        vec![10, 10, 10, 200, 200, 200],
+    )
+    .unwrap();
+    raw.add_icc_profile(&icc);
+
+    let output = raw.create_optimized_png(&Options::default()).unwrap();
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    assert!(matches!(
+        png.raw().ihdr().color_type,
+        ColorType::RGB { .. } | ColorType::Indexed { .. }
+    ));
+    assert!(png.aux_chunks().iter().any(|c| &c.name == b"iCCP"));
+
+    let opts = Options {
+        drop_incompatible_icc: true,
+        ..Options::default()
+    };
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), GRAYSCALE);
+    assert!(!png.aux_chunks().iter().any(|c| &c.name == b"iCCP"));
+}
+
+#[test]
+fn narrow_range_cicp_prevents_bit_depth_reduction() {
+    // 16-bit samples which would be reducible to 8-bit, if not for the narrow range
+    let raw = |full_range: u8| {
+        let mut raw = RawImage::new(
+            2,
+            1,
+            ColorType::Grayscale {
+                transparent_shade: None,
+            },
+            BitDepth::Sixteen,
+            vec![0x10, 0x10, 0xEB, 0xEB],
+        )
+        .unwrap();
+        raw.add_png_chunk(*b"cICP", vec![1, 1, 0, full_range]);
+        raw
+    };
+
+    let output = raw(0).create_optimized_png(&Options::default()).unwrap();
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Sixteen);
+
+    let output = raw(1).create_optimized_png(&Options::default()).unwrap();
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    assert_ne!(png.raw().ihdr().bit_depth, BitDepth::Sixteen);
+}
+
+#[test]
+fn dedup_text() {
+    let long_text = [b"Comment\0".as_slice(), &[b'a'; 200]].concat();
+    let chunks = [
+        (*b"tEXt", b"Title\0Picture".to_vec()),
+        (*b"iTXt", b"Title\0\0\0en\0\0Picture".to_vec()),
+        (*b"tEXt", b"Title\0Picture".to_vec()),
+        (*b"iTXt", b"Title\0\0\0de\0Titel\0Bild".to_vec()),
+        (*b"iTXt", b"Title\0\0\0en\0\0Picture".to_vec()),
+        (*b"tEXt", long_text.clone()),
+    ];
+    let opts = Options {
+        dedup_text: true,
+        ..Default::default()
+    };
+    let png = optimize_gray_pixel_with_chunks(&chunks, &opts);
+
+    let text: Vec<_> = png
+        .aux_chunks()
+        .iter()
+        .filter(|c| matches!(&c.name, b"tEXt" | b"zTXt" | b"iTXt"))
+        .collect();
+    assert_eq!(text.len(), 4);
+    assert_eq!(text[0].data, b"Title\0Picture");
+    assert_eq!(text[1].data, b"Title\0\0\0en\0\0Picture");
+    assert_eq!(text[2].data, b"Title\0\0\0de\0Titel\0Bild");
+    assert_eq!(&text[3].name, b"zTXt");
+    assert!(text[3].data.len() < long_text.len());
+    let value = inflate(&text[3].data[9..], 1000).unwrap();
+    assert_eq!(value, &long_text[8..]);
+}
+
+#[test]
+fn phys_dedup() {
+    let phys = PhysicalDimensions::from_dpi(72).unwrap();
+    let chunks = [(*b"pHYs", phys.chunk_data()), (*b"pHYs", phys.chunk_data())];
+    let png = optimize_gray_pixel_with_chunks(&chunks, &Options::default());
+
+    assert_eq!(count_chunk(&png, b"pHYs"), 1);
+}
+
+#[test]
+fn phys_set_dpi() {
+    let opts = Options {
+        physical_dimensions: Some(PhysicalDimensions::from_dpi(300).unwrap()),
+        ..Default::default()
+    };
+    let png =
+        optimize_gray_pixel_with_chunks(&[(*b"pHYs", vec![0, 0, 0, 1, 0, 0, 0, 2, 0])], &opts);
+
+    assert_eq!(count_chunk(&png, b"pHYs"), 1);
+    let phys = png
+        .aux_chunks()
+        .iter()
+        .find(|c| &c.name == b"pHYs")
+        .unwrap();
+    assert_eq!(
+        parse_phys_chunk(&phys.data).unwrap(),
+        PhysicalDimensions {
+            x_pixels_per_unit: 11811,
+            y_pixels_per_unit: 11811,
+            unit_is_meter: true,
+        }
+    );
+
+    assert!(PhysicalDimensions::from_dpi(0).is_err());
+    assert!(PhysicalDimensions::from_dpi(u32::MAX).is_err());
+}
+
+#[test]
+fn time_modes() {
+    let original = PngTime {
+        year: 2001,
+        month: 2,
+        day: 3,
+        hour: 4,
+        minute: 5,
+        second: 6,
+    };
+    let output_time = |time: TimeMode, strip: StripChunks| {
+        let opts = Options {
+            time,
+            strip,
+            ..Default::default()
+        };
+        let png = optimize_gray_pixel_with_chunks(&[(*b"tIME", original.chunk_data())], &opts);
+        assert!(count_chunk(&png, b"tIME") <= 1);
+        png.aux_chunks()
+            .iter()
+            .find(|c| &c.name == b"tIME")
+            .map(|c| parse_time_chunk(&c.data).unwrap())
+    };
+
+    assert_eq!(
+        output_time(TimeMode::Keep, StripChunks::None),
+        Some(original)
+    );
+    assert_eq!(output_time(TimeMode::Keep, StripChunks::Safe), None);
+    assert_eq!(output_time(TimeMode::Strip, StripChunks::None), None);
+
+    let fixed = PngTime::from_unix_time(951_782_400).unwrap();
+    assert_eq!(
+        fixed,
+        PngTime {
+            year: 2000,
+            month: 2,
+            day: 29,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        }
+    );
+    // A new time is written even if the chunk would be stripped
+    assert_eq!(
+        output_time(TimeMode::Set(Some(fixed)), StripChunks::All),
+        Some(fixed)
+    );
+
+    // The variable itself is read in PngTime::current, which isn't tested here because changing
+    // the environment would affect tests running on other threads
+    assert!(output_time(TimeMode::Set(None), StripChunks::None).is_some());
+    assert_eq!(
+        PngTime::from_source_date_epoch(" 1700000000\n").unwrap(),
+        PngTime {
+            year: 2023,
+            month: 11,
+            day: 14,
+            hour: 22,
+            minute: 13,
+            second: 20,
+        }
+    );
+    assert!(PngTime::from_source_date_epoch("").is_err());
+    assert!(PngTime::from_source_date_epoch("-1").is_err());
+    assert!(PngTime::from_source_date_epoch("2023-11-14").is_err());
+
+    assert_eq!(
+        PngTime::from_unix_time(0).unwrap().chunk_data(),
+        [7, 178, 1, 1, 0, 0, 0]
+    );
+    assert!(PngTime::from_unix_time(u64::MAX).is_err());
+}
+
+#[test]
+fn chunk_order() {
+    let chunks = [
+        (*b"tEXt", b"Title\0Picture".to_vec()),
+        (*b"tIME", vec![7, 231, 1, 1, 0, 0, 0]),
+        (*b"pHYs", vec![0, 0, 0, 1, 0, 0, 0, 1, 0]),
+        (*b"iTXt", b"Title\0\0\0\0\0Picture".to_vec()),
+    ];
+    let opts = Options {
+        chunk_order: Some(vec![*b"pHYs", *b"iTXt"]),
+        ..Default::default()
+    };
+    let png = optimize_gray_pixel_with_chunks(&chunks, &opts);
+
+    let names: Vec<_> = png
+        .aux_chunks()
+        .iter()
+        .map(|c| &c.name)
+        .filter(|&name| name != b"IDAT")
+        .collect();
+    assert_eq!(names, [b"pHYs", b"iTXt", b"tEXt", b"tIME"]);
+}
+
+#[test]
+fn max_idat_size() {
+    let input = std::fs::read("tests/files/rgb_8_should_be_rgb_8.png").unwrap();
+    let idat_sizes = |png: &[u8]| {
+        let mut sizes = Vec::new();
+        let mut byte_offset = 8;
+        while let Some(chunk) = parse_next_chunk(png, &mut byte_offset, false).unwrap() {
+            if &chunk.name == b"IDAT" {
+                sizes.push(chunk.data.len());
+            }
+        }
+        sizes
+    };
+
+    let opts = Options {
+        force: true,
+        ..Default::default()
+    };
+    let single = optimize_from_memory(&input, &opts).unwrap();
+    let total = match idat_sizes(&single)[..] {
+        [size] => size,
+        ref sizes => panic!("Expected a single IDAT by default, got {:?}", sizes),
+    };
+
+    let max_size = total / 3 + 1;
+    let opts = Options {
+        max_idat_size: Some(max_size),
+        ..opts
+    };
+    let split = optimize_from_memory(&input, &opts).unwrap();
+    let sizes = idat_sizes(&split);
+    assert_eq!(sizes.len(), 3);
+    assert!(sizes.iter().all(|&size| size <= max_size));
+    assert_eq!(sizes.iter().sum::<usize>(), total);
+    assert_eq!(split.len(), single.len() + 2 * 12);
+
+    // Splitting doesn't change the image
+    let single = PngData::from_slice(&single, &Options::default()).unwrap();
+    let split = PngData::from_slice(&split, &Options::default()).unwrap();
+    assert_eq!(split.raw().data(), single.raw().data());
+    assert_eq!(split.idat_data(), single.idat_data());
+
+    assert!(OptionsBuilder::new()
+        .max_idat_size(Some(0))
+        .build()
+        .is_err());
+}
+
+#[test]
+fn lossless_only() {
+    let build = |builder: OptionsBuilder| builder.scale_16(true, true).build();
+    assert!(build(OptionsBuilder::new()).is_err());
+    assert!(OptionsBuilder::new()
+        .max_palette_colors(Some(16))
+        .build()
+        .is_err());
+    let opts = build(OptionsBuilder::new().lossless_only(false)).unwrap();
+    assert!(opts.scale_16 && opts.dither_16_to_8);
+
+    // Options made without the builder have the lossy ones ignored
+    let input = std::fs::read("tests/files/rgb_16_should_be_rgb_16.png").unwrap();
+    let lossy = Options {
+        lossless_only: true,
+        ..opts.clone()
+    };
+    let output = optimize_from_memory(&input, &lossy).unwrap();
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Sixteen);
+    let output = optimize_from_memory(&input, &opts).unwrap();
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
+}
+
 #[test]
 fn interlacing_0_to_1() {
     let input = PathBuf::from("tests/files/interlacing_0_to_1.png");
@@ -354,7 +956,7 @@ fn interlacing_0_to_1() {
 
     let png = PngData::new(&input, &opts).unwrap();
 
-    assert_eq!(png.raw.ihdr.interlaced, Interlacing::None);
+    assert_eq!(png.raw().ihdr().interlaced, Interlacing::None);
 
     match oxipng::optimize(&InFile::Path(input), &output, &opts) {
         Ok(_) => (),
@@ -371,7 +973,7 @@ fn interlacing_0_to_1() {
         }
     };
 
-    assert_eq!(png.raw.ihdr.interlaced, Interlacing::Adam7);
+    assert_eq!(png.raw().ihdr().interlaced, Interlacing::Adam7);
 
     remove_file(output).ok();
 }
@@ -384,7 +986,7 @@ fn interlacing_1_to_0() {
 
     let png = PngData::new(&input, &opts).unwrap();
 
-    assert_eq!(png.raw.ihdr.interlaced, Interlacing::Adam7);
+    assert_eq!(png.raw().ihdr().interlaced, Interlacing::Adam7);
 
     match oxipng::optimize(&InFile::Path(input), &output, &opts) {
         Ok(_) => (),
@@ -401,7 +1003,7 @@ fn interlacing_1_to_0() {
         }
     };
 
-    assert_eq!(png.raw.ihdr.interlaced, Interlacing::None);
+    assert_eq!(png.raw().ihdr().interlaced, Interlacing::None);
 
     remove_file(output).ok();
 }
@@ -414,9 +1016,9 @@ fn interlacing_0_to_1_small_files() {
 
     let png = PngData::new(&input, &opts).unwrap();
 
-    assert_eq!(png.raw.ihdr.interlaced, Interlacing::None);
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), INDEXED);
-    assert_eq!(png.raw.ihdr.bit_depth, BitDepth::Eight);
+    assert_eq!(png.raw().ihdr().interlaced, Interlacing::None);
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), INDEXED);
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
 
     match oxipng::optimize(&InFile::Path(input), &output, &opts) {
         Ok(_) => (),
@@ -433,9 +1035,9 @@ fn interlacing_0_to_1_small_files() {
         }
     };
 
-    assert_eq!(png.raw.ihdr.interlaced, Interlacing::Adam7);
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), RGB);
-    assert_eq!(png.raw.ihdr.bit_depth, BitDepth::Eight);
+    assert_eq!(png.raw().ihdr().interlaced, Interlacing::Adam7);
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), RGB);
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
 
     remove_file(output).ok();
 }
@@ -448,9 +1050,9 @@ fn interlacing_1_to_0_small_files() {
 
     let png = PngData::new(&input, &opts).unwrap();
 
-    assert_eq!(png.raw.ihdr.interlaced, Interlacing::Adam7);
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), INDEXED);
-    assert_eq!(png.raw.ihdr.bit_depth, BitDepth::Eight);
+    assert_eq!(png.raw().ihdr().interlaced, Interlacing::Adam7);
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), INDEXED);
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
 
     match oxipng::optimize(&InFile::Path(input), &output, &opts) {
         Ok(_) => (),
@@ -467,9 +1069,9 @@ fn interlacing_1_to_0_small_files() {
         }
     };
 
-    assert_eq!(png.raw.ihdr.interlaced, Interlacing::None);
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), RGB);
-    assert_eq!(png.raw.ihdr.bit_depth, BitDepth::Eight);
+    assert_eq!(png.raw().ihdr().interlaced, Interlacing::None);
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), RGB);
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
 
     remove_file(output).ok();
 }
@@ -485,7 +1087,7 @@ fn interlaced_0_to_1_other_filter_mode() {
 
     let png = PngData::new(&input, &opts).unwrap();
 
-    assert_eq!(png.raw.ihdr.interlaced, Interlacing::None);
+    assert_eq!(png.raw().ihdr().interlaced, Interlacing::None);
 
     match oxipng::optimize(&InFile::Path(input), &output, &opts) {
         Ok(_) => (),
@@ -502,7 +1104,7 @@ fn interlaced_0_to_1_other_filter_mode() {
         }
     };
 
-    assert_eq!(png.raw.ihdr.interlaced, Interlacing::Adam7);
+    assert_eq!(png.raw().ihdr().interlaced, Interlacing::Adam7);
 
     remove_file(output).ok();
 }
@@ -571,6 +1173,82 @@ fn preserve_attrs() {
     // TODO: Actually check permissions
 }
 
+#[test]
+#[cfg(feature = "filetime")]
+fn preserve_mtime() {
+    let input = PathBuf::from("tests/files/preserve_mtime.in.png");
+    std::fs::copy("tests/files/preserve_attrs.png", &input).unwrap();
+    let mtime = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_mtime(&input, mtime).unwrap();
+
+    let output = PathBuf::from("tests/files/preserve_mtime.out.png");
+    let mut opts = Options::from_preset(0);
+    opts.force = true;
+    opts.preserve_mtime = true;
+    let result = oxipng::optimize(
+        &InFile::Path(input.clone()),
+        &OutFile::Path(Some(output.clone())),
+        &opts,
+    );
+    remove_file(&input).ok();
+    result.unwrap();
+
+    let meta_output = output.metadata().unwrap();
+    remove_file(&output).ok();
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&meta_output),
+        mtime
+    );
+}
+
+#[test]
+fn atomic_write() {
+    let dir = PathBuf::from("tests/files/atomic_write");
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("in.png");
+    std::fs::copy("tests/files/preserve_attrs.png", &input).unwrap();
+    let mut opts = Options::from_preset(0);
+    opts.force = true;
+    opts.atomic_write = true;
+    let entries = || {
+        let mut names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    };
+
+    let in_place = oxipng::optimize(
+        &InFile::Path(input.clone()),
+        &OutFile::Path(Some(input.clone())),
+        &opts,
+    );
+    let output = std::fs::read(&input).unwrap();
+    let names_in_place = entries();
+
+    // Renaming over a directory fails, after the temporary file was written
+    let output_dir = dir.join("out.png");
+    std::fs::create_dir_all(&output_dir).unwrap();
+    std::fs::write(output_dir.join("keep"), b"keep").unwrap();
+    let failed = oxipng::optimize(
+        &InFile::Path(input),
+        &OutFile::Path(Some(output_dir.clone())),
+        &opts,
+    );
+    let names_failed = entries();
+    let kept = std::fs::read(output_dir.join("keep"));
+    std::fs::remove_dir_all(&dir).ok();
+
+    in_place.unwrap();
+    PngData::from_slice(&output, &Options::default()).unwrap();
+    assert_eq!(names_in_place, ["in.png"]);
+    assert!(failed.is_err());
+    // The temporary file is gone, and the target is untouched
+    assert_eq!(names_failed, ["in.png", "out.png"]);
+    assert_eq!(kept.unwrap(), b"keep");
+}
+
 #[test]
 fn fix_errors() {
     let input = PathBuf::from("tests/files/fix_errors.png");
@@ -579,8 +1257,8 @@ fn fix_errors() {
 
     let png = PngData::new(&input, &opts).unwrap();
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), RGBA);
-    assert_eq!(png.raw.ihdr.bit_depth, BitDepth::Eight);
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), RGBA);
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
 
     match oxipng::optimize(&InFile::Path(input), &output, &opts) {
         Ok(_) => (),
@@ -597,8 +1275,8 @@ fn fix_errors() {
         }
     };
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), GRAYSCALE);
-    assert_eq!(png.raw.ihdr.bit_depth, BitDepth::Eight);
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), GRAYSCALE);
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
 
     // Cannot check if pixels are equal because image crate cannot read corrupt (input) PNGs
     remove_file(output).ok();
@@ -660,6 +1338,7 @@ fn scale_16() {
     let input = PathBuf::from("tests/files/rgb_16_should_be_rgb_16.png");
     let (output, mut opts) = get_opts(&input);
     opts.scale_16 = true;
+    opts.lossless_only = false;
 
     test_it_converts(
         input,
@@ -691,3 +1370,535 @@ fn zopfli_mode() {
         BitDepth::Eight,
     );
 }
+
+#[test]
+#[cfg(feature = "zlib-ng")]
+fn zlib_ng_mode() {
+    let input = PathBuf::from("tests/files/zopfli_mode.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.deflate = Deflaters::ZlibNg { level: 9 };
+
+    test_it_converts(
+        input,
+        &output,
+        &opts,
+        RGB,
+        BitDepth::Eight,
+        RGB,
+        BitDepth::Eight,
+    );
+}
+
+#[test]
+fn progress_callback() {
+    let input = PathBuf::from("tests/files/rgb_16_should_be_rgb_8.png");
+    let (output, mut opts) = get_opts(&input);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = events.clone();
+    opts.progress = Some(ProgressCallback::new(move |event| {
+        sink.lock().unwrap().push(event)
+    }));
+
+    test_it_converts(
+        input,
+        &output,
+        &opts,
+        RGB,
+        BitDepth::Sixteen,
+        RGB,
+        BitDepth::Eight,
+    );
+
+    let events = events.lock().unwrap();
+    assert!(events.contains(&ProgressEvent::ReductionApplied {
+        kind: ReductionKind::BitDepth16To8
+    }));
+    assert!(events.contains(&ProgressEvent::FilterTrialStarted {
+        filter: RowFilter::None
+    }));
+    assert!(events.iter().any(|e| matches!(
+        e,
+        ProgressEvent::DeflateTrialCompleted { size: Some(_), .. }
+    )));
+}
+
+#[test]
+fn reduction_filter() {
+    let input = PathBuf::from("tests/files/rgb_16_should_be_palette_1.png");
+    let (output, mut opts) = get_opts(&input);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = events.clone();
+    opts.progress = Some(ProgressCallback::new(move |event| {
+        sink.lock().unwrap().push(event)
+    }));
+    opts.reduction_filter = Some(ReductionFilter::new(|kind| {
+        kind != ReductionKind::ColorType
+    }));
+
+    // The depth is still reduced, but not the color type
+    test_it_converts(
+        input,
+        &output,
+        &opts,
+        RGB,
+        BitDepth::Sixteen,
+        RGB,
+        BitDepth::Eight,
+    );
+
+    let events = events.lock().unwrap();
+    assert!(events.contains(&ProgressEvent::ReductionApplied {
+        kind: ReductionKind::BitDepth16To8
+    }));
+    assert!(!events.contains(&ProgressEvent::ReductionApplied {
+        kind: ReductionKind::ColorType
+    }));
+}
+
+#[test]
+fn filter_trials_stop_at_best_size() {
+    // Rows of noise which each brighten by one, so only compress well with the Up filter
+    let mut state = 1u32;
+    let row: Vec<u8> = (0..256)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+    let data: Vec<u8> = (0..64u8)
+        .flat_map(|y| row.iter().map(move |v| v.wrapping_add(y)))
+        .collect();
+    let image = RawImage::new(
+        256,
+        64,
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Eight,
+        data,
+    )
+    .unwrap();
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = events.clone();
+    let mut opts = Options::from_preset(0);
+    opts.fast_evaluation = false;
+    opts.color_type_reduction = false;
+    opts.bit_depth_reduction = false;
+    opts.palette_reduction = false;
+    opts.filter = indexset! {RowFilter::Up, RowFilter::None, RowFilter::Sub, RowFilter::Average};
+    opts.progress = Some(ProgressCallback::new(move |event| {
+        sink.lock().unwrap().push(event)
+    }));
+    // Run the trials in order, so that the winner is tried first
+    #[cfg(feature = "parallel")]
+    {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        opts.thread_pool = Some(Arc::new(pool));
+    }
+    image.create_optimized_png(&opts).unwrap();
+
+    // Every later trial gives up once it exceeds the size of the first
+    let events = events.lock().unwrap();
+    let completed: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            ProgressEvent::DeflateTrialCompleted { filter, size } => Some((*filter, *size)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(completed.len(), 4);
+    assert!(matches!(completed[0], (RowFilter::Up, Some(_))));
+    assert!(completed[1..].iter().all(|(_, size)| size.is_none()));
+}
+
+#[test]
+fn converging_filters_share_deflate() {
+    // Small noise which brightens every few rows, where these strategies all produce the same
+    // filtered data, which is only compressed once
+    let mut state = 1u32;
+    let row: Vec<u8> = (0..512)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state % 8) as u8 + 100
+        })
+        .collect();
+    let data: Vec<u8> = (0..256u32)
+        .flat_map(|y| row.iter().map(move |v| v + (y / 4) as u8))
+        .collect();
+    let image = RawImage::new(
+        512,
+        256,
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Eight,
+        data,
+    )
+    .unwrap();
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = events.clone();
+    let mut opts = Options::from_preset(2);
+    opts.fast_evaluation = false;
+    opts.color_type_reduction = false;
+    opts.bit_depth_reduction = false;
+    opts.filter = indexset! {RowFilter::Entropy, RowFilter::Bigrams, RowFilter::BigEnt};
+    opts.progress = Some(ProgressCallback::new(move |event| {
+        sink.lock().unwrap().push(event)
+    }));
+    let output = image.create_optimized_png(&opts).unwrap();
+    PngData::from_slice(&output, &Options::default()).unwrap();
+
+    let events = events.lock().unwrap();
+    let sizes: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            ProgressEvent::DeflateTrialCompleted { size, .. } => Some(*size),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(sizes.len(), 3);
+    assert!(sizes[0].is_some());
+    assert!(sizes.iter().all(|size| size == &sizes[0]));
+}
+
+/// Build a TIFF structure with GPS and Orientation tags, as found in an eXIf chunk
+fn exif_data(big_endian: bool) -> Vec<u8> {
+    let u16_bytes = |v: u16| {
+        if big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    };
+    let u32_bytes = |v: u32| {
+        if big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    };
+    let mut data = Vec::new();
+    data.extend_from_slice(if big_endian { b"MM" } else { b"II" });
+    data.extend_from_slice(&u16_bytes(42));
+    data.extend_from_slice(&u32_bytes(8));
+    data.extend_from_slice(&u16_bytes(2));
+    // Orientation: rotate 90 degrees
+    data.extend_from_slice(&u16_bytes(0x0112));
+    data.extend_from_slice(&u16_bytes(3));
+    data.extend_from_slice(&u32_bytes(1));
+    data.extend_from_slice(&u16_bytes(6));
+    data.extend_from_slice(&[0, 0]);
+    // GPS IFD pointer
+    data.extend_from_slice(&u16_bytes(0x8825));
+    data.extend_from_slice(&u16_bytes(4));
+    data.extend_from_slice(&u32_bytes(1));
+    data.extend_from_slice(&u32_bytes(38));
+    data.extend_from_slice(&u32_bytes(0));
+    data.extend_from_slice(&[0xAA; 16]);
+    data
+}
+
+#[test]
+fn exif_orientation_only_parsing() {
+    for big_endian in [false, true] {
+        let exif = exif_orientation_only(&exif_data(big_endian)).unwrap();
+        assert_eq!(exif.len(), 26);
+        assert_eq!(exif[0..8], exif_data(big_endian)[0..8]);
+        let entry_count = if big_endian { [0, 1] } else { [1, 0] };
+        assert_eq!(exif[8..10], entry_count);
+        assert_eq!(exif[10..22], exif_data(big_endian)[10..22]);
+        assert_eq!(exif[22..26], [0; 4]);
+    }
+
+    // Malformed data must not panic
+    let exif = exif_data(false);
+    for len in 0..exif.len() {
+        assert!(exif_orientation_only(&exif[..len]).map_or(true, |e| e.len() == 26));
+    }
+    let mut bad_offset = exif.clone();
+    bad_offset[4] = 0xFF;
+    assert!(exif_orientation_only(&bad_offset).is_none());
+    assert!(exif_orientation_only(b"XX\0\x2a\0\0\0\x08").is_none());
+}
+
+#[test]
+fn strip_exif_except_orientation() {
+    let mut raw = RawImage::new(
+        1,
+        1,
+        ColorType::GrayscaleAlpha,
+        BitDepth::Eight,
+        vec![0, 255],
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"eXIf", exif_data(true));
+    let opts = Options {
+        strip: StripChunks::Safe,
+        strip_exif_except_orientation: true,
+        ..Default::default()
+    };
+    let output = raw.create_optimized_png(&opts).unwrap();
+
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    let exif = png
+        .aux_chunks()
+        .iter()
+        .find(|c| &c.name == b"eXIf")
+        .unwrap();
+    assert_eq!(exif.data, exif_orientation_only(&exif_data(true)).unwrap());
+}
+
+#[test]
+fn offs_and_scal_strip_levels() {
+    let offset = ImageOffset {
+        x: -20,
+        y: 300,
+        unit_is_micrometer: true,
+    };
+    let scale = PhysicalScale {
+        unit_is_radian: false,
+        pixel_width: "2.5e-5".to_owned(),
+        pixel_height: "0.000025".to_owned(),
+    };
+    let input = gray_pixel_with_chunks(
+        &[
+            (*b"oFFs", offset.chunk_data()),
+            (*b"sCAL", scale.chunk_data()),
+        ],
+        &Options::default(),
+    );
+
+    let levels = [
+        (StripChunks::None, 1, 1),
+        (StripChunks::Safe, 1, 1),
+        (StripChunks::Strip(indexset! {*b"oFFs"}), 0, 1),
+        (StripChunks::Keep(indexset! {*b"oFFs"}), 1, 0),
+        (StripChunks::All, 0, 0),
+    ];
+    for (strip, offs_count, scal_count) in levels {
+        let opts = Options {
+            strip,
+            ..Options::default()
+        };
+        let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+        let png = PngData::from_slice(&output, &Options::default()).unwrap();
+        assert_eq!(count_chunk(&png, b"oFFs"), offs_count);
+        assert_eq!(count_chunk(&png, b"sCAL"), scal_count);
+        for chunk in png.aux_chunks() {
+            match &chunk.name {
+                b"oFFs" => assert_eq!(parse_offs_chunk(&chunk.data).unwrap(), offset),
+                b"sCAL" => assert_eq!(parse_scal_chunk(&chunk.data).unwrap(), scale),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[test]
+fn scal_invalid() {
+    for data in [
+        &b"\x011.5"[..],
+        b"\x031\x001",
+        b"\x011\x00-2",
+        b"\x010\x000.5",
+        b"\x011.5\x00e5",
+        b"\x011,5\x001",
+        b"\x01.\x001",
+        b"\x011\x001\x001",
+    ] {
+        assert!(parse_scal_chunk(data).is_err(), "{:?}", data);
+    }
+    for data in [&b"\x011\x00+2"[..], b"\x02.5\x003.E+2", b"\x0110e-3\x001."] {
+        assert!(parse_scal_chunk(data).is_ok(), "{:?}", data);
+    }
+
+    let input =
+        gray_pixel_with_chunks(&[(*b"sCAL", b"\x01abc\x001".to_vec())], &Options::default());
+    assert!(oxipng::optimize_from_memory(&input, &Options::default()).is_err());
+    let opts = Options {
+        fix_errors: true,
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    assert_eq!(count_chunk(&png, b"sCAL"), 0);
+}
+
+#[test]
+fn hdr_chunks_preserved() {
+    let cicp = CodingIndependentCodePoints {
+        color_primaries: 9,
+        transfer_function: 16,
+        matrix_coefficients: 0,
+        video_full_range: true,
+    };
+    let mdcv = MasteringDisplayColorVolume {
+        primaries: [(35400, 14600), (8500, 39850), (6550, 2300)],
+        white_point: (15635, 16450),
+        max_luminance: 10_000_000,
+        min_luminance: 50,
+    };
+    let clli = ContentLightLevel {
+        max_content_light_level: 4_000_000,
+        max_frame_average_light_level: 1_000_000,
+    };
+    // Every sample could be reduced to 8 bits by scaling, but not losslessly
+    let data = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC].repeat(4);
+    let mut raw = RawImage::new(
+        2,
+        2,
+        ColorType::RGB {
+            transparent_color: None,
+        },
+        BitDepth::Sixteen,
+        data,
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"cICP", cicp.chunk_data());
+    raw.add_png_chunk(*b"mDCV", mdcv.chunk_data());
+    raw.add_png_chunk(*b"cLLI", clli.chunk_data());
+    let input = raw.create_optimized_png(&Options::default()).unwrap();
+
+    let opts = Options {
+        strip: StripChunks::Safe,
+        scale_16: true,
+        lossless_only: false,
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    validate_png(&output).unwrap();
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    // Scaling would lose the precision the HDR metadata describes
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Sixteen);
+    let chunk_data = |name: &[u8; 4]| {
+        &png.aux_chunks()
+            .iter()
+            .find(|c| &c.name == name)
+            .unwrap()
+            .data
+    };
+    assert_eq!(parse_cicp_chunk(chunk_data(b"cICP")).unwrap(), cicp);
+    assert_eq!(parse_mdcv_chunk(chunk_data(b"mDCV")).unwrap(), mdcv);
+    assert_eq!(parse_clli_chunk(chunk_data(b"cLLI")).unwrap(), clli);
+
+    let output = oxipng::optimize_from_memory(
+        &input,
+        &Options {
+            strip: StripChunks::All,
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    assert!(png.aux_chunks().iter().all(|c| &c.name == b"IDAT"));
+}
+
+#[test]
+fn hdr_chunks_spec_names() {
+    // The chunks have the names of the final specification, as written by libpng
+    let input = std::fs::read("tests/files/hdr_chunks.png").unwrap();
+    let opts = Options {
+        strip: StripChunks::Safe,
+        scale_16: true,
+        lossless_only: false,
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    validate_png(&output).unwrap();
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Sixteen);
+    for name in [b"cICP", b"mDCV", b"cLLI"] {
+        assert_eq!(count_chunk(&png, name), 1);
+    }
+}
+
+#[test]
+fn hdr_chunks_invalid() {
+    assert!(parse_cicp_chunk(&[9, 16, 0]).is_err());
+    assert!(parse_cicp_chunk(&[9, 16, 1, 1]).is_err());
+    assert!(parse_cicp_chunk(&[9, 16, 0, 2]).is_err());
+    assert!(parse_mdcv_chunk(&[0; 23]).is_err());
+    assert!(parse_clli_chunk(&[0; 9]).is_err());
+
+    for (name, data) in [
+        (*b"cICP", vec![9, 16, 0, 1, 0]),
+        (*b"mDCV", vec![0; 20]),
+        (*b"cLLI", vec![0; 4]),
+    ] {
+        let input = gray_pixel_with_chunks(&[(name, data)], &Options::default());
+        assert!(oxipng::optimize_from_memory(&input, &Options::default()).is_err());
+        let opts = Options {
+            fix_errors: true,
+            ..Options::default()
+        };
+        let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+        let png = PngData::from_slice(&output, &Options::default()).unwrap();
+        assert_eq!(count_chunk(&png, &name), 0);
+    }
+}
+
+#[test]
+fn fix_errors_idat_crc() {
+    let mut data = std::fs::read("tests/files/rgb_8_should_be_rgb_8.png").unwrap();
+    // Corrupt the CRC of the first IDAT chunk
+    let idat = data.windows(4).position(|w| w == b"IDAT").unwrap();
+    let length = u32::from_be_bytes(data[idat - 4..idat].try_into().unwrap()) as usize;
+    let crc = idat + 4 + length;
+    data[crc] ^= 0xFF;
+
+    assert!(PngData::from_slice(&data, &Options::default()).is_err());
+
+    let opts = Options {
+        fix_errors: true,
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&data, &opts).unwrap();
+    // The output must have correct CRCs
+    PngData::from_slice(&output, &Options::default()).unwrap();
+
+    // A chunk running past the end of the file is still an error
+    let truncated = &data[..crc - 1];
+    assert!(matches!(
+        PngData::from_slice(truncated, &opts),
+        Err(PngError::TruncatedChunk { chunk, expected, got })
+            if &chunk == b"IDAT" && expected == length + 4 && got == length - 1
+    ));
+}
+
+#[test]
+fn idat_zero_length_interleaved() {
+    let input = PathBuf::from("tests/files/idat_zero_length_interleaved.png");
+    let original =
+        PngData::new(Path::new("tests/files/issue-82.png"), &Options::default()).unwrap();
+
+    let png = PngData::new(&input, &Options::default()).unwrap();
+    assert_eq!(png.raw().data(), original.raw().data());
+    assert_eq!(png.idat_data(), original.idat_data());
+    // Only the position of the first IDAT is recorded, with the interleaved chunk after it
+    assert_eq!(count_chunk(&png, b"IDAT"), 1);
+    let idat = png.aux_chunks().iter().position(|c| &c.name == b"IDAT");
+    let text = png.aux_chunks().iter().position(|c| &c.name == b"tEXt");
+    assert!(idat < text);
+
+    let optimize = |path| {
+        let output =
+            oxipng::optimize_from_memory(&std::fs::read(path).unwrap(), &Options::default())
+                .unwrap();
+        PngData::from_slice(&output, &Options::default()).unwrap()
+    };
+    let png = optimize(input.as_path());
+    assert_eq!(
+        png.raw().data(),
+        optimize(Path::new("tests/files/issue-82.png")).raw().data()
+    );
+    assert_eq!(count_chunk(&png, b"tEXt"), 1);
+}
@@ -37,9 +37,9 @@ fn test_it_converts(
     let (output, opts) = get_opts(&input);
     let png = PngData::new(&input, &opts).unwrap();
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), color_type_in);
-    assert_eq!(png.raw.ihdr.bit_depth, bit_depth_in);
-    assert_eq!(png.raw.ihdr.interlaced, Interlacing::Adam7);
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), color_type_in);
+    assert_eq!(png.raw().ihdr().bit_depth, bit_depth_in);
+    assert_eq!(png.raw().ihdr().interlaced, Interlacing::Adam7);
 
     match oxipng::optimize(&InFile::Path(input), &output, &opts) {
         Ok(_) => (),
@@ -56,8 +56,11 @@ fn test_it_converts(
         }
     };
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), color_type_out);
-    assert_eq!(png.raw.ihdr.bit_depth, bit_depth_out);
+    assert_eq!(
+        png.raw().ihdr().color_type.png_header_code(),
+        color_type_out
+    );
+    assert_eq!(png.raw().ihdr().bit_depth, bit_depth_out);
 
     remove_file(output).ok();
 }
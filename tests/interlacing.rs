@@ -35,10 +35,10 @@ fn test_it_converts(
     let (output, mut opts) = get_opts(&input);
     let png = PngData::new(&input, &opts).unwrap();
     opts.interlace = Some(interlace);
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), color_type_in);
-    assert_eq!(png.raw.ihdr.bit_depth, bit_depth_in);
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), color_type_in);
+    assert_eq!(png.raw().ihdr().bit_depth, bit_depth_in);
     assert_eq!(
-        png.raw.ihdr.interlaced,
+        png.raw().ihdr().interlaced,
         if interlace == Interlacing::Adam7 {
             Interlacing::None
         } else {
@@ -61,8 +61,11 @@ fn test_it_converts(
         }
     };
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), color_type_out);
-    assert_eq!(png.raw.ihdr.bit_depth, bit_depth_out);
+    assert_eq!(
+        png.raw().ihdr().color_type.png_header_code(),
+        color_type_out
+    );
+    assert_eq!(png.raw().ihdr().bit_depth, bit_depth_out);
 
     remove_file(output).ok();
 }
@@ -210,3 +213,31 @@ fn interlace_palette_1() {
         BitDepth::One,
     );
 }
+
+#[test]
+fn interlace_auto_keeps_smaller() {
+    for file in [
+        "tests/files/interlaced_rgb_8_should_be_rgb_8.png",
+        "tests/files/rgb_8_should_be_rgb_8.png",
+    ] {
+        let input = std::fs::read(file).unwrap();
+        let mut opts = Options::from_preset(1);
+        let mut sizes = Vec::new();
+        for interlacing in [Interlacing::None, Interlacing::Adam7] {
+            opts.interlace = Some(interlacing);
+            sizes.push(optimize_from_memory(&input, &opts).unwrap().len());
+        }
+
+        opts.interlace_auto = true;
+        let (output, report) = optimize_from_memory_with_report(&input, &opts).unwrap();
+        assert_eq!(output.len(), *sizes.iter().min().unwrap(), "{}", file);
+        let expected = if sizes[1] < sizes[0] {
+            Interlacing::Adam7
+        } else {
+            Interlacing::None
+        };
+        assert_eq!(report.interlacing, expected, "{}", file);
+        let png = PngData::from_slice(&output, &opts).unwrap();
+        assert_eq!(png.raw().ihdr().interlaced, expected, "{}", file);
+    }
+}
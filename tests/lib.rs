@@ -1,7 +1,9 @@
+use oxipng::internal_tests::*;
 use oxipng::*;
 use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
+use std::sync::Arc;
 
 #[test]
 fn optimize_from_memory() {
@@ -23,6 +25,59 @@ fn optimize_from_memory_corrupted() {
     assert!(result.is_err());
 }
 
+#[test]
+fn optimize_from_memory_mng() {
+    let png = fs::read("tests/files/fully_optimized.png").unwrap();
+    for (signature, format) in [(b"\x8aMNG\r\n\x1a\n", "MNG"), (b"\x8bJNG\r\n\x1a\n", "JNG")] {
+        let mut data = signature.to_vec();
+        data.extend_from_slice(&png[8..]);
+        let result = oxipng::optimize_from_memory(&data, &Options::default());
+        assert!(
+            matches!(result, Err(PngError::UnsupportedFormat(f)) if f == format),
+            "{:?}",
+            result
+        );
+        assert!(matches!(
+            validate_png(&data),
+            Err(PngError::UnsupportedFormat(f)) if f == format
+        ));
+    }
+}
+
+#[test]
+#[cfg(feature = "mng")]
+fn extract_png_from_mng() {
+    let png = fs::read("tests/files/fully_optimized.png").unwrap();
+    let chunk = |name: &[u8; 4], data: &[u8]| {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(name);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+        chunk
+    };
+    let mut mng = b"\x8aMNG\r\n\x1a\n".to_vec();
+    mng.extend(chunk(b"MHDR", &[0; 28]));
+    mng.extend(chunk(b"TERM", &[0]));
+    mng.extend_from_slice(&png[8..]);
+    mng.extend(chunk(b"MEND", &[]));
+
+    let extracted = oxipng::extract_png_from_mng(&mng).unwrap();
+    assert_eq!(
+        PngData::from_slice(&extracted, &Options::default())
+            .unwrap()
+            .content_hash(),
+        PngData::from_slice(&png, &Options::default())
+            .unwrap()
+            .content_hash()
+    );
+    oxipng::optimize_from_memory(&extracted, &Options::default()).unwrap();
+
+    let mut empty = b"\x8aMNG\r\n\x1a\n".to_vec();
+    empty.extend(chunk(b"MHDR", &[0; 28]));
+    empty.extend(chunk(b"MEND", &[]));
+    assert!(oxipng::extract_png_from_mng(&empty).is_err());
+}
+
 #[test]
 fn optimize_from_memory_apng() {
     let mut in_file = File::open("tests/files/apng_file.png").unwrap();
@@ -33,6 +88,247 @@ fn optimize_from_memory_apng() {
     assert!(result.is_ok());
 }
 
+#[test]
+fn optimize_from_memory_with_report() {
+    let in_file_buf = fs::read("tests/files/rgb_16_should_be_rgb_8.png").unwrap();
+
+    let (output, report) =
+        oxipng::optimize_from_memory_with_report(&in_file_buf, &Options::default()).unwrap();
+    assert_eq!(report.original_size, in_file_buf.len());
+    assert_eq!(report.final_size, output.len());
+    assert!(report.filter.is_some());
+    assert_eq!(report.bit_depth, BitDepth::Eight);
+    assert!(report.reductions.contains(&ReductionKind::BitDepth16To8));
+    assert!(!report.interlacing_changed);
+}
+
+#[test]
+fn optimize_from_memory_with_report_unchanged() {
+    let in_file_buf = fs::read("tests/files/fully_optimized.png").unwrap();
+
+    let (output, report) =
+        oxipng::optimize_from_memory_with_report(&in_file_buf, &Options::default()).unwrap();
+    assert_eq!(output, in_file_buf);
+    assert_eq!(report.final_size, report.original_size);
+    assert!(report.filter.is_none());
+    assert!(report.reductions.is_empty());
+}
+
+#[test]
+fn optimize_from_memory_no_improvement() {
+    let in_file_buf = fs::read("tests/files/fully_optimized.png").unwrap();
+    // Recompressing at the lowest level would make the file larger
+    let opts = Options {
+        deflate: Deflaters::Libdeflater { compression: 1 },
+        filter: indexset! { RowFilter::None },
+        strip: StripChunks::None,
+        ..Options::default()
+    };
+    let forced = Options {
+        force: true,
+        ..opts.clone()
+    };
+    let grown = oxipng::optimize_from_memory(&in_file_buf, &forced).unwrap();
+    assert!(grown.len() > in_file_buf.len());
+
+    let (output, report) = oxipng::optimize_from_memory_with_report(&in_file_buf, &opts).unwrap();
+    assert_eq!(output, in_file_buf);
+    assert!(report.no_improvement);
+    assert_eq!(report.final_size, report.original_size);
+    assert!(report.filter.is_none());
+
+    // When the output is smaller it is used, and the flag is not set
+    let in_file_buf = fs::read("tests/files/rgb_16_should_be_rgb_8.png").unwrap();
+    let (output, report) = oxipng::optimize_from_memory_with_report(&in_file_buf, &opts).unwrap();
+    assert!(output.len() < in_file_buf.len());
+    assert!(!report.no_improvement);
+}
+
+#[test]
+fn check_savings() {
+    let path = "tests/files/rgb_16_should_be_rgb_8.png";
+    let in_file_buf = fs::read(path).unwrap();
+    let optimized = oxipng::optimize_from_memory(&in_file_buf, &Options::default()).unwrap();
+
+    let result = oxipng::check(&InFile::Path(path.into()), &Options::default()).unwrap();
+    assert!(result.would_shrink());
+    assert_eq!(result.original_size, in_file_buf.len());
+    assert_eq!(result.savings(), in_file_buf.len() - optimized.len());
+    // The input must not have been touched
+    assert_eq!(fs::read(path).unwrap(), in_file_buf);
+
+    let input = InFile::Path("tests/files/fully_optimized.png".into());
+    let result = oxipng::check(&input, &Options::default()).unwrap();
+    assert!(!result.would_shrink());
+    assert_eq!(result.savings(), 0);
+}
+
+#[test]
+fn optimize_check_only_fast() {
+    let in_file_buf = fs::read("tests/files/rgb_16_should_be_rgb_8.png").unwrap();
+    let opts = Options {
+        check_only_fast: true,
+        ..Options::default()
+    };
+
+    let (optimized, report) =
+        oxipng::optimize_from_memory_with_report(&in_file_buf, &opts).unwrap();
+    assert!(!report.already_optimal);
+    assert!(optimized.len() < in_file_buf.len());
+
+    let (output, report) = oxipng::optimize_from_memory_with_report(&optimized, &opts).unwrap();
+    assert!(report.already_optimal);
+    assert_eq!(output, optimized);
+}
+
+#[test]
+fn optimize_with_buffer_pool() {
+    let in_file_buf = fs::read("tests/files/rgb_16_should_be_rgb_8.png").unwrap();
+    let pool = Arc::new(BufferPool::new());
+    let opts = Options {
+        buffer_pool: Some(pool.clone()),
+        ..Options::default()
+    };
+
+    let first = oxipng::optimize_from_memory(&in_file_buf, &opts).unwrap();
+    let first_allocations = pool.allocations();
+    assert!(first_allocations > 0);
+    let second = oxipng::optimize_from_memory(&in_file_buf, &opts).unwrap();
+    assert_eq!(first, second);
+    // The second run should mostly reuse the buffers of the first
+    assert!(pool.allocations() - first_allocations < first_allocations);
+}
+
+#[test]
+#[cfg(threads)]
+fn optimize_in_thread_pool() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(2)
+        .thread_name(|i| format!("oxipng-pool-{}", i))
+        .build()
+        .unwrap();
+    let outside_pool = Arc::new(AtomicBool::new(false));
+    let flag = outside_pool.clone();
+    let opts = Options {
+        thread_pool: Some(Arc::new(pool)),
+        progress: Some(ProgressCallback::new(move |_| {
+            let name = std::thread::current().name().map(str::to_owned);
+            if !name.map_or(false, |n| n.starts_with("oxipng-pool-")) {
+                flag.store(true, Ordering::SeqCst);
+            }
+        })),
+        ..Default::default()
+    };
+
+    let in_file_buf = fs::read("tests/files/rgb_16_should_be_rgb_8.png").unwrap();
+    let result = oxipng::optimize_from_memory(&in_file_buf, &opts);
+    assert!(result.is_ok());
+    assert!(!outside_pool.load(Ordering::SeqCst));
+}
+
+#[test]
+#[cfg(not(threads))]
+fn optimize_on_calling_thread() {
+    use std::sync::Mutex;
+
+    let calling_thread = std::thread::current().id();
+    let other_threads = Arc::new(Mutex::new(Vec::new()));
+    let sink = other_threads.clone();
+    let mut opts = Options::from_preset(3);
+    opts.progress = Some(ProgressCallback::new(move |_| {
+        let id = std::thread::current().id();
+        if id != calling_thread {
+            sink.lock().unwrap().push(id);
+        }
+    }));
+    // The pool is ignored, as no threads may be spawned
+    #[cfg(feature = "parallel")]
+    {
+        opts.thread_pool = Some(Arc::new(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(4)
+                .build()
+                .unwrap(),
+        ));
+    }
+
+    let in_file_buf = fs::read("tests/files/rgba_8_should_be_palette_8.png").unwrap();
+    let output = oxipng::optimize_from_memory(&in_file_buf, &opts).unwrap();
+    assert!(output.len() < in_file_buf.len());
+    assert!(other_threads.lock().unwrap().is_empty());
+}
+
+#[test]
+fn optimize_cancelled() {
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let flag = cancel.clone();
+    let opts = Options {
+        force: true,
+        cancel: Some(cancel),
+        // Cancel as soon as any work has started
+        progress: Some(ProgressCallback::new(move |_| {
+            flag.store(true, Ordering::SeqCst)
+        })),
+        ..Default::default()
+    };
+
+    let output = Path::new("tests/files/optimize_cancelled.out.png");
+    let handle = std::thread::spawn(move || {
+        oxipng::optimize(
+            &"tests/files/rgb_16_should_be_rgb_8.png".into(),
+            &OutFile::Path(Some(output.to_owned())),
+            &opts,
+        )
+    });
+    let result = handle.join().unwrap();
+    assert!(matches!(result, Err(PngError::Cancelled)));
+    assert!(!output.exists());
+}
+
+#[test]
+fn optimize_from_reader() {
+    use std::io::Cursor;
+
+    let in_file_buf = fs::read("tests/files/rgb_16_should_be_rgb_8.png").unwrap();
+    let mut out = Cursor::new(Vec::new());
+    let result =
+        oxipng::optimize_from_reader(Cursor::new(&in_file_buf), &mut out, &Options::default());
+    assert!(result.is_ok());
+
+    let out = out.into_inner();
+    assert!(out.len() < in_file_buf.len());
+    assert_eq!(
+        out,
+        oxipng::optimize_from_memory(&in_file_buf, &Options::default()).unwrap()
+    );
+}
+
+#[test]
+#[cfg(feature = "mmap")]
+fn optimize_from_mmap() {
+    let input = std::path::Path::new("tests/files/rgb_16_should_be_rgb_8.png");
+    let output = input.with_extension("mmap.out.png");
+    let result = oxipng::optimize_from_mmap(
+        input,
+        &OutFile::Path(Some(output.clone())),
+        &Options::default(),
+    );
+    assert!(result.is_ok());
+
+    let out = fs::read(&output).unwrap();
+    fs::remove_file(&output).ok();
+    let in_file_buf = fs::read(input).unwrap();
+    assert_eq!(
+        out,
+        oxipng::optimize_from_memory(&in_file_buf, &Options::default()).unwrap()
+    );
+}
+
 #[test]
 fn optimize() {
     let result = oxipng::optimize(
@@ -53,6 +349,70 @@ fn optimize_corrupted() {
     assert!(result.is_err());
 }
 
+fn test_apng_frames(input: &str) -> PngData {
+    let opts = Options {
+        optimize_animation: true,
+        ..Options::default()
+    };
+    let input = fs::read(input).unwrap();
+    let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    assert!(output.len() < input.len());
+
+    let original = PngData::from_slice(&input, &opts).unwrap();
+    let optimized = PngData::from_slice(&output, &opts).unwrap();
+    let original_frames = decode_frames(&original).unwrap();
+    let optimized_frames = decode_frames(&optimized).unwrap();
+    assert_eq!(original_frames.len(), optimized_frames.len());
+    for (a, b) in original_frames.iter().zip(&optimized_frames) {
+        // Only the sequence numbers may change
+        let control = FrameControl {
+            sequence_number: a.control.sequence_number,
+            ..b.control
+        };
+        assert_eq!(a.control, control);
+        assert_eq!(b.image.ihdr().color_type, optimized.raw().ihdr().color_type);
+        assert_eq!(b.image.ihdr().bit_depth, optimized.raw().ihdr().bit_depth);
+    }
+    let sequence: Vec<_> = optimized
+        .aux_chunks()
+        .iter()
+        .filter(|c| &c.name == b"fcTL" || &c.name == b"fdAT")
+        .map(|c| u32::from_be_bytes(c.data[0..4].try_into().unwrap()))
+        .collect();
+    assert!(sequence.iter().copied().eq(0..sequence.len() as u32));
+
+    #[cfg(feature = "sanity-checks")]
+    assert!(validate_output(&output, &input));
+
+    optimized
+}
+
+#[test]
+fn optimize_apng_frames() {
+    test_apng_frames("tests/files/apng_file.png");
+}
+
+#[test]
+fn optimize_apng_frames_reduced() {
+    let png = test_apng_frames("tests/files/apng_reducible.png");
+    // All frames should have been reduced together from RGBA
+    assert_ne!(png.raw().ihdr().color_type.png_header_code(), 6);
+}
+
+#[test]
+fn optimize_apng_frames_shared_palette() {
+    // The default image only uses red and green, and the other frame only blue and white
+    let png = test_apng_frames("tests/files/apng_disjoint_colors.png");
+    let palette = match &png.raw().ihdr().color_type {
+        ColorType::Indexed { palette } => palette,
+        _ => panic!("The frames should have been reduced to a palette"),
+    };
+    assert_eq!(palette.len(), 4);
+    for color in [(255, 0, 0), (0, 255, 0), (0, 0, 255), (255, 255, 255)] {
+        assert!(palette.contains(&rgb::RGBA8::new(color.0, color.1, color.2, 255)));
+    }
+}
+
 #[test]
 fn optimize_apng() {
     let result = oxipng::optimize(
@@ -63,6 +423,62 @@ fn optimize_apng() {
     assert!(result.is_ok());
 }
 
+/// Set the number of frames in the acTL chunk of an APNG, keeping its CRC correct
+fn with_num_frames(apng: &[u8], num_frames: u32) -> Vec<u8> {
+    let mut data = apng.to_vec();
+    let name = data.windows(4).position(|w| w == b"acTL").unwrap();
+    data[name + 4..name + 8].copy_from_slice(&num_frames.to_be_bytes());
+    let crc = crc32(&data[name..name + 12]);
+    data[name + 12..name + 16].copy_from_slice(&crc.to_be_bytes());
+    data
+}
+
+/// The headers of the acTL chunk of a PNG
+fn actl(png: &PngData) -> AnimationControl {
+    let chunk = png
+        .aux_chunks()
+        .iter()
+        .find(|c| &c.name == b"acTL")
+        .unwrap();
+    parse_actl_chunk(&chunk.data).unwrap()
+}
+
+#[test]
+fn apng_wrong_num_frames() {
+    let original = fs::read("tests/files/apng_file.png").unwrap();
+    let png = PngData::from_slice(&original, &Options::default()).unwrap();
+    let num_frames = actl(&png).num_frames;
+    let num_fctl = png
+        .aux_chunks()
+        .iter()
+        .filter(|c| &c.name == b"fcTL")
+        .count();
+    assert_eq!(num_frames as usize, num_fctl);
+
+    let data = with_num_frames(&original, num_frames + 3);
+    assert!(matches!(
+        PngData::from_slice(&data, &Options::default()),
+        Err(PngError::InvalidChunk(name, _)) if &name == b"acTL"
+    ));
+    assert!(validate_png(&data).is_err());
+
+    let opts = Options {
+        fix_errors: true,
+        collect_warnings: true,
+        ..Options::default()
+    };
+    let png = PngData::from_slice(&data, &opts).unwrap();
+    assert_eq!(actl(&png).num_frames, num_frames);
+    let (output, report) = oxipng::optimize_from_memory_with_report(&data, &opts).unwrap();
+    assert!(matches!(
+        report.warnings[..],
+        [PngWarning::InvalidChunk(name, _)] if &name == b"acTL"
+    ));
+    validate_png(&output).unwrap();
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    assert_eq!(actl(&png).num_frames, num_frames);
+}
+
 #[test]
 fn optimize_srgb_icc() {
     let file = fs::read("tests/files/badsrgb.png").unwrap();
@@ -75,3 +491,782 @@ fn optimize_srgb_icc() {
     let result = oxipng::optimize_from_memory(&file, &opts);
     assert!(result.unwrap().len() < 1000);
 }
+
+#[test]
+fn optimize_many() {
+    let out_dir = std::env::temp_dir().join("oxipng_optimize_many");
+    fs::create_dir_all(&out_dir).unwrap();
+    let inputs = [
+        InFile::from("tests/files/rgb_16_should_be_rgb_8.png"),
+        InFile::from("tests/files/corrupted_header.png"),
+        InFile::from("tests/files/fully_optimized.png"),
+    ];
+    let results = oxipng::optimize_many(&inputs, Some(&out_dir), &Options::default());
+    assert_eq!(results.len(), 3);
+
+    let report = results[0].as_ref().unwrap();
+    assert_eq!(report.bit_depth, BitDepth::Eight);
+    let out = fs::read(out_dir.join("rgb_16_should_be_rgb_8.png")).unwrap();
+    assert_eq!(out.len(), report.final_size);
+    assert!(results[1].is_err());
+    assert!(results[2].is_ok());
+    fs::remove_dir_all(&out_dir).ok();
+}
+
+#[test]
+fn optimize_best_of() {
+    let input = fs::read("tests/files/rgba_8_should_be_palette_8.png").unwrap();
+    let mut only_none = Options::from_preset(1);
+    only_none.filter = indexset! {RowFilter::None};
+    let configs = [
+        only_none,
+        Options::from_preset(0),
+        Options::from_preset(3),
+        // A duplicate can't win, as ties go to the first
+        Options::from_preset(3),
+    ];
+    let (best, index) = oxipng::optimize_best_of(&input, &configs).unwrap();
+    for (i, opts) in configs.iter().enumerate() {
+        let output = oxipng::optimize_from_memory(&input, opts).unwrap();
+        assert!(best.len() <= output.len());
+        if i == index {
+            assert_eq!(best, output);
+        }
+    }
+    assert_ne!(index, 3);
+
+    let corrupted = fs::read("tests/files/corrupted_header.png").unwrap();
+    assert!(oxipng::optimize_best_of(&corrupted, &configs).is_err());
+    assert!(oxipng::optimize_best_of(&input, &[]).is_err());
+}
+
+#[test]
+fn optimize_phase_timeouts() {
+    let input = fs::read("tests/files/rgb_16_should_be_rgb_8.png").unwrap();
+    // Without time to evaluate filters, a filter is picked for the main compression instead
+    let opts = Options {
+        phase_timeouts: Some(PhaseTimeouts {
+            filter_evaluation: Some(std::time::Duration::ZERO),
+            deflate: None,
+        }),
+        ..Options::default()
+    };
+    let (output, report) = oxipng::optimize_from_memory_with_report(&input, &opts).unwrap();
+    assert!(output.len() < input.len());
+    assert_eq!(report.bit_depth, BitDepth::Eight);
+    assert!(report.filter.is_some());
+
+    // Without time for the main compression, the input is kept
+    let opts = Options {
+        phase_timeouts: Some(PhaseTimeouts {
+            filter_evaluation: None,
+            deflate: Some(std::time::Duration::ZERO),
+        }),
+        filter: [RowFilter::None].into_iter().collect(),
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    assert_eq!(output, input);
+}
+
+#[test]
+fn optimize_target_size() {
+    let input = fs::read("tests/files/rgb_16_should_be_rgb_8.png").unwrap();
+    let optimize = |target_size| {
+        let trials = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = trials.clone();
+        let opts = Options {
+            target_size,
+            progress: Some(ProgressCallback::new(move |event| {
+                if matches!(event, ProgressEvent::FilterTrialStarted { .. }) {
+                    counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                }
+            })),
+            ..Options::from_preset(6)
+        };
+        let (output, report) = oxipng::optimize_from_memory_with_report(&input, &opts).unwrap();
+        (
+            output,
+            report,
+            trials.load(std::sync::atomic::Ordering::SeqCst),
+        )
+    };
+    let (full, report, full_trials) = optimize(None);
+    assert_eq!(report.target_size_met, None);
+
+    // Any result that is smaller than the input is good enough, so effort stops early
+    let (output, report, trials) = optimize(Some(input.len()));
+    assert!(output.len() <= input.len());
+    assert_eq!(report.target_size_met, Some(true));
+    assert!(trials < full_trials, "{} >= {}", trials, full_trials);
+
+    // A target that can't be met takes full effort
+    let (output, report, trials) = optimize(Some(1));
+    assert_eq!(output.len(), full.len());
+    assert_eq!(report.target_size_met, Some(false));
+    assert_eq!(trials, full_trials);
+}
+
+#[test]
+#[cfg(all(feature = "binary", feature = "zopfli"))]
+fn to_cli_args_round_trip() {
+    let mut custom = Options::from_preset(4);
+    custom.strip = StripChunks::Keep([*b"iCCP", *b"tEXt"].into_iter().collect());
+    custom.interlace = None;
+    custom.optimize_alpha = true;
+    custom.palette_reduction = false;
+    custom.idat_recoding = false;
+    custom.timeout = Some(std::time::Duration::from_secs(7));
+    custom.trial_compression = Some(7);
+    custom.fixed_filter = Some(RowFilter::Paeth);
+    let mut zopfli = Options::max_compression();
+    zopfli.deflate = Deflaters::Zopfli {
+        iterations: std::num::NonZeroU8::new(15).unwrap(),
+    };
+    zopfli.strip = StripChunks::Safe;
+    let mut private = Options::from_preset(1);
+    private.strip = StripChunks::SafeKeepPrivate;
+    private.preserve_mtime = true;
+
+    let configs = (0..=6)
+        .map(Options::from_preset)
+        .chain([custom, zopfli, private]);
+    for opts in configs {
+        let args = opts.to_cli_args().unwrap();
+        let matches = oxipng::cli::command()
+            .try_get_matches_from(
+                ["oxipng", "file.png"]
+                    .into_iter()
+                    .map(String::from)
+                    .chain(args.clone()),
+            )
+            .unwrap();
+        let parsed = oxipng::cli::parse_options(&matches).unwrap();
+        assert_eq!(format!("{:?}", parsed), format!("{:?}", opts), "{:?}", args);
+    }
+
+    // Settings the command line can't express are rejected rather than dropped
+    let rejected: Vec<fn(&mut Options)> = vec![
+        |o| o.timeout = Some(std::time::Duration::from_millis(500)),
+        |o| o.optimize_icc = true,
+        |o| o.dedup_text = true,
+        |o| o.strip_exif_except_orientation = true,
+        |o| o.max_idat_size = Some(1000),
+        |o| o.chunk_order = Some(vec![*b"tEXt"]),
+        |o| {
+            o.physical_dimensions = Some(PhysicalDimensions {
+                x_pixels_per_unit: 2835,
+                y_pixels_per_unit: 2835,
+                unit_is_meter: true,
+            })
+        },
+        |o| o.time = TimeMode::Strip,
+        |o| {
+            o.force_color_type = Some((
+                ColorType::Grayscale {
+                    transparent_shade: None,
+                },
+                BitDepth::Eight,
+            ))
+        },
+        |o| o.force_bit_depth = Some(BitDepth::Eight),
+        |o| o.verify_output = true,
+        |o| o.max_chunk_size = Some(1000),
+        |o| o.target_size = Some(1000),
+        |o| o.phase_timeouts = Some(PhaseTimeouts::default()),
+        |o| {
+            o.filter.clear();
+            o.optimize_palette_order = true;
+        },
+    ];
+    for change in rejected {
+        let mut opts = Options::default();
+        change(&mut opts);
+        assert!(opts.to_cli_args().is_err(), "{:?}", opts);
+    }
+}
+
+#[test]
+fn to_cli_args_unsupported() {
+    let custom = Options {
+        deflate: Deflaters::Custom(Arc::new(TestDeflater::default())),
+        ..Options::default()
+    };
+    assert!(custom.to_cli_args().is_err());
+    #[cfg(feature = "zopfli")]
+    {
+        let zopfli = Options {
+            deflate: Deflaters::Zopfli {
+                iterations: std::num::NonZeroU8::new(5).unwrap(),
+            },
+            ..Options::default()
+        };
+        assert!(zopfli.to_cli_args().is_err());
+    }
+    let lossy = Options {
+        lossless_only: false,
+        ..Options::default()
+    };
+    assert!(lossy.to_cli_args().is_err());
+    let scaled = Options {
+        scale_16: true,
+        ..lossy
+    };
+    assert!(scaled
+        .to_cli_args()
+        .unwrap()
+        .contains(&"--scale16".to_owned()));
+}
+
+#[test]
+#[cfg(feature = "parallel")]
+fn optimize_deterministic_across_thread_counts() {
+    let files = [
+        "tests/files/rgb_16_should_be_rgb_8.png",
+        "tests/files/rgba_8_should_be_palette_8.png",
+        "tests/files/apng_file.png",
+    ];
+    let pools: Vec<_> = [1, 8]
+        .into_iter()
+        .map(|n| {
+            Arc::new(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(n)
+                    .build()
+                    .unwrap(),
+            )
+        })
+        .collect();
+    for file in files {
+        let input = fs::read(file).unwrap();
+        for level in [2, 3] {
+            let mut opts = Options::from_preset(level);
+            opts.optimize_palette_order = true;
+            let outputs: Vec<_> = pools
+                .iter()
+                .map(|pool| {
+                    opts.thread_pool = Some(pool.clone());
+                    oxipng::optimize_from_memory(&input, &opts).unwrap()
+                })
+                .collect();
+            assert!(
+                outputs[0] == outputs[1],
+                "{} differs at level {}",
+                file,
+                level
+            );
+        }
+    }
+}
+
+#[test]
+fn optimize_verify_output() {
+    let opts = Options {
+        verify_output: true,
+        ..Options::default()
+    };
+    for file in [
+        "tests/files/rgb_16_should_be_rgb_8.png",
+        "tests/files/apng_file.png",
+    ] {
+        let input = fs::read(file).unwrap();
+        let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+        assert!(output.len() < input.len());
+    }
+
+    // Stand in for a broken compressor by pairing data with the wrong compressed data
+    let data: Vec<u8> = (0..1000u32).map(|i| (i * i % 251) as u8).collect();
+    let compressed = deflate(&data, 6, &AtomicMin::new(None)).unwrap();
+    assert!(verify_deflated(&compressed, &data).is_ok());
+    let mut other = data.clone();
+    other[500] ^= 1;
+    let wrong = deflate(&other, 6, &AtomicMin::new(None)).unwrap();
+    assert!(matches!(
+        verify_deflated(&wrong, &data),
+        Err(PngError::VerificationFailed)
+    ));
+    let truncated = &compressed[..compressed.len() / 2];
+    assert!(matches!(
+        verify_deflated(truncated, &data),
+        Err(PngError::VerificationFailed)
+    ));
+    assert!(matches!(
+        verify_deflated(&compressed, &data[..999]),
+        Err(PngError::VerificationFailed)
+    ));
+}
+
+/// A deflater which returns a truncated stream that can't be inflated
+#[derive(Debug)]
+struct TruncatingDeflater;
+
+impl std::fmt::Display for TruncatingDeflater {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("truncating")
+    }
+}
+
+impl Deflater for TruncatingDeflater {
+    fn deflate(&self, data: &[u8], max_size: &AtomicMin) -> PngResult<Vec<u8>> {
+        let mut compressed = Deflaters::Libdeflater { compression: 6 }.deflate(data, max_size)?;
+        compressed.truncate(compressed.len() / 2);
+        Ok(compressed)
+    }
+}
+
+#[test]
+fn verify_output_rejects_bad_deflater() {
+    let input = fs::read("tests/files/rgb_16_should_be_rgb_8.png").unwrap();
+    let opts = Options {
+        deflate: Deflaters::Custom(Arc::new(TruncatingDeflater)),
+        verify_output: true,
+        ..Options::default()
+    };
+    assert!(matches!(
+        oxipng::optimize_from_memory(&input, &opts),
+        Err(PngError::VerificationFailed)
+    ));
+}
+
+/// A deflater which counts its uses, and optionally corrupts its output or never beats the
+/// existing data
+#[derive(Debug, Default)]
+struct TestDeflater {
+    calls: std::sync::atomic::AtomicUsize,
+    corrupt: bool,
+    refuse: bool,
+}
+
+impl std::fmt::Display for TestDeflater {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("test")
+    }
+}
+
+impl Deflater for TestDeflater {
+    fn deflate(&self, data: &[u8], max_size: &AtomicMin) -> PngResult<Vec<u8>> {
+        self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        if self.refuse {
+            return Err(PngError::DeflatedDataTooLong(max_size.get().unwrap_or(0)));
+        }
+        let mut data = data.to_vec();
+        if self.corrupt {
+            data[0] ^= 1;
+        }
+        Deflaters::Libdeflater { compression: 6 }.deflate(&data, max_size)
+    }
+}
+
+#[test]
+fn optimize_custom_deflater() {
+    let input = fs::read("tests/files/rgb_16_should_be_rgb_8.png").unwrap();
+    let deflater = Arc::new(TestDeflater::default());
+    let opts = Options {
+        deflate: Deflaters::Custom(deflater.clone()),
+        verify_output: true,
+        ..Options::default()
+    };
+    let (output, report) = oxipng::optimize_from_memory_with_report(&input, &opts).unwrap();
+    assert!(output.len() < input.len());
+    assert!(deflater.calls.load(std::sync::atomic::Ordering::SeqCst) > 0);
+    assert_eq!(report.deflate, opts.deflate);
+    assert_eq!(report.deflate.to_string(), "test");
+
+    // A broken deflater is caught by verification
+    let corrupt = Deflaters::Custom(Arc::new(TestDeflater {
+        corrupt: true,
+        ..TestDeflater::default()
+    }));
+    let mut opts = Options {
+        deflate: corrupt,
+        ..Options::default()
+    };
+    assert!(oxipng::optimize_from_memory(&input, &opts).is_ok());
+    opts.verify_output = true;
+    assert!(matches!(
+        oxipng::optimize_from_memory(&input, &opts),
+        Err(PngError::VerificationFailed)
+    ));
+}
+
+#[test]
+fn optimize_ancillary_deflater() {
+    // The image has an iCCP chunk, which is kept
+    let input = fs::read("tests/files/filter_0_for_grayscale_8.png").unwrap();
+    let deflater = Arc::new(TestDeflater::default());
+    let opts = Options {
+        ancillary_deflate: Some(Deflaters::Custom(deflater.clone())),
+        ..Options::default()
+    };
+    let (output, report) = oxipng::optimize_from_memory_with_report(&input, &opts).unwrap();
+    // Only the profile is compressed with it
+    assert_eq!(deflater.calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    assert_eq!(report.deflate, opts.deflate);
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    assert!(png.aux_chunks().iter().any(|c| &c.name == b"iCCP"));
+}
+
+/// Replace the image data of a PNG file, which is in one IDAT chunk
+fn with_idat(png: &[u8], idat_data: &[u8]) -> Vec<u8> {
+    let name = png.windows(4).position(|w| w == b"IDAT").unwrap();
+    let length = u32::from_be_bytes(png[name - 4..name].try_into().unwrap()) as usize;
+    let mut output = png[..name - 4].to_vec();
+    output.extend_from_slice(&(idat_data.len() as u32).to_be_bytes());
+    output.extend_from_slice(b"IDAT");
+    output.extend_from_slice(idat_data);
+    output.extend_from_slice(&crc32(&output[name..]).to_be_bytes());
+    output.extend_from_slice(&png[name + length + 8..]);
+    output
+}
+
+#[test]
+fn optimize_trims_idat_padding() {
+    let input = fs::read("tests/files/rgb_8_should_be_rgb_8.png").unwrap();
+    let png = PngData::from_slice(&input, &Options::default()).unwrap();
+    // Store the image data uncompressed, followed by the empty blocks of flushing and an empty
+    // final block
+    let filtered = png.raw().filter_image(RowFilter::None, false);
+    let mut idat_data = vec![0x78, 0x01];
+    for block in filtered.chunks(0xFFFF) {
+        let len = block.len() as u16;
+        idat_data.push(0);
+        idat_data.extend_from_slice(&len.to_le_bytes());
+        idat_data.extend_from_slice(&(!len).to_le_bytes());
+        idat_data.extend_from_slice(block);
+    }
+    for _ in 0..3 {
+        idat_data.extend_from_slice(&[0, 0, 0, 0xFF, 0xFF]);
+    }
+    idat_data.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    idat_data.extend_from_slice(&libdeflater::adler32(&filtered).to_be_bytes());
+    let padded = with_idat(&png.output(), &idat_data);
+
+    // Recompressing never improves on the padded data, so it is kept, without the empty blocks
+    let mut opts = Options {
+        deflate: Deflaters::Custom(Arc::new(TestDeflater {
+            refuse: true,
+            ..TestDeflater::default()
+        })),
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&padded, &opts).unwrap();
+    assert_eq!(output.len(), padded.len() - 20);
+    let trimmed = PngData::from_slice(&output, &Options::default()).unwrap();
+    assert_eq!(trimmed.raw().data(), png.raw().data());
+
+    opts.trim_idat_padding = false;
+    assert_eq!(
+        oxipng::optimize_from_memory(&padded, &opts).unwrap(),
+        padded
+    );
+
+    // The original image data ends with an empty compressed block, which is found too
+    let trimmed = trim_empty_blocks(png.idat_data(), png.raw().ihdr().raw_data_size()).unwrap();
+    assert_eq!(trimmed.len(), png.idat_data().len() - 2);
+    let compressed = deflate(&filtered, 6, &AtomicMin::new(None)).unwrap();
+    assert_eq!(
+        trim_empty_blocks(&compressed, png.raw().ihdr().raw_data_size()),
+        None
+    );
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn json_log_schema() {
+    // Changing the expected output here requires increasing `JSON_LOG_VERSION`,
+    // unless fields were only added
+    assert_eq!(JSON_LOG_VERSION, 1);
+    let report = OptimizationReport {
+        original_size: 1000,
+        final_size: 600,
+        filter: Some(RowFilter::Bigrams),
+        color_type: ColorType::Indexed {
+            palette: vec![rgb::RGBA8::new(0, 0, 0, 255); 3],
+        },
+        bit_depth: BitDepth::Two,
+        reductions: vec![ReductionKind::ColorType, ReductionKind::BitDepth],
+        deflate: Deflaters::Libdeflater { compression: 11 },
+        interlacing: Interlacing::None,
+        interlacing_changed: true,
+        already_optimal: false,
+        no_improvement: false,
+        filter_stats: None,
+        target_size_met: None,
+        warnings: Vec::new(),
+        timings: None,
+    };
+    let input = InFile::Path("dir/file.png".into());
+    assert_eq!(
+        report.to_json(&input, std::time::Duration::from_millis(1500)),
+        concat!(
+            r#"{"version":1,"input":"dir/file.png","original_size":1000,"final_size":600,"#,
+            r#""filter":"Bigrams","color_type":3,"bit_depth":2,"palette_size":3,"#,
+            r#""interlaced":false,"reductions":["ColorType","BitDepth"],"deflate":"11","#,
+            r#""already_optimal":false,"elapsed_ms":1500}"#
+        )
+    );
+
+    let report = OptimizationReport {
+        filter: None,
+        color_type: ColorType::RGBA,
+        bit_depth: BitDepth::Eight,
+        reductions: Vec::new(),
+        interlacing: Interlacing::Adam7,
+        already_optimal: true,
+        ..report
+    };
+    assert_eq!(
+        report.to_json(&InFile::StdIn, std::time::Duration::ZERO),
+        concat!(
+            r#"{"version":1,"input":"stdin","original_size":1000,"final_size":600,"#,
+            r#""filter":null,"color_type":6,"bit_depth":8,"palette_size":null,"#,
+            r#""interlaced":true,"reductions":[],"deflate":"11","#,
+            r#""already_optimal":true,"elapsed_ms":0}"#
+        )
+    );
+}
+
+#[test]
+fn optimize_trial_compression() {
+    for file in [
+        "tests/files/rgb_8_should_be_rgb_8.png",
+        "tests/files/palette_8_should_be_palette_8.png",
+        "tests/files/grayscale_alpha_8_should_be_grayscale_alpha_8.png",
+    ] {
+        let input = fs::read(file).unwrap();
+        let full = Options::from_preset(4);
+        let trial = Options {
+            trial_compression: Some(7),
+            ..full.clone()
+        };
+        let full_size = oxipng::optimize_from_memory(&input, &full).unwrap().len();
+        let output = oxipng::optimize_from_memory(&input, &trial).unwrap();
+        // The filter chosen at the lower level should be close to the best one
+        assert!(
+            output.len() * 100 <= full_size * 101,
+            "{}: {} > {}",
+            file,
+            output.len(),
+            full_size
+        );
+        PngData::from_slice(&output, &Options::default()).unwrap();
+    }
+}
+
+#[test]
+fn estimate_optimized_size() {
+    let files = [
+        "tests/files/rgb_8_should_be_rgb_8.png",
+        "tests/files/rgba_8_should_be_rgba_8.png",
+        "tests/files/palette_8_should_be_palette_8.png",
+        "tests/files/grayscale_8_should_be_grayscale_8.png",
+        "tests/files/grayscale_alpha_8_should_be_grayscale_alpha_8.png",
+        "tests/files/interlaced_rgb_16_should_be_rgb_16.png",
+    ];
+    for opts in [Options::from_preset(2), Options::from_preset(6)] {
+        let (mut estimated, mut actual) = (0, 0);
+        for file in files {
+            let input = fs::read(file).unwrap();
+            estimated += oxipng::estimate_optimized_size(&input, &opts).unwrap();
+            actual += oxipng::optimize_from_memory(&input, &opts).unwrap().len();
+        }
+        // The estimate should correlate closely with the real size over the corpus
+        assert!(
+            estimated * 10 >= actual * 9 && estimated * 10 <= actual * 11,
+            "{:?}: estimated {} but was {}",
+            opts.deflate,
+            estimated,
+            actual
+        );
+    }
+}
+
+#[test]
+fn content_hash() {
+    let hash = |data: &[u8]| {
+        PngData::from_slice(data, &Options::default())
+            .unwrap()
+            .content_hash()
+    };
+    for file in [
+        "tests/files/rgb_16_should_be_rgb_8.png",
+        "tests/files/rgba_8_should_be_palette_4.png",
+        "tests/files/interlaced_rgb_8_should_be_rgb_8.png",
+        "tests/files/grayscale_8_should_be_grayscale_1.png",
+    ] {
+        let input = fs::read(file).unwrap();
+        let original = hash(&input);
+        // Re-optimizing changes the encoding, but not the pixels
+        for opts in [
+            Options::from_preset(2),
+            Options {
+                interlace: Some(Interlacing::Adam7),
+                deflate: Deflaters::Libdeflater { compression: 1 },
+                force: true,
+                ..Options::default()
+            },
+        ] {
+            let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+            assert_ne!(output, input, "{}", file);
+            assert_eq!(hash(&output), original, "{}", file);
+            let again = oxipng::optimize_from_memory(&output, &opts).unwrap();
+            assert_eq!(hash(&again), original, "{}", file);
+        }
+    }
+
+    // A change to a single pixel changes the hash
+    let pixels = |first| [first, 0, 0, 255, 0, 255, 0, 255];
+    let a = RawImage::new(2, 1, ColorType::RGBA, BitDepth::Eight, pixels(255).to_vec())
+        .unwrap()
+        .create_optimized_png(&Options::default())
+        .unwrap();
+    let b = RawImage::new(2, 1, ColorType::RGBA, BitDepth::Eight, pixels(254).to_vec())
+        .unwrap()
+        .create_optimized_png(&Options::default())
+        .unwrap();
+    assert_ne!(hash(&a), hash(&b));
+}
+
+#[test]
+fn collect_filter_stats() {
+    let input = fs::read("tests/files/rgb_8_should_be_rgb_8.png").unwrap();
+    let height = PngImage::from_slice(&input).unwrap().height() as usize;
+    let opts = Options {
+        filter: indexset! { RowFilter::MinSum },
+        collect_filter_stats: true,
+        force: true,
+        ..Options::default()
+    };
+    let (_, report) = oxipng::optimize_from_memory_with_report(&input, &opts).unwrap();
+    let stats = report.filter_stats.unwrap();
+    assert_eq!(stats.len(), height);
+    assert!(stats.iter().all(|&(filter, _)| filter <= RowFilter::Paeth));
+    assert!(stats.iter().any(|&(_, score)| score > 0));
+
+    // A standard filter is used for every line
+    let opts = Options {
+        filter: indexset! { RowFilter::Sub },
+        ..opts
+    };
+    let (_, report) = oxipng::optimize_from_memory_with_report(&input, &opts).unwrap();
+    let stats = report.filter_stats.unwrap();
+    assert_eq!(stats.len(), height);
+    assert!(stats.iter().all(|&stat| stat == (RowFilter::Sub, 0)));
+
+    // Nothing is recorded unless asked for
+    let opts = Options {
+        collect_filter_stats: false,
+        ..opts
+    };
+    let (_, report) = oxipng::optimize_from_memory_with_report(&input, &opts).unwrap();
+    assert!(report.filter_stats.is_none());
+}
+
+#[test]
+fn collect_timings() {
+    let input = std::path::Path::new("tests/files/rgba_8_should_be_palette_8.png");
+    let output = input.with_extension("timings.out.png");
+    // Evaluate several filters first, so that every stage runs
+    let opts = Options {
+        filter: indexset! { RowFilter::None, RowFilter::Sub, RowFilter::Bigrams },
+        collect_timings: true,
+        force: true,
+        ..Options::default()
+    };
+    let report = oxipng::optimize_with_report(
+        &InFile::Path(input.to_owned()),
+        &OutFile::Path(Some(output.clone())),
+        &opts,
+    )
+    .unwrap();
+    fs::remove_file(&output).ok();
+    let timings = report.timings.unwrap();
+    for (stage, time) in [
+        ("parse", timings.parse),
+        ("reduction", timings.reduction),
+        ("filter evaluation", timings.filter_evaluation),
+        ("deflate", timings.deflate),
+        ("write", timings.write),
+    ] {
+        assert!(!time.is_zero(), "no time recorded for {}", stage);
+    }
+
+    // Nothing is recorded unless asked for
+    let opts = Options {
+        collect_timings: false,
+        ..opts
+    };
+    let (_, report) =
+        oxipng::optimize_from_memory_with_report(&fs::read(input).unwrap(), &opts).unwrap();
+    assert!(report.timings.is_none());
+}
+
+/// Build an ICO file holding the given PNG images, each in its own directory entry
+fn ico_with_pngs(pngs: &[&[u8]]) -> Vec<u8> {
+    let mut ico = vec![0, 0, 1, 0];
+    ico.extend_from_slice(&(pngs.len() as u16).to_le_bytes());
+    let mut offset = 6 + 16 * pngs.len();
+    for png in pngs {
+        // The size is taken from the PNG, so 0 is used here as for 256 pixels
+        ico.extend_from_slice(&[0, 0, 0, 0, 1, 0, 32, 0]);
+        ico.extend_from_slice(&(png.len() as u32).to_le_bytes());
+        ico.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += png.len();
+    }
+    for png in pngs {
+        ico.extend_from_slice(png);
+    }
+    ico
+}
+
+/// The offset and size of each image in an ICO file
+fn ico_entries(ico: &[u8]) -> Vec<(usize, usize)> {
+    let count = u16::from_le_bytes([ico[4], ico[5]]) as usize;
+    (0..count)
+        .map(|i| {
+            let entry = &ico[6 + 16 * i..];
+            let size = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as usize;
+            let offset = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as usize;
+            (offset, size)
+        })
+        .collect()
+}
+
+#[test]
+fn optimize_png_slice_in_ico() {
+    let first = fs::read("tests/files/rgba_8_should_be_palette_8.png").unwrap();
+    let second = fs::read("tests/files/fully_optimized.png").unwrap();
+    let ico = ico_with_pngs(&[&first, &second]);
+    let opts = Options::default();
+
+    let optimized: Vec<Vec<u8>> = ico_entries(&ico)
+        .into_iter()
+        .map(|(offset, _)| {
+            // The rest of the file follows the first image, and is ignored
+            let output = optimize_png_slice(&ico[offset..], &opts).unwrap();
+            assert!(output.ends_with(&[0xAE, 0x42, 0x60, 0x82]));
+            output
+        })
+        .collect();
+    assert!(optimized[0].len() < first.len());
+    assert_eq!(optimized[1], second);
+
+    let new_ico = ico_with_pngs(&[&optimized[0], &optimized[1]]);
+    assert!(new_ico.len() < ico.len());
+    for ((offset, size), original) in ico_entries(&new_ico).into_iter().zip([&first, &second]) {
+        let png = PngData::from_slice(&new_ico[offset..offset + size], &opts).unwrap();
+        let original = PngData::from_slice(original, &opts).unwrap();
+        assert_eq!(png.content_hash(), original.content_hash());
+    }
+
+    // The leading ICO data is only skipped when fixing errors
+    assert!(matches!(
+        optimize_png_slice(&ico, &opts),
+        Err(PngError::NotPNG)
+    ));
+    let fix_opts = Options {
+        fix_errors: true,
+        ..Options::default()
+    };
+    assert_eq!(optimize_png_slice(&ico, &fix_opts).unwrap(), optimized[0]);
+}
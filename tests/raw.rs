@@ -1,7 +1,6 @@
 use oxipng::internal_tests::*;
 use oxipng::*;
 use std::path::PathBuf;
-use std::sync::Arc;
 
 fn get_opts() -> Options {
     Options {
@@ -15,30 +14,30 @@ fn test_it_converts(input: &str) {
     let input = PathBuf::from(input);
     let opts = get_opts();
 
-    let original_data = PngData::read_file(&PathBuf::from(input)).unwrap();
+    let original_data = PngData::read_file(&input).unwrap();
     let image = PngData::from_slice(&original_data, &opts).unwrap();
-    let png = Arc::try_unwrap(image.raw).unwrap();
+    let png = image.raw().clone();
 
-    let num_chunks = image.aux_chunks.len();
+    let num_chunks = image.aux_chunks().len();
     assert!(num_chunks > 0);
 
     let mut raw = RawImage::new(
-        png.ihdr.width,
-        png.ihdr.height,
-        png.ihdr.color_type,
-        png.ihdr.bit_depth,
-        png.data,
+        png.ihdr().width,
+        png.ihdr().height,
+        png.ihdr().color_type.clone(),
+        png.ihdr().bit_depth,
+        png.data().to_vec(),
     )
     .unwrap();
 
-    for chunk in image.aux_chunks {
-        raw.add_png_chunk(chunk.name, chunk.data);
+    for chunk in image.aux_chunks() {
+        raw.add_png_chunk(chunk.name, chunk.data.clone());
     }
 
     let output = raw.create_optimized_png(&opts).unwrap();
 
     let new = PngData::from_slice(&output, &opts).unwrap();
-    assert!(new.aux_chunks.len() == num_chunks);
+    assert!(new.aux_chunks().len() == num_chunks);
 
     #[cfg(feature = "sanity-checks")]
     assert!(validate_output(&output, &original_data));
@@ -95,3 +94,125 @@ fn incorrect_length() {
     )
     .expect_err("Expected incorrect data length");
 }
+
+#[test]
+fn png_image_scanlines() {
+    let input = std::fs::read("tests/files/interlaced_rgb_8_should_be_rgb_8.png").unwrap();
+    let image = PngImage::from_slice(&input).unwrap();
+    assert_eq!(image.interlacing(), Interlacing::Adam7);
+    assert_eq!(image.bit_depth(), BitDepth::Eight);
+    let bytes_per_pixel = 3;
+
+    // Interlaced rows come in seven passes, which together cover every pixel once
+    let rows: Vec<&[u8]> = image.scanlines().collect();
+    let total: usize = rows.iter().map(|row| row.len()).sum();
+    assert_eq!(
+        total,
+        (image.width() * image.height()) as usize * bytes_per_pixel
+    );
+
+    // Deinterlaced, there is a row of the full width for each line of the image
+    let progressive = image.change_interlacing(Interlacing::None).unwrap();
+    let rows: Vec<&[u8]> = progressive.scanlines().collect();
+    assert_eq!(rows.len(), image.height() as usize);
+    assert!(rows
+        .iter()
+        .all(|row| row.len() == image.width() as usize * bytes_per_pixel));
+    // The first pass begins with the top-left pixel
+    assert_eq!(&rows[0][..3], &image.scanlines().next().unwrap()[..3]);
+}
+
+#[test]
+fn png_image_new() {
+    // The data of an interlaced image is checked against the size of all seven passes
+    let input = std::fs::read("tests/files/interlaced_rgb_8_should_be_rgb_8.png").unwrap();
+    let image = PngImage::from_slice(&input).unwrap();
+    let copy = PngImage::new(image.ihdr().clone(), image.data().to_vec()).unwrap();
+    assert_eq!(copy.data(), image.data());
+
+    let mut data = image.data().to_vec();
+    data.pop();
+    assert!(matches!(
+        PngImage::new(image.ihdr().clone(), data),
+        Err(PngError::IncorrectDataLength(..))
+    ));
+    let ihdr = IhdrData {
+        bit_depth: BitDepth::Four,
+        ..image.ihdr().clone()
+    };
+    assert!(matches!(
+        PngImage::new(ihdr, image.data().to_vec()),
+        Err(PngError::InvalidDepthForType(..))
+    ));
+}
+
+#[test]
+fn from_rgba8_reduces_to_indexed() {
+    let red = [255, 0, 0, 255];
+    let green = [0, 255, 0, 255];
+    let blue = [0, 0, 255, 128];
+    // A palette costs more than it saves on a tiny image, so use a larger, irregular pattern
+    let pixels: Vec<_> = (0..16 * 16u32)
+        .map(|i| [red, green, blue][(i.wrapping_mul(2_654_435_761) >> 24) as usize % 3])
+        .collect();
+    let raw = RawImage::from_rgba8(16, 16, &pixels.concat()).unwrap();
+    let output = raw.create_optimized_png(&Options::default()).unwrap();
+
+    let image = PngImage::from_slice(&output).unwrap();
+    assert_eq!((image.width(), image.height()), (16, 16));
+    let palette = match image.color_type() {
+        ColorType::Indexed { palette } => palette,
+        _ => panic!("Expected an indexed image, got {}", image.color_type()),
+    };
+    let mut colors: Vec<_> = palette.iter().map(|c| [c.r, c.g, c.b, c.a]).collect();
+    colors.sort_unstable();
+    let mut expected = vec![red, green, blue];
+    expected.sort_unstable();
+    assert_eq!(colors, expected);
+
+    assert!(matches!(
+        RawImage::from_rgba8(2, 2, &red),
+        Err(PngError::IncorrectDataLength(4, 16))
+    ));
+}
+
+#[test]
+fn split_and_join_strips() {
+    let input = PathBuf::from("tests/files/raw_api.png");
+    let original_data = PngData::read_file(&input).unwrap();
+    let png = PngData::from_slice(&original_data, &Options::default()).unwrap();
+    let image = &png.raw();
+
+    let (strips, strip_opts) = split_strips(image, 3, &get_opts()).unwrap();
+    assert_eq!(strips.len(), 3);
+    let heights: Vec<u32> = strips.iter().map(|s| s.ihdr().height).collect();
+    assert_eq!(heights.iter().sum::<u32>(), image.ihdr().height);
+    assert!(heights.iter().max().unwrap() - heights.iter().min().unwrap() <= 1);
+
+    // Optimize each strip on its own, as if on different machines, and decode the results
+    let optimized: Vec<PngImage> = strips
+        .into_iter()
+        .map(|strip| {
+            let raw = RawImage::new(
+                strip.ihdr().width,
+                strip.ihdr().height,
+                strip.ihdr().color_type.clone(),
+                strip.ihdr().bit_depth,
+                strip.data().to_vec(),
+            )
+            .unwrap();
+            let output = raw.create_optimized_png(&strip_opts).unwrap();
+            PngImage::from_slice(&output).unwrap()
+        })
+        .collect();
+
+    let joined = join_strips(&optimized).unwrap();
+    assert_eq!(joined.ihdr().width, image.ihdr().width);
+    assert_eq!(joined.ihdr().height, image.ihdr().height);
+    assert_eq!(joined.ihdr().color_type, image.ihdr().color_type);
+    assert_eq!(joined.ihdr().bit_depth, image.ihdr().bit_depth);
+    assert_eq!(joined.data(), image.data());
+
+    assert!(split_strips(image, 0, &get_opts()).is_err());
+    assert!(join_strips(&[]).is_err());
+}
@@ -1,6 +1,7 @@
 use indexmap::IndexSet;
 use oxipng::internal_tests::*;
 use oxipng::*;
+use rgb::RGB16;
 use std::fs::remove_file;
 use std::path::Path;
 use std::path::PathBuf;
@@ -34,23 +35,47 @@ fn test_it_converts(
     color_type_out: u8,
     bit_depth_out: BitDepth,
 ) {
-    let input = PathBuf::from(input);
-    let (output, mut opts) = get_opts(&input);
+    let (output, mut opts) = get_opts(Path::new(input));
     opts.optimize_alpha = optimize_alpha;
-    let png = PngData::new(&input, &opts).unwrap();
+    test_it_converts_with_opts(
+        input,
+        &output,
+        &opts,
+        color_type_in,
+        bit_depth_in,
+        color_type_out,
+        bit_depth_out,
+    );
+}
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), color_type_in);
-    assert_eq!(png.raw.ihdr.bit_depth, bit_depth_in, "test file is broken");
-    assert_eq!(png.raw.ihdr.interlaced, Interlacing::None);
+fn test_it_converts_with_opts(
+    input: &str,
+    output: &OutFile,
+    opts: &oxipng::Options,
+    color_type_in: u8,
+    bit_depth_in: BitDepth,
+    color_type_out: u8,
+    bit_depth_out: BitDepth,
+) {
+    let input = PathBuf::from(input);
+    let png = PngData::new(&input, opts).unwrap();
 
-    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), color_type_in);
+    assert_eq!(
+        png.raw().ihdr().bit_depth,
+        bit_depth_in,
+        "test file is broken"
+    );
+    assert_eq!(png.raw().ihdr().interlaced, Interlacing::None);
+
+    match oxipng::optimize(&InFile::Path(input), output, opts) {
         Ok(_) => (),
         Err(x) => panic!("{}", x),
     };
     let output = output.path().unwrap();
     assert!(output.exists());
 
-    let png = match PngData::new(output, &opts) {
+    let png = match PngData::new(output, opts) {
         Ok(x) => x,
         Err(x) => {
             remove_file(output).ok();
@@ -58,8 +83,11 @@ fn test_it_converts(
         }
     };
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), color_type_out);
-    assert_eq!(png.raw.ihdr.bit_depth, bit_depth_out);
+    assert_eq!(
+        png.raw().ihdr().color_type.png_header_code(),
+        color_type_out
+    );
+    assert_eq!(png.raw().ihdr().bit_depth, bit_depth_out);
 
     remove_file(output).ok();
 }
@@ -846,9 +874,14 @@ fn grayscale_2_should_be_grayscale_1() {
 
 #[test]
 fn grayscale_8_should_be_palette_8() {
-    test_it_converts(
-        "tests/files/grayscale_8_should_be_palette_8.png",
-        false,
+    let input = "tests/files/grayscale_8_should_be_palette_8.png";
+    let (output, mut opts) = get_opts(Path::new(input));
+    // The file has a gray ICC profile, which can't be kept for an indexed image
+    opts.drop_incompatible_icc = true;
+    test_it_converts_with_opts(
+        input,
+        &output,
+        &opts,
         GRAYSCALE,
         BitDepth::Eight,
         INDEXED,
@@ -947,9 +980,9 @@ fn palette_should_be_reduced_with_dupes() {
 
     let png = PngData::new(&input, &opts).unwrap();
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), INDEXED);
-    assert_eq!(png.raw.ihdr.bit_depth, BitDepth::Eight);
-    if let ColorType::Indexed { palette } = &png.raw.ihdr.color_type {
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), INDEXED);
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
+    if let ColorType::Indexed { palette } = &png.raw().ihdr().color_type {
         assert_eq!(palette.len(), 43);
     }
 
@@ -968,9 +1001,9 @@ fn palette_should_be_reduced_with_dupes() {
         }
     };
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), INDEXED);
-    assert_eq!(png.raw.ihdr.bit_depth, BitDepth::Eight);
-    if let ColorType::Indexed { palette } = &png.raw.ihdr.color_type {
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), INDEXED);
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
+    if let ColorType::Indexed { palette } = &png.raw().ihdr().color_type {
         assert_eq!(palette.len(), 35);
     }
 
@@ -984,9 +1017,9 @@ fn palette_should_be_reduced_with_unused() {
 
     let png = PngData::new(&input, &opts).unwrap();
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), INDEXED);
-    assert_eq!(png.raw.ihdr.bit_depth, BitDepth::Eight);
-    if let ColorType::Indexed { palette } = &png.raw.ihdr.color_type {
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), INDEXED);
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
+    if let ColorType::Indexed { palette } = &png.raw().ihdr().color_type {
         assert_eq!(palette.len(), 35);
     }
 
@@ -1005,9 +1038,9 @@ fn palette_should_be_reduced_with_unused() {
         }
     };
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), INDEXED);
-    assert_eq!(png.raw.ihdr.bit_depth, BitDepth::Eight);
-    if let ColorType::Indexed { palette } = &png.raw.ihdr.color_type {
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), INDEXED);
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
+    if let ColorType::Indexed { palette } = &png.raw().ihdr().color_type {
         assert_eq!(palette.len(), 33);
     }
 
@@ -1021,9 +1054,9 @@ fn palette_should_be_reduced_with_both() {
 
     let png = PngData::new(&input, &opts).unwrap();
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), INDEXED);
-    assert_eq!(png.raw.ihdr.bit_depth, BitDepth::Eight);
-    if let ColorType::Indexed { palette } = &png.raw.ihdr.color_type {
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), INDEXED);
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
+    if let ColorType::Indexed { palette } = &png.raw().ihdr().color_type {
         assert_eq!(palette.len(), 43);
     }
 
@@ -1042,9 +1075,9 @@ fn palette_should_be_reduced_with_both() {
         }
     };
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), INDEXED);
-    assert_eq!(png.raw.ihdr.bit_depth, BitDepth::Eight);
-    if let ColorType::Indexed { palette } = &png.raw.ihdr.color_type {
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), INDEXED);
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
+    if let ColorType::Indexed { palette } = &png.raw().ihdr().color_type {
         assert_eq!(palette.len(), 33);
     }
 
@@ -1098,3 +1131,1131 @@ fn grayscale_alpha_8_reduce_alpha() {
         BitDepth::Eight,
     );
 }
+
+fn indexed_frame(palette: Vec<RGBA8>, data: Vec<u8>) -> PngImage {
+    PngImage::new(
+        IhdrData {
+            width: data.len() as u32,
+            height: 1,
+            color_type: ColorType::Indexed { palette },
+            bit_depth: BitDepth::Eight,
+            interlaced: Interlacing::None,
+        },
+        data,
+    )
+    .unwrap()
+}
+
+#[test]
+fn dithered_16_to_8_banding() {
+    // A shallow gradient which covers only a few 8-bit levels
+    let width = 256;
+    let height = 16;
+    let value = |x: usize| 0x1000 + (x * 4) as u16;
+    let data = (0..height)
+        .flat_map(|_| (0..width).flat_map(|x| value(x).to_be_bytes()))
+        .collect();
+    let png = PngImage::new(
+        IhdrData {
+            width: width as u32,
+            height: height as u32,
+            color_type: ColorType::Grayscale {
+                transparent_shade: None,
+            },
+            bit_depth: BitDepth::Sixteen,
+            interlaced: Interlacing::None,
+        },
+        data,
+    )
+    .unwrap();
+
+    // Compare the average of each column with the original value
+    let banding = |reduced: &PngImage| {
+        assert_eq!(reduced.ihdr().bit_depth, BitDepth::Eight);
+        (0..width)
+            .map(|x| {
+                let sum: f64 = (0..height)
+                    .map(|y| reduced.data()[y * width + x] as f64 * 65535.0 / 255.0)
+                    .sum();
+                (sum / height as f64 - value(x) as f64).abs()
+            })
+            .sum::<f64>()
+            / width as f64
+    };
+    let scaled = bit_depth::scaled_bit_depth_16_to_8(&png).unwrap();
+    let dithered = bit_depth::dithered_bit_depth_16_to_8(&png).unwrap();
+    assert!(banding(&dithered) * 2.0 < banding(&scaled));
+    // The result must be reproducible
+    let again = bit_depth::dithered_bit_depth_16_to_8(&png).unwrap();
+    assert_eq!(again.data(), dithered.data());
+
+    // Images which can be reduced losslessly are unchanged by dithering
+    let exact = PngImage::new(
+        png.ihdr().clone(),
+        png.data().chunks(2).flat_map(|p| [p[0], p[0]]).collect(),
+    )
+    .unwrap();
+    assert_eq!(
+        bit_depth::dithered_bit_depth_16_to_8(&exact)
+            .unwrap()
+            .data(),
+        bit_depth::reduced_bit_depth_16_to_8(&exact, false)
+            .unwrap()
+            .data()
+    );
+}
+
+#[test]
+fn lossless_16_to_8() {
+    let gray16 = |values: &[u16], transparent_shade: Option<u16>| {
+        PngImage::new(
+            IhdrData {
+                width: values.len() as u32,
+                height: 1,
+                color_type: ColorType::Grayscale { transparent_shade },
+                bit_depth: BitDepth::Sixteen,
+                interlaced: Interlacing::None,
+            },
+            values.iter().flat_map(|v| v.to_be_bytes()).collect(),
+        )
+        .unwrap()
+    };
+
+    let reduced =
+        bit_depth::lossless_bit_depth_16_to_8(&gray16(&[0x0101, 0xFFFF, 0], None)).unwrap();
+    assert_eq!(reduced.ihdr().bit_depth, BitDepth::Eight);
+    assert_eq!(reduced.data(), [0x01, 0xFF, 0]);
+    assert!(bit_depth::lossless_bit_depth_16_to_8(&gray16(&[0x0101, 0x0102], None)).is_none());
+    // Not even when scaling is allowed in general
+    let scaled = bit_depth::reduced_bit_depth_16_to_8(&gray16(&[0x0102], None), true).unwrap();
+    assert_eq!(scaled.data(), [0x01]);
+    assert!(bit_depth::reduced_bit_depth_16_to_8(&gray16(&[0x0102], None), false).is_none());
+
+    // The transparent shade is reduced with the image, and must also be exact
+    let reduced =
+        bit_depth::lossless_bit_depth_16_to_8(&gray16(&[0x0101, 0xFFFF], Some(0xFFFF))).unwrap();
+    assert_eq!(
+        reduced.ihdr().color_type,
+        ColorType::Grayscale {
+            transparent_shade: Some(0xFF)
+        }
+    );
+    assert!(
+        bit_depth::lossless_bit_depth_16_to_8(&gray16(&[0x0101, 0xFFFF], Some(0x0102))).is_none()
+    );
+
+    let rgb = PngImage::new(
+        IhdrData {
+            width: 1,
+            height: 1,
+            color_type: ColorType::RGB {
+                transparent_color: Some(RGB16::new(0x0202, 0x0303, 0x0404)),
+            },
+            bit_depth: BitDepth::Sixteen,
+            interlaced: Interlacing::None,
+        },
+        vec![1, 1, 2, 2, 3, 3],
+    )
+    .unwrap();
+    let reduced = bit_depth::lossless_bit_depth_16_to_8(&rgb).unwrap();
+    assert_eq!(reduced.data(), [1, 2, 3]);
+    assert_eq!(
+        reduced.ihdr().color_type,
+        ColorType::RGB {
+            transparent_color: Some(RGB16::new(2, 3, 4))
+        }
+    );
+}
+
+#[test]
+fn scaled_16_to_8_transparency() {
+    // Every shade but the first scales to the same value as the transparent one, or is exact
+    let values: [u16; 5] = [1000, 999, 1001, 1100, 257 * 4];
+    let png = PngImage::new(
+        IhdrData {
+            width: values.len() as u32,
+            height: 1,
+            color_type: ColorType::Grayscale {
+                transparent_shade: Some(1000),
+            },
+            bit_depth: BitDepth::Sixteen,
+            interlaced: Interlacing::None,
+        },
+        values.iter().flat_map(|v| v.to_be_bytes()).collect(),
+    )
+    .unwrap();
+
+    for reduced in [
+        bit_depth::scaled_bit_depth_16_to_8(&png).unwrap(),
+        bit_depth::dithered_bit_depth_16_to_8(&png).unwrap(),
+    ] {
+        assert_eq!(reduced.ihdr().bit_depth, BitDepth::Eight);
+        assert_eq!(
+            reduced.ihdr().color_type,
+            ColorType::Grayscale {
+                transparent_shade: Some(4)
+            }
+        );
+        // Only the transparent pixel keeps the transparent value, and the others stay close
+        assert_eq!(reduced.data()[0], 4);
+        assert!(reduced.data()[1..]
+            .iter()
+            .all(|&v| v != 4 && v.abs_diff(4) <= 1));
+    }
+
+    // An expanded image keeps no transparent shade that its samples can't hold
+    let gray2 = PngImage::new(
+        IhdrData {
+            width: 4,
+            color_type: ColorType::Grayscale {
+                transparent_shade: Some(4),
+            },
+            bit_depth: BitDepth::Two,
+            ..png.ihdr().clone()
+        },
+        vec![0b0001_1011],
+    )
+    .unwrap();
+    let expanded = bit_depth::expanded_bit_depth_to_8(&gray2).unwrap();
+    assert_eq!(
+        expanded.ihdr().color_type,
+        ColorType::Grayscale {
+            transparent_shade: None
+        }
+    );
+}
+
+#[test]
+fn near_gray_tolerance() {
+    // Each pixel has a channel spread of 3
+    let png = PngImage::new(
+        IhdrData {
+            width: 3,
+            height: 1,
+            color_type: ColorType::RGB {
+                transparent_color: None,
+            },
+            bit_depth: BitDepth::Eight,
+            interlaced: Interlacing::None,
+        },
+        vec![10, 12, 13, 100, 100, 97, 200, 203, 201],
+    )
+    .unwrap();
+
+    let reduced = color::near_gray_rgb_to_grayscale(&png, 4).unwrap();
+    assert_eq!(
+        reduced.ihdr().color_type,
+        ColorType::Grayscale {
+            transparent_shade: None
+        }
+    );
+    assert_eq!(reduced.data(), vec![12, 100, 202]);
+    assert!(color::near_gray_rgb_to_grayscale(&png, 2).is_none());
+    assert!(color::near_gray_rgb_to_grayscale(&png, 0).is_none());
+
+    // A tolerance of 0 only reduces exact grays
+    let gray = PngImage::new(
+        png.ihdr().clone(),
+        vec![10, 10, 10, 100, 100, 100, 200, 200, 200],
+    )
+    .unwrap();
+    assert_eq!(
+        color::near_gray_rgb_to_grayscale(&gray, 0).unwrap().data(),
+        color::reduced_rgb_to_grayscale(&gray).unwrap().data()
+    );
+}
+
+#[test]
+fn palette_orders() {
+    let red = RGBA8::new(255, 0, 0, 255);
+    let dark_red = RGBA8::new(200, 0, 0, 255);
+    let blue = RGBA8::new(0, 0, 255, 255);
+    let clear = RGBA8::new(0, 0, 0, 0);
+    let palette = vec![red, blue, clear, dark_red];
+    let data = vec![1, 1, 1, 1, 0, 0, 0, 2, 2, 3];
+    let png = indexed_frame(palette.clone(), data.clone());
+    let colors = |png: &PngImage| -> Vec<RGBA8> {
+        match &png.ihdr().color_type {
+            ColorType::Indexed { palette } => {
+                png.data().iter().map(|&b| palette[b as usize]).collect()
+            }
+            _ => panic!("Not indexed"),
+        }
+    };
+    let expected = colors(&png);
+
+    let by_frequency = palette::sorted_palette_by_frequency(&png).unwrap();
+    assert_eq!(
+        by_frequency.ihdr().color_type,
+        ColorType::Indexed {
+            palette: vec![clear, blue, red, dark_red]
+        }
+    );
+    assert_eq!(colors(&by_frequency), expected);
+
+    let by_neighbor = palette::sorted_palette_by_nearest_neighbor(&png).unwrap();
+    assert_eq!(
+        by_neighbor.ihdr().color_type,
+        ColorType::Indexed {
+            palette: vec![clear, dark_red, red, blue]
+        }
+    );
+    assert_eq!(colors(&by_neighbor), expected);
+
+    let best =
+        palette::optimized_palette_order(&png, Deflaters::Libdeflater { compression: 5 }).unwrap();
+    assert_eq!(colors(&best), expected);
+}
+
+/// A deflater which compresses the given data well, and everything else badly
+#[derive(Debug)]
+struct PreferringDeflater(Vec<u8>);
+
+impl std::fmt::Display for PreferringDeflater {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("preferring")
+    }
+}
+
+impl Deflater for PreferringDeflater {
+    fn deflate(&self, data: &[u8], max_size: &AtomicMin) -> PngResult<Vec<u8>> {
+        let mut compressed = Deflaters::Libdeflater { compression: 5 }.deflate(data, max_size)?;
+        if data != self.0 {
+            compressed.resize(compressed.len() + 100, 0);
+        }
+        Ok(compressed)
+    }
+}
+
+#[test]
+fn palette_order_original_wins() {
+    let red = RGBA8::new(255, 0, 0, 255);
+    let blue = RGBA8::new(0, 0, 255, 255);
+    let clear = RGBA8::new(0, 0, 0, 0);
+    let dark_red = RGBA8::new(128, 0, 0, 255);
+    let png = indexed_frame(
+        vec![red, blue, clear, dark_red],
+        vec![1, 1, 1, 1, 0, 0, 0, 2, 2, 3],
+    );
+    assert!(palette::sorted_palette(&png).is_some());
+
+    // Every reordering compresses worse than the current order, so none is used
+    let original = png.filter_image(RowFilter::None, false);
+    let deflater = Deflaters::Custom(std::sync::Arc::new(PreferringDeflater(original)));
+    assert!(palette::optimized_palette_order(&png, deflater).is_none());
+}
+
+/// Insert a chunk straight after the IHDR chunk of a PNG file
+fn with_chunk(png: &[u8], name: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    // The signature and IHDR chunk always take up 33 bytes
+    let mut output = png[..33].to_vec();
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    output.extend_from_slice(name);
+    output.extend_from_slice(data);
+    let crc = crc32(&output[37..]);
+    output.extend_from_slice(&crc.to_be_bytes());
+    output.extend_from_slice(&png[33..]);
+    output
+}
+
+/// Optimize a test file after adding an sBIT chunk to it, returning the output
+fn optimize_with_sbit(input: &str, sbit: &[u8], opts: &Options) -> PngData {
+    let png = PngData::new(Path::new(input), opts).unwrap();
+    let input = with_chunk(&png.output(), b"sBIT", sbit);
+    let output = oxipng::optimize_from_memory(&input, opts).unwrap();
+    PngData::from_slice(&output, opts).unwrap()
+}
+
+fn sbit_data(png: &PngData) -> Option<&[u8]> {
+    png.aux_chunks()
+        .iter()
+        .find(|c| &c.name == b"sBIT")
+        .map(|c| c.data.as_slice())
+}
+
+#[test]
+fn sbit_rgb_16_to_8() {
+    let png = optimize_with_sbit(
+        "tests/files/rgb_16_should_be_rgb_8.png",
+        &[16, 12, 16],
+        &Options::default(),
+    );
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
+    assert_eq!(sbit_data(&png), Some(&[8, 8, 8][..]));
+}
+
+#[test]
+fn sbit_rgb_to_grayscale() {
+    let png = optimize_with_sbit(
+        "tests/files/rgb_8_should_be_grayscale_8.png",
+        &[5, 6, 5],
+        &Options::default(),
+    );
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), GRAYSCALE);
+    assert_eq!(sbit_data(&png), Some(&[6][..]));
+}
+
+#[test]
+fn sbit_invalid_dropped() {
+    let png = optimize_with_sbit(
+        "tests/files/rgb_16_should_be_rgb_8.png",
+        &[16, 16],
+        &Options::default(),
+    );
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
+    assert_eq!(sbit_data(&png), None);
+}
+
+#[test]
+fn sbit_grayscale_significant_bits() {
+    let input = "tests/files/grayscale_8_should_be_grayscale_8.png";
+    let opts = Options {
+        use_significant_bits: true,
+        lossless_only: false,
+        ..Options::default()
+    };
+    let png = optimize_with_sbit(input, &[3], &opts);
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), GRAYSCALE);
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Four);
+    assert_eq!(sbit_data(&png), Some(&[3][..]));
+
+    // The significant bits of each pixel must be unchanged
+    let orig = PngData::new(Path::new(input), &opts).unwrap();
+    let expanded = bit_depth::expanded_bit_depth_to_8(png.raw()).unwrap();
+    assert_eq!(expanded.data().len(), orig.raw().data().len());
+    for (&new, &old) in expanded.data().iter().zip(orig.raw().data()) {
+        assert_eq!(new >> 5, old >> 5);
+    }
+
+    // Without the option, the image is left at 8 bits
+    let png = optimize_with_sbit(input, &[3], &Options::default());
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
+    assert_eq!(sbit_data(&png), Some(&[3][..]));
+}
+
+/// Optimize an indexed image with a bKGD chunk, returning the output
+fn optimize_with_bkgd(palette: Vec<RGBA8>, data: Vec<u8>, bkgd: u8, opts: &Options) -> PngData {
+    let width = data.len() as u32;
+    let mut raw = RawImage::new(
+        width,
+        1,
+        ColorType::Indexed { palette },
+        BitDepth::Eight,
+        data,
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"bKGD", vec![bkgd]);
+    let output = raw.create_optimized_png(opts).unwrap();
+    PngData::from_slice(&output, opts).unwrap()
+}
+
+fn bkgd_color(png: &PngData) -> Option<RGBA8> {
+    let bkgd = png.aux_chunks().iter().find(|c| &c.name == b"bKGD")?;
+    match &png.raw().ihdr().color_type {
+        ColorType::Indexed { palette } => Some(palette[bkgd.data[0] as usize]),
+        _ => panic!("image should be indexed"),
+    }
+}
+
+#[test]
+fn bkgd_follows_palette_sorting() {
+    let red = RGBA8::new(255, 0, 0, 255);
+    let green = RGBA8::new(0, 255, 0, 255);
+    let blue = RGBA8::new(0, 0, 255, 255);
+    let png = optimize_with_bkgd(
+        vec![red, green, blue],
+        vec![0, 1, 2, 2, 1, 0, 0, 2],
+        2,
+        &Options::default(),
+    );
+    assert_eq!(bkgd_color(&png), Some(blue));
+}
+
+#[test]
+fn bkgd_removed_from_palette() {
+    let red = RGBA8::new(255, 0, 0, 255);
+    let green = RGBA8::new(0, 255, 0, 255);
+    let blue = RGBA8::new(0, 0, 255, 255);
+    let white = RGBA8::new(255, 255, 255, 255);
+    let palette = vec![red, green, blue, white];
+    // The background color isn't used by any pixel, so palette reduction removes it
+    let data = vec![0, 1, 2, 2, 1, 0, 0, 2];
+
+    let png = optimize_with_bkgd(palette.clone(), data.clone(), 3, &Options::default());
+    assert_eq!(bkgd_color(&png), None);
+    assert!(png.aux_chunks().iter().all(|c| &c.name != b"bKGD"));
+
+    let opts = Options {
+        add_bkgd_to_palette: true,
+        ..Options::default()
+    };
+    let png = optimize_with_bkgd(palette, data, 3, &opts);
+    assert_eq!(bkgd_color(&png), Some(white));
+    match &png.raw().ihdr().color_type {
+        ColorType::Indexed { palette } => assert_eq!(palette.len(), 4),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn hist_follows_palette_reduction() {
+    let red = RGBA8::new(255, 0, 0, 255);
+    let green = RGBA8::new(0, 255, 0, 255);
+    let blue = RGBA8::new(0, 0, 255, 255);
+    let white = RGBA8::new(255, 255, 255, 255);
+    // White is unused, and will be removed from the palette
+    let data = vec![0, 1, 2, 2, 1, 0, 0, 2, 2];
+    let frequencies = [(red, 3), (green, 2), (blue, 4), (white, 0)];
+    let mut raw = RawImage::new(
+        data.len() as u32,
+        1,
+        ColorType::Indexed {
+            palette: vec![red, green, blue, white],
+        },
+        BitDepth::Eight,
+        data,
+    )
+    .unwrap();
+    let hist = frequencies
+        .iter()
+        .flat_map(|(_, f)| u16::to_be_bytes(*f))
+        .collect();
+    raw.add_png_chunk(*b"hIST", hist);
+    let output = raw.create_optimized_png(&Options::default()).unwrap();
+
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    let palette = match &png.raw().ihdr().color_type {
+        ColorType::Indexed { palette } => palette,
+        _ => panic!("image should be indexed"),
+    };
+    assert_eq!(palette.len(), 3);
+    let hist = &png
+        .aux_chunks()
+        .iter()
+        .find(|c| &c.name == b"hIST")
+        .unwrap()
+        .data;
+    assert_eq!(hist.len(), palette.len() * 2);
+    for (entry, pair) in palette.iter().zip(hist.chunks(2)) {
+        let expected = frequencies.iter().find(|(c, _)| c == entry).unwrap().1;
+        assert_eq!(u16::from_be_bytes([pair[0], pair[1]]), expected);
+    }
+}
+
+#[test]
+fn hist_distinct_transparent_entries() {
+    let green = RGBA8::new(0, 255, 0, 255);
+    let clear_red = RGBA8::new(255, 0, 0, 0);
+    let clear_blue = RGBA8::new(0, 0, 255, 0);
+    // The duplicate green entry will be merged, but the transparent ones are different colors
+    let data = vec![0, 1, 2, 3, 0, 1, 2, 3];
+    let mut raw = RawImage::new(
+        data.len() as u32,
+        1,
+        ColorType::Indexed {
+            palette: vec![green, clear_red, green, clear_blue],
+        },
+        BitDepth::Eight,
+        data,
+    )
+    .unwrap();
+    raw.add_png_chunk(
+        *b"hIST",
+        [2u16, 5, 1, 7]
+            .iter()
+            .flat_map(|f| f.to_be_bytes())
+            .collect(),
+    );
+    let optimized = |optimize_alpha: bool| {
+        // Keep the image indexed, which would otherwise be smaller as RGBA
+        let opts = Options {
+            color_type_reduction: false,
+            optimize_alpha,
+            ..Options::default()
+        };
+        let output = raw.create_optimized_png(&opts).unwrap();
+        let png = PngData::from_slice(&output, &Options::default()).unwrap();
+        let palette = match &png.raw().ihdr().color_type {
+            ColorType::Indexed { palette } => palette.clone(),
+            _ => panic!("image should be indexed"),
+        };
+        let hist: Vec<_> = png
+            .aux_chunks()
+            .iter()
+            .find(|c| &c.name == b"hIST")
+            .unwrap()
+            .data
+            .chunks(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect();
+        (palette, hist)
+    };
+
+    let (palette, hist) = optimized(false);
+    let expected = |entry: &RGBA8| match entry {
+        e if *e == green => 3,
+        e if *e == clear_red => 5,
+        e if *e == clear_blue => 7,
+        _ => panic!("unexpected palette entry {:?}", entry),
+    };
+    assert_eq!(palette.len(), 3);
+    assert_eq!(hist, palette.iter().map(expected).collect::<Vec<_>>());
+
+    // Alpha optimization merges the transparent entries, and so their frequencies
+    let (palette, hist) = optimized(true);
+    let expected = |entry: &RGBA8| match entry {
+        e if *e == green => 3,
+        e if e.a == 0 => 12,
+        _ => panic!("unexpected palette entry {:?}", entry),
+    };
+    assert_eq!(palette.len(), 2);
+    assert_eq!(hist, palette.iter().map(expected).collect::<Vec<_>>());
+}
+
+#[test]
+fn hist_invalid() {
+    let png = PngData::new(
+        Path::new("tests/files/rgb_16_should_be_rgb_16.png"),
+        &Options::default(),
+    )
+    .unwrap();
+    // A truecolor image can't have a histogram
+    let input = with_chunk(&png.output(), b"hIST", &[0, 1]);
+    assert!(oxipng::optimize_from_memory(&input, &Options::default()).is_err());
+
+    let opts = Options {
+        fix_errors: true,
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    let png = PngData::from_slice(&output, &opts).unwrap();
+    assert!(png.aux_chunks().iter().all(|c| &c.name != b"hIST"));
+}
+
+#[test]
+fn max_palette_colors() {
+    let input = "tests/files/rgb_8_should_be_rgb_8.png";
+    for max_colors in [2, 16, 200] {
+        let opts = Options {
+            max_palette_colors: Some(max_colors),
+            lossless_only: false,
+            ..Options::default()
+        };
+        let output = oxipng::optimize_from_memory(&std::fs::read(input).unwrap(), &opts).unwrap();
+        let png = PngData::from_slice(&output, &opts).unwrap();
+        let palette = match &png.raw().ihdr().color_type {
+            ColorType::Indexed { palette } => palette,
+            _ => panic!("image should be indexed"),
+        };
+        assert!(palette.len() <= max_colors as usize);
+        let bits = match max_colors {
+            2 => BitDepth::One,
+            16 => BitDepth::Four,
+            _ => BitDepth::Eight,
+        };
+        assert_eq!(png.raw().ihdr().bit_depth, bits);
+    }
+}
+
+#[test]
+fn optimize_alpha_aggressive() {
+    let opts = Options::default();
+    let png = PngData::new(Path::new("tests/files/rgba_8_reduce_alpha.png"), &opts).unwrap();
+    let cleaned = alpha::aggressively_cleaned_alpha_channel(png.raw()).unwrap();
+    assert_eq!(cleaned.ihdr().color_type, png.raw().ihdr().color_type);
+    let mut changed = false;
+    for (orig, new) in png.raw().data().chunks(4).zip(cleaned.data().chunks(4)) {
+        if orig[3] == 0 {
+            assert_eq!(new[3], 0);
+            changed |= new != orig;
+        } else {
+            assert_eq!(new, orig);
+        }
+    }
+    assert!(changed);
+
+    let zeroed = oxipng::optimize_from_memory(
+        &std::fs::read("tests/files/rgba_8_reduce_alpha.png").unwrap(),
+        &Options {
+            optimize_alpha: true,
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    let aggressive = oxipng::optimize_from_memory(
+        &std::fs::read("tests/files/rgba_8_reduce_alpha.png").unwrap(),
+        &Options {
+            optimize_alpha: true,
+            optimize_alpha_aggressive: true,
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    assert!(aggressive.len() <= zeroed.len());
+}
+
+fn rgba_image(pixels: &[[u8; 4]]) -> PngImage {
+    PngImage::new(
+        IhdrData {
+            width: pixels.len() as u32,
+            height: 1,
+            color_type: ColorType::RGBA,
+            bit_depth: BitDepth::Eight,
+            interlaced: Interlacing::None,
+        },
+        pixels.concat(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn alpha_all_opaque_is_removed() {
+    let png = rgba_image(&[[10, 20, 30, 255], [40, 50, 60, 255]]);
+    let reduced = alpha::reduced_alpha_channel(&png, false).unwrap();
+    assert_eq!(
+        reduced.ihdr().color_type,
+        ColorType::RGB {
+            transparent_color: None
+        }
+    );
+    assert_eq!(reduced.data(), [10, 20, 30, 40, 50, 60]);
+}
+
+#[test]
+fn alpha_single_transparent_color_becomes_trns() {
+    let png = rgba_image(&[
+        [10, 20, 30, 255],
+        [1, 2, 3, 0],
+        [40, 50, 60, 255],
+        [1, 2, 3, 0],
+    ]);
+    let reduced = alpha::reduced_alpha_channel(&png, false).unwrap();
+    assert_eq!(
+        reduced.ihdr().color_type,
+        ColorType::RGB {
+            transparent_color: Some(RGB16::new(1, 2, 3))
+        }
+    );
+    assert_eq!(reduced.data(), [10, 20, 30, 1, 2, 3, 40, 50, 60, 1, 2, 3]);
+
+    // The transparent color can't be used if an opaque pixel has it too
+    let png = rgba_image(&[[1, 2, 3, 255], [1, 2, 3, 0]]);
+    assert!(alpha::reduced_alpha_channel(&png, false).is_none());
+    // Nor if the transparent pixels have different colors, unless they may be altered
+    let png = rgba_image(&[[10, 20, 30, 255], [1, 2, 3, 0], [4, 5, 6, 0]]);
+    assert!(alpha::reduced_alpha_channel(&png, false).is_none());
+    let reduced = alpha::reduced_alpha_channel(&png, true).unwrap();
+    assert!(matches!(
+        reduced.ihdr().color_type,
+        ColorType::RGB {
+            transparent_color: Some(_)
+        }
+    ));
+}
+
+/// Build a 4x1 indexed PNG using each of four palette entries once, with the given tRNS data
+fn indexed_png_with_trns(trns: &[u8]) -> Vec<u8> {
+    let chunk = |name: &[u8; 4], data: &[u8], output: &mut Vec<u8>| {
+        output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = output.len();
+        output.extend_from_slice(name);
+        output.extend_from_slice(data);
+        let crc = crc32(&output[start..]);
+        output.extend_from_slice(&crc.to_be_bytes());
+    };
+    let idat = deflate(&[0, 0, 1, 2, 3], 1, &AtomicMin::new(None)).unwrap();
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    chunk(b"IHDR", &[0, 0, 0, 4, 0, 0, 0, 1, 8, 3, 0, 0, 0], &mut png);
+    chunk(
+        b"PLTE",
+        &[0, 0, 0, 80, 80, 80, 160, 160, 160, 240, 240, 240],
+        &mut png,
+    );
+    chunk(b"tRNS", trns, &mut png);
+    chunk(b"IDAT", &idat, &mut png);
+    chunk(b"IEND", &[], &mut png);
+    png
+}
+
+/// Find the data of the tRNS chunk in an encoded PNG
+fn trns_chunk(png: &[u8]) -> Option<&[u8]> {
+    let mut pos = 8;
+    while pos + 8 <= png.len() {
+        let len = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+        if &png[pos + 4..pos + 8] == b"tRNS" {
+            return Some(&png[pos + 8..pos + 8 + len]);
+        }
+        pos += len + 12;
+    }
+    None
+}
+
+#[test]
+fn palette_short_trns() {
+    let png = PngData::from_slice(&indexed_png_with_trns(&[0, 128]), &Options::default()).unwrap();
+    match &png.raw().ihdr().color_type {
+        ColorType::Indexed { palette } => {
+            let alphas: Vec<_> = palette.iter().map(|c| c.a).collect();
+            assert_eq!(alphas, [0, 128, 255, 255]);
+        }
+        _ => panic!("Expected an indexed image"),
+    }
+}
+
+#[test]
+fn palette_trailing_opaque_trns_trimmed() {
+    let cases: [(&[u8], usize); 3] = [
+        (&[0, 128, 255, 255], 2),
+        (&[0, 255, 255], 1),
+        // Sorting the palette should move the transparent entry to the front
+        (&[255, 255, 255, 0], 1),
+    ];
+    for (trns, expected_len) in cases {
+        let input = indexed_png_with_trns(trns);
+        let opts = Options {
+            force: true,
+            color_type_reduction: false,
+            grayscale_reduction: false,
+            ..Options::default()
+        };
+        let output = optimize_from_memory(&input, &opts).unwrap();
+        let output_trns = trns_chunk(&output).unwrap();
+        assert_eq!(output_trns.len(), expected_len, "{:?}", trns);
+        assert_ne!(output_trns.last(), Some(&255), "{:?}", trns);
+
+        let png = PngData::from_slice(&output, &opts).unwrap();
+        let mut alphas: Vec<_> = match &png.raw().ihdr().color_type {
+            ColorType::Indexed { palette } => palette.iter().map(|c| c.a).collect(),
+            _ => panic!("Expected an indexed image"),
+        };
+        let mut expected: Vec<_> = trns.to_vec();
+        expected.resize(4, 255);
+        alphas.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(alphas, expected, "{:?}", trns);
+    }
+}
+
+#[test]
+fn indexed_sub_byte_odd_width() {
+    let indexed = |palette_len: usize, width: u32, data: Vec<u8>| {
+        PngImage::new(
+            IhdrData {
+                width,
+                height: data.len() as u32 / width,
+                color_type: ColorType::Indexed {
+                    palette: (0..palette_len)
+                        .map(|i| RGBA8::new(i as u8 * 16, 0, 0, 255))
+                        .collect(),
+                },
+                bit_depth: BitDepth::Eight,
+                interlaced: Interlacing::None,
+            },
+            data,
+        )
+        .unwrap()
+    };
+    let cases: [(usize, u32, BitDepth, &[u8]); 4] = [
+        // Each row ends in a partial byte, which must be padded with zero bits
+        (16, 3, BitDepth::Four, &[0x12, 0x30, 0xF0, 0xE0]),
+        (4, 3, BitDepth::Two, &[0b0110_1100, 0b1100_0000]),
+        (2, 3, BitDepth::One, &[0b1010_0000, 0b0100_0000]),
+        (
+            2,
+            9,
+            BitDepth::One,
+            &[0b1000_0000, 0b1000_0000, 0b0111_1111, 0b0000_0000],
+        ),
+    ];
+    let rows: [&[u8]; 4] = [
+        &[1, 2, 3, 15, 0, 14],
+        &[1, 2, 3, 3, 0, 0],
+        &[1, 0, 1, 0, 1, 0],
+        &[1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 1, 1, 1, 1, 1, 1, 0],
+    ];
+    for ((palette_len, width, depth, expected), data) in cases.into_iter().zip(rows) {
+        let png = indexed(palette_len, width, data.to_vec());
+        let reduced = bit_depth::reduced_bit_depth_8_or_less(&png).unwrap();
+        assert_eq!(reduced.ihdr().bit_depth, depth);
+        assert_eq!(reduced.data(), expected, "width {} at {:?}", width, depth);
+        let expanded = bit_depth::expanded_bit_depth_to_8(&reduced).unwrap();
+        assert_eq!(
+            expanded.data(),
+            png.data(),
+            "width {} at {:?}",
+            width,
+            depth
+        );
+    }
+
+    // Indices beyond the palette can't be packed into fewer bits
+    assert!(bit_depth::reduced_bit_depth_8_or_less(&indexed(2, 3, vec![0, 1, 2])).is_none());
+
+    // And end-to-end, the image is reduced and decodes to the same pixels
+    let png = indexed(3, 3, vec![0, 1, 2, 2, 1, 0, 1, 1, 1]);
+    let raw = RawImage::new(
+        3,
+        3,
+        png.ihdr().color_type.clone(),
+        BitDepth::Eight,
+        png.data().to_vec(),
+    )
+    .unwrap();
+    let opts = Options {
+        palette_reduction: false,
+        ..Options::default()
+    };
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let decoded = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(decoded.raw().ihdr().bit_depth, BitDepth::Two);
+    let expanded = bit_depth::expanded_bit_depth_to_8(decoded.raw()).unwrap();
+    assert_eq!(expanded.data(), png.data());
+}
+
+#[test]
+fn force_color_type() {
+    let convert = |file: &str, color_type: ColorType, bit_depth: BitDepth| {
+        let input = std::fs::read(file).unwrap();
+        let opts = oxipng::Options {
+            force_color_type: Some((color_type, bit_depth)),
+            ..Default::default()
+        };
+        let output = oxipng::optimize_from_memory(&input, &opts)?;
+        let original = PngData::from_slice(&input, &opts).unwrap();
+        let png = PngData::from_slice(&output, &opts).unwrap();
+        assert_eq!(png.content_hash(), original.content_hash());
+        Ok::<_, PngError>(png.raw().ihdr().clone())
+    };
+
+    let ihdr = convert(
+        "tests/files/grayscale_8_should_be_grayscale_1.png",
+        ColorType::RGBA,
+        BitDepth::Eight,
+    )
+    .unwrap();
+    assert_eq!(ihdr.color_type, ColorType::RGBA);
+    assert_eq!(ihdr.bit_depth, BitDepth::Eight);
+
+    let ihdr = convert(
+        "tests/files/rgb_8_should_be_palette_8.png",
+        ColorType::Indexed {
+            palette: Vec::new(),
+        },
+        BitDepth::Eight,
+    )
+    .unwrap();
+    assert_eq!(ihdr.color_type.png_header_code(), INDEXED);
+    assert_eq!(ihdr.bit_depth, BitDepth::Eight);
+
+    // Expanding to a higher bit depth is exact
+    let ihdr = convert(
+        "tests/files/grayscale_8_should_be_grayscale_8.png",
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Sixteen,
+    )
+    .unwrap();
+    assert_eq!(ihdr.bit_depth, BitDepth::Sixteen);
+
+    // The image must fit the format exactly
+    assert!(convert(
+        "tests/files/rgb_8_should_be_rgb_8.png",
+        ColorType::Grayscale {
+            transparent_shade: None
+        },
+        BitDepth::Eight,
+    )
+    .is_err());
+    // And the combination must be valid
+    assert!(matches!(
+        convert(
+            "tests/files/rgb_8_should_be_palette_8.png",
+            ColorType::Indexed {
+                palette: Vec::new()
+            },
+            BitDepth::Sixteen,
+        ),
+        Err(PngError::InvalidDepthForType(..))
+    ));
+    assert!(OptionsBuilder::new()
+        .force_color_type(Some((ColorType::RGBA, BitDepth::Four)))
+        .build()
+        .is_err());
+}
+
+#[test]
+fn palette_duplicates_merged() {
+    let red = RGBA8::new(255, 0, 0, 255);
+    let green = RGBA8::new(0, 255, 0, 255);
+    let indexed = |palette: Vec<RGBA8>, data: Vec<u8>| {
+        PngImage::new(
+            IhdrData {
+                width: data.len() as u32,
+                height: 1,
+                color_type: ColorType::Indexed { palette },
+                bit_depth: BitDepth::Eight,
+                interlaced: Interlacing::None,
+            },
+            data,
+        )
+        .unwrap()
+    };
+
+    // The duplicate opaque entries are merged into the first of each
+    let png = indexed(vec![red, green, red, green], vec![0, 1, 2, 3, 2, 0]);
+    let reduced = palette::reduced_palette(&png, false).unwrap();
+    assert_eq!(
+        reduced.ihdr().color_type,
+        ColorType::Indexed {
+            palette: vec![red, green]
+        }
+    );
+    assert_eq!(reduced.data(), [0, 1, 0, 1, 0, 0]);
+
+    // Entries with the same color but a different alpha are distinct
+    let translucent_red = RGBA8::new(255, 0, 0, 128);
+    let png = indexed(vec![red, translucent_red], vec![0, 1]);
+    assert!(palette::reduced_palette(&png, false).is_none());
+
+    // And end-to-end, the smaller palette allows a lower bit depth
+    let png = indexed(vec![red, green, red, green], vec![0, 1, 2, 3, 2, 0]);
+    let raw = RawImage::new(
+        6,
+        1,
+        png.ihdr().color_type.clone(),
+        BitDepth::Eight,
+        png.data().to_vec(),
+    )
+    .unwrap();
+    let opts = Options {
+        color_type_reduction: false,
+        ..Options::default()
+    };
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let decoded = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(decoded.raw().ihdr().bit_depth, BitDepth::One);
+    match &decoded.raw().ihdr().color_type {
+        ColorType::Indexed { palette } => assert_eq!(palette.len(), 2),
+        _ => panic!("Expected an indexed image"),
+    }
+}
+
+#[test]
+fn gray_ramp_palette_becomes_grayscale() {
+    let ramp = |transparent: Option<u8>| -> Vec<RGBA8> {
+        (0..16)
+            .map(|i| {
+                let alpha = if Some(i) == transparent { 0 } else { 255 };
+                RGBA8::new(i * 17, i * 17, i * 17, alpha)
+            })
+            .collect()
+    };
+    let data: Vec<u8> = (0..64 * 64)
+        .map(|i| ((i / 64 + i % 64) % 16) as u8)
+        .collect();
+    let optimize = |palette: Vec<RGBA8>| {
+        let raw = RawImage::new(
+            64,
+            64,
+            ColorType::Indexed { palette },
+            BitDepth::Eight,
+            data.clone(),
+        )
+        .unwrap();
+        let output = raw.create_optimized_png(&Options::default()).unwrap();
+        PngData::from_slice(&output, &Options::default()).unwrap()
+    };
+
+    let png = optimize(ramp(None));
+    assert_eq!(
+        png.raw().ihdr().color_type,
+        ColorType::Grayscale {
+            transparent_shade: None
+        }
+    );
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Four);
+
+    // The transparent entry becomes the transparent shade
+    let png = optimize(ramp(Some(0)));
+    assert_eq!(
+        png.raw().ihdr().color_type,
+        ColorType::Grayscale {
+            transparent_shade: Some(0)
+        }
+    );
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Four);
+
+    // Partial transparency, or a transparent shade shared with an opaque entry, can't be a tRNS
+    let indexed = |palette: Vec<RGBA8>| {
+        PngImage::new(
+            IhdrData {
+                width: 2,
+                height: 1,
+                color_type: ColorType::Indexed { palette },
+                bit_depth: BitDepth::Eight,
+                interlaced: Interlacing::None,
+            },
+            vec![0, 1],
+        )
+        .unwrap()
+    };
+    let gray = |v, a| RGBA8::new(v, v, v, a);
+    assert!(color::indexed_to_grayscale(&indexed(vec![gray(0, 255), gray(0, 0)])).is_none());
+    assert!(color::indexed_to_grayscale(&indexed(vec![gray(0, 255), gray(9, 128)])).is_none());
+    let reduced = color::indexed_to_grayscale(&indexed(vec![gray(7, 255), gray(9, 0)])).unwrap();
+    assert_eq!(reduced.data(), [7, 9]);
+    assert_eq!(
+        reduced.ihdr().color_type,
+        ColorType::Grayscale {
+            transparent_shade: Some(9)
+        }
+    );
+}
+
+#[test]
+fn force_bit_depth() {
+    let optimize = |file: &str, opts: &oxipng::Options| {
+        let input = std::fs::read(file).unwrap();
+        let output = oxipng::optimize_from_memory(&input, opts)?;
+        Ok::<_, PngError>(PngData::from_slice(&output, opts).unwrap())
+    };
+    let forced = |bit_depth| oxipng::Options {
+        force_bit_depth: Some(bit_depth),
+        ..Default::default()
+    };
+
+    // A higher depth than necessary is kept, along with the reduced color type
+    let file = "tests/files/grayscale_8_should_be_grayscale_1.png";
+    let png = optimize(file, &forced(BitDepth::Eight)).unwrap();
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), GRAYSCALE);
+    let original = PngData::from_slice(&std::fs::read(file).unwrap(), &forced(BitDepth::Eight));
+    assert_eq!(png.content_hash(), original.unwrap().content_hash());
+
+    // Lower depths are only used exactly
+    let png = optimize(file, &forced(BitDepth::Two)).unwrap();
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Two);
+
+    // The data of this image needs all 16 bits, so it can't be reduced without loss
+    let file = "tests/files/rgb_16_should_be_rgb_16.png";
+    assert!(optimize(file, &forced(BitDepth::Eight)).is_err());
+    let opts = oxipng::Options {
+        scale_16: true,
+        lossless_only: false,
+        ..forced(BitDepth::Eight)
+    };
+    let png = optimize(file, &opts).unwrap();
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
+
+    // The depth must be valid for the color type
+    assert!(matches!(
+        optimize(file, &forced(BitDepth::Four)),
+        Err(PngError::InvalidDepthForType(BitDepth::Four, _))
+    ));
+    let opts = oxipng::Options {
+        force_color_type: Some((ColorType::RGBA, BitDepth::Sixteen)),
+        ..forced(BitDepth::Eight)
+    };
+    assert!(optimize(file, &opts).is_err());
+}
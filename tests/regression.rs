@@ -39,11 +39,15 @@ fn test_it_converts(
     let png = PngData::new(&input, &opts).unwrap();
 
     assert_eq!(
-        png.raw.ihdr.color_type.png_header_code(),
+        png.raw().ihdr().color_type.png_header_code(),
         color_type_in,
         "test file is broken"
     );
-    assert_eq!(png.raw.ihdr.bit_depth, bit_depth_in, "test file is broken");
+    assert_eq!(
+        png.raw().ihdr().bit_depth,
+        bit_depth_in,
+        "test file is broken"
+    );
 
     match oxipng::optimize(&InFile::Path(input), &output, &opts) {
         Ok(_) => (),
@@ -61,16 +65,17 @@ fn test_it_converts(
     };
 
     assert_eq!(
-        png.raw.ihdr.color_type.png_header_code(),
+        png.raw().ihdr().color_type.png_header_code(),
         color_type_out,
         "optimized to wrong color type"
     );
     assert_eq!(
-        png.raw.ihdr.bit_depth, bit_depth_out,
+        png.raw().ihdr().bit_depth,
+        bit_depth_out,
         "optimized to wrong bit depth"
     );
-    if let ColorType::Indexed { palette } = &png.raw.ihdr.color_type {
-        assert!(palette.len() <= 1 << (png.raw.ihdr.bit_depth as u8));
+    if let ColorType::Indexed { palette } = &png.raw().ihdr().color_type {
+        assert!(palette.len() <= 1 << (png.raw().ihdr().bit_depth as u8));
     }
 
     remove_file(output).ok();
@@ -96,9 +101,9 @@ fn issue_42() {
 
     let png = PngData::new(&input, &opts).unwrap();
 
-    assert_eq!(png.raw.ihdr.interlaced, Interlacing::None);
-    assert_eq!(png.raw.ihdr.color_type, ColorType::GrayscaleAlpha);
-    assert_eq!(png.raw.ihdr.bit_depth, BitDepth::Eight);
+    assert_eq!(png.raw().ihdr().interlaced, Interlacing::None);
+    assert_eq!(png.raw().ihdr().color_type, ColorType::GrayscaleAlpha);
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
 
     match oxipng::optimize(&InFile::Path(input), &output, &opts) {
         Ok(_) => (),
@@ -115,9 +120,9 @@ fn issue_42() {
         }
     };
 
-    assert_eq!(png.raw.ihdr.interlaced, Interlacing::Adam7);
-    assert_eq!(png.raw.ihdr.color_type, ColorType::GrayscaleAlpha);
-    assert_eq!(png.raw.ihdr.bit_depth, BitDepth::Eight);
+    assert_eq!(png.raw().ihdr().interlaced, Interlacing::Adam7);
+    assert_eq!(png.raw().ihdr().color_type, ColorType::GrayscaleAlpha);
+    assert_eq!(png.raw().ihdr().bit_depth, BitDepth::Eight);
 
     remove_file(output).ok();
 }
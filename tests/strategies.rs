@@ -39,8 +39,8 @@ fn test_it_converts(
     let png = PngData::new(&input, &opts).unwrap();
     opts.filter = IndexSet::new();
     opts.filter.insert(filter);
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), color_type_in);
-    assert_eq!(png.raw.ihdr.bit_depth, bit_depth_in);
+    assert_eq!(png.raw().ihdr().color_type.png_header_code(), color_type_in);
+    assert_eq!(png.raw().ihdr().bit_depth, bit_depth_in);
 
     match oxipng::optimize(&InFile::Path(input), &output, &opts) {
         Ok(_) => (),
@@ -57,10 +57,13 @@ fn test_it_converts(
         }
     };
 
-    assert_eq!(png.raw.ihdr.color_type.png_header_code(), color_type_out);
-    assert_eq!(png.raw.ihdr.bit_depth, bit_depth_out);
-    if let ColorType::Indexed { palette } = &png.raw.ihdr.color_type {
-        assert!(palette.len() <= 1 << (png.raw.ihdr.bit_depth as u8));
+    assert_eq!(
+        png.raw().ihdr().color_type.png_header_code(),
+        color_type_out
+    );
+    assert_eq!(png.raw().ihdr().bit_depth, bit_depth_out);
+    if let ColorType::Indexed { palette } = &png.raw().ihdr().color_type {
+        assert!(palette.len() <= 1 << (png.raw().ihdr().bit_depth as u8));
     }
 
     remove_file(output).ok();
@@ -125,3 +128,56 @@ fn filter_brute() {
         BitDepth::Eight,
     );
 }
+
+#[test]
+fn filter_weighted_msad() {
+    test_it_converts(
+        "tests/files/rgb_8_should_be_rgb_8.png",
+        RowFilter::WeightedMsad,
+        RGB,
+        BitDepth::Eight,
+        RGB,
+        BitDepth::Eight,
+    );
+}
+
+#[test]
+fn filter_weighted_msad_deterministic() {
+    let input = PathBuf::from("tests/files/rgb_16_should_be_rgb_16.png");
+    let png = PngData::new(&input, &Options::default()).unwrap();
+
+    let filtered = png.raw().filter_image(RowFilter::WeightedMsad, false);
+    assert_eq!(
+        filtered,
+        png.raw().filter_image(RowFilter::WeightedMsad, false)
+    );
+    assert_eq!(
+        filtered.len(),
+        png.raw().filter_image(RowFilter::MinSum, false).len()
+    );
+}
+
+#[test]
+fn filter_lookback() {
+    test_it_converts(
+        "tests/files/rgba_8_should_be_rgba_8.png",
+        RowFilter::LookBack,
+        RGBA,
+        BitDepth::Eight,
+        RGBA,
+        BitDepth::Eight,
+    );
+}
+
+#[test]
+fn filter_lookback_size() {
+    // A photographic image
+    let input = PathBuf::from("tests/files/rgb_8_should_be_rgb_8.png");
+    let png = PngData::new(&input, &Options::default()).unwrap();
+
+    let size = |filter| {
+        let filtered = png.raw().filter_image(filter, false);
+        deflate(&filtered, 9, &AtomicMin::new(None)).unwrap().len()
+    };
+    assert!(size(RowFilter::LookBack) < size(RowFilter::MinSum));
+}
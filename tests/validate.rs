@@ -0,0 +1,443 @@
+use oxipng::internal_tests::*;
+use oxipng::*;
+
+/// Encode the given chunks as a PNG file, with correct CRCs and an IEND chunk
+fn png(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+    let mut output = b"\x89PNG\r\n\x1a\n".to_vec();
+    for &(name, data) in chunks.iter().chain([(b"IEND", &[][..])].iter()) {
+        output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = output.len();
+        output.extend_from_slice(name);
+        output.extend_from_slice(data);
+        let crc = crc32(&output[start..]);
+        output.extend_from_slice(&crc.to_be_bytes());
+    }
+    output
+}
+
+/// IHDR data for a 2x1 image
+fn ihdr(bit_depth: u8, color_type: u8) -> [u8; 13] {
+    [0, 0, 0, 2, 0, 0, 0, 1, bit_depth, color_type, 0, 0, 0]
+}
+
+/// Compress image data, which starts with the filter type of the row
+fn idat(row: &[u8]) -> Vec<u8> {
+    deflate(row, 1, &AtomicMin::new(None)).unwrap()
+}
+
+fn gray_png(extra: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+    let ihdr = ihdr(8, 0);
+    let idat = idat(&[0, 10, 20]);
+    let mut chunks = vec![(b"IHDR", &ihdr[..])];
+    chunks.extend_from_slice(extra);
+    chunks.push((b"IDAT", &idat));
+    png(&chunks)
+}
+
+#[test]
+fn validate_accepts_optimized_output() {
+    for file in [
+        "tests/files/rgb_8_should_be_rgb_8.png",
+        "tests/files/palette_8_should_be_palette_8.png",
+        "tests/files/interlaced_rgb_16_should_be_rgb_16.png",
+        "tests/files/issue-82.png",
+    ] {
+        let input = std::fs::read(file).unwrap();
+        let output = optimize_from_memory(&input, &Options::default()).unwrap();
+        validate_png(&output).unwrap();
+    }
+    validate_png(&gray_png(&[])).unwrap();
+}
+
+#[test]
+fn validate_signature() {
+    let mut data = gray_png(&[]);
+    data[1] = b'Q';
+    assert!(matches!(validate_png(&data), Err(PngError::NotPNG)));
+    assert!(matches!(validate_png(b"\x89PNG"), Err(PngError::NotPNG)));
+}
+
+#[test]
+fn validate_crc() {
+    let mut data = gray_png(&[(b"gAMA", &[0, 0, 177, 143])]);
+    let gama = data.windows(4).position(|w| w == b"gAMA").unwrap();
+    data[gama + 8] ^= 1;
+    assert!(matches!(
+        validate_png(&data),
+        Err(PngError::CRCMismatch(name)) if &name == b"gAMA"
+    ));
+
+    let mut data = gray_png(&[]);
+    let len = data.len();
+    data[len - 1] ^= 1;
+    assert!(matches!(
+        validate_png(&data),
+        Err(PngError::CRCMismatch(name)) if &name == b"IEND"
+    ));
+}
+
+#[test]
+fn validate_chunk_order() {
+    let ihdr = ihdr(8, 3);
+    let plte = [0; 6];
+    let idat = idat(&[0, 0, 1]);
+    let gama = [0, 0, 177, 143];
+    let out_of_order = |chunks: &[(&[u8; 4], &[u8])], expected: &[u8; 4]| {
+        assert!(
+            matches!(validate_png(&png(chunks)), Err(PngError::ChunkOutOfOrder(name)) if &name == expected),
+            "{}",
+            String::from_utf8_lossy(expected)
+        );
+    };
+    out_of_order(&[(b"PLTE", &plte), (b"IHDR", &ihdr)], b"PLTE");
+    out_of_order(
+        &[(b"IHDR", &ihdr), (b"IDAT", &idat), (b"PLTE", &plte)],
+        b"PLTE",
+    );
+    out_of_order(
+        &[
+            (b"IHDR", &ihdr),
+            (b"PLTE", &plte),
+            (b"gAMA", &gama),
+            (b"IDAT", &idat),
+        ],
+        b"gAMA",
+    );
+    out_of_order(
+        &[
+            (b"IHDR", &ihdr),
+            (b"tRNS", &[0]),
+            (b"PLTE", &plte),
+            (b"IDAT", &idat),
+        ],
+        b"tRNS",
+    );
+    out_of_order(
+        &[
+            (b"IHDR", &ihdr),
+            (b"PLTE", &plte),
+            (b"IDAT", &idat),
+            (b"pHYs", &[0; 9]),
+        ],
+        b"pHYs",
+    );
+    // Image data split by another chunk
+    out_of_order(
+        &[
+            (b"IHDR", &ihdr),
+            (b"PLTE", &plte),
+            (b"IDAT", &idat[..2]),
+            (b"tEXt", b"a\0b"),
+            (b"IDAT", &idat[2..]),
+        ],
+        b"IDAT",
+    );
+
+    let data = png(&[
+        (b"IHDR", &ihdr),
+        (b"PLTE", &plte),
+        (b"tIME", &[7, 230, 1, 1, 0, 0, 0]),
+        (b"IDAT", &idat),
+        (b"tIME", &[7, 230, 1, 1, 0, 0, 0]),
+    ]);
+    assert!(matches!(
+        validate_png(&data),
+        Err(PngError::InvalidChunk(name, _)) if &name == b"tIME"
+    ));
+}
+
+#[test]
+fn validate_header() {
+    let idat = idat(&[0, 0, 0]);
+    let invalid = [
+        // Zero width
+        [0, 0, 0, 0, 0, 0, 0, 1, 8, 0, 0, 0, 0],
+        // 16-bit indexed
+        ihdr(16, 3),
+        // 4-bit RGB
+        ihdr(4, 2),
+        // Unknown color type
+        ihdr(8, 5),
+        // Unknown interlacing
+        [0, 0, 0, 2, 0, 0, 0, 1, 8, 0, 0, 0, 2],
+        // Unknown compression method
+        [0, 0, 0, 2, 0, 0, 0, 1, 8, 0, 1, 0, 0],
+    ];
+    for ihdr in invalid {
+        let data = png(&[(b"IHDR", &ihdr), (b"IDAT", &idat)]);
+        assert!(
+            matches!(validate_png(&data), Err(PngError::InvalidHeader(_))),
+            "{:?}",
+            ihdr
+        );
+    }
+    let data = png(&[(b"IHDR", &ihdr(8, 0)[..12]), (b"IDAT", &idat)]);
+    assert!(matches!(
+        validate_png(&data),
+        Err(PngError::InvalidHeader(_))
+    ));
+}
+
+#[test]
+fn validate_palette_and_transparency() {
+    let idat = idat(&[0, 0, 1]);
+    let invalid_chunk = |ihdr: [u8; 13], chunks: &[(&[u8; 4], &[u8])], expected: &[u8; 4]| {
+        let mut all = vec![(b"IHDR", &ihdr[..])];
+        all.extend_from_slice(chunks);
+        all.push((b"IDAT", &idat));
+        assert!(
+            matches!(validate_png(&png(&all)), Err(PngError::InvalidChunk(name, _)) if &name == expected),
+            "{:?} {:?}",
+            ihdr,
+            chunks
+        );
+    };
+    // Palette length not a multiple of 3
+    invalid_chunk(ihdr(8, 3), &[(b"PLTE", &[0; 7])], b"PLTE");
+    // Too many entries for the bit depth
+    invalid_chunk(ihdr(1, 3), &[(b"PLTE", &[0; 9])], b"PLTE");
+    // Palette in a grayscale image
+    invalid_chunk(ihdr(8, 0), &[(b"PLTE", &[0; 6])], b"PLTE");
+    // More transparency entries than palette entries
+    invalid_chunk(
+        ihdr(8, 3),
+        &[(b"PLTE", &[0; 6]), (b"tRNS", &[0; 3])],
+        b"tRNS",
+    );
+    // Wrong length for a single transparent color
+    invalid_chunk(ihdr(8, 0), &[(b"tRNS", &[0; 6])], b"tRNS");
+    invalid_chunk(ihdr(8, 2), &[(b"tRNS", &[0; 2])], b"tRNS");
+    // Transparency with an alpha channel
+    invalid_chunk(ihdr(8, 6), &[(b"tRNS", &[0; 6])], b"tRNS");
+
+    // A missing palette
+    let data = png(&[(b"IHDR", &ihdr(8, 3)), (b"IDAT", &idat)]);
+    assert!(matches!(
+        validate_png(&data),
+        Err(PngError::ChunkMissing("PLTE"))
+    ));
+    // A short tRNS is allowed
+    let data = png(&[
+        (b"IHDR", &ihdr(8, 3)),
+        (b"PLTE", &[0; 6]),
+        (b"tRNS", &[0]),
+        (b"IDAT", &idat),
+    ]);
+    validate_png(&data).unwrap();
+}
+
+#[test]
+fn validate_image_data() {
+    let ihdr = ihdr(8, 0);
+    let check = |idat: &[u8]| validate_png(&png(&[(b"IHDR", &ihdr), (b"IDAT", idat)]));
+
+    assert!(matches!(
+        validate_png(&png(&[(b"IHDR", &ihdr)])),
+        Err(PngError::ChunkMissing("IDAT"))
+    ));
+    assert!(matches!(
+        check(&idat(&[0, 1])),
+        Err(PngError::IncorrectDataLength(2, 3))
+    ));
+    assert!(matches!(
+        check(&idat(&[0, 1, 2, 3])),
+        Err(PngError::InvalidChunk(name, _)) if &name == b"IDAT"
+    ));
+    assert!(matches!(check(&[1, 2, 3, 4]), Err(PngError::InvalidData)));
+    // Unknown filter type
+    assert!(matches!(
+        check(&idat(&[9, 1, 2])),
+        Err(PngError::InvalidData)
+    ));
+}
+
+#[test]
+fn validate_end() {
+    let mut data = gray_png(&[]);
+    data.push(0);
+    assert!(matches!(
+        validate_png(&data),
+        Err(PngError::InvalidChunk(name, _)) if &name == b"IEND"
+    ));
+
+    let data = gray_png(&[]);
+    assert!(matches!(
+        validate_png(&data[..data.len() - 12]),
+        Err(PngError::TruncatedData)
+    ));
+}
+
+#[test]
+fn leading_data() {
+    let png = gray_png(&[]);
+    let mut data = b"\xEF\xBB\xBFa junk prefix".to_vec();
+    data.extend_from_slice(&png);
+    assert_eq!(data.len(), png.len() + 16);
+
+    // Only the start of the data is searched for the signature when not fixing errors
+    let opts = Options::default();
+    assert!(matches!(
+        PngData::from_slice(&data, &opts),
+        Err(PngError::NotPNG)
+    ));
+    assert!(optimize_from_memory(&data, &opts).is_err());
+
+    let opts = Options {
+        fix_errors: true,
+        ..Options::default()
+    };
+    let recovered = PngData::from_slice(&data, &opts).unwrap();
+    let original = PngData::from_slice(&png, &opts).unwrap();
+    assert_eq!(recovered.raw().data(), original.raw().data());
+    let output = optimize_from_memory(&data, &opts).unwrap();
+    assert!(output.starts_with(b"\x89PNG\r\n\x1a\n"));
+    assert_eq!(
+        PngData::from_slice(&output, &Options::default())
+            .unwrap()
+            .raw()
+            .data(),
+        original.raw().data()
+    );
+
+    // Data with no signature at all is still rejected
+    assert!(matches!(
+        PngData::from_slice(&data[..16], &opts),
+        Err(PngError::NotPNG)
+    ));
+}
+
+#[test]
+fn parse_errors() {
+    let opts = Options::default();
+    let mut data = gray_png(&[]);
+    data[0] = 0;
+    assert!(matches!(
+        PngData::from_slice(&data, &opts),
+        Err(PngError::NotPNG)
+    ));
+
+    // Cut the IDAT chunk short, leaving its length and name but only 2 bytes of data
+    let data = gray_png(&[]);
+    let idat = data.windows(4).position(|w| w == b"IDAT").unwrap();
+    let length = u32::from_be_bytes(data[idat - 4..idat].try_into().unwrap()) as usize;
+    assert!(matches!(
+        PngData::from_slice(&data[..idat + 6], &opts),
+        Err(PngError::TruncatedChunk { chunk, expected, got })
+            if &chunk == b"IDAT" && expected == length + 4 && got == 2
+    ));
+    // Without the name of the chunk, there is nothing more to say
+    assert!(matches!(
+        PngData::from_slice(&data[..idat + 2], &opts),
+        Err(PngError::TruncatedData)
+    ));
+
+    let mut data = gray_png(&[]);
+    data[idat + 4] ^= 0xFF;
+    assert!(matches!(
+        PngData::from_slice(&data, &opts),
+        Err(PngError::CRCMismatch(name)) if &name == b"IDAT"
+    ));
+
+    let idat = self::idat(&[0, 10, 20]);
+    for ihdr in [
+        ihdr(8, 5),
+        ihdr(3, 0),
+        [0, 0, 0, 2, 0, 0, 0, 1, 8, 0, 0, 0, 2],
+    ] {
+        let data = png(&[(b"IHDR", &ihdr), (b"IDAT", &idat)]);
+        assert!(
+            matches!(
+                PngData::from_slice(&data, &opts),
+                Err(PngError::InvalidHeader(_))
+            ),
+            "{:?}",
+            ihdr
+        );
+    }
+
+    for (name, chunk) in [
+        (b"oFFs", &[0; 8][..]),
+        (b"oFFs", &[0, 0, 0, 0, 0, 0, 0, 0, 2]),
+        (b"cLLI", &[0; 9]),
+    ] {
+        let data = gray_png(&[(name, chunk)]);
+        assert!(
+            matches!(
+                PngData::from_slice(&data, &opts),
+                Err(PngError::InvalidChunk(n, _)) if &n == name
+            ),
+            "{:?}",
+            chunk
+        );
+    }
+    assert!(matches!(
+        parse_phys_chunk(&[0; 8]),
+        Err(PngError::InvalidChunk(name, _)) if &name == b"pHYs"
+    ));
+}
+
+#[test]
+fn collect_warnings() {
+    let opts = Options {
+        collect_warnings: true,
+        ..Options::default()
+    };
+    let ihdr = ihdr(8, 0);
+    let idat = idat(&[0, 10, 20]);
+    let gama = 45455u32.to_be_bytes();
+    // The optimizer accepts a gAMA chunk after the image data, but the specification does not
+    let data = png(&[(b"IHDR", &ihdr), (b"IDAT", &idat), (b"gAMA", &gama)]);
+    let (_, report) = optimize_from_memory_with_report(&data, &opts).unwrap();
+    assert_eq!(report.warnings, [PngWarning::ChunkOutOfOrder(*b"gAMA")]);
+
+    let (_, report) = optimize_from_memory_with_report(&data, &Options::default()).unwrap();
+    assert!(report.warnings.is_empty());
+    let (_, report) = optimize_from_memory_with_report(&gray_png(&[]), &opts).unwrap();
+    assert!(report.warnings.is_empty());
+
+    let mut data = gray_png(&[]);
+    data.extend_from_slice(b"junk");
+    let (_, report) = optimize_from_memory_with_report(&data, &opts).unwrap();
+    assert_eq!(report.warnings, [PngWarning::TrailingData(4)]);
+
+    let mut data = vec![0xEF; 16];
+    data.extend_from_slice(&gray_png(&[]));
+    let opts = Options {
+        fix_errors: true,
+        ..opts.clone()
+    };
+    let (_, report) = optimize_from_memory_with_report(&data, &opts).unwrap();
+    assert_eq!(report.warnings, [PngWarning::LeadingData(16)]);
+
+    let ihdr = self::ihdr(8, 3);
+    let idat = self::idat(&[0, 0, 1]);
+    let data = png(&[(b"IHDR", &ihdr), (b"PLTE", &[1, 2, 3]), (b"IDAT", &idat)]);
+    let (_, report) = optimize_from_memory_with_report(&data, &opts).unwrap();
+    assert_eq!(
+        report.warnings,
+        [PngWarning::PaletteIndexOutOfRange {
+            palette: 1,
+            index: 1
+        }]
+    );
+}
+
+#[test]
+fn pixels_equal_across_color_types() {
+    let input = std::fs::read("tests/files/rgb_8_should_be_palette_8.png").unwrap();
+    let output = optimize_from_memory(&input, &Options::default()).unwrap();
+    let reduced = PngData::from_slice(&output, &Options::default()).unwrap();
+    assert!(matches!(
+        reduced.raw().ihdr().color_type,
+        ColorType::Indexed { .. }
+    ));
+    assert!(pixels_equal(&input, &output).unwrap());
+
+    let ihdr = self::ihdr(8, 2);
+    let rgb = |row: &[u8]| png(&[(b"IHDR", &ihdr[..]), (b"IDAT", &idat(row))]);
+    let gray = gray_png(&[]);
+    assert!(pixels_equal(&gray, &rgb(&[0, 10, 10, 10, 20, 20, 20])).unwrap());
+    assert!(!pixels_equal(&gray, &rgb(&[0, 10, 10, 10, 20, 20, 21])).unwrap());
+    assert!(!pixels_equal(&gray, &input).unwrap());
+    assert!(pixels_equal(&gray, b"not a png").is_err());
+}